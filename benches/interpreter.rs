@@ -0,0 +1,65 @@
+use bfi::{Bytecode, Interpreter, OptimisationsFlags, Pass};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+const PROGRAMS: &[&str] = &["hello_world", "bottles", "mandelbrot"];
+
+fn read(name: &str) -> String {
+    std::fs::read_to_string(format!("sample_programs/{}.bf", name)).unwrap()
+}
+
+fn execute(c: &mut Criterion) {
+    let mut group = c.benchmark_group("execute");
+    group.sample_size(10);
+
+    for name in PROGRAMS {
+        let instructions = bfc_ir::parse(&read(name)).unwrap();
+        let (instructions, _) = bfc_ir::optimize(instructions, OptimisationsFlags::all());
+        let bytecode = Bytecode::compile(&instructions).optimize(Pass::ALL);
+        let interpreter = Interpreter::from_bytecode(bytecode, None);
+
+        group.bench_function(*name, |b| {
+            b.iter(|| interpreter.run_outcome(vec![]).into_result().unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn lowering(c: &mut Criterion) {
+    let instructions = bfc_ir::parse(&read("mandelbrot")).unwrap();
+    let (instructions, _) = bfc_ir::optimize(instructions, OptimisationsFlags::all());
+    let walked = Interpreter::builder()
+        .tree_walker(true)
+        .build(instructions.clone());
+    let interpreter = Interpreter::from_bytecode(Bytecode::compile(&instructions), None);
+
+    let mut group = c.benchmark_group("lowering");
+    group.sample_size(10);
+    group.bench_function("tree", |b| {
+        b.iter(|| walked.run_outcome(vec![]).into_result().unwrap())
+    });
+    group.bench_function("bytecode", |b| {
+        b.iter(|| interpreter.run_outcome(vec![]).into_result().unwrap())
+    });
+    group.finish();
+}
+
+fn phases(c: &mut Criterion) {
+    let program = read("mandelbrot");
+    let instructions = bfc_ir::parse(&program).unwrap();
+    let (optimized, _) = bfc_ir::optimize(instructions.clone(), OptimisationsFlags::all());
+    let bytecode = Bytecode::compile(&optimized);
+
+    let mut group = c.benchmark_group("phases");
+    group.bench_function("parse", |b| b.iter(|| bfc_ir::parse(&program).unwrap()));
+    group.bench_function("optimize", |b| {
+        b.iter(|| bfc_ir::optimize(instructions.clone(), OptimisationsFlags::all()))
+    });
+    group.bench_function("compile", |b| b.iter(|| Bytecode::compile(&optimized)));
+    group.bench_function("passes", |b| {
+        b.iter(|| bytecode.clone().optimize(Pass::ALL))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, execute, lowering, phases);
+criterion_main!(benches);
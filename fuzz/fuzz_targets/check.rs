@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The first byte splits the data into a program and its input
+fuzz_target!(|data: &[u8]| {
+    let Some((&split, data)) = data.split_first() else {
+        return;
+    };
+    let (program, input) = data.split_at((split as usize).min(data.len()));
+
+    if let Err(failure) = bfi::fuzz::check(&bfi::fuzz::program(program), input) {
+        panic!("{}", failure);
+    }
+});
@@ -0,0 +1,70 @@
+/*
+ * C API for bfi, build with `cargo build --release --features capi` and link against
+ * target/release/libbfi.so (or .dylib / .dll).
+ *
+ *     BfiProgram *program;
+ *     if (bfi_parse(source, strlen(source), 0, &program) != BFI_OK) {
+ *         fprintf(stderr, "%s\n", bfi_last_error());
+ *         return 1;
+ *     }
+ *
+ *     uint8_t *output;
+ *     size_t output_len;
+ *     BfiStatus status = bfi_run(program, input, input_len, &output, &output_len);
+ *     fwrite(output, 1, output_len, stdout);
+ *     bfi_free_output(output, output_len);
+ *     bfi_program_free(program);
+ */
+
+#ifndef BFI_H
+#define BFI_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum BfiStatus {
+    BFI_OK = 0,
+    BFI_PARSE_ERROR = 1,
+    BFI_RUN_TIME_ERROR = 2,
+    /* A null pointer where one isn't allowed, or source that isn't UTF-8 */
+    BFI_INVALID_ARGUMENT = 3,
+} BfiStatus;
+
+/* A parsed and optimized program, can be run any number of times */
+typedef struct BfiProgram BfiProgram;
+
+/* Returns a byte, or a negative number at the end of input */
+typedef int (*BfiRead)(void *user_data);
+typedef void (*BfiWrite)(void *user_data, uint8_t byte);
+
+/* Why the last call on this thread failed, or NULL. Valid until the next failing call on the
+ * same thread. */
+const char *bfi_last_error(void);
+
+/* Parses `len` bytes of `source` into `*program`, free it with bfi_program_free. Runs stop after
+ * `max_iterations` instructions, 0 means unlimited. */
+BfiStatus bfi_parse(const uint8_t *source, size_t len, uint64_t max_iterations,
+                    BfiProgram **program);
+
+void bfi_program_free(BfiProgram *program);
+
+/* Runs `program` with all of its input up front. `*output` is set even on BFI_RUN_TIME_ERROR to
+ * whatever was written before the error, free it with bfi_free_output. */
+BfiStatus bfi_run(const BfiProgram *program, const uint8_t *input, size_t input_len,
+                  uint8_t **output, size_t *output_len);
+
+void bfi_free_output(uint8_t *output, size_t len);
+
+/* Runs `program` calling `read` whenever it reads and `write` with every byte it writes */
+BfiStatus bfi_run_with_callbacks(const BfiProgram *program, BfiRead read, BfiWrite write,
+                                 void *user_data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
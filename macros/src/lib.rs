@@ -0,0 +1,130 @@
+//! The `bf!` macro, re-exported by bfi behind its `macros` feature
+//!
+//! Programs are parsed and run through `bfc_ir`'s optimizer while the calling crate compiles, so
+//! unbalanced brackets are compile errors and nothing is parsed at run time.
+
+use bfc_ir::{AstNode, OptimisationsFlags, Position};
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, LitStr};
+
+/// Parses and optimizes a Brainfuck program at compile time, expanding to a [`bfi::Program`]
+///
+/// ```ignore
+/// let program = bfi::bf!("++[->+<]");
+/// ```
+#[proc_macro]
+pub fn bf(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let source = parse_macro_input!(input as LitStr);
+
+    let text = source.value();
+    let instructions = match bfc_ir::parse(&text) {
+        Ok(instructions) => instructions,
+        Err(err) => {
+            let message = format!(
+                "parse error at offset {}: {}",
+                err.position.start, err.message
+            );
+            return syn::Error::new(source.span(), message)
+                .to_compile_error()
+                .into();
+        }
+    };
+    let (instructions, _) = bfc_ir::optimize(instructions, OptimisationsFlags::all());
+    let instructions = nodes(&instructions);
+
+    quote! {
+        ::bfi::__private::program(#text, #instructions)
+    }
+    .into()
+}
+
+/// An expression building `instructions` again
+fn nodes(instructions: &[AstNode]) -> TokenStream {
+    let nodes = instructions.iter().map(node);
+    quote!(::bfi::__private::vec![#(#nodes),*])
+}
+
+fn node(instruction: &AstNode) -> TokenStream {
+    match instruction {
+        AstNode::Increment {
+            amount,
+            offset,
+            position,
+        } => {
+            let (amount, position) = (amount.0, position_of(position));
+            quote! {
+                ::bfi::__private::AstNode::Increment {
+                    amount: ::core::num::Wrapping(#amount),
+                    offset: #offset,
+                    position: #position,
+                }
+            }
+        }
+        AstNode::PointerIncrement { amount, position } => {
+            let position = position_of(position);
+            quote! {
+                ::bfi::__private::AstNode::PointerIncrement {
+                    amount: #amount,
+                    position: #position,
+                }
+            }
+        }
+        AstNode::Read { position } => {
+            let position = position_of(position);
+            quote!(::bfi::__private::AstNode::Read { position: #position })
+        }
+        AstNode::Write { position } => {
+            let position = position_of(position);
+            quote!(::bfi::__private::AstNode::Write { position: #position })
+        }
+        AstNode::Set {
+            amount,
+            offset,
+            position,
+        } => {
+            let (amount, position) = (amount.0, position_of(position));
+            quote! {
+                ::bfi::__private::AstNode::Set {
+                    amount: ::core::num::Wrapping(#amount),
+                    offset: #offset,
+                    position: #position,
+                }
+            }
+        }
+        AstNode::MultiplyMove { changes, position } => {
+            // Sorted so the expansion doesn't depend on map ordering
+            let mut changes: Vec<_> = changes.iter().map(|(k, v)| (*k, v.0)).collect();
+            changes.sort_unstable();
+            let (offsets, factors): (Vec<_>, Vec<_>) = changes.into_iter().unzip();
+            let position = position_of(position);
+            quote! {
+                ::bfi::__private::AstNode::MultiplyMove {
+                    changes: ::core::iter::IntoIterator::into_iter([
+                        #((#offsets, ::core::num::Wrapping(#factors))),*
+                    ])
+                    .collect(),
+                    position: #position,
+                }
+            }
+        }
+        AstNode::Loop { body, position } => {
+            let (body, position) = (nodes(body), position_of(position));
+            quote! {
+                ::bfi::__private::AstNode::Loop {
+                    body: #body,
+                    position: #position,
+                }
+            }
+        }
+    }
+}
+
+fn position_of(position: &Option<Position>) -> TokenStream {
+    match position {
+        Some(Position { start, end }) => quote! {
+            ::core::option::Option::Some(::bfi::Position { start: #start, end: #end })
+        },
+        None => quote!(::core::option::Option::None),
+    }
+}
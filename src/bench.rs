@@ -0,0 +1,62 @@
+//! Times each phase of running a program separately, to tell which one got slower
+
+use std::time::{Duration, Instant};
+
+use crate::{Bytecode, Error, Interpreter, OptimisationsFlags, Pass};
+
+/// How long each phase of a run took
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Timings {
+    /// Parsing the source into instructions
+    pub parse: Duration,
+    /// Running `bfc_ir`'s optimizer and bfi's own passes
+    pub optimize: Duration,
+    /// Lowering instructions into bytecode
+    pub compile: Duration,
+    /// Running the program to completion
+    pub execute: Duration,
+    /// Instructions executed by the run
+    pub iterations: u64,
+}
+
+impl Timings {
+    /// Time spent across every phase
+    pub fn total(&self) -> Duration {
+        self.parse + self.optimize + self.compile + self.execute
+    }
+}
+
+/// Parses, optimizes, compiles and runs `program` with `input`, timing each phase
+///
+/// ```ignore
+/// let timings = bfi::bench::time(&program, b"")?;
+/// println!("{:?} in the interpreter loop", timings.execute);
+/// ```
+pub fn time(program: &str, input: &[u8]) -> Result<Timings, Error> {
+    let mut timings = Timings::default();
+
+    let start = Instant::now();
+    let instructions = bfc_ir::parse(program).map_err(Error::ParseError)?;
+    timings.parse = start.elapsed();
+
+    let start = Instant::now();
+    let (instructions, _) = bfc_ir::optimize(instructions, OptimisationsFlags::all());
+    timings.optimize = start.elapsed();
+
+    let start = Instant::now();
+    let bytecode = Bytecode::compile(&instructions);
+    timings.compile = start.elapsed();
+
+    let start = Instant::now();
+    let bytecode = bytecode.optimize(Pass::ALL);
+    timings.optimize += start.elapsed();
+
+    let interpreter = Interpreter::from_bytecode(bytecode, None);
+    let start = Instant::now();
+    let outcome = interpreter.run_outcome(input.iter().copied());
+    timings.execute = start.elapsed();
+    timings.iterations = outcome.iterations;
+
+    outcome.into_result().map_err(Error::RunTimeError)?;
+    Ok(timings)
+}
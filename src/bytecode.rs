@@ -0,0 +1,317 @@
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::{num::Wrapping, ops::RangeInclusive};
+
+use bfc_ir::{AstNode, Position};
+
+use crate::{passes::Pass, OverflowBehavior};
+
+/// A single instruction of the flattened program. Amounts are sign extended to 32 bits and are
+/// truncated to the interpreter's cell width at run time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Add `amount` to the cell at `pointer + offset`
+    Add {
+        amount: Wrapping<u32>,
+        offset: isize,
+    },
+    /// Overwrite the cell at `pointer + offset` with `amount`
+    Set {
+        amount: Wrapping<u32>,
+        offset: isize,
+    },
+    /// Move the pointer by `amount` cells
+    Move {
+        amount: isize,
+    },
+    Read,
+    Write,
+    /// Loop open: jump to `target` when the current cell is zero
+    JumpIfZero {
+        target: usize,
+    },
+    /// Loop close: jump to `target` when the current cell is not zero
+    JumpIfNonZero {
+        target: usize,
+    },
+    /// Add `current * factor` to every `pointer + offset`, then zero the current cell
+    MultiplyMove {
+        changes: Box<[(isize, Wrapping<u32>)]>,
+    },
+    /// Move the pointer by `stride` cells until it lands on a zero cell, like `[>]`
+    Scan {
+        stride: isize,
+    },
+    /// Report the machine's state, compiled from `#` by [`Bytecode::with_dumps`]. Doesn't count
+    /// as an iteration.
+    Dump,
+    /// Define the procedure numbered by the current cell as everything up to the matching
+    /// `Return`, then jump to `end` just past it. Compiled from pbrain's `(`.
+    Procedure {
+        end: usize,
+    },
+    /// Go back to where the current procedure was called from, compiled from pbrain's `)`
+    Return,
+    /// Call the procedure numbered by the current cell, compiled from pbrain's `:`
+    Call,
+    /// Run the handler registered for `command`, compiled by [`Bytecode::with_extensions`]. Does
+    /// nothing if there isn't one.
+    Extension {
+        command: char,
+    },
+    /// Start a thread with a copy of the tape, compiled from Brainfork's `Y`. The current cell
+    /// becomes 0 in the original thread, the new thread moves right and sets that cell to 1.
+    Fork,
+}
+
+/// An optimized program lowered into a flat list of [`Op`]s with precomputed jump targets
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bytecode {
+    ops: Vec<Op>,
+    positions: Vec<Option<Position>>,
+}
+
+impl Bytecode {
+    /// Lowers a list of instructions, usually the output of [`bfc_ir::optimize`]
+    pub fn compile(instructions: &[AstNode]) -> Self {
+        let mut bytecode = Self::default();
+
+        // Walk the tree with an explicit stack so deeply nested programs can't overflow the native
+        // stack. Each frame remembers the index of the `JumpIfZero` that opened it.
+        let mut stack = vec![(instructions.iter(), None)];
+        while let Some((iter, open)) = stack.last_mut() {
+            let open = *open;
+            match iter.next() {
+                Some(AstNode::Loop { body, position }) => {
+                    let open = bytecode.push(Op::JumpIfZero { target: 0 }, *position);
+                    stack.push((body.iter(), Some(open)));
+                }
+                Some(instruction) => bytecode.lower(instruction),
+                None => {
+                    stack.pop();
+
+                    if let Some(open) = open {
+                        let position = bytecode.positions[open];
+                        let close = bytecode.push(Op::JumpIfNonZero { target: open + 1 }, position);
+                        bytecode.ops[open] = Op::JumpIfZero { target: close + 1 };
+                    }
+                }
+            }
+        }
+
+        bytecode
+    }
+
+    /// Runs `passes` over the program in order, for cells that wrap
+    ///
+    /// ```ignore
+    /// let bytecode = Bytecode::compile(&instructions).optimize(Pass::ALL);
+    /// ```
+    pub fn optimize(self, passes: &[Pass]) -> Self {
+        self.optimize_for(passes, OverflowBehavior::Wrap)
+    }
+
+    /// Runs `passes` over the program in order, leaving out the rewrites that are only right
+    /// when cells wrap unless `overflow` is [`OverflowBehavior::Wrap`]
+    pub fn optimize_for(self, passes: &[Pass], overflow: OverflowBehavior) -> Self {
+        let mut ops: Vec<_> = self.ops.into_iter().zip(self.positions).collect();
+        for pass in passes {
+            ops = pass.run(ops, overflow);
+        }
+
+        Self::link(ops)
+    }
+
+    /// Adds an [`Op::Dump`] for every `#` in `program`, the source this bytecode was compiled
+    /// from. The parser treats `#` as a comment so this has to work from source positions.
+    pub fn with_dumps(self, program: &str) -> Self {
+        self.insert(
+            program
+                .match_indices('#')
+                .map(|(offset, _)| (offset, Op::Dump, None)),
+        )
+    }
+
+    /// Adds an [`Op::Extension`] for each of `commands` in `program`, the source this bytecode
+    /// was compiled from. The commands need handlers, see
+    /// [`InterpreterBuilder::extension`](crate::InterpreterBuilder::extension).
+    pub fn with_extensions(self, program: &str, commands: &[char]) -> Self {
+        self.insert(
+            program
+                .char_indices()
+                .filter(|(_, c)| commands.contains(c))
+                .map(|(offset, command)| {
+                    let position = Position {
+                        start: offset,
+                        end: offset,
+                    };
+                    (offset, Op::Extension { command }, Some(position))
+                }),
+        )
+    }
+
+    /// Adds an [`Op::Fork`] for every `Y` in `program`, the source this bytecode was compiled from
+    #[cfg(feature = "std")]
+    pub(crate) fn with_forks(self, program: &str) -> Self {
+        self.insert(program.match_indices('Y').map(|(offset, _)| {
+            let position = Position {
+                start: offset,
+                end: offset,
+            };
+            (offset, Op::Fork, Some(position))
+        }))
+    }
+
+    /// Adds ops found in the source at the given offsets, which must be in order, before the
+    /// instructions that come after them
+    fn insert<I>(self, inserts: I) -> Self
+    where
+        I: Iterator<Item = (usize, Op, Option<Position>)>,
+    {
+        let mut inserts = inserts.peekable();
+        let mut ops = Vec::with_capacity(self.ops.len());
+
+        for (op, position) in self.ops.into_iter().zip(self.positions) {
+            // A loop close sits at the `]`, the end of the loop's span
+            let offset = match (&op, position) {
+                (Op::JumpIfNonZero { .. }, Some(position)) => Some(position.end),
+                (_, position) => position.map(|position| position.start),
+            };
+
+            if let Some(offset) = offset {
+                while let Some((_, op, position)) = inserts.next_if(|(insert, ..)| *insert < offset)
+                {
+                    ops.push((op, position));
+                }
+            }
+            ops.push((op, position));
+        }
+        ops.extend(inserts.map(|(_, op, position)| (op, position)));
+
+        Self::link(ops)
+    }
+
+    /// Rebuilds bytecode from rewritten instructions, recomputing every jump target
+    pub(crate) fn link(ops: Vec<(Op, Option<Position>)>) -> Self {
+        let mut bytecode = Self::default();
+        let mut opens = vec![];
+        for (op, position) in ops {
+            match op {
+                Op::JumpIfZero { .. } | Op::Procedure { .. } => {
+                    opens.push(bytecode.push(op, position))
+                }
+                Op::JumpIfNonZero { .. } => {
+                    let open = opens.pop().expect("passes keep loops balanced");
+                    let close = bytecode.push(Op::JumpIfNonZero { target: open + 1 }, position);
+                    bytecode.ops[open] = Op::JumpIfZero { target: close + 1 };
+                }
+                Op::Return => {
+                    let open = opens.pop().expect("passes keep procedures balanced");
+                    let close = bytecode.push(Op::Return, position);
+                    bytecode.ops[open] = Op::Procedure { end: close + 1 };
+                }
+                op => {
+                    bytecode.push(op, position);
+                }
+            }
+        }
+
+        bytecode
+    }
+
+    /// The compiled instructions
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    /// The source position of the instruction at `pc`, if the parser recorded one
+    pub fn position(&self, pc: usize) -> Option<Position> {
+        self.positions.get(pc).copied().flatten()
+    }
+
+    /// The source offsets the instruction at `pc` was compiled from. Loop jumps only cover their
+    /// own bracket rather than the whole loop.
+    pub(crate) fn source_range(&self, pc: usize) -> Option<RangeInclusive<usize>> {
+        let position = self.position(pc)?;
+        Some(match self.ops[pc] {
+            Op::JumpIfZero { .. } => position.start..=position.start,
+            Op::JumpIfNonZero { .. } => position.end..=position.end,
+            _ => position.start..=position.end,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Whether the program uses pbrain procedures or Brainfork threads, which only the
+    /// interpreter runs
+    pub fn needs_interpreter(&self) -> bool {
+        self.interpreter_only().is_some()
+    }
+
+    /// The first pbrain procedure or Brainfork thread instruction
+    pub(crate) fn interpreter_only(&self) -> Option<usize> {
+        self.ops
+            .iter()
+            .position(|op| matches!(op, Op::Procedure { .. } | Op::Return | Op::Call | Op::Fork))
+    }
+
+    fn push(&mut self, op: Op, position: Option<Position>) -> usize {
+        self.ops.push(op);
+        self.positions.push(position);
+        self.ops.len() - 1
+    }
+
+    /// Lowers every non-loop instruction
+    fn lower(&mut self, instruction: &AstNode) {
+        match instruction {
+            AstNode::Increment {
+                amount,
+                offset,
+                position,
+            } => self.push(
+                Op::Add {
+                    amount: Wrapping(amount.0 as u32),
+                    offset: *offset,
+                },
+                *position,
+            ),
+            AstNode::PointerIncrement { amount, position } => {
+                self.push(Op::Move { amount: *amount }, *position)
+            }
+            AstNode::Read { position } => self.push(Op::Read, *position),
+            AstNode::Write { position } => self.push(Op::Write, *position),
+            AstNode::Set {
+                amount,
+                offset,
+                position,
+            } => self.push(
+                Op::Set {
+                    amount: Wrapping(amount.0 as u32),
+                    offset: *offset,
+                },
+                *position,
+            ),
+            AstNode::MultiplyMove { changes, position } => {
+                let mut changes: Vec<_> = changes
+                    .iter()
+                    .map(|(offset, factor)| (*offset, Wrapping(factor.0 as u32)))
+                    .collect();
+                // Keep the compiled form deterministic regardless of map ordering
+                changes.sort_by_key(|(offset, _)| *offset);
+
+                self.push(
+                    Op::MultiplyMove {
+                        changes: changes.into_boxed_slice(),
+                    },
+                    *position,
+                )
+            }
+            AstNode::Loop { .. } => unreachable!("loops are lowered by Bytecode::compile"),
+        };
+    }
+}
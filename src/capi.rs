@@ -0,0 +1,170 @@
+//! A C API for embedding the interpreter, see `include/bfi.h`
+//!
+//! Programs are parsed once into an opaque `BfiProgram` and can be run any number of times. Every
+//! function returns a [`BfiStatus`], and when it isn't `BFI_OK` [`bfi_last_error`] says why.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CString},
+    num::NonZeroU64,
+    ptr, slice,
+};
+
+use crate::{compile, CellWidth, Error, Interpreter};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BfiStatus {
+    Ok = 0,
+    ParseError = 1,
+    RunTimeError = 2,
+    /// A null pointer where one isn't allowed, or source that isn't UTF-8
+    InvalidArgument = 3,
+}
+
+/// A parsed and optimized program
+pub struct BfiProgram {
+    interpreter: Interpreter,
+}
+
+/// Returns a byte, or a negative number at the end of input
+pub type BfiRead = extern "C" fn(user_data: *mut c_void) -> c_int;
+pub type BfiWrite = extern "C" fn(user_data: *mut c_void, byte: u8);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn fail(status: BfiStatus, message: String) -> BfiStatus {
+    // Interior NULs can't be represented, runtime errors never contain them anyway
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    status
+}
+
+/// Why the last call on this thread failed, or null. Valid until the next failing call on the
+/// same thread.
+#[no_mangle]
+pub extern "C" fn bfi_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |err| err.as_ptr())
+    })
+}
+
+/// Parses `len` bytes of `source`, storing the program in `*program` to be freed with
+/// `bfi_program_free`. Runs stop after `max_iterations` instructions, `0` means unlimited.
+///
+/// # Safety
+///
+/// `source` must point to `len` readable bytes and `program` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn bfi_parse(
+    source: *const u8,
+    len: usize,
+    max_iterations: u64,
+    program: *mut *mut BfiProgram,
+) -> BfiStatus {
+    if source.is_null() || program.is_null() {
+        return fail(BfiStatus::InvalidArgument, "null pointer".to_string());
+    }
+    let source = match std::str::from_utf8(slice::from_raw_parts(source, len)) {
+        Ok(source) => source,
+        Err(err) => return fail(BfiStatus::InvalidArgument, err.to_string()),
+    };
+
+    match compile(source, NonZeroU64::new(max_iterations), CellWidth::U8) {
+        Ok(interpreter) => {
+            *program = Box::into_raw(Box::new(BfiProgram { interpreter }));
+            BfiStatus::Ok
+        }
+        Err(err) => fail(BfiStatus::ParseError, Error::ParseError(err).to_string()),
+    }
+}
+
+/// # Safety
+///
+/// `program` must come from `bfi_parse` and not already be freed, or be null.
+#[no_mangle]
+pub unsafe extern "C" fn bfi_program_free(program: *mut BfiProgram) {
+    if !program.is_null() {
+        drop(Box::from_raw(program));
+    }
+}
+
+/// Runs `program` with `input_len` bytes of input, storing its output in `*output` and
+/// `*output_len` to be freed with `bfi_free_output`. Output is stored on runtime errors too, it's
+/// whatever was written before the error.
+///
+/// # Safety
+///
+/// `program` must come from `bfi_parse`, `input` must point to `input_len` readable bytes (or be
+/// null if it's 0), and `output` and `output_len` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn bfi_run(
+    program: *const BfiProgram,
+    input: *const u8,
+    input_len: usize,
+    output: *mut *mut u8,
+    output_len: *mut usize,
+) -> BfiStatus {
+    if program.is_null() || output.is_null() || output_len.is_null() {
+        return fail(BfiStatus::InvalidArgument, "null pointer".to_string());
+    }
+    let input = match (input.is_null(), input_len) {
+        (true, 0) => &[][..],
+        (true, _) => return fail(BfiStatus::InvalidArgument, "null input".to_string()),
+        (false, _) => slice::from_raw_parts(input, input_len),
+    };
+
+    let outcome = (*program).interpreter.run_outcome(input.iter().copied());
+    let result = match outcome.error {
+        None => BfiStatus::Ok,
+        Some(err) => fail(BfiStatus::RunTimeError, err.to_string()),
+    };
+
+    *output_len = outcome.output.len();
+    *output = Box::into_raw(outcome.output.into_boxed_slice()) as *mut u8;
+    result
+}
+
+/// Frees output from `bfi_run`
+///
+/// # Safety
+///
+/// `output` and `len` must be exactly what `bfi_run` stored, and not already be freed.
+#[no_mangle]
+pub unsafe extern "C" fn bfi_free_output(output: *mut u8, len: usize) {
+    if !output.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(output, len)));
+    }
+}
+
+/// Runs `program` calling `read` whenever it reads and `write` with every byte it writes, both
+/// get `user_data`
+///
+/// # Safety
+///
+/// `program` must come from `bfi_parse`, and the callbacks must be safe to call with `user_data`.
+#[no_mangle]
+pub unsafe extern "C" fn bfi_run_with_callbacks(
+    program: *const BfiProgram,
+    read: Option<BfiRead>,
+    write: Option<BfiWrite>,
+    user_data: *mut c_void,
+) -> BfiStatus {
+    let (program, read, write) = match (program.as_ref(), read, write) {
+        (Some(program), Some(read), Some(write)) => (program, read, write),
+        _ => return fail(BfiStatus::InvalidArgument, "null pointer".to_string()),
+    };
+
+    let result = program.interpreter.run_with_io(
+        || u8::try_from(read(user_data)).ok(),
+        |byte| write(user_data, byte),
+    );
+    match result {
+        Ok(()) => BfiStatus::Ok,
+        Err(err) => fail(BfiStatus::RunTimeError, err.to_string()),
+    }
+}
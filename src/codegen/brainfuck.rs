@@ -0,0 +1,158 @@
+use crate::{Bytecode, Op};
+
+/// Commands per line of output
+const LINE_WIDTH: usize = 80;
+
+/// Lowers `bytecode` back into plain Brainfuck for 8-bit cells, so other interpreters benefit
+/// from the optimizer. Offsets are applied by walking there and back, sets become `[-]` followed
+/// by adds and `Dump`s become `#`. Procedures and forks are written back out as pbrain and
+/// Brainfork.
+///
+/// The pointer is moved lazily: runs of offset adds walk the tape once instead of returning to
+/// the current cell after each one. As a result the program may not step off the end of the
+/// tape where the original did.
+pub fn to_brainfuck(bytecode: &Bytecode) -> String {
+    let mut emitter = Emitter::default();
+
+    for op in bytecode.ops() {
+        match op {
+            Op::Add { amount, offset } => {
+                emitter.seek(*offset);
+                emitter.add(amount.0 as i8);
+            }
+            Op::Set { amount, offset } => {
+                emitter.seek(*offset);
+                emitter.push_str("[-]");
+                emitter.add(amount.0 as i8);
+            }
+            // The head stays put, it's now further from the cell the program is pointing at
+            Op::Move { amount } => emitter.head -= amount,
+            Op::Read => emitter.command(','),
+            Op::Write => emitter.command('.'),
+            Op::JumpIfZero { .. } => emitter.command('['),
+            Op::JumpIfNonZero { .. } => emitter.command(']'),
+            Op::MultiplyMove { changes } => {
+                emitter.command('[');
+                emitter.push_str("-");
+                for (offset, factor) in changes.iter() {
+                    emitter.seek(*offset);
+                    emitter.add(factor.0 as i8);
+                }
+                emitter.command(']');
+            }
+            Op::Scan { stride } => {
+                emitter.command('[');
+                emitter.walk(*stride);
+                emitter.push_str("]");
+            }
+            Op::Dump => emitter.command('#'),
+            Op::Procedure { .. } => emitter.command('('),
+            Op::Return => emitter.command(')'),
+            Op::Call => emitter.command(':'),
+            Op::Extension { command } => emitter.command(*command),
+            Op::Fork => emitter.command('Y'),
+        }
+    }
+
+    let mut program = emitter.program;
+    program.push('\n');
+    program
+}
+
+/// Generates a program that prints `text`, for 8-bit cells. One cell steps from each byte to the
+/// next, big steps are multiplied out in a loop on the cell to its right.
+///
+/// ```ignore
+/// let program = print_string(b"Hello");
+/// assert_eq!(Program::compile(&program)?.run(vec![], None)?, b"Hello");
+/// ```
+pub fn print_string(text: &[u8]) -> String {
+    let mut emitter = Emitter::default();
+    let mut current = 0u8;
+
+    for byte in text {
+        emitter.step(byte.wrapping_sub(current) as i8);
+        emitter.push('.');
+        current = *byte;
+    }
+
+    let mut program = emitter.program;
+    program.push('\n');
+    program
+}
+
+#[derive(Default)]
+struct Emitter {
+    program: String,
+    /// Where the head is relative to the cell the original program points at
+    head: isize,
+    /// Commands on the current line
+    column: usize,
+}
+
+impl Emitter {
+    /// Moves the head to the current cell then emits `command`
+    fn command(&mut self, command: char) {
+        self.seek(0);
+        self.push(command);
+    }
+
+    fn seek(&mut self, offset: isize) {
+        self.walk(offset - self.head);
+        self.head = offset;
+    }
+
+    fn walk(&mut self, distance: isize) {
+        let step = if distance < 0 { '<' } else { '>' };
+        for _ in 0..distance.unsigned_abs() {
+            self.push(step);
+        }
+    }
+
+    /// Emits whichever of `+` or `-` is shorter, cells wrap at 256
+    fn add(&mut self, amount: i8) {
+        let step = if amount < 0 { '-' } else { '+' };
+        for _ in 0..amount.unsigned_abs() {
+            self.push(step);
+        }
+    }
+
+    /// Adds `delta` to the current cell using the cell to the right as a loop counter when
+    /// that's shorter, `>` a `[<` b `>-]<` c adds a * b + c
+    fn step(&mut self, delta: i8) {
+        let delta = delta as i16;
+        let best = (2..=16)
+            .map(|a: i16| {
+                let b = (delta + delta.signum() * a / 2) / a;
+                let c = delta - a * b;
+                (a + b.abs() + c.abs() + 6, a, b, c)
+            })
+            .filter(|&(_, _, b, _)| b != 0)
+            .min();
+
+        match best {
+            Some((length, a, b, c)) if length < delta.abs() => {
+                self.push('>');
+                self.add(a as i8);
+                self.push_str("[<");
+                self.add(b as i8);
+                self.push_str(">-]<");
+                self.add(c as i8);
+            }
+            _ => self.add(delta as i8),
+        }
+    }
+
+    fn push_str(&mut self, commands: &str) {
+        commands.chars().for_each(|command| self.push(command));
+    }
+
+    fn push(&mut self, command: char) {
+        if self.column == LINE_WIDTH {
+            self.program.push('\n');
+            self.column = 0;
+        }
+        self.program.push(command);
+        self.column += 1;
+    }
+}
@@ -0,0 +1,177 @@
+use std::fmt::Write;
+
+use crate::{Bytecode, CellWidth, EofBehavior, Op};
+
+use super::{supported, CodegenOptions, UnsupportedOp};
+
+/// The parts of the generated program that don't depend on the Brainfuck source. Helpers are
+/// `static inline` so compilers don't warn about the ones a program doesn't use.
+const RUNTIME: &str = r#"
+static cell tape[TAPE_SIZE];
+static long long pointer = 0;
+static unsigned long long iterations = 0;
+
+static void fail(const char *message) {
+    fflush(stdout);
+    fprintf(stderr, "%s (pointer %lld, after %llu iterations)\n", message, pointer, iterations);
+    exit(1);
+}
+
+static inline void step(void) {
+    if (++iterations > MAX_ITERATIONS) {
+        fail("exceeded the maximum number of iterations");
+    }
+}
+
+static inline size_t at(long long offset) {
+    long long index = pointer + offset;
+    if (index < 0) {
+        fail("moved off the left end of the tape");
+    }
+    if (index >= TAPE_SIZE) {
+        fail("moved off the right end of the tape");
+    }
+    return (size_t)index;
+}
+
+static inline void write_cell(void) {
+    if (putchar((unsigned char)tape[at(0)]) == EOF) {
+        fail("I/O error while writing output");
+    }
+}
+
+static inline void scan(long long stride) {
+    while (tape[at(0)] != 0) {
+        pointer += stride;
+    }
+}
+"#;
+
+/// Generates a standalone C99 program that behaves like running `bytecode` in the interpreter,
+/// reading stdin and writing stdout. Instructions are left out or rejected as described on
+/// [`CodegenOptions`].
+pub fn to_c(bytecode: &Bytecode, options: &CodegenOptions) -> Result<String, UnsupportedOp> {
+    supported(bytecode)?;
+
+    let mut out = String::new();
+    writeln!(out, "/* Generated by bfi from a Brainfuck program */").unwrap();
+    writeln!(out, "#include <stdint.h>").unwrap();
+    writeln!(out, "#include <stdio.h>").unwrap();
+    writeln!(out, "#include <stdlib.h>").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "typedef {} cell;", cell_type(options.cell_width)).unwrap();
+    writeln!(out, "#define TAPE_SIZE {}LL", options.tape_size).unwrap();
+    writeln!(
+        out,
+        "#define MAX_ITERATIONS {}ULL",
+        options.iteration_limit()
+    )
+    .unwrap();
+    out.push_str(RUNTIME);
+
+    let eof = match options.eof_behavior {
+        EofBehavior::Zero => "tape[index] = 0;",
+        EofBehavior::MinusOne => "tape[index] = (cell)-1;",
+        EofBehavior::Unchanged => "return;",
+        EofBehavior::Error => "fail(\"read past the end of the input\");",
+    };
+    write!(
+        out,
+        r#"
+static inline void read_cell(void) {{
+    size_t index = at(0);
+    int input = getchar();
+    if (input == EOF) {{
+        if (ferror(stdin)) {{
+            fail("I/O error while reading input");
+        }}
+        {}
+    }} else {{
+        tape[index] = (cell)input;
+    }}
+}}
+
+int main(void) {{
+"#,
+        eof
+    )
+    .unwrap();
+
+    let mut depth = 1;
+    for op in bytecode.ops() {
+        let indent = "    ".repeat(depth);
+        if options.limited() && !matches!(op, Op::Dump) {
+            writeln!(out, "{}step();", indent).unwrap();
+        }
+
+        match op {
+            Op::Add { amount, offset } => {
+                writeln!(out, "{}tape[at({})] += (cell){}u;", indent, offset, amount).unwrap()
+            }
+            Op::Set { amount, offset } => {
+                writeln!(out, "{}tape[at({})] = (cell){}u;", indent, offset, amount).unwrap()
+            }
+            Op::Move { amount } => {
+                writeln!(out, "{}pointer += {};", indent, amount).unwrap();
+                writeln!(out, "{}at(0);", indent).unwrap();
+            }
+            Op::Read => writeln!(out, "{}read_cell();", indent).unwrap(),
+            Op::Write => writeln!(out, "{}write_cell();", indent).unwrap(),
+            // Tested at both ends, see `CodegenOptions::limited`
+            Op::JumpIfZero { .. } if options.limited() => {
+                writeln!(out, "{}if (tape[at(0)] != 0) {{", indent).unwrap();
+                writeln!(out, "{}    for (;;) {{", indent).unwrap();
+                depth += 2;
+            }
+            Op::JumpIfZero { .. } => {
+                writeln!(out, "{}while (tape[at(0)] != 0) {{", indent).unwrap();
+                depth += 1;
+            }
+            Op::JumpIfNonZero { .. } if options.limited() => {
+                writeln!(out, "{}if (tape[at(0)] == 0) {{", indent).unwrap();
+                writeln!(out, "{}    break;", indent).unwrap();
+                writeln!(out, "{}}}", indent).unwrap();
+                depth -= 2;
+                let indent = "    ".repeat(depth);
+                writeln!(out, "{}    }}", indent).unwrap();
+                writeln!(out, "{}}}", indent).unwrap();
+            }
+            Op::JumpIfNonZero { .. } => {
+                depth -= 1;
+                writeln!(out, "{}}}", "    ".repeat(depth)).unwrap();
+            }
+            Op::MultiplyMove { changes } => {
+                writeln!(out, "{}if (tape[at(0)] != 0) {{", indent).unwrap();
+                for (offset, factor) in changes.iter() {
+                    writeln!(
+                        out,
+                        "{}    tape[at({})] += (cell)(tape[at(0)] * {}u);",
+                        indent, offset, factor
+                    )
+                    .unwrap();
+                }
+                writeln!(out, "{}    tape[at(0)] = 0;", indent).unwrap();
+                writeln!(out, "{}}}", indent).unwrap();
+            }
+            Op::Scan { stride } => writeln!(out, "{}scan({});", indent, stride).unwrap(),
+            // See `CodegenOptions`
+            Op::Dump | Op::Extension { .. } => {}
+            Op::Procedure { .. } | Op::Return | Op::Call | Op::Fork => {
+                unreachable!("checked by `supported`")
+            }
+        }
+    }
+
+    writeln!(out, "    fflush(stdout);").unwrap();
+    writeln!(out, "    return 0;").unwrap();
+    writeln!(out, "}}").unwrap();
+    Ok(out)
+}
+
+fn cell_type(cell_width: CellWidth) -> &'static str {
+    match cell_width {
+        CellWidth::U8 => "uint8_t",
+        CellWidth::U16 => "uint16_t",
+        CellWidth::U32 => "uint32_t",
+    }
+}
@@ -0,0 +1,84 @@
+//! Translates [`Bytecode`](crate::Bytecode) into source code for other languages
+
+mod brainfuck;
+mod c;
+mod rust;
+mod wasm;
+
+pub use brainfuck::{print_string, to_brainfuck};
+pub use c::to_c;
+pub use rust::to_rust;
+pub use wasm::to_wasm;
+
+use core::{fmt, num::NonZeroU64};
+
+use crate::{Bytecode, CellWidth, EofBehavior, DEFAULT_TAPE_SIZE};
+
+/// Settings for generated programs, matching the interpreter's configuration of the same name.
+/// Generated programs report the same errors as the interpreter and exit with status 1.
+///
+/// Every backend leaves out [`Op::Dump`](crate::Op::Dump) and
+/// [`Op::Extension`](crate::Op::Extension): a compiled program has nowhere sensible to dump its
+/// state to and no handlers to run. Programs using pbrain procedures or Brainfork threads can't
+/// be translated at all, backends fail with [`UnsupportedOp`] for those.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodegenOptions {
+    pub tape_size: usize,
+    pub cell_width: CellWidth,
+    pub eof_behavior: EofBehavior,
+    /// Generated code only counts iterations when there's a limit
+    pub max_iterations: Option<NonZeroU64>,
+}
+
+impl Default for CodegenOptions {
+    fn default() -> Self {
+        CodegenOptions {
+            tape_size: DEFAULT_TAPE_SIZE,
+            cell_width: CellWidth::U8,
+            eof_behavior: EofBehavior::Error,
+            max_iterations: None,
+        }
+    }
+}
+
+/// Why a backend can't translate a program: it uses pbrain procedures or Brainfork threads,
+/// which only the interpreter runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedOp {
+    /// The first instruction the backend can't translate
+    pub pc: usize,
+}
+
+impl fmt::Display for UnsupportedOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "instruction {} is a pbrain procedure or Brainfork thread, only the interpreter runs those",
+            self.pc
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedOp {}
+
+/// Checks every backend can translate `bytecode`, dumps and extensions aren't a problem since
+/// they're left out
+fn supported(bytecode: &Bytecode) -> Result<(), UnsupportedOp> {
+    match bytecode.interpreter_only() {
+        Some(pc) => Err(UnsupportedOp { pc }),
+        None => Ok(()),
+    }
+}
+
+impl CodegenOptions {
+    /// Whether generated code counts iterations. Counting them exactly means testing a loop's
+    /// condition at both ends like the bytecode's two jumps, unlimited programs get plain loops.
+    fn limited(&self) -> bool {
+        self.max_iterations.is_some()
+    }
+
+    /// The limit as generated code compares it, never reached when unlimited
+    fn iteration_limit(&self) -> u64 {
+        self.max_iterations.map_or(u64::MAX, NonZeroU64::get)
+    }
+}
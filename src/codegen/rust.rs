@@ -0,0 +1,218 @@
+use std::fmt::Write;
+
+use crate::{Bytecode, CellWidth, EofBehavior, Op};
+
+use super::{supported, CodegenOptions, UnsupportedOp};
+
+/// The parts of the generated program that don't depend on the Brainfuck source
+const RUNTIME: &str = r#"
+struct Machine {
+    tape: Vec<Cell>,
+    pointer: isize,
+    iterations: u64,
+    input: io::Bytes<io::StdinLock<'static>>,
+    output: io::BufWriter<io::StdoutLock<'static>>,
+}
+
+impl Machine {
+    fn fail(&mut self, message: &str) -> ! {
+        let _ = self.output.flush();
+        eprintln!(
+            "{} (pointer {}, after {} iterations)",
+            message, self.pointer, self.iterations
+        );
+        process::exit(1)
+    }
+
+    fn step(&mut self) {
+        self.iterations += 1;
+        if self.iterations > MAX_ITERATIONS {
+            self.fail("exceeded the maximum number of iterations");
+        }
+    }
+
+    fn at(&mut self, offset: isize) -> usize {
+        let index = self.pointer + offset;
+        if index < 0 {
+            self.fail("moved off the left end of the tape");
+        }
+        if index as usize >= TAPE_SIZE {
+            self.fail("moved off the right end of the tape");
+        }
+        index as usize
+    }
+
+    fn cell(&mut self) -> Cell {
+        let index = self.at(0);
+        self.tape[index]
+    }
+
+    fn add(&mut self, offset: isize, amount: u32) {
+        let index = self.at(offset);
+        self.tape[index] = self.tape[index].wrapping_add(amount as Cell);
+    }
+
+    fn set(&mut self, offset: isize, amount: u32) {
+        let index = self.at(offset);
+        self.tape[index] = amount as Cell;
+    }
+
+    fn move_by(&mut self, amount: isize) {
+        self.pointer += amount;
+        self.at(0);
+    }
+
+    fn write(&mut self) {
+        let output = self.cell() as u8;
+        if self.output.write_all(&[output]).is_err() {
+            self.fail("I/O error while writing output");
+        }
+    }
+
+    fn multiply_move(&mut self, changes: &[(isize, u32)]) {
+        let current = self.cell();
+        if current != 0 {
+            for &(offset, factor) in changes {
+                let index = self.at(offset);
+                self.tape[index] = self.tape[index].wrapping_add(current.wrapping_mul(factor as Cell));
+            }
+            let index = self.at(0);
+            self.tape[index] = 0;
+        }
+    }
+
+    fn scan(&mut self, stride: isize) {
+        while self.cell() != 0 {
+            self.pointer += stride;
+        }
+    }
+"#;
+
+/// Start of `main`, the program's code follows
+const MAIN: &str = r#"
+fn main() {
+    let mut m = Machine {
+        tape: vec![0; TAPE_SIZE],
+        pointer: 0,
+        iterations: 0,
+        input: io::stdin().lock().bytes(),
+        output: io::BufWriter::new(io::stdout().lock()),
+    };
+
+"#;
+
+/// Generates a standalone Rust program that behaves like running `bytecode` in the interpreter,
+/// reading stdin and writing stdout. It needs nothing beyond the standard library. See
+/// [`CodegenOptions`] for what's left out and what can't be translated.
+pub fn to_rust(bytecode: &Bytecode, options: &CodegenOptions) -> Result<String, UnsupportedOp> {
+    supported(bytecode)?;
+
+    let mut out = String::new();
+    writeln!(out, "// Generated by bfi from a Brainfuck program").unwrap();
+    // Only the helpers the program uses get called
+    writeln!(out, "#![allow(dead_code)]").unwrap();
+    writeln!(out, "use std::io::{{self, Read, Write}};").unwrap();
+    writeln!(out, "use std::process;").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "type Cell = {};", cell_type(options.cell_width)).unwrap();
+    writeln!(out, "const TAPE_SIZE: usize = {};", options.tape_size).unwrap();
+    writeln!(
+        out,
+        "const MAX_ITERATIONS: u64 = {};",
+        options.iteration_limit()
+    )
+    .unwrap();
+    out.push_str(RUNTIME);
+
+    let eof = match options.eof_behavior {
+        EofBehavior::Zero => "self.tape[index] = 0",
+        EofBehavior::MinusOne => "self.tape[index] = Cell::MAX",
+        EofBehavior::Unchanged => "{}",
+        EofBehavior::Error => "self.fail(\"read past the end of the input\")",
+    };
+    write!(
+        out,
+        r#"
+    fn read(&mut self) {{
+        let index = self.at(0);
+        match self.input.next() {{
+            Some(Ok(byte)) => self.tape[index] = byte as Cell,
+            Some(Err(_)) => self.fail("I/O error while reading input"),
+            None => {},
+        }}
+    }}
+}}
+"#,
+        eof
+    )
+    .unwrap();
+    out.push_str(MAIN);
+
+    let mut depth = 1;
+    for op in bytecode.ops() {
+        let indent = "    ".repeat(depth);
+        if options.limited() && !matches!(op, Op::Dump) {
+            writeln!(out, "{}m.step();", indent).unwrap();
+        }
+
+        match op {
+            Op::Add { amount, offset } => {
+                writeln!(out, "{}m.add({}, {});", indent, offset, amount).unwrap()
+            }
+            Op::Set { amount, offset } => {
+                writeln!(out, "{}m.set({}, {});", indent, offset, amount).unwrap()
+            }
+            Op::Move { amount } => writeln!(out, "{}m.move_by({});", indent, amount).unwrap(),
+            Op::Read => writeln!(out, "{}m.read();", indent).unwrap(),
+            Op::Write => writeln!(out, "{}m.write();", indent).unwrap(),
+            // See `CodegenOptions::limited`
+            Op::JumpIfZero { .. } if options.limited() => {
+                writeln!(out, "{}if m.cell() != 0 {{", indent).unwrap();
+                writeln!(out, "{}    loop {{", indent).unwrap();
+                depth += 2;
+            }
+            Op::JumpIfZero { .. } => {
+                writeln!(out, "{}while m.cell() != 0 {{", indent).unwrap();
+                depth += 1;
+            }
+            Op::JumpIfNonZero { .. } if options.limited() => {
+                writeln!(out, "{}if m.cell() == 0 {{", indent).unwrap();
+                writeln!(out, "{}    break;", indent).unwrap();
+                writeln!(out, "{}}}", indent).unwrap();
+                depth -= 2;
+                let indent = "    ".repeat(depth);
+                writeln!(out, "{}    }}", indent).unwrap();
+                writeln!(out, "{}}}", indent).unwrap();
+            }
+            Op::JumpIfNonZero { .. } => {
+                depth -= 1;
+                writeln!(out, "{}}}", "    ".repeat(depth)).unwrap();
+            }
+            Op::MultiplyMove { changes } => {
+                let changes: Vec<String> = changes
+                    .iter()
+                    .map(|(offset, factor)| format!("({}, {})", offset, factor))
+                    .collect();
+                writeln!(out, "{}m.multiply_move(&[{}]);", indent, changes.join(", ")).unwrap()
+            }
+            Op::Scan { stride } => writeln!(out, "{}m.scan({});", indent, stride).unwrap(),
+            // Left out, see `CodegenOptions`
+            Op::Dump | Op::Extension { .. } => {}
+            Op::Procedure { .. } | Op::Return | Op::Call | Op::Fork => {
+                unreachable!("checked by `supported`")
+            }
+        }
+    }
+
+    writeln!(out, "    let _ = m.output.flush();").unwrap();
+    writeln!(out, "}}").unwrap();
+    Ok(out)
+}
+
+fn cell_type(cell_width: CellWidth) -> &'static str {
+    match cell_width {
+        CellWidth::U8 => "u8",
+        CellWidth::U16 => "u16",
+        CellWidth::U32 => "u32",
+    }
+}
@@ -0,0 +1,410 @@
+use crate::{Bytecode, CellWidth, EofBehavior, Op};
+
+use super::{supported, CodegenOptions, UnsupportedOp};
+
+// Function indices, the imports come first
+const READ: u32 = 0;
+const WRITE: u32 = 1;
+const FAIL: u32 = 2;
+const AT: u32 = 4;
+
+// Locals of the `run` function
+const POINTER: u32 = 0;
+const ADDRESS: u32 = 1;
+const VALUE: u32 = 2;
+const ITERATIONS: u32 = 3;
+
+// Codes passed to the imported `fail` function
+const FAIL_LEFT: i32 = 0;
+const FAIL_RIGHT: i32 = 1;
+const FAIL_MAX_ITERATIONS: i32 = 2;
+const FAIL_INPUT_EXHAUSTED: i32 = 3;
+
+const I32: u8 = 0x7f;
+const I64: u8 = 0x7e;
+const EMPTY_BLOCK: u8 = 0x40;
+
+/// Generates a WebAssembly module that behaves like running `bytecode` in the interpreter. The
+/// module exports its tape as `memory` and a `run` function, and imports from `env`:
+///
+/// - `read() -> i32` returning the next input byte or -1 at the end of input
+/// - `write(byte: i32)`
+/// - `fail(code: i32, pointer: i32)` called just before trapping. The code is 0 for moving off the
+///   left of the tape, 1 for the right, 2 for running out of iterations and 3 for reading past
+///   the end of input
+///
+/// ```ignore
+/// const { instance } = await WebAssembly.instantiate(bytes, {
+///     env: { read, write, fail: (code, pointer) => console.error(code, pointer) },
+/// });
+/// instance.exports.run();
+/// ```
+///
+/// Like the other backends it skips some instructions and rejects others, see
+/// [`CodegenOptions`].
+pub fn to_wasm(bytecode: &Bytecode, options: &CodegenOptions) -> Result<Vec<u8>, UnsupportedOp> {
+    supported(bytecode)?;
+
+    let mut module = b"\0asm".to_vec();
+    module.extend(1u32.to_le_bytes());
+
+    let types: [(&[u8], &[u8]); 5] = [
+        (&[], &[I32]),      // read
+        (&[I32], &[]),      // write
+        (&[I32, I32], &[]), // fail
+        (&[], &[]),         // run
+        (&[I32], &[I32]),   // at
+    ];
+    section(&mut module, 1, types.len(), |out| {
+        for (params, results) in types {
+            out.push(0x60);
+            vector(out, params);
+            vector(out, results);
+        }
+    });
+
+    let imports = [("read", 0), ("write", 1), ("fail", 2)];
+    section(&mut module, 2, imports.len(), |out| {
+        for (name, ty) in imports {
+            name_bytes(out, "env");
+            name_bytes(out, name);
+            out.push(0x00);
+            uleb(out, ty);
+        }
+    });
+
+    // `run` then `at`
+    section(&mut module, 3, 2, |out| out.extend([3, 4]));
+
+    let bytes = options.tape_size as u64 * width(options.cell_width) as u64;
+    section(&mut module, 5, 1, |out| {
+        out.push(0x00);
+        uleb(out, bytes.div_ceil(65536).max(1));
+    });
+
+    section(&mut module, 7, 2, |out| {
+        name_bytes(out, "run");
+        out.push(0x00);
+        uleb(out, 3);
+        name_bytes(out, "memory");
+        out.push(0x02);
+        uleb(out, 0);
+    });
+
+    let run = run_body(bytecode, options);
+    let at = at_body(options);
+    section(&mut module, 10, 2, |out| {
+        for body in [run, at] {
+            uleb(out, body.len() as u64);
+            out.extend(body);
+        }
+    });
+
+    Ok(module)
+}
+
+/// `at(index) -> address` checks a cell index is on the tape and converts it into an address
+fn at_body(options: &CodegenOptions) -> Vec<u8> {
+    let mut f = Function::default();
+    f.code.push(0x00); // no locals
+
+    f.local_get(0);
+    f.i32_const(0);
+    f.code.push(0x48); // i32.lt_s
+    f.fail_if(FAIL_LEFT, 0);
+
+    f.local_get(0);
+    f.i32_const(options.tape_size as i32);
+    f.code.push(0x4e); // i32.ge_s
+    f.fail_if(FAIL_RIGHT, 0);
+
+    f.local_get(0);
+    f.i32_const(width(options.cell_width) as i32);
+    f.code.push(0x6c); // i32.mul
+    f.code.push(0x0b);
+    f.code
+}
+
+fn run_body(bytecode: &Bytecode, options: &CodegenOptions) -> Vec<u8> {
+    let mut f = Function {
+        code: vec![],
+        width: width(options.cell_width),
+    };
+    // Pointer, address and value are i32s, then the iteration count
+    f.code.extend([2, 3, I32, 1, I64]);
+
+    for op in bytecode.ops() {
+        if options.limited() && !matches!(op, Op::Dump) {
+            f.step(options.iteration_limit());
+        }
+
+        match op {
+            Op::Add { amount, offset } => {
+                f.address(*offset);
+                f.local_tee(ADDRESS);
+                f.local_get(ADDRESS);
+                f.load();
+                f.i32_const(amount.0 as i32);
+                f.code.push(0x6a); // i32.add
+                f.store();
+            }
+            Op::Set { amount, offset } => {
+                f.address(*offset);
+                f.i32_const(amount.0 as i32);
+                f.store();
+            }
+            Op::Move { amount } => {
+                f.local_get(POINTER);
+                f.i32_const(*amount as i32);
+                f.code.push(0x6a); // i32.add
+                f.local_tee(POINTER);
+                f.call(AT);
+                f.code.push(0x1a); // drop
+            }
+            Op::Read => {
+                f.address(0);
+                f.local_set(ADDRESS);
+                f.call(READ);
+                f.local_tee(VALUE);
+                f.i32_const(-1);
+                f.code.push(0x46); // i32.eq
+                f.code.extend([0x04, EMPTY_BLOCK]);
+                match options.eof_behavior {
+                    EofBehavior::Zero => {
+                        f.local_get(ADDRESS);
+                        f.i32_const(0);
+                        f.store();
+                    }
+                    EofBehavior::MinusOne => {
+                        f.local_get(ADDRESS);
+                        f.i32_const(-1);
+                        f.store();
+                    }
+                    EofBehavior::Unchanged => {}
+                    EofBehavior::Error => {
+                        f.i32_const(FAIL_INPUT_EXHAUSTED);
+                        f.local_get(POINTER);
+                        f.call(FAIL);
+                        f.code.push(0x00); // unreachable
+                    }
+                }
+                f.code.push(0x05); // else
+                f.local_get(ADDRESS);
+                f.local_get(VALUE);
+                f.store();
+                f.code.push(0x0b);
+            }
+            Op::Write => {
+                f.cell();
+                f.i32_const(0xff);
+                f.code.push(0x71); // i32.and
+                f.call(WRITE);
+            }
+            // block { br_if zero; loop { body; br_if nonzero } }, the same checks the interpreter
+            // makes so iteration counts line up
+            Op::JumpIfZero { .. } => {
+                f.code.extend([0x02, EMPTY_BLOCK]);
+                f.cell();
+                f.code.push(0x45); // i32.eqz
+                f.code.extend([0x0d, 0]); // br_if 0
+                f.code.extend([0x03, EMPTY_BLOCK]);
+            }
+            Op::JumpIfNonZero { .. } => {
+                f.cell();
+                f.code.extend([0x0d, 0]); // br_if 0
+                f.code.extend([0x0b, 0x0b]);
+            }
+            Op::MultiplyMove { changes } => {
+                f.cell();
+                f.local_tee(VALUE);
+                f.code.extend([0x04, EMPTY_BLOCK]);
+                for (offset, factor) in changes.iter() {
+                    f.address(*offset);
+                    f.local_tee(ADDRESS);
+                    f.local_get(ADDRESS);
+                    f.load();
+                    f.local_get(VALUE);
+                    f.i32_const(factor.0 as i32);
+                    f.code.push(0x6c); // i32.mul
+                    f.code.push(0x6a); // i32.add
+                    f.store();
+                }
+                f.address(0);
+                f.i32_const(0);
+                f.store();
+                f.code.push(0x0b);
+            }
+            Op::Scan { stride } => {
+                f.code.extend([0x02, EMPTY_BLOCK, 0x03, EMPTY_BLOCK]);
+                f.cell();
+                f.code.push(0x45); // i32.eqz
+                f.code.extend([0x0d, 1]); // br_if 1
+                f.local_get(POINTER);
+                f.i32_const(*stride as i32);
+                f.code.push(0x6a); // i32.add
+                f.local_set(POINTER);
+                f.code.extend([0x0c, 0]); // br 0
+                f.code.extend([0x0b, 0x0b]);
+            }
+            // Skipped, see `CodegenOptions`
+            Op::Dump | Op::Extension { .. } => {}
+            Op::Procedure { .. } | Op::Return | Op::Call | Op::Fork => {
+                unreachable!("checked by `supported`")
+            }
+        }
+    }
+
+    f.code.push(0x0b);
+    f.code
+}
+
+/// Instructions for a function body
+#[derive(Default)]
+struct Function {
+    code: Vec<u8>,
+    /// Bytes per cell
+    width: u32,
+}
+
+impl Function {
+    fn i32_const(&mut self, value: i32) {
+        self.code.push(0x41);
+        sleb(&mut self.code, value as i64);
+    }
+
+    fn local_get(&mut self, local: u32) {
+        self.code.push(0x20);
+        uleb(&mut self.code, local as u64);
+    }
+
+    fn local_set(&mut self, local: u32) {
+        self.code.push(0x21);
+        uleb(&mut self.code, local as u64);
+    }
+
+    fn local_tee(&mut self, local: u32) {
+        self.code.push(0x22);
+        uleb(&mut self.code, local as u64);
+    }
+
+    fn call(&mut self, function: u32) {
+        self.code.push(0x10);
+        uleb(&mut self.code, function as u64);
+    }
+
+    /// Pushes the checked address of the cell at `pointer + offset`
+    fn address(&mut self, offset: isize) {
+        self.local_get(POINTER);
+        if offset != 0 {
+            self.i32_const(offset as i32);
+            self.code.push(0x6a); // i32.add
+        }
+        self.call(AT);
+    }
+
+    /// Pushes the current cell
+    fn cell(&mut self) {
+        self.address(0);
+        self.load();
+    }
+
+    fn load(&mut self) {
+        let (opcode, align) = match self.width {
+            1 => (0x2d, 0), // i32.load8_u
+            2 => (0x2f, 1), // i32.load16_u
+            _ => (0x28, 2), // i32.load
+        };
+        self.code.extend([opcode, align, 0]);
+    }
+
+    /// Stores a value into an address, both on the stack. Narrow stores truncate for free.
+    fn store(&mut self) {
+        let (opcode, align) = match self.width {
+            1 => (0x3a, 0), // i32.store8
+            2 => (0x3b, 1), // i32.store16
+            _ => (0x36, 2), // i32.store
+        };
+        self.code.extend([opcode, align, 0]);
+    }
+
+    /// Calls `fail` with `code` and traps when the condition on the stack is true
+    fn fail_if(&mut self, code: i32, pointer: u32) {
+        self.code.extend([0x04, EMPTY_BLOCK]);
+        self.i32_const(code);
+        self.local_get(pointer);
+        self.call(FAIL);
+        self.code.push(0x00); // unreachable
+        self.code.push(0x0b);
+    }
+
+    /// Counts an iteration, failing once there have been more than `max_iterations`
+    fn step(&mut self, max_iterations: u64) {
+        self.local_get(ITERATIONS);
+        self.code.push(0x42); // i64.const
+        sleb(&mut self.code, 1);
+        self.code.push(0x7c); // i64.add
+        self.local_tee(ITERATIONS);
+        self.code.push(0x42); // i64.const
+        sleb(&mut self.code, max_iterations as i64);
+        self.code.push(0x56); // i64.gt_u
+        self.code.extend([0x04, EMPTY_BLOCK]);
+        self.i32_const(FAIL_MAX_ITERATIONS);
+        self.local_get(POINTER);
+        self.call(FAIL);
+        self.code.push(0x00); // unreachable
+        self.code.push(0x0b);
+    }
+}
+
+fn width(cell_width: CellWidth) -> u32 {
+    match cell_width {
+        CellWidth::U8 => 1,
+        CellWidth::U16 => 2,
+        CellWidth::U32 => 4,
+    }
+}
+
+/// Appends a section with `count` entries written by `entries`
+fn section<F: FnOnce(&mut Vec<u8>)>(module: &mut Vec<u8>, id: u8, count: usize, entries: F) {
+    let mut content = vec![];
+    uleb(&mut content, count as u64);
+    entries(&mut content);
+
+    module.push(id);
+    uleb(module, content.len() as u64);
+    module.extend(content);
+}
+
+fn vector(out: &mut Vec<u8>, items: &[u8]) {
+    uleb(out, items.len() as u64);
+    out.extend(items);
+}
+
+fn name_bytes(out: &mut Vec<u8>, name: &str) {
+    vector(out, name.as_bytes());
+}
+
+fn uleb(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
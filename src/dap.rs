@@ -0,0 +1,712 @@
+//! `bfi dap`, a Debug Adapter Protocol server on stdin and stdout
+//!
+//! Editors launch a program with `{"program": "hello.bf", "input": "...", "stopOnEntry": true}`.
+//! There's a single thread, each loop the current instruction is inside shows up as a stack
+//! frame and the tape is shown as variables.
+
+use std::{
+    fs,
+    io::{self, Write},
+    sync::mpsc::{channel, Receiver, TryRecvError},
+    thread,
+};
+
+use bfi::{Bytecode, Event, Interpreter, Location, Machine, Op};
+use serde_json::{json, Value};
+
+use crate::rpc::{read_message, write_message};
+
+/// Steps taken between checks for new requests while running
+const BATCH: usize = 10_000;
+/// The most cells shown in the tape view
+const MAX_CELLS: usize = 1024;
+
+const TAPE: u64 = 1;
+const REGISTERS: u64 = 2;
+
+/// Serves one debugging session, returning once the editor disconnects
+pub fn serve() -> io::Result<()> {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        while let Ok(Some(message)) = read_message(&mut stdin) {
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    Session::new(rx, io::stdout()).run()
+}
+
+/// The program being debugged
+struct Program {
+    path: String,
+    source: String,
+    machine: Machine,
+    /// Wait to be continued once configuration is done rather than starting straight away
+    stop_on_entry: bool,
+}
+
+/// Where a running program should stop on its own
+#[derive(Clone, Copy)]
+enum Until {
+    /// Only at breakpoints or the end
+    Forever,
+    /// When the program counter reaches this instruction, for stepping over and out of loops
+    Pc(usize),
+}
+
+/// A debugging session, answering `requests` with responses and events written to `writer`
+struct Session<W> {
+    requests: Receiver<Value>,
+    writer: W,
+    seq: u64,
+    program: Option<Program>,
+    /// Set while the program is running rather than stopped
+    running: Option<Until>,
+}
+
+impl<W: Write> Session<W> {
+    fn new(requests: Receiver<Value>, writer: W) -> Self {
+        Session {
+            requests,
+            writer,
+            seq: 0,
+            program: None,
+            running: None,
+        }
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        loop {
+            let request = if self.running.is_some() {
+                match self.requests.try_recv() {
+                    Ok(request) => Some(request),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            } else {
+                match self.requests.recv() {
+                    Ok(request) => Some(request),
+                    Err(_) => return Ok(()),
+                }
+            };
+
+            if let Some(request) = request {
+                if !self.request(&request)? {
+                    return Ok(());
+                }
+            }
+            if let Some(until) = self.running {
+                self.advance(until)?;
+            }
+        }
+    }
+
+    /// Handles a request, returning `false` once the session is over
+    fn request(&mut self, request: &Value) -> io::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let arguments = &request["arguments"];
+
+        let body = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsStepBack": true,
+            })),
+            "launch" => self.launch(arguments),
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "configurationDone" => Ok(json!({})),
+            "threads" => Ok(json!({ "threads": [{ "id": 1, "name": "main" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Tape", "variablesReference": TAPE, "expensive": false },
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+            ]})),
+            "variables" => self.variables(arguments["variablesReference"].as_u64()),
+            "continue" => self
+                .launched()
+                .map(|_| json!({ "allThreadsContinued": true })),
+            "next" | "stepIn" | "stepOut" | "stepBack" | "reverseContinue" | "pause" => {
+                self.launched().map(|_| json!({}))
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(json!({})))?;
+                return Ok(false);
+            }
+            _ => Err(format!("unsupported request `{}`", command)),
+        };
+        let succeeded = body.is_ok();
+        self.respond(request, body)?;
+        if !succeeded {
+            return Ok(true);
+        }
+
+        // Requests that change the program's state are answered before the events they cause
+        match command {
+            "launch" => self.event("initialized", json!({}))?,
+            "configurationDone" => match &self.program {
+                Some(program) if program.stop_on_entry => self.stopped("entry", None)?,
+                Some(_) => self.running = Some(Until::Forever),
+                None => {}
+            },
+            "continue" => self.running = Some(Until::Forever),
+            "next" => self.step_over()?,
+            "stepIn" => self.step_in()?,
+            "stepOut" => self.step_out()?,
+            "stepBack" => {
+                if let Some(program) = &mut self.program {
+                    program.machine.step_back(1);
+                }
+                self.running = None;
+                self.stopped("step", None)?;
+            }
+            "reverseContinue" => {
+                if let Some(program) = &mut self.program {
+                    program.machine.reverse_continue();
+                }
+                self.running = None;
+                self.stopped("breakpoint", None)?;
+            }
+            "pause" if self.running.is_some() => {
+                self.running = None;
+                self.stopped("pause", None)?;
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+        let path = arguments["program"]
+            .as_str()
+            .ok_or("launch needs the path of a program")?;
+        let source =
+            fs::read_to_string(path).map_err(|err| format!("failed to read {}: {}", path, err))?;
+
+        // Unoptimized so every step is one command and breakpoints line up with the source
+        let instructions = bfc_ir::parse(&source).map_err(|err| {
+            format!(
+                "parse error at {}: {}",
+                Location::new(&source, err.position.start),
+                err.message
+            )
+        })?;
+        let mut builder = Interpreter::builder();
+        if let Some(tape_size) = arguments["tapeSize"].as_u64() {
+            builder = builder.tape_size(tape_size as usize);
+        }
+
+        let mut machine = builder.build(instructions).machine();
+        machine.keep_history();
+        // There's no terminal to type into, the input is all given up front
+        if let Some(input) = arguments["input"].as_str() {
+            machine.push_input(input.bytes());
+        }
+        machine.close_input();
+
+        self.program = Some(Program {
+            path: path.to_string(),
+            source,
+            machine,
+            stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+        });
+        Ok(json!({}))
+    }
+
+    fn launched(&self) -> Result<&Program, String> {
+        self.program
+            .as_ref()
+            .ok_or_else(|| "no program has been launched".to_string())
+    }
+
+    /// Replaces every breakpoint, they're placed on the first command at or after each line and
+    /// column
+    fn set_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let program = self
+            .program
+            .as_mut()
+            .ok_or("no program has been launched")?;
+        for offset in program.machine.breakpoints().collect::<Vec<_>>() {
+            program.machine.remove_breakpoint(offset);
+        }
+
+        let requested = arguments["breakpoints"].as_array().cloned();
+        let breakpoints: Vec<Value> = requested
+            .unwrap_or_default()
+            .iter()
+            .map(|breakpoint| {
+                let line = breakpoint["line"].as_u64().unwrap_or(1) as usize;
+                let column = breakpoint["column"].as_u64().unwrap_or(1) as usize;
+                match command_at(&program.source, line, column) {
+                    Some(offset) => {
+                        program.machine.add_breakpoint(offset);
+                        let location = Location::new(&program.source, offset);
+                        json!({
+                            "verified": true,
+                            "line": location.line,
+                            "column": location.column,
+                        })
+                    }
+                    None => json!({
+                        "verified": false,
+                        "message": "there are no commands on this line",
+                    }),
+                }
+            })
+            .collect();
+
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    /// The current instruction, then every loop around it from the innermost out
+    fn stack_trace(&self) -> Result<Value, String> {
+        let program = self.launched()?;
+        let machine = &program.machine;
+        let bytecode = machine.bytecode();
+
+        let mut frames = Vec::new();
+        let mut frame = |name: String, offset: usize| {
+            let location = Location::new(&program.source, offset);
+            frames.push(json!({
+                "id": frames.len(),
+                "name": name,
+                "source": { "path": program.path },
+                "line": location.line,
+                "column": location.column,
+            }));
+        };
+
+        match bytecode.position(machine.pc()) {
+            Some(position) => {
+                // The closing bracket's position covers the whole loop
+                let offset = match bytecode.ops()[machine.pc()] {
+                    Op::JumpIfNonZero { .. } => position.end,
+                    _ => position.start,
+                };
+                let name = program.source[offset..].chars().next().unwrap_or(' ');
+                frame(format!("`{}` at pc {}", name, machine.pc()), offset);
+            }
+            None => frame("end of program".to_string(), program.source.len()),
+        }
+        for open in enclosing_loops(bytecode, machine.pc()).into_iter().rev() {
+            if let Some(position) = bytecode.position(open) {
+                frame(format!("loop at pc {}", open), position.start);
+            }
+        }
+
+        let total = frames.len();
+        Ok(json!({ "stackFrames": frames, "totalFrames": total }))
+    }
+
+    fn variables(&self, reference: Option<u64>) -> Result<Value, String> {
+        let machine = &self.launched()?.machine;
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+
+        let variables: Vec<Value> = match reference {
+            Some(TAPE) => {
+                // Everything up to the last cell that's been used
+                let tape = machine.tape();
+                let used = tape.iter().rposition(|cell| cell.0 != 0).unwrap_or(0);
+                let end = (used.max(machine.pointer().max(0) as usize) + 1)
+                    .min(tape.len())
+                    .min(MAX_CELLS);
+                (0..end)
+                    .map(|index| {
+                        let name = if index as isize == machine.pointer() {
+                            format!("[{}] <- pointer", index)
+                        } else {
+                            format!("[{}]", index)
+                        };
+                        variable(name, tape[index].0.to_string())
+                    })
+                    .collect()
+            }
+            Some(REGISTERS) => vec![
+                variable("pc".to_string(), machine.pc().to_string()),
+                variable("pointer".to_string(), machine.pointer().to_string()),
+                variable("iterations".to_string(), machine.iterations().to_string()),
+            ],
+            _ => Vec::new(),
+        };
+
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn step_in(&mut self) -> io::Result<()> {
+        self.running = None;
+        let event = match &mut self.program {
+            Some(program) => program.machine.step(),
+            None => return Ok(()),
+        };
+        self.handle(event, "step")?;
+        Ok(())
+    }
+
+    /// Runs a whole loop when stopped at its start, otherwise steps in
+    fn step_over(&mut self) -> io::Result<()> {
+        let after = self.program.as_ref().and_then(|program| {
+            match program.machine.bytecode().ops().get(program.machine.pc()) {
+                Some(Op::JumpIfZero { target }) => Some(*target),
+                _ => None,
+            }
+        });
+
+        match after {
+            Some(target) => {
+                self.running = Some(Until::Pc(target));
+                Ok(())
+            }
+            None => self.step_in(),
+        }
+    }
+
+    /// Runs until the innermost loop is left
+    fn step_out(&mut self) -> io::Result<()> {
+        let after = self.program.as_ref().and_then(|program| {
+            let machine = &program.machine;
+            let open = *enclosing_loops(machine.bytecode(), machine.pc()).last()?;
+            match machine.bytecode().ops()[open] {
+                Op::JumpIfZero { target } => Some(target),
+                _ => None,
+            }
+        });
+
+        match after {
+            Some(target) => {
+                self.running = Some(Until::Pc(target));
+                Ok(())
+            }
+            None => {
+                self.running = Some(Until::Forever);
+                Ok(())
+            }
+        }
+    }
+
+    /// Runs a batch of steps, stopping when something happens
+    fn advance(&mut self, until: Until) -> io::Result<()> {
+        for _ in 0..BATCH {
+            let event = match &mut self.program {
+                Some(program) => program.machine.step(),
+                None => return Ok(()),
+            };
+            if self.handle(event, "breakpoint")? {
+                return Ok(());
+            }
+
+            if let (Until::Pc(target), Some(program)) = (until, &self.program) {
+                if program.machine.pc() == target {
+                    self.running = None;
+                    return self.stopped("step", None);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells the editor about a step's event, returning whether the program stopped. `reason` is
+    /// why it stopped after an instruction ran normally.
+    fn handle(&mut self, event: Event, reason: &str) -> io::Result<bool> {
+        match event {
+            Event::Executed | Event::Output(_) => {
+                if let Event::Output(output) = event {
+                    self.output(output)?;
+                }
+                if self.running.is_some() {
+                    return Ok(false);
+                }
+                self.stopped(reason, None)?;
+                Ok(true)
+            }
+            Event::Breakpoint(_) | Event::Watchpoint { .. } | Event::FuelExhausted => {
+                self.running = None;
+                self.stopped("breakpoint", None)?;
+                Ok(true)
+            }
+            Event::Error(err) => {
+                self.running = None;
+                self.stopped("exception", Some(format!("runtime error: {}", err)))?;
+                Ok(true)
+            }
+            // Input is closed from the start so reads never wait
+            Event::NeedsInput | Event::Halted => {
+                self.running = None;
+                self.event("exited", json!({ "exitCode": 0 }))?;
+                self.event("terminated", json!({}))?;
+                Ok(true)
+            }
+        }
+    }
+
+    fn output(&mut self, output: u8) -> io::Result<()> {
+        let text = String::from_utf8_lossy(&[output]).into_owned();
+        self.event("output", json!({ "category": "stdout", "output": text }))
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        let mut body = json!({ "reason": reason, "threadId": 1, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body)
+    }
+
+    fn respond(&mut self, request: &Value, body: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": body.is_ok(),
+        });
+        match body {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.writer, &message)
+    }
+}
+
+/// The opening instruction of every loop `pc` is inside, outermost first
+fn enclosing_loops(bytecode: &Bytecode, pc: usize) -> Vec<usize> {
+    let mut loops = Vec::new();
+    for (open, op) in bytecode.ops().iter().enumerate().take(pc) {
+        if let Op::JumpIfZero { target } = op {
+            // `target` is just past the closing bracket, which is part of the loop
+            if pc < *target {
+                loops.push(open);
+            }
+        }
+    }
+    loops
+}
+
+/// The offset of the first command on `line` at or after `column`, both counted from 1
+fn command_at(source: &str, line: usize, column: usize) -> Option<usize> {
+    let start: usize = source
+        .split_inclusive('\n')
+        .take(line.checked_sub(1)?)
+        .map(str::len)
+        .sum();
+    let text = source[start..].split('\n').next()?;
+
+    text.char_indices()
+        .skip(column.saturating_sub(1))
+        .find(|(_, c)| matches!(c, '+' | '-' | '<' | '>' | '.' | ',' | '[' | ']'))
+        .map(|(offset, _)| start + offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, process,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use crate::rpc::read_message;
+
+    /// A session with `source` launched from a file of its own
+    fn launched(source: &str, stop_on_entry: bool) -> Session<Vec<u8>> {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "bfi-dap-{}-{}.bf",
+            process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, source).unwrap();
+
+        let mut session = Session::new(channel().1, Vec::new());
+        request(
+            &mut session,
+            "launch",
+            json!({ "program": path.to_str().unwrap(), "stopOnEntry": stop_on_entry }),
+        );
+        fs::remove_file(&path).unwrap();
+        session
+    }
+
+    /// Sends a request, then lets the program run until it stops. Returns the response and the
+    /// events that followed it.
+    fn request(session: &mut Session<Vec<u8>>, command: &str, arguments: Value) -> Vec<Value> {
+        let request = json!({ "seq": 1, "command": command, "arguments": arguments });
+        assert!(session.request(&request).unwrap());
+        while let Some(until) = session.running {
+            session.advance(until).unwrap();
+        }
+
+        let written = std::mem::take(&mut session.writer);
+        let mut reader = &written[..];
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+        assert_eq!(messages[0]["type"], "response");
+        assert_eq!(messages[0]["command"], command);
+        messages
+    }
+
+    fn events(messages: &[Value]) -> Vec<&str> {
+        messages[1..]
+            .iter()
+            .map(|message| message["event"].as_str().unwrap())
+            .collect()
+    }
+
+    /// Where the top stack frame is, as a line and column
+    fn stopped_at(session: &mut Session<Vec<u8>>) -> (u64, u64) {
+        let frame = &request(session, "stackTrace", json!({}))[0]["body"]["stackFrames"][0];
+        (
+            frame["line"].as_u64().unwrap(),
+            frame["column"].as_u64().unwrap(),
+        )
+    }
+
+    #[test]
+    fn commands_on_lines() {
+        let source = "+\n  ->\n\nfoo\n[.]";
+
+        assert_eq!(command_at(source, 1, 1), Some(0));
+        assert_eq!(command_at(source, 2, 1), Some(4));
+        assert_eq!(command_at(source, 2, 4), Some(5));
+        assert_eq!(command_at(source, 2, 5), None);
+        assert_eq!(command_at(source, 3, 1), None);
+        assert_eq!(command_at(source, 4, 1), None);
+        assert_eq!(command_at(source, 5, 2), Some(13));
+        assert_eq!(command_at(source, 0, 1), None);
+        assert_eq!(command_at(source, 9, 1), None);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut session = launched("+\n  ->\n\nfoo\n.", false);
+
+        let response = &request(
+            &mut session,
+            "setBreakpoints",
+            json!({ "breakpoints": [{ "line": 2 }, { "line": 4 }, { "line": 5 }] }),
+        )[0];
+        let breakpoints = &response["body"]["breakpoints"];
+        assert_eq!(
+            breakpoints[0],
+            json!({ "verified": true, "line": 2, "column": 3 })
+        );
+        assert_eq!(breakpoints[1]["verified"], false);
+        assert_eq!(
+            breakpoints[2],
+            json!({ "verified": true, "line": 5, "column": 1 })
+        );
+
+        let messages = request(&mut session, "configurationDone", json!({}));
+        assert_eq!(events(&messages), ["stopped"]);
+        assert_eq!(messages[1]["body"]["reason"], "breakpoint");
+        assert_eq!(stopped_at(&mut session), (2, 3));
+
+        let messages = request(&mut session, "continue", json!({}));
+        assert_eq!(messages[0]["body"]["allThreadsContinued"], true);
+        assert_eq!(events(&messages), ["stopped"]);
+        assert_eq!(stopped_at(&mut session), (5, 1));
+
+        // Replacing the breakpoints clears the old ones
+        request(&mut session, "setBreakpoints", json!({ "breakpoints": [] }));
+        let messages = request(&mut session, "continue", json!({}));
+        assert_eq!(events(&messages), ["output", "exited", "terminated"]);
+        assert_eq!(
+            messages[1]["body"],
+            json!({ "category": "stdout", "output": "\0" })
+        );
+    }
+
+    #[test]
+    fn stepping() {
+        let mut session = launched("+[-]+.", true);
+        let messages = request(&mut session, "configurationDone", json!({}));
+        assert_eq!(messages[1]["body"]["reason"], "entry");
+        assert_eq!(stopped_at(&mut session), (1, 1));
+
+        let messages = request(&mut session, "stepIn", json!({}));
+        assert_eq!(events(&messages), ["stopped"]);
+        assert_eq!(messages[1]["body"]["reason"], "step");
+        assert_eq!(stopped_at(&mut session), (1, 2));
+
+        // Stepping over a loop runs all of it
+        request(&mut session, "next", json!({}));
+        assert_eq!(stopped_at(&mut session), (1, 5));
+
+        request(&mut session, "stepBack", json!({}));
+        assert_eq!(stopped_at(&mut session), (1, 4));
+
+        // Inside a loop the stack has a frame for it
+        let frames = &request(&mut session, "stackTrace", json!({}))[0]["body"];
+        assert_eq!(frames["totalFrames"], 2);
+        assert_eq!(frames["stackFrames"][1]["name"], "loop at pc 1");
+
+        request(&mut session, "stepOut", json!({}));
+        assert_eq!(stopped_at(&mut session), (1, 5));
+
+        let registers = &request(
+            &mut session,
+            "variables",
+            json!({ "variablesReference": REGISTERS }),
+        )[0]["body"]["variables"];
+        assert_eq!(
+            registers[0],
+            json!({ "name": "pc", "value": "4", "variablesReference": 0 })
+        );
+        let tape = &request(
+            &mut session,
+            "variables",
+            json!({ "variablesReference": TAPE }),
+        )[0]["body"]["variables"];
+        assert_eq!(tape[0]["name"], "[0] <- pointer");
+        assert_eq!(tape[0]["value"], "0");
+    }
+
+    #[test]
+    fn runtime_errors() {
+        let mut session = launched("+<", false);
+        let messages = request(&mut session, "configurationDone", json!({}));
+
+        assert_eq!(events(&messages), ["stopped"]);
+        assert_eq!(messages[1]["body"]["reason"], "exception");
+        assert!(messages[1]["body"]["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("runtime error: "));
+    }
+
+    #[test]
+    fn failed_requests() {
+        let mut session = Session::new(channel().1, Vec::new());
+
+        let response = &request(&mut session, "continue", json!({}))[0];
+        assert_eq!(response["success"], false);
+        assert_eq!(response["message"], "no program has been launched");
+
+        let response = &request(&mut session, "evaluate", json!({}))[0];
+        assert_eq!(response["success"], false);
+        assert_eq!(response["message"], "unsupported request `evaluate`");
+
+        let response = &request(
+            &mut session,
+            "launch",
+            json!({ "program": "/nonexistent/program.bf" }),
+        )[0];
+        assert_eq!(response["success"], false);
+
+        assert!(!session
+            .request(&json!({ "seq": 2, "command": "disconnect" }))
+            .unwrap());
+    }
+}
@@ -0,0 +1,181 @@
+//! Parse errors and the optimizer's warnings about a program, rendered like lints or as JSON for
+//! editors and CI
+//!
+//! ```ignore
+//! for diagnostic in Program::compile(&source)?.warnings() {
+//!     eprintln!("{}\n", diagnostic.render(&source));
+//! }
+//! ```
+
+use std::{fmt::Write, ops::Range};
+
+use bfc_ir::ParseError;
+
+use crate::{
+    judge::json_string,
+    lint::Severity,
+    source::{annotate_span_styled, Style},
+    Location,
+};
+
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const GREEN: &str = "\x1b[1;32m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+/// Something a tool noticed about a program without running it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// The byte range in the source it's about, when the tool knows
+    pub span: Option<Range<usize>>,
+    pub notes: Vec<Note>,
+}
+
+/// More about a [`Diagnostic`], pointing somewhere else in the source
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Note {
+    pub message: String,
+    pub span: Range<usize>,
+}
+
+impl Diagnostic {
+    /// `err` pointing at where it happened in `program`. Unmatched brackets get a note on the
+    /// closest bracket they could have been meant to pair with.
+    pub fn parse_error(program: &str, err: &ParseError) -> Self {
+        let offset = err.position.start;
+        let candidate = match program[offset..].chars().next() {
+            Some('[') => program[offset + 1..].find(']').map(|i| {
+                let message = "the closest `]` after it closes a `[` opened later";
+                (offset + 1 + i, message)
+            }),
+            Some(']') => program[..offset]
+                .rfind('[')
+                .map(|i| (i, "the closest `[` before it is already closed")),
+            _ => None,
+        };
+
+        Diagnostic {
+            severity: Severity::Error,
+            message: err.message.clone(),
+            span: Some(offset..offset + 1),
+            notes: candidate
+                .map(|(i, message)| Note {
+                    message: message.to_string(),
+                    span: i..i + 1,
+                })
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// Formats the diagnostic like rustc does, with the source it's about underlined when there
+    /// is some
+    ///
+    /// ```text
+    /// warning: <message>
+    ///  --> line 1, column 2
+    ///   |
+    /// 1 | +[-]
+    ///   |  ^^^
+    /// ```
+    pub fn render(&self, program: &str) -> String {
+        self.render_styled(program, false)
+    }
+
+    /// Like [`Diagnostic::render`] with ANSI colors for a terminal
+    pub fn render_color(&self, program: &str) -> String {
+        self.render_styled(program, true)
+    }
+
+    fn render_styled(&self, program: &str, color: bool) -> String {
+        let paint = |code: &'static str| if color { code } else { "" };
+        let (bold, reset) = (paint(BOLD), paint(RESET));
+        let style = |accent| Style {
+            accent: paint(accent),
+            gutter: paint(BLUE),
+            reset,
+        };
+
+        let accent = match self.severity {
+            Severity::Error => RED,
+            Severity::Warning => YELLOW,
+        };
+        let message = format!(
+            "{}{}{}{}: {}{}",
+            paint(accent),
+            self.severity,
+            reset,
+            bold,
+            self.message,
+            reset
+        );
+        let mut rendered = match &self.span {
+            Some(span) => annotate_span_styled(program, span.clone(), &message, style(accent)),
+            None => message,
+        };
+
+        for note in &self.notes {
+            let message = format!(
+                "{}note{}{}: {}{}",
+                paint(GREEN),
+                reset,
+                bold,
+                note.message,
+                reset
+            );
+            rendered.push('\n');
+            rendered.push_str(&annotate_span_styled(
+                program,
+                note.span.clone(),
+                &message,
+                style(GREEN),
+            ));
+        }
+        rendered
+    }
+
+    /// A single line JSON object with the span's byte offsets and where it starts, or `null`s
+    /// without a span
+    ///
+    /// ```text
+    /// {"severity":"warning","message":"...","start":0,"end":3,"line":1,"column":1}
+    /// ```
+    pub fn to_json(&self, program: &str) -> String {
+        let mut json = format!(
+            r#"{{"severity":"{}","message":{}"#,
+            self.severity,
+            json_string(&self.message)
+        );
+        match &self.span {
+            Some(span) => {
+                let location = Location::new(program, span.start);
+                write!(
+                    json,
+                    r#","start":{},"end":{},"line":{},"column":{}}}"#,
+                    span.start, span.end, location.line, location.column
+                )
+                .unwrap();
+            }
+            None => json.push_str(r#","start":null,"end":null,"line":null,"column":null}"#),
+        }
+        json
+    }
+}
+
+impl From<bfc_ir::Warning> for Diagnostic {
+    fn from(warning: bfc_ir::Warning) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            message: warning.message,
+            // bfc_ir's positions include their last byte
+            span: warning
+                .position
+                .map(|position| position.start..position.end + 1),
+            notes: Vec::new(),
+        }
+    }
+}
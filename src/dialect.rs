@@ -0,0 +1,330 @@
+use std::{borrow::Cow, fmt, path::Path, str::FromStr};
+
+use bfc_ir::{AstNode, ParseError, Position};
+
+use crate::{Bytecode, Op};
+
+/// A Brainfuck variant bfi can load, most translate one to one into Brainfuck
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Dialect {
+    #[default]
+    Brainfuck,
+    /// Ook!, where each pair of `Ook.`, `Ook?` and `Ook!` tokens is one command
+    Ook,
+    /// pbrain, which adds procedures. `(` defines the procedure numbered by the current cell as
+    /// everything up to the matching `)` and `:` calls the procedure numbered by the current cell.
+    /// Only [`Dialect::compile`] supports it.
+    Pbrain,
+    /// Brainfork, which adds `Y` to fork a thread with a copy of the tape. The current cell is
+    /// set to 0 in the original thread, the new one moves right and sets that cell to 1. Threads
+    /// take turns running an instruction each. Only [`Dialect::compile`] supports it.
+    Brainfork,
+}
+
+impl Dialect {
+    /// Picks a dialect from a file's extension, anything unrecognized is Brainfuck
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("ook") => Dialect::Ook,
+            _ => Dialect::Brainfuck,
+        }
+    }
+
+    /// Translates `source` into Brainfuck. Every command is placed at the offset of the text it
+    /// came from and everything else becomes whitespace, so positions in the translation point
+    /// into the original source.
+    ///
+    /// pbrain programs only translate if they don't use procedures.
+    pub fn translate<'a>(&self, source: &'a str) -> Result<Cow<'a, str>, ParseError> {
+        match self {
+            Dialect::Brainfuck => Ok(Cow::Borrowed(source)),
+            Dialect::Ook => translate_ook(source).map(Cow::Owned),
+            Dialect::Pbrain => match source.find(['(', ')', ':']) {
+                None => Ok(Cow::Borrowed(source)),
+                Some(offset) => Err(error(
+                    "pbrain procedures have no Brainfuck equivalent",
+                    offset,
+                    offset,
+                )),
+            },
+            Dialect::Brainfork => match source.find('Y') {
+                None => Ok(Cow::Borrowed(source)),
+                Some(offset) => Err(error(
+                    "Brainfork threads have no Brainfuck equivalent",
+                    offset,
+                    offset,
+                )),
+            },
+        }
+    }
+
+    /// Translates and parses `source`
+    pub fn parse(&self, source: &str) -> Result<Vec<AstNode>, ParseError> {
+        bfc_ir::parse(&self.translate(source)?)
+    }
+
+    /// Parses `source` into unoptimized bytecode, the only way to load pbrain procedures and
+    /// Brainfork threads. `bfc_ir`'s optimizer doesn't know about either and would move code
+    /// across them, but bfi's own [`Pass`](crate::Pass)es are safe to run afterwards.
+    pub fn compile(&self, source: &str) -> Result<Bytecode, ParseError> {
+        match self {
+            Dialect::Pbrain => compile_pbrain(source),
+            Dialect::Brainfork => Ok(Bytecode::compile(&bfc_ir::parse(source)?).with_forks(source)),
+            _ => Ok(Bytecode::compile(&self.parse(source)?)),
+        }
+    }
+}
+
+impl FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bf" | "brainfuck" => Ok(Dialect::Brainfuck),
+            "ook" => Ok(Dialect::Ook),
+            "pbrain" => Ok(Dialect::Pbrain),
+            "brainfork" => Ok(Dialect::Brainfork),
+            _ => Err(format!(
+                "unknown dialect {:?}, expected brainfuck, ook, pbrain or brainfork",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Dialect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dialect::Brainfuck => write!(f, "brainfuck"),
+            Dialect::Ook => write!(f, "ook"),
+            Dialect::Pbrain => write!(f, "pbrain"),
+            Dialect::Brainfork => write!(f, "brainfork"),
+        }
+    }
+}
+
+fn translate_ook(source: &str) -> Result<String, ParseError> {
+    // Start from a blank copy of the source that keeps its line breaks
+    let mut translated: Vec<u8> = source
+        .bytes()
+        .map(|b| if b == b'\n' { b'\n' } else { b' ' })
+        .collect();
+
+    let tokens = source.match_indices("Ook").filter_map(|(start, _)| {
+        let punctuation = *source.as_bytes().get(start + 3)?;
+        matches!(punctuation, b'.' | b'?' | b'!').then_some((start, punctuation))
+    });
+
+    let mut first = None;
+    for (start, punctuation) in tokens {
+        let (offset, a) = match first.take() {
+            None => {
+                first = Some((start, punctuation));
+                continue;
+            }
+            Some(first) => first,
+        };
+
+        translated[offset] = match (a, punctuation) {
+            (b'.', b'?') => b'>',
+            (b'?', b'.') => b'<',
+            (b'.', b'.') => b'+',
+            (b'!', b'!') => b'-',
+            (b'!', b'.') => b'.',
+            (b'.', b'!') => b',',
+            (b'!', b'?') => b'[',
+            (b'?', b'!') => b']',
+            _ => {
+                return Err(error(
+                    "`Ook? Ook?` is not an Ook! command",
+                    offset,
+                    start + 3,
+                ))
+            }
+        };
+    }
+
+    if let Some((offset, _)) = first {
+        return Err(error(
+            "Ook! commands come in pairs, this one has no partner",
+            offset,
+            offset + 3,
+        ));
+    }
+
+    // Every byte was replaced with ASCII
+    Ok(String::from_utf8(translated).unwrap())
+}
+
+/// Compiles pbrain, loops can't cross procedure boundaries so the Brainfuck between them is
+/// compiled a piece at a time
+fn compile_pbrain(source: &str) -> Result<Bytecode, ParseError> {
+    let mut ops = Vec::new();
+    let mut procedures = Vec::new();
+    let mut start = 0;
+
+    for (offset, c) in source.match_indices(['(', ')', ':']) {
+        compile_segment(source, start..offset, &mut ops)?;
+        start = offset + 1;
+
+        let op = match c {
+            "(" => {
+                procedures.push(offset);
+                Op::Procedure { end: 0 }
+            }
+            ")" => match procedures.pop() {
+                Some(_) => Op::Return,
+                None => return Err(error("`)` has no matching `(`", offset, offset)),
+            },
+            _ => Op::Call,
+        };
+        ops.push((
+            op,
+            Some(Position {
+                start: offset,
+                end: offset,
+            }),
+        ));
+    }
+    compile_segment(source, start..source.len(), &mut ops)?;
+
+    if let Some(offset) = procedures.pop() {
+        return Err(error("`(` has no matching `)`", offset, offset));
+    }
+
+    Ok(Bytecode::link(ops))
+}
+
+/// Compiles the Brainfuck in `source[range]`, keeping positions relative to all of `source`
+fn compile_segment(
+    source: &str,
+    range: std::ops::Range<usize>,
+    ops: &mut Vec<(Op, Option<Position>)>,
+) -> Result<(), ParseError> {
+    let shift = |position: Position| Position {
+        start: position.start + range.start,
+        end: position.end + range.start,
+    };
+
+    let instructions = bfc_ir::parse(&source[range.clone()]).map_err(|err| ParseError {
+        message: err.message,
+        position: shift(err.position),
+    })?;
+
+    let bytecode = Bytecode::compile(&instructions);
+    for (pc, op) in bytecode.ops().iter().enumerate() {
+        ops.push((op.clone(), bytecode.position(pc).map(shift)));
+    }
+
+    Ok(())
+}
+
+fn error(message: &str, start: usize, end: usize) -> ParseError {
+    ParseError {
+        message: message.to_string(),
+        position: Position { start, end },
+    }
+}
+
+/// The commands an [`Alphabet`] assigns tokens to, in the order they're given
+const COMMANDS: [u8; 8] = *b"><+-.,[]";
+
+/// A Brainfuck substitution, where each command is spelled with a different token. Covers the
+/// trivial substitutions like Alphuck and ReverseFuck as well as anything made up on the spot.
+///
+/// ```ignore
+/// let alphabet: Alphabet = "right left inc dec out in loop end".parse()?;
+/// let program = parse_with_alphabet("inc inc loop dec end", &alphabet)?;
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Alphabet {
+    /// Each token paired with its command, longest first so the lexer is greedy
+    tokens: Vec<(String, u8)>,
+}
+
+impl Alphabet {
+    /// Creates an alphabet from the tokens for `>`, `<`, `+`, `-`, `.`, `,`, `[` and `]` in that
+    /// order. Tokens can't be empty or repeated.
+    pub fn new<S: Into<String>>(tokens: [S; 8]) -> Result<Self, String> {
+        let mut tokens: Vec<(String, u8)> =
+            tokens.into_iter().map(Into::into).zip(COMMANDS).collect();
+
+        if tokens.iter().any(|(token, _)| token.is_empty()) {
+            return Err("alphabet tokens can't be empty".to_string());
+        }
+        for (i, (token, _)) in tokens.iter().enumerate() {
+            if tokens[..i].iter().any(|(other, _)| other == token) {
+                return Err(format!("alphabet token {:?} is used twice", token));
+            }
+        }
+
+        tokens.sort_by_key(|(token, _)| std::cmp::Reverse(token.len()));
+        Ok(Self { tokens })
+    }
+
+    /// Alphuck, `a c e i j o p s`
+    pub fn alphuck() -> Self {
+        Self::new(["a", "c", "e", "i", "j", "o", "p", "s"]).unwrap()
+    }
+
+    /// ReverseFuck, every command swapped with its opposite
+    pub fn reversefuck() -> Self {
+        Self::new(["<", ">", "-", "+", ",", ".", "]", "["]).unwrap()
+    }
+
+    /// Translates `source` into Brainfuck. Like [`Dialect::translate`] each command is placed at
+    /// the offset of its token and everything else becomes whitespace.
+    pub fn translate(&self, source: &str) -> String {
+        let mut translated = String::with_capacity(source.len());
+        let mut rest = source;
+
+        while let Some(c) = rest.chars().next() {
+            match self
+                .tokens
+                .iter()
+                .find(|(token, _)| rest.starts_with(token.as_str()))
+            {
+                Some((token, command)) => {
+                    translated.push(*command as char);
+                    translated.extend(std::iter::repeat_n(' ', token.len() - 1));
+                    rest = &rest[token.len()..];
+                }
+                None => {
+                    let blank = if c == '\n' { '\n' } else { ' ' };
+                    translated.extend(std::iter::repeat_n(blank, c.len_utf8()));
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+
+        translated
+    }
+}
+
+impl FromStr for Alphabet {
+    type Err = String;
+
+    /// Either the name of a known substitution (`alphuck` or `reversefuck`) or eight whitespace
+    /// separated tokens in the order `> < + - . , [ ]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "alphuck" => return Ok(Self::alphuck()),
+            "reversefuck" => return Ok(Self::reversefuck()),
+            _ => {}
+        }
+
+        let tokens: Vec<&str> = s.split_whitespace().collect();
+        let tokens: [&str; 8] = tokens.try_into().map_err(|tokens: Vec<&str>| {
+            format!(
+                "expected alphuck, reversefuck or 8 tokens for `> < + - . , [ ]`, got {} tokens",
+                tokens.len()
+            )
+        })?;
+        Self::new(tokens)
+    }
+}
+
+/// Parses a program written with `alphabet` in place of the usual commands
+pub fn parse_with_alphabet(program: &str, alphabet: &Alphabet) -> Result<Vec<AstNode>, ParseError> {
+    bfc_ir::parse(&alphabet.translate(program))
+}
@@ -0,0 +1,149 @@
+use std::fmt::Write;
+
+/// Bytes shown on each row of [`hexdiff`]
+const ROW: usize = 8;
+/// Rows shown either side of the first difference
+const CONTEXT: usize = 2;
+
+/// Formats a side by side hexdump of `expected` and `output` around the first byte where they
+/// differ, marking that row and byte. Returns an empty string when they're equal.
+///
+/// ```text
+/// first difference at byte 9: expected 0x6a, got 0x4a
+///          expected                         | output
+///  000000  61 62 63 64 65 66 67 68 abcdefgh | 61 62 63 64 65 66 67 68 abcdefgh
+/// >000008  69 6a 6b 6c             ijkl     | 69 4a 6b 6c             iJkl
+///             ^^                                 ^^
+/// ```
+pub fn hexdiff(expected: &[u8], output: &[u8]) -> String {
+    let first = match expected.iter().zip(output).position(|(e, o)| e != o) {
+        Some(first) => first,
+        None if expected.len() == output.len() => return String::new(),
+        None => expected.len().min(output.len()),
+    };
+
+    let mut diff = String::new();
+    match (expected.get(first), output.get(first)) {
+        (Some(e), Some(o)) => writeln!(
+            diff,
+            "first difference at byte {}: expected {:#04x}, got {:#04x}",
+            first, e, o
+        ),
+        (Some(_), None) => writeln!(
+            diff,
+            "output ended after {} bytes, expected {}",
+            output.len(),
+            expected.len()
+        ),
+        (None, _) => writeln!(
+            diff,
+            "output continued past the expected {} bytes to {}",
+            expected.len(),
+            output.len()
+        ),
+    }
+    .unwrap();
+
+    let width = ROW * 4;
+    writeln!(
+        diff,
+        "{:9}{:<width$} | output",
+        "",
+        "expected",
+        width = width
+    )
+    .unwrap();
+
+    let row = first / ROW;
+    let rows = expected.len().max(output.len()).div_ceil(ROW);
+    for r in row.saturating_sub(CONTEXT)..rows.min(row + CONTEXT + 1) {
+        let marker = if r == row { '>' } else { ' ' };
+        writeln!(
+            diff,
+            "{}{:06x}  {} | {}",
+            marker,
+            r * ROW,
+            render_row(expected, r),
+            render_row(output, r)
+        )
+        .unwrap();
+
+        if r == row {
+            let column = 9 + (first % ROW) * 3;
+            writeln!(diff, "{:column$}^^{:gap$}^^", "", "", gap = width + 1).unwrap();
+        }
+    }
+
+    diff
+}
+
+/// Formats a line by line diff of `expected` and `output`, decoded as UTF-8 with invalid bytes
+/// replaced. Lines only in `expected` start with `- `, lines only in `output` with `+ ` and shared
+/// lines with two spaces.
+///
+/// ```text
+///   hello
+/// - world
+/// + World
+/// ```
+pub fn linediff(expected: &[u8], output: &[u8]) -> String {
+    let expected = String::from_utf8_lossy(expected);
+    let output = String::from_utf8_lossy(output);
+    let expected: Vec<&str> = expected.split('\n').collect();
+    let output: Vec<&str> = output.split('\n').collect();
+
+    // lcs[i][j] is the longest common subsequence of expected[i..] and output[j..]
+    let mut lcs = vec![vec![0usize; output.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..output.len()).rev() {
+            lcs[i][j] = if expected[i] == output[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < output.len() {
+        if i < expected.len() && j < output.len() && expected[i] == output[j] {
+            writeln!(diff, "  {}", expected[i]).unwrap();
+            i += 1;
+            j += 1;
+        } else if j == output.len() || (i < expected.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            writeln!(diff, "- {}", expected[i]).unwrap();
+            i += 1;
+        } else {
+            writeln!(diff, "+ {}", output[j]).unwrap();
+            j += 1;
+        }
+    }
+
+    diff
+}
+
+/// Hex then ASCII for one row, padded to the full width when the bytes run out
+fn render_row(bytes: &[u8], row: usize) -> String {
+    let mut hex = String::new();
+    let mut ascii = String::new();
+
+    for i in row * ROW..(row + 1) * ROW {
+        match bytes.get(i) {
+            Some(&byte) => {
+                write!(hex, "{:02x} ", byte).unwrap();
+                ascii.push(if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                });
+            }
+            None => {
+                hex.push_str("   ");
+                ascii.push(' ');
+            }
+        }
+    }
+
+    hex + &ascii
+}
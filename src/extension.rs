@@ -0,0 +1,121 @@
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::{fmt, num::Wrapping};
+
+use crate::{machine::Io, RunTimeError};
+
+/// Runs an extra instruction, letting embedders expose their own functions to Brainfuck
+/// programs. Register handlers with [`InterpreterBuilder::extension`] and compile the commands
+/// with [`Bytecode::with_extensions`].
+///
+/// Closures taking an [`ExtensionContext`] are handlers:
+///
+/// ```ignore
+/// let interpreter = Interpreter::builder()
+///     .extension('@', |context: &mut ExtensionContext| {
+///         let doubled = context.cell() * 2;
+///         context.set_cell(doubled);
+///         Ok(())
+///     })
+///     .build_bytecode(bytecode.with_extensions(program, &['@']));
+/// ```
+///
+/// [`InterpreterBuilder::extension`]: crate::InterpreterBuilder::extension
+/// [`Bytecode::with_extensions`]: crate::Bytecode::with_extensions
+pub trait ExtensionHandler: Send + Sync {
+    /// Runs the instruction, an error stops the program like any other runtime error
+    fn call(&self, context: &mut ExtensionContext<'_>) -> Result<(), RunTimeError>;
+}
+
+impl<F> ExtensionHandler for F
+where
+    F: Fn(&mut ExtensionContext<'_>) -> Result<(), RunTimeError> + Send + Sync,
+{
+    fn call(&self, context: &mut ExtensionContext<'_>) -> Result<(), RunTimeError> {
+        self(context)
+    }
+}
+
+/// The machine as an [`ExtensionHandler`] sees it
+pub struct ExtensionContext<'a> {
+    pub(crate) command: char,
+    pub(crate) tape: &'a mut [Wrapping<u32>],
+    pub(crate) pointer: &'a mut isize,
+    pub(crate) mask: Wrapping<u32>,
+    pub(crate) io: &'a mut dyn Io,
+}
+
+impl ExtensionContext<'_> {
+    /// The character that invoked the handler
+    pub fn command(&self) -> char {
+        self.command
+    }
+
+    pub fn tape(&self) -> &[Wrapping<u32>] {
+        self.tape
+    }
+
+    pub fn pointer(&self) -> isize {
+        *self.pointer
+    }
+
+    /// Moves the pointer. It's checked once the handler returns, so it can leave the tape along
+    /// the way.
+    pub fn set_pointer(&mut self, pointer: isize) {
+        *self.pointer = pointer;
+    }
+
+    /// The current cell, zero if the pointer is off the tape
+    pub fn cell(&self) -> u32 {
+        usize::try_from(*self.pointer)
+            .ok()
+            .and_then(|index| self.tape.get(index))
+            .map_or(0, |cell| cell.0)
+    }
+
+    /// Overwrites the current cell, truncating `value` to the cell width. Does nothing if the
+    /// pointer is off the tape.
+    pub fn set_cell(&mut self, value: u32) {
+        if let Some(cell) = usize::try_from(*self.pointer)
+            .ok()
+            .and_then(|index| self.tape.get_mut(index))
+        {
+            *cell = Wrapping(value) & self.mask;
+        }
+    }
+
+    /// Reads the program's next input byte, `None` once the input has been closed. The
+    /// interpreter's EOF behavior doesn't apply.
+    pub fn read(&mut self) -> Result<Option<u8>, RunTimeError> {
+        self.io.read()
+    }
+
+    /// Writes a byte to the program's output
+    pub fn write(&mut self, output: u8) -> Result<(), RunTimeError> {
+        self.io.write(output)
+    }
+}
+
+/// Handlers by command, shared by every machine an interpreter creates
+#[derive(Clone, Default)]
+pub(crate) struct Extensions(BTreeMap<char, Arc<dyn ExtensionHandler>>);
+
+impl Extensions {
+    pub(crate) fn insert(&mut self, command: char, handler: Arc<dyn ExtensionHandler>) {
+        self.0.insert(command, handler);
+    }
+
+    pub(crate) fn get(&self, command: char) -> Option<Arc<dyn ExtensionHandler>> {
+        self.0.get(&command).cloned()
+    }
+
+    #[cfg(feature = "jit")]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
@@ -0,0 +1,166 @@
+/// How [`format_with`] lays out a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// Runs of commands are wrapped so lines fit in this many columns, indentation included.
+    /// Every line gets at least one command however deeply it's nested.
+    pub width: usize,
+    /// Keep comments, each on its own line at the depth it was written. Otherwise everything
+    /// other than the eight commands is dropped.
+    pub comments: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            width: 80,
+            comments: false,
+        }
+    }
+}
+
+/// Reformats a program with one loop bracket per line, indenting loop bodies by four spaces per
+/// level of nesting. Everything other than the eight commands is dropped.
+///
+/// ```ignore
+/// assert_eq!(bfi::format("+[->+<]"), "+\n[\n    ->+<\n]\n");
+/// ```
+pub fn format(program: &str) -> String {
+    format_with(program, &FormatOptions::default())
+}
+
+/// Like [`format`] with control over line width and comments
+pub fn format_with(program: &str, options: &FormatOptions) -> String {
+    let mut formatter = Formatter {
+        formatted: String::new(),
+        line: String::new(),
+        depth: 0,
+        width: options.width,
+    };
+    let mut comment = String::new();
+
+    for c in program.chars() {
+        if !matches!(c, '+' | '-' | '<' | '>' | '.' | ',' | '[' | ']') {
+            if options.comments {
+                comment.push(c);
+            }
+            continue;
+        }
+        formatter.comment(&comment);
+        comment.clear();
+
+        match c {
+            '[' => {
+                formatter.push_line();
+                formatter.line.push('[');
+                formatter.push_line();
+                formatter.depth += 1;
+            }
+            ']' => {
+                formatter.push_line();
+                // Unbalanced programs still format, they just don't parse
+                formatter.depth = formatter.depth.saturating_sub(1);
+                formatter.line.push(']');
+                formatter.push_line();
+            }
+            _ => {
+                if formatter.indent().len() + formatter.line.len() >= formatter.width {
+                    formatter.push_line();
+                }
+                formatter.line.push(c);
+            }
+        }
+    }
+    formatter.comment(&comment);
+    formatter.push_line();
+
+    formatter.formatted
+}
+
+struct Formatter {
+    formatted: String,
+    /// Commands waiting to be written out
+    line: String,
+    depth: usize,
+    width: usize,
+}
+
+impl Formatter {
+    fn indent(&self) -> String {
+        "    ".repeat(self.depth)
+    }
+
+    /// Moves `line` into `formatted` at the current depth, if there's anything on it
+    fn push_line(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+
+        self.formatted.push_str(&self.indent());
+        self.formatted.push_str(&self.line);
+        self.formatted.push('\n');
+        self.line.clear();
+    }
+
+    /// Writes each non-blank line of a comment on its own line
+    fn comment(&mut self, comment: &str) {
+        let lines: Vec<&str> = comment
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect();
+        if lines.is_empty() {
+            return;
+        }
+
+        self.push_line();
+        for line in lines {
+            self.formatted.push_str(&self.indent());
+            self.formatted.push_str(line);
+            self.formatted.push('\n');
+        }
+    }
+}
+
+/// Drops everything other than the eight commands
+///
+/// ```ignore
+/// assert_eq!(bfi::strip_comments("add two ++ and print ."), "++.");
+/// ```
+pub fn strip_comments(program: &str) -> String {
+    program
+        .chars()
+        .filter(|c| matches!(c, '+' | '-' | '<' | '>' | '.' | ',' | '[' | ']'))
+        .collect()
+}
+
+/// Strips comments and cancels out adjacent `+-`, `-+`, `<>` and `><` pairs, repeatedly, so
+/// `+<>-` disappears entirely. Cancelling `<>` at the left end of the tape removes an error that
+/// the original program would have run into.
+///
+/// ```ignore
+/// assert_eq!(bfi::minify("++- >>< [-+]"), "+>[]");
+/// ```
+pub fn minify(program: &str) -> String {
+    let mut minified = String::with_capacity(program.len());
+
+    for c in strip_comments(program).chars() {
+        let inverse = match c {
+            '+' => '-',
+            '-' => '+',
+            '<' => '>',
+            '>' => '<',
+            _ => {
+                minified.push(c);
+                continue;
+            }
+        };
+
+        if minified.ends_with(inverse) {
+            minified.pop();
+        } else {
+            minified.push(c);
+        }
+    }
+
+    minified
+}
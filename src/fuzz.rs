@@ -0,0 +1,113 @@
+//! Random programs and inputs for fuzzing the interpreter and optimizer
+//!
+//! ```ignore
+//! let mut rng = bfi::fuzz::Rng::new(42);
+//! for _ in 0..1000 {
+//!     let program = rng.program(64);
+//!     let input = rng.input(8);
+//!     bfi::fuzz::check(&program, &input).unwrap();
+//! }
+//! ```
+
+use std::{
+    fmt,
+    num::NonZeroU64,
+    panic::{self, AssertUnwindSafe},
+};
+
+use crate::{interpreter::panic_message, verify, Divergence};
+
+/// Iterations each run gets in [`check`], enough for most short programs to finish
+pub const MAX_ITERATIONS: u64 = 10_000;
+
+const COMMANDS: &[u8; 8] = b"+-<>,.[]";
+
+/// A small xorshift generator, fuzzing doesn't need anything better and it keeps the crate free
+/// of a `rand` dependency
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck at zero
+        Rng(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A random program of about `len` commands with balanced brackets
+    pub fn program(&mut self, len: usize) -> String {
+        let bytes: Vec<u8> = (0..len).map(|_| self.next_u64() as u8).collect();
+        program(&bytes)
+    }
+
+    /// Up to `len` random bytes
+    pub fn input(&mut self, len: usize) -> Vec<u8> {
+        let len = self.next_u64() as usize % (len + 1);
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+}
+
+/// Turns arbitrary bytes into a program that always parses, each byte picks a command. A `]`
+/// with nothing to close becomes a `[` and unclosed loops are closed at the end.
+pub fn program(bytes: &[u8]) -> String {
+    let mut program = String::with_capacity(bytes.len());
+    let mut depth = 0usize;
+
+    for byte in bytes {
+        match COMMANDS[*byte as usize % COMMANDS.len()] {
+            b'[' => {
+                depth += 1;
+                program.push('[');
+            }
+            b']' if depth == 0 => {
+                depth += 1;
+                program.push('[');
+            }
+            b']' => {
+                depth -= 1;
+                program.push(']');
+            }
+            command => program.push(command as char),
+        }
+    }
+
+    program.push_str(&"]".repeat(depth));
+    program
+}
+
+/// A broken invariant found by [`check`]
+#[derive(Debug)]
+pub enum Failure {
+    /// Compiling or running the program panicked, with the panic message
+    Panicked(String),
+    /// The optimizer changed what the program does
+    Diverged(Box<Divergence>),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Panicked(message) => write!(f, "panicked: {}", message),
+            Failure::Diverged(divergence) => {
+                write!(f, "optimizer changed behavior\n{}", divergence)
+            }
+        }
+    }
+}
+
+/// Runs `program` optimized and unoptimized with [`MAX_ITERATIONS`], checking that neither
+/// panics and that both agree. Programs that don't parse pass trivially.
+pub fn check(program: &str, input: &[u8]) -> Result<(), Failure> {
+    let max_iterations = NonZeroU64::new(MAX_ITERATIONS);
+    match panic::catch_unwind(AssertUnwindSafe(|| verify(program, input, max_iterations))) {
+        Ok(Ok(None) | Err(_)) => Ok(()),
+        Ok(Ok(Some(divergence))) => Err(Failure::Diverged(Box::new(divergence))),
+        Err(payload) => Err(Failure::Panicked(panic_message(&*payload))),
+    }
+}
@@ -0,0 +1,91 @@
+use alloc::{vec, vec::Vec};
+
+use crate::machine::Snapshot;
+
+/// Steps between checkpoints to start with
+const INTERVAL: u64 = 1024;
+/// Once there are more checkpoints than this every other one is dropped and the interval doubles,
+/// so long runs keep a bounded number of tapes at the cost of coarser rewinds
+const MAX_CHECKPOINTS: usize = 64;
+
+/// Checkpoints of a [`crate::Machine`] so it can step backwards, created by
+/// [`crate::Machine::keep_history`]. Going back restores the nearest checkpoint before the target
+/// and steps forward again from there.
+#[derive(Debug)]
+pub(crate) struct History {
+    /// Steps taken since history was turned on
+    pub(crate) steps: u64,
+    interval: u64,
+    /// Oldest first, the first is where history was turned on and is never dropped
+    checkpoints: Vec<Checkpoint>,
+    /// Every byte ever queued as input, so stepping forward again reads the same bytes
+    pub(crate) input: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Checkpoint {
+    pub(crate) steps: u64,
+    pub(crate) snapshot: Snapshot,
+    /// How much of [`History::input`] had been read
+    pub(crate) read: usize,
+}
+
+impl History {
+    /// Starts from `snapshot`, with whatever input was already queued
+    pub(crate) fn new(snapshot: Snapshot) -> Self {
+        let input = snapshot.input.clone();
+        History {
+            steps: 0,
+            interval: INTERVAL,
+            checkpoints: vec![Checkpoint {
+                steps: 0,
+                snapshot,
+                read: 0,
+            }],
+            input,
+        }
+    }
+
+    /// Counts a step, returning whether a checkpoint is due
+    pub(crate) fn step(&mut self) -> bool {
+        self.steps += 1;
+        self.steps.is_multiple_of(self.interval)
+    }
+
+    /// Saves the machine's state after the current step. `unread` is how much input is still
+    /// queued.
+    pub(crate) fn checkpoint(&mut self, snapshot: Snapshot, unread: usize) {
+        self.checkpoints.push(Checkpoint {
+            steps: self.steps,
+            snapshot,
+            read: self.input.len() - unread,
+        });
+
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            self.interval *= 2;
+            let interval = self.interval;
+            self.checkpoints
+                .retain(|checkpoint| checkpoint.steps % interval == 0);
+        }
+    }
+
+    /// Checkpoints starting before `steps`, latest first
+    pub(crate) fn before(&self, steps: u64) -> impl Iterator<Item = &Checkpoint> {
+        self.checkpoints
+            .iter()
+            .rev()
+            .filter(move |checkpoint| checkpoint.steps < steps)
+    }
+
+    /// Forgets everything after `steps`, returning the latest checkpoint at or before it to step
+    /// forward from
+    pub(crate) fn rewind(&mut self, steps: u64) -> Checkpoint {
+        self.checkpoints
+            .retain(|checkpoint| checkpoint.steps <= steps);
+        self.steps = steps;
+        self.checkpoints
+            .last()
+            .expect("the first checkpoint is never dropped")
+            .clone()
+    }
+}
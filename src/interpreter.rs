@@ -1,271 +1,1363 @@
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use core::{
+    fmt,
+    num::{NonZeroU64, Wrapping},
+    str::FromStr,
+    time::Duration,
+};
+#[cfg(feature = "std")]
 use std::{
-    cmp::Ordering,
-    num::Wrapping,
+    any::Any,
+    io::{self, Read, Write},
+    panic::{self, AssertUnwindSafe},
     sync::{
-        mpsc::{channel, Receiver, Sender},
-        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender},
+        Condvar, Mutex,
     },
     thread::{self, JoinHandle},
+    time::Instant,
 };
 
-use bfc_ir::AstNode;
+use bfc_ir::{AstNode, Position};
+
+#[cfg(feature = "jit")]
+use crate::jit::JitProgram;
+#[cfg(feature = "std")]
+use crate::profile::{Profile, Profiler};
+use crate::{
+    bytecode::Bytecode,
+    extension::{ExtensionHandler, Extensions},
+    machine::{Io, Machine, MachineState, Snapshot},
+    optimized,
+    program::Program,
+    record::{Recording, ReplayError},
+    source::Location,
+    tree::TreeWalker,
+};
 
 #[derive(Debug)]
 pub enum RunTimeError {
+    OutOfBoundsLeft(ErrorContext),
+    OutOfBoundsRight(ErrorContext),
+    MaxIterationsExceeded(ErrorContext),
+    TimedOut,
+    /// The program tried to read after the input was closed
+    InputExhausted(ErrorContext),
+    /// Reading input or writing output failed
+    #[cfg(feature = "std")]
+    Io(io::Error),
+    /// The run was cancelled through its [`SpawnHandle`]
+    Cancelled,
+    /// The receiving end of the output channel was dropped so the machine stopped
+    OutputClosed,
+    /// The program called a procedure that hasn't been defined
+    UndefinedProcedure(ErrorContext),
+    /// A cell went past its maximum or below zero with [`OverflowBehavior::Trap`]
+    CellOverflow(ErrorContext),
+    /// The program tried to write more than [`InterpreterBuilder::max_output`] bytes
+    OutputLimitExceeded(ErrorContext),
+    /// The machine used up its fuel, see [`InterpreterBuilder::fuel`]. Stepping machines pause
+    /// with [`Event::FuelExhausted`](crate::Event::FuelExhausted) instead.
+    FuelExhausted(ErrorContext),
+    /// The interpreter panicked, a bug in bfi or an extension handler. Holds the panic message.
+    Internal(String),
+}
+
+// `io::Error` isn't `Clone`, copies of I/O errors keep its kind and message
+impl Clone for RunTimeError {
+    fn clone(&self) -> Self {
+        match self {
+            RunTimeError::OutOfBoundsLeft(context) => RunTimeError::OutOfBoundsLeft(*context),
+            RunTimeError::OutOfBoundsRight(context) => RunTimeError::OutOfBoundsRight(*context),
+            RunTimeError::MaxIterationsExceeded(context) => {
+                RunTimeError::MaxIterationsExceeded(*context)
+            }
+            RunTimeError::TimedOut => RunTimeError::TimedOut,
+            RunTimeError::InputExhausted(context) => RunTimeError::InputExhausted(*context),
+            #[cfg(feature = "std")]
+            RunTimeError::Io(err) => RunTimeError::Io(io::Error::new(err.kind(), err.to_string())),
+            RunTimeError::Cancelled => RunTimeError::Cancelled,
+            RunTimeError::OutputClosed => RunTimeError::OutputClosed,
+            RunTimeError::UndefinedProcedure(context) => RunTimeError::UndefinedProcedure(*context),
+            RunTimeError::CellOverflow(context) => RunTimeError::CellOverflow(*context),
+            RunTimeError::OutputLimitExceeded(context) => {
+                RunTimeError::OutputLimitExceeded(*context)
+            }
+            RunTimeError::FuelExhausted(context) => RunTimeError::FuelExhausted(*context),
+            RunTimeError::Internal(message) => RunTimeError::Internal(message.clone()),
+        }
+    }
+}
+
+/// Which [`RunTimeError`] happened, without the details
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RunTimeErrorKind {
     OutOfBoundsLeft,
     OutOfBoundsRight,
     MaxIterationsExceeded,
+    TimedOut,
+    InputExhausted,
+    Io,
+    Cancelled,
+    OutputClosed,
+    UndefinedProcedure,
+    CellOverflow,
+    OutputLimitExceeded,
+    FuelExhausted,
+    Internal,
+}
+
+impl RunTimeError {
+    pub fn kind(&self) -> RunTimeErrorKind {
+        match self {
+            RunTimeError::OutOfBoundsLeft(_) => RunTimeErrorKind::OutOfBoundsLeft,
+            RunTimeError::OutOfBoundsRight(_) => RunTimeErrorKind::OutOfBoundsRight,
+            RunTimeError::MaxIterationsExceeded(_) => RunTimeErrorKind::MaxIterationsExceeded,
+            RunTimeError::TimedOut => RunTimeErrorKind::TimedOut,
+            RunTimeError::InputExhausted(_) => RunTimeErrorKind::InputExhausted,
+            #[cfg(feature = "std")]
+            RunTimeError::Io(_) => RunTimeErrorKind::Io,
+            RunTimeError::Cancelled => RunTimeErrorKind::Cancelled,
+            RunTimeError::OutputClosed => RunTimeErrorKind::OutputClosed,
+            RunTimeError::UndefinedProcedure(_) => RunTimeErrorKind::UndefinedProcedure,
+            RunTimeError::CellOverflow(_) => RunTimeErrorKind::CellOverflow,
+            RunTimeError::OutputLimitExceeded(_) => RunTimeErrorKind::OutputLimitExceeded,
+            RunTimeError::FuelExhausted(_) => RunTimeErrorKind::FuelExhausted,
+            RunTimeError::Internal(_) => RunTimeErrorKind::Internal,
+        }
+    }
+
+    /// Where the machine was, for errors raised by the program itself
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            RunTimeError::OutOfBoundsLeft(context)
+            | RunTimeError::OutOfBoundsRight(context)
+            | RunTimeError::MaxIterationsExceeded(context)
+            | RunTimeError::InputExhausted(context)
+            | RunTimeError::UndefinedProcedure(context)
+            | RunTimeError::CellOverflow(context)
+            | RunTimeError::OutputLimitExceeded(context)
+            | RunTimeError::FuelExhausted(context) => Some(context),
+            #[cfg(feature = "std")]
+            RunTimeError::Io(_) => None,
+            RunTimeError::TimedOut
+            | RunTimeError::Cancelled
+            | RunTimeError::OutputClosed
+            | RunTimeError::Internal(_) => None,
+        }
+    }
+}
+
+/// Where the machine was when a [`RunTimeError`] happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// Source position of the instruction that failed, if the parser recorded one
+    pub position: Option<Position>,
+    /// The memory pointer, which may be off the tape
+    pub pointer: isize,
+    /// Instructions executed, including the one that failed
+    pub iterations: u64,
+}
+
+impl ErrorContext {
+    /// The line and column of the failing instruction in `program`, the source it was compiled
+    /// from
+    pub fn location(&self, program: &str) -> Option<Location> {
+        self.position
+            .map(|position| Location::new(program, position.start))
+    }
+}
+
+impl fmt::Display for RunTimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunTimeError::OutOfBoundsLeft(context) => {
+                write!(f, "moved off the left end of the tape {}", context)
+            }
+            RunTimeError::OutOfBoundsRight(context) => {
+                write!(f, "moved off the right end of the tape {}", context)
+            }
+            RunTimeError::MaxIterationsExceeded(context) => {
+                write!(f, "exceeded the maximum number of iterations {}", context)
+            }
+            RunTimeError::TimedOut => write!(f, "timed out"),
+            RunTimeError::InputExhausted(context) => {
+                write!(f, "read past the end of the input {}", context)
+            }
+            #[cfg(feature = "std")]
+            RunTimeError::Io(err) => write!(f, "I/O error: {}", err),
+            RunTimeError::Cancelled => write!(f, "cancelled"),
+            RunTimeError::OutputClosed => write!(f, "output channel closed"),
+            RunTimeError::UndefinedProcedure(context) => {
+                write!(f, "called a procedure that was never defined {}", context)
+            }
+            RunTimeError::CellOverflow(context) => {
+                write!(f, "cell overflowed {}", context)
+            }
+            RunTimeError::OutputLimitExceeded(context) => {
+                write!(f, "exceeded the output limit {}", context)
+            }
+            RunTimeError::FuelExhausted(context) => write!(f, "ran out of fuel {}", context),
+            RunTimeError::Internal(message) => write!(f, "internal error: {}", message),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RunTimeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RunTimeError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(position) = self.position {
+            write!(f, "at offset {} ", position.start)?;
+        }
+
+        write!(
+            f,
+            "(pointer {}, after {} iterations)",
+            self.pointer, self.iterations
+        )
+    }
+}
+
+/// Number of cells on the tape unless configured otherwise
+pub const DEFAULT_TAPE_SIZE: usize = 30000;
+
+/// The number of bits in each tape cell. Cell arithmetic wraps at this width while input and
+/// output are still exchanged as bytes (outputs are truncated to their low 8 bits).
+///
+/// `bfc_ir`'s optimizer folds constants as 8-bit values so programs that rely on wider cells
+/// should be run unoptimized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CellWidth {
+    #[default]
+    U8,
+    U16,
+    U32,
+}
+
+impl CellWidth {
+    /// Mask that truncates a `u32` to this width
+    pub(crate) fn mask(self) -> Wrapping<u32> {
+        match self {
+            CellWidth::U8 => Wrapping(u8::MAX as u32),
+            CellWidth::U16 => Wrapping(u16::MAX as u32),
+            CellWidth::U32 => Wrapping(u32::MAX),
+        }
+    }
+}
+
+/// What `,` does once the input has been closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EofBehavior {
+    /// Set the current cell to 0
+    Zero,
+    /// Set the current cell to -1 (255 for byte cells)
+    MinusOne,
+    /// Leave the current cell as it is
+    Unchanged,
+    /// Stop with `RunTimeError::InputExhausted`
+    #[default]
+    Error,
+}
+
+/// Whether a program can use cells left of the one it starts on. Either way the tape is a single
+/// buffer, [`Machine::origin`] is where the starting cell is in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NegativeCells {
+    /// Moving left of the start fails with `RunTimeError::OutOfBoundsLeft`
+    #[default]
+    Error,
+    /// Start this many cells into the tape
+    Offset(usize),
+    /// Add cells to the left end of the tape on demand, doubling them each time, up to this many
+    Growable(usize),
+}
+
+/// When the pointer leaving the tape is an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BoundsCheck {
+    /// As soon as it moves off either end
+    #[default]
+    Strict,
+    /// Only once a cell is read or written there, so the pointer can step off the tape and back
+    Lenient,
+}
+
+impl FromStr for BoundsCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(BoundsCheck::Strict),
+            "lenient" => Ok(BoundsCheck::Lenient),
+            _ => Err(format!(
+                "unknown bounds check `{}`, expected strict or lenient",
+                s
+            )),
+        }
+    }
+}
+
+/// What one instruction adds to the iteration count that
+/// [`InterpreterBuilder::iteration_limit`] is checked against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IterationCost {
+    /// One per instruction, however much source the optimizer folded into it
+    #[default]
+    Instruction,
+    /// The number of source characters the instruction was compiled from. Loop brackets cost 1
+    /// each and instructions without a source position cost 1. A multiply is charged what the
+    /// loop it replaced would have cost, so `[->+<]` costs the same optimized or not, but clear
+    /// loops and scans are charged their length once however many times they would have looped.
+    Source,
+}
+
+impl FromStr for IterationCost {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "instruction" => Ok(IterationCost::Instruction),
+            "source" => Ok(IterationCost::Source),
+            _ => Err(format!(
+                "unknown iteration cost `{}`, expected instruction or source",
+                s
+            )),
+        }
+    }
+}
+
+/// What happens when arithmetic takes a cell past its maximum or below zero. Folded instructions
+/// are checked as a whole, so with the optimizer on `+-` at the maximum doesn't overflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowBehavior {
+    /// Wrap around, 255 + 1 is 0 for byte cells
+    #[default]
+    Wrap,
+    /// Stay at the maximum or at zero
+    Saturate,
+    /// Stop with `RunTimeError::CellOverflow`
+    Trap,
+}
+
+impl OverflowBehavior {
+    /// `cell + delta` for a cell no larger than `mask`
+    pub(crate) fn apply(
+        self,
+        cell: Wrapping<u32>,
+        delta: i64,
+        mask: Wrapping<u32>,
+    ) -> Option<Wrapping<u32>> {
+        let max = mask.0 as i64;
+        let value = cell.0 as i64 + delta;
+        match self {
+            OverflowBehavior::Wrap => Some(Wrapping(value as u32) & mask),
+            OverflowBehavior::Saturate => Some(Wrapping(value.clamp(0, max) as u32)),
+            OverflowBehavior::Trap => (0..=max).contains(&value).then_some(Wrapping(value as u32)),
+        }
+    }
+}
+
+impl FromStr for OverflowBehavior {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" | "wrapping" => Ok(OverflowBehavior::Wrap),
+            "saturate" | "saturating" => Ok(OverflowBehavior::Saturate),
+            "trap" => Ok(OverflowBehavior::Trap),
+            _ => Err(format!(
+                "unknown overflow behavior `{}`, expected wrap, saturate or trap",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for EofBehavior {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" | "0" => Ok(EofBehavior::Zero),
+            "minus-one" | "-1" | "255" => Ok(EofBehavior::MinusOne),
+            "unchanged" => Ok(EofBehavior::Unchanged),
+            "error" => Ok(EofBehavior::Error),
+            _ => Err(format!(
+                "unknown EOF behavior `{}`, expected zero, minus-one, unchanged or error",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for CellWidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" | "u8" => Ok(CellWidth::U8),
+            "16" | "u16" => Ok(CellWidth::U16),
+            "32" | "u32" => Ok(CellWidth::U32),
+            _ => Err(format!(
+                "unsupported cell width `{}`, expected 8, 16 or 32",
+                s
+            )),
+        }
+    }
 }
 
+/// Everything a run to completion produced, whether or not the program halted normally
 #[derive(Debug)]
+pub struct RunOutcome {
+    /// Output written before the program halted or failed
+    pub output: Vec<u8>,
+    /// Why the program stopped, `None` if it halted normally
+    pub error: Option<RunTimeError>,
+    pub iterations: u64,
+    /// Where the pointer finished, an index into the tape like [`Machine::pointer`]
+    pub pointer: isize,
+}
+
+impl RunOutcome {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// The output if the program halted normally, otherwise the output along with the error. The
+    /// shape [`Interpreter::run`] used to return.
+    pub fn into_result(self) -> Result<Vec<u8>, (Vec<u8>, RunTimeError)> {
+        match self.error {
+            None => Ok(self.output),
+            Some(err) => Err((self.output, err)),
+        }
+    }
+}
+
+/// What a run did along the way, see [`Interpreter::run_with_stats`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionStats {
+    /// Instructions executed, counted the way the iteration limit counts them
+    pub iterations: u64,
+    pub bytes_read: usize,
+    pub bytes_written: usize,
+    /// The furthest right of the starting cell the pointer went
+    pub peak_pointer: isize,
+    /// Wall-clock time the run took
+    pub elapsed: Duration,
+    /// The limits the run used at least 90% of, including one it ran into
+    pub limits_approached: Vec<Limit>,
+}
+
+/// A limit [`ExecutionStats`] can report a run came close to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Limit {
+    /// The iteration limit, see [`InterpreterBuilder::iteration_limit`]
+    Iterations,
+    /// The output limit, see [`InterpreterBuilder::max_output`]
+    Output,
+    /// The right end of the tape, or how far it's allowed to grow
+    Tape,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Iterations => write!(f, "iterations"),
+            Limit::Output => write!(f, "output"),
+            Limit::Tape => write!(f, "tape"),
+        }
+    }
+}
+
+/// Runs a compiled program. Cloning is cheap, clones share the program and the tapes kept
+/// around between runs.
+#[derive(Debug, Clone)]
 pub struct Interpreter {
-    instructions: Arc<Vec<AstNode>>,
-    max_iterations: u64,
+    bytecode: Arc<Bytecode>,
+    config: Arc<Config>,
+    #[cfg(feature = "jit")]
+    jit: Option<Arc<JitProgram>>,
+    tree: Option<Arc<TreeWalker>>,
+    #[cfg(feature = "std")]
+    tapes: Arc<TapePool>,
 }
 
-impl Interpreter {
-    pub fn new(instructions: Vec<AstNode>, max_iterations: u64) -> Self {
+/// Where [`Op::Dump`](crate::Op::Dump) sends the machine's state
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub enum DumpTarget {
+    Stderr,
+    Channel(Sender<String>),
+}
+
+/// Settings shared by every machine an [`Interpreter`] creates
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    pub(crate) max_iterations: Option<NonZeroU64>,
+    pub(crate) iteration_cost: IterationCost,
+    pub(crate) fuel: Option<u64>,
+    pub(crate) tape_size: usize,
+    pub(crate) max_tape_size: Option<usize>,
+    pub(crate) circular_tape: bool,
+    pub(crate) negative_cells: NegativeCells,
+    pub(crate) bounds_check: BoundsCheck,
+    pub(crate) cell_width: CellWidth,
+    pub(crate) eof_behavior: EofBehavior,
+    pub(crate) overflow: OverflowBehavior,
+    pub(crate) max_output: Option<usize>,
+    pub(crate) initial_memory: Vec<u8>,
+    #[cfg(feature = "std")]
+    pub(crate) dump: Option<DumpTarget>,
+    #[cfg(feature = "std")]
+    pub(crate) output_capacity: Option<usize>,
+    pub(crate) extensions: Extensions,
+    #[cfg_attr(not(feature = "jit"), allow(dead_code))]
+    pub(crate) jit: bool,
+    pub(crate) tree_walker: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
         Self {
-            instructions: Arc::new(instructions),
-            max_iterations,
+            max_iterations: None,
+            iteration_cost: IterationCost::Instruction,
+            fuel: None,
+            tape_size: DEFAULT_TAPE_SIZE,
+            max_tape_size: None,
+            circular_tape: false,
+            negative_cells: NegativeCells::Error,
+            bounds_check: BoundsCheck::Strict,
+            cell_width: CellWidth::U8,
+            eof_behavior: EofBehavior::Error,
+            overflow: OverflowBehavior::Wrap,
+            max_output: None,
+            initial_memory: Vec::new(),
+            #[cfg(feature = "std")]
+            dump: None,
+            #[cfg(feature = "std")]
+            output_capacity: None,
+            extensions: Extensions::default(),
+            jit: false,
+            tree_walker: false,
         }
     }
+}
 
-    /// Spawn a new machine and provide channels to communicate with it asynchronously
-    pub fn spawn(&self) -> (InputTx, OutputRx, JoinHandle<()>) {
-        let (input_tx, output_rx, inner) = self.create();
+/// Configures and builds an [`Interpreter`]
+///
+/// ```ignore
+/// let interpreter = InterpreterBuilder::new()
+///     .iteration_limit(NonZeroU64::new(1_000_000))
+///     .tape_size(1024)
+///     .eof_behavior(EofBehavior::Zero)
+///     .build(instructions);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct InterpreterBuilder {
+    config: Config,
+}
 
-        let handle = inner.run();
+impl InterpreterBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        (input_tx, output_rx, handle)
+    /// Stop with `RunTimeError::MaxIterationsExceeded` after this many instructions, or never for
+    /// `None`, the default
+    pub fn iteration_limit(mut self, max_iterations: Option<NonZeroU64>) -> Self {
+        self.config.max_iterations = max_iterations;
+        self
     }
 
-    /// Spawn a new interpreter and run it to completion with provide input
-    pub fn run<I>(&self, inputs: I) -> Result<Vec<u8>, (Vec<u8>, RunTimeError)>
+    /// Stop after `max_iterations` instructions, where `0` now means unlimited rather than
+    /// failing at the first instruction
+    #[deprecated(since = "0.1.7", note = "use `iteration_limit`")]
+    pub fn max_iterations(self, max_iterations: u64) -> Self {
+        self.iteration_limit(NonZeroU64::new(max_iterations))
+    }
+
+    /// Choose how instructions count towards the iteration limit, see [`IterationCost`]
+    pub fn iteration_cost(mut self, iteration_cost: IterationCost) -> Self {
+        self.config.iteration_cost = iteration_cost;
+        self
+    }
+
+    /// Start machines with `fuel` instructions to spend, each instruction burns one. Unlike
+    /// [`InterpreterBuilder::iteration_limit`] the budget can be topped up with
+    /// [`Machine::add_fuel`], a stepping machine that runs dry pauses with
+    /// [`Event::FuelExhausted`](crate::Event::FuelExhausted) until it is. Runs to completion fail
+    /// with `RunTimeError::FuelExhausted`. Unmetered by default.
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.config.fuel = Some(fuel);
+        self
+    }
+
+    /// Use a tape of `tape_size` cells instead of the default 30,000
+    pub fn tape_size(mut self, tape_size: usize) -> Self {
+        self.config.tape_size = tape_size;
+        self
+    }
+
+    /// Grow the tape on demand when the pointer moves past its right end instead of failing with
+    /// `RunTimeError::OutOfBoundsRight`. The tape doubles in size each time, up to
+    /// `max_tape_size` cells.
+    pub fn growable_tape(mut self, max_tape_size: usize) -> Self {
+        self.config.max_tape_size = Some(max_tape_size);
+        self
+    }
+
+    /// Wrap the pointer around to the other end of the tape instead of failing with
+    /// `RunTimeError::OutOfBoundsLeft` or `RunTimeError::OutOfBoundsRight`, offsets wrap the same
+    /// way. A circular tape never grows.
+    pub fn circular_tape(mut self, circular: bool) -> Self {
+        self.config.circular_tape = circular;
+        self
+    }
+
+    /// Let the program use cells left of the one it starts on, see [`NegativeCells`]
+    pub fn negative_cells(mut self, negative_cells: NegativeCells) -> Self {
+        self.config.negative_cells = negative_cells;
+        self
+    }
+
+    /// Choose when the pointer leaving the tape is an error, see [`BoundsCheck`]
+    pub fn bounds_check(mut self, bounds_check: BoundsCheck) -> Self {
+        self.config.bounds_check = bounds_check;
+        self
+    }
+
+    /// Use cells of the given width instead of bytes
+    pub fn cell_width(mut self, cell_width: CellWidth) -> Self {
+        self.config.cell_width = cell_width;
+        self
+    }
+
+    /// Choose what reading past the end of the input does
+    pub fn eof_behavior(mut self, eof_behavior: EofBehavior) -> Self {
+        self.config.eof_behavior = eof_behavior;
+        self
+    }
+
+    /// Choose what arithmetic past a cell's maximum or below zero does, it wraps by default.
+    /// `bfc_ir`'s optimizer folds constants with wrapping arithmetic so only use it with
+    /// [`OverflowBehavior::Wrap`], [`build_program`](Self::build_program) takes care of that.
+    pub fn overflow(mut self, overflow: OverflowBehavior) -> Self {
+        self.config.overflow = overflow;
+        self
+    }
+
+    /// Start every run with the first cells of the tape set to `memory` instead of zero. The tape
+    /// is lengthened if `memory` doesn't fit.
+    pub fn initial_memory(mut self, memory: Vec<u8>) -> Self {
+        self.config.initial_memory = memory;
+        self
+    }
+
+    /// Report the machine's state at every [`Op::Dump`](crate::Op::Dump), see
+    /// [`Bytecode::with_dumps`]. Dumps are ignored otherwise.
+    #[cfg(feature = "std")]
+    pub fn dump_state(mut self, target: DumpTarget) -> Self {
+        self.config.dump = Some(target);
+        self
+    }
+
+    /// Stop with `RunTimeError::OutputLimitExceeded` instead of writing more than `bytes` bytes of
+    /// output. Unlimited by default. Bytes written by extension handlers aren't counted.
+    pub fn max_output(mut self, bytes: usize) -> Self {
+        self.config.max_output = Some(bytes);
+        self
+    }
+
+    /// Buffer at most `capacity` bytes of output from [`Interpreter::spawn`], blocking the
+    /// machine until the receiver catches up. Unbounded by default, so a program writing faster
+    /// than its output is read grows memory without limit. A blocked machine only notices
+    /// [`SpawnHandle::cancel`] once there's room again or the receiver is dropped.
+    #[cfg(feature = "std")]
+    pub fn output_capacity(mut self, capacity: usize) -> Self {
+        self.config.output_capacity = Some(capacity);
+        self
+    }
+
+    /// Run `handler` at every [`Op::Extension`](crate::Op::Extension) for `command`, see
+    /// [`Bytecode::with_extensions`]. Registering a command again replaces its handler.
+    pub fn extension<H>(mut self, command: char, handler: H) -> Self
     where
-        I: IntoIterator<Item = u8>,
+        H: ExtensionHandler + 'static,
     {
-        let (input_tx, output_rx, inner) = self.create();
+        self.config.extensions.insert(command, Arc::new(handler));
+        self
+    }
+
+    /// Compile the program to native code and use it for [`Interpreter::run_outcome`],
+    /// [`Interpreter::run_with_streams`] and [`Interpreter::run_with_io`]. Stepping, spawning and
+    /// timeouts always use the interpreter, as does everything when the `jit` feature is off or
+    /// the settings need more than 8-bit cells on a fixed size tape.
+    pub fn jit(mut self, jit: bool) -> Self {
+        self.config.jit = jit;
+        self
+    }
 
-        inputs
-            .into_iter()
-            .map(|i| Wrapping(i))
-            .for_each(|i| input_tx.send(i).unwrap());
+    /// Run programs by walking the tree they were parsed into rather than as bytecode, for
+    /// [`Interpreter::run_outcome`], [`Interpreter::run_with_streams`] and
+    /// [`Interpreter::run_with_io`]. Much slower, it's kept to check the bytecode interpreter
+    /// against. Only interpreters made with [`build`](Self::build) have a tree to walk, and like
+    /// [`jit`](Self::jit) it needs 8-bit cells on a fixed size tape. Takes precedence over `jit`.
+    pub fn tree_walker(mut self, tree_walker: bool) -> Self {
+        self.config.tree_walker = tree_walker;
+        self
+    }
 
-        inner.run_blocking();
+    /// Compile `instructions` and create the interpreter
+    pub fn build(self, instructions: Vec<AstNode>) -> Interpreter {
+        let bytecode = Bytecode::compile(&instructions);
+        let tree = (self.config.tree_walker && TreeWalker::supports(&self.config))
+            .then(|| Arc::new(TreeWalker::new(instructions, &self.config)));
 
-        let mut outputs = vec![];
-        for output in output_rx.iter() {
-            match output {
-                Ok(b) => outputs.push(b.0),
-                Err(err) => return Err((outputs, err)),
-            }
+        Interpreter {
+            tree,
+            ..self.build_bytecode(bytecode)
+        }
+    }
+
+    /// Create the interpreter from an already compiled program
+    pub fn build_bytecode(self, bytecode: Bytecode) -> Interpreter {
+        self.build_shared(Arc::new(bytecode))
+    }
+
+    /// Create the interpreter from a [`Program`], sharing its bytecode. The cell width is the one
+    /// the program was compiled for. Programs are optimized for wrapping cells, with any other
+    /// [`overflow`](InterpreterBuilder::overflow) the source is optimized again without the
+    /// rewrites that rely on wrapping.
+    pub fn build_program(mut self, program: &Program) -> Interpreter {
+        self.config.cell_width = program.cell_width;
+        if self.config.overflow != OverflowBehavior::Wrap {
+            let bytecode = optimized(&program.source, program.cell_width, self.config.overflow)
+                .expect("the program parsed when compiled");
+            return self.build_bytecode(bytecode);
         }
+        self.build_shared(program.bytecode.clone())
+    }
+
+    fn build_shared(self, bytecode: Arc<Bytecode>) -> Interpreter {
+        // Native code is only an accelerator, fall back to the interpreter if it can't be built
+        #[cfg(feature = "jit")]
+        let jit = (self.config.jit && JitProgram::supports(&self.config))
+            .then(|| JitProgram::compile(bytecode.clone(), &self.config).ok())
+            .flatten()
+            .map(Arc::new);
+
+        Interpreter {
+            bytecode,
+            config: Arc::new(self.config),
+            #[cfg(feature = "jit")]
+            jit,
+            tree: None,
+            #[cfg(feature = "std")]
+            tapes: Arc::default(),
+        }
+    }
+}
+
+impl Interpreter {
+    /// Create an interpreter limited to `max_iterations` instructions, 0 means unlimited
+    #[deprecated(
+        since = "0.1.7",
+        note = "use `from_instructions`, it takes `None` for no limit"
+    )]
+    pub fn new(instructions: Vec<AstNode>, max_iterations: u64) -> Self {
+        Self::from_instructions(instructions, NonZeroU64::new(max_iterations))
+    }
 
-        Ok(outputs)
+    /// Create an interpreter limited to `max_iterations` instructions, unlimited for `None`
+    pub fn from_instructions(
+        instructions: Vec<AstNode>,
+        max_iterations: Option<NonZeroU64>,
+    ) -> Self {
+        Self::builder()
+            .iteration_limit(max_iterations)
+            .build(instructions)
     }
 
-    fn create(&self) -> (InputTx, OutputRx, InterpreterInner) {
-        // Create two channels to handle inputs and outputs
+    /// Create an interpreter from an already compiled program
+    pub fn from_bytecode(bytecode: Bytecode, max_iterations: Option<NonZeroU64>) -> Self {
+        Self::builder()
+            .iteration_limit(max_iterations)
+            .build_bytecode(bytecode)
+    }
+
+    /// Create an interpreter for a compiled [`Program`]
+    pub fn from_program(program: &Program, max_iterations: Option<NonZeroU64>) -> Self {
+        Self::builder()
+            .iteration_limit(max_iterations)
+            .build_program(program)
+    }
+
+    pub fn builder() -> InterpreterBuilder {
+        InterpreterBuilder::new()
+    }
+
+    /// The compiled program this interpreter executes
+    pub fn bytecode(&self) -> &Bytecode {
+        &self.bytecode
+    }
+
+    /// Spawn a new machine and provide channels to communicate with it asynchronously
+    #[cfg(feature = "std")]
+    pub fn spawn(&self) -> (InputTx, OutputRx, SpawnHandle) {
         let (input_tx, input_rx): (InputTx, InputRx) = channel();
-        let (output_tx, output_rx): (OutputTx, OutputRx) = channel();
-
-        (
-            input_tx,
-            output_rx,
-            InterpreterInner {
-                instructions: self.instructions.clone(),
-                max_iterations: self.max_iterations,
-                memory: vec![Wrapping(0); 30000],
-                memory_pointer: 0,
-                iterations: 0,
+        let (output_tx, output_rx) = match self.config.output_capacity {
+            Some(capacity) => {
+                let (tx, rx) = sync_channel(capacity);
+                (OutputSender::Bounded(tx), rx)
+            }
+            None => {
+                let (tx, rx) = channel();
+                (OutputSender::Unbounded(tx), rx)
+            }
+        };
+        let control = Arc::new(Control::default());
+        let mut machine = self.machine();
+        machine.control = Some(control.clone());
+
+        let io_control = control.clone();
+        let handle = thread::spawn(move || {
+            let start = Instant::now();
+            let mut io = ChannelIo {
                 inputs: input_rx,
                 outputs: output_tx,
-            },
-        )
+                control: io_control,
+            };
+
+            // A panic would otherwise only surface as a disconnected channel
+            let result = panic::catch_unwind(AssertUnwindSafe(|| machine.execute(&mut io)))
+                .unwrap_or_else(|payload| Err(RunTimeError::Internal(panic_message(&*payload))));
+
+            if let Err(err) = &result {
+                // Nobody is listening if the output receiver was dropped
+                let _ = io.outputs.send(Err(err.clone()));
+            }
+            (result, machine.stats(start.elapsed()))
+        });
+
+        (input_tx, output_rx, SpawnHandle { handle, control })
+    }
+
+    /// Run to completion with the provided input. Reading past the end of `inputs` follows the
+    /// configured [`EofBehavior`], by default failing with `RunTimeError::InputExhausted` instead
+    /// of waiting forever.
+    pub fn run_outcome<I>(&self, inputs: I) -> RunOutcome
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        let mut io = BufferIo {
+            input: inputs.into_iter(),
+            output: vec![],
+        };
+
+        let (result, iterations, pointer) = self.execute_counted(&mut io);
+        RunOutcome {
+            output: io.output,
+            error: result.err(),
+            iterations,
+            pointer,
+        }
+    }
+
+    /// Like [`Interpreter::run_outcome`] but also returns what the run did along the way
+    #[cfg(feature = "std")]
+    pub fn run_with_stats<I>(&self, inputs: I) -> (RunOutcome, ExecutionStats)
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        let start = Instant::now();
+        let mut machine = self.machine();
+        let outcome = Self::run_machine(&mut machine, inputs);
+        let stats = machine.stats(start.elapsed());
+        self.recycle(machine);
+        (outcome, stats)
+    }
+
+    /// Run to completion with the provided input, see [`Interpreter::run_outcome`]
+    #[deprecated(
+        since = "0.1.7",
+        note = "use `run_outcome`, `RunOutcome::into_result` gives the same result"
+    )]
+    pub fn run<I>(&self, inputs: I) -> Result<Vec<u8>, (Vec<u8>, RunTimeError)>
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        self.run_outcome(inputs).into_result()
+    }
+
+    /// Run to completion on the current thread, reading input from `input` and writing output to
+    /// `output` as it is produced. Input is read one byte at a time so consider wrapping slow
+    /// readers in a `BufReader`.
+    #[cfg(feature = "std")]
+    pub fn run_with_streams<R, W>(&self, input: &mut R, output: &mut W) -> Result<(), RunTimeError>
+    where
+        R: Read,
+        W: Write,
+    {
+        let mut io = StreamIo { input, output };
+        self.execute(&mut io)?;
+        io.output.flush().map_err(RunTimeError::Io)
+    }
+
+    /// Run to completion on the current thread, calling `input` whenever the program reads (`None`
+    /// means the input is exhausted) and `output` whenever it writes
+    pub fn run_with_io<I, O>(&self, input: I, output: O) -> Result<(), RunTimeError>
+    where
+        I: FnMut() -> Option<u8>,
+        O: FnMut(u8),
+    {
+        self.execute(&mut FnIo { input, output })
+    }
+
+    /// Like [`Interpreter::run_outcome`] but returns the whole state the machine finished with,
+    /// tape included, whether or not the run succeeded
+    pub fn run_with_state<I>(&self, inputs: I) -> (RunOutcome, MachineState)
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        let mut machine = self.machine();
+        let outcome = Self::run_machine(&mut machine, inputs);
+        (outcome, machine.into_state())
+    }
+
+    /// Like [`Interpreter::run_with_state`] but gives up with `RunTimeError::TimedOut` once
+    /// `deadline` passes
+    #[cfg(feature = "std")]
+    pub fn run_with_deadline<I>(&self, inputs: I, deadline: Instant) -> (RunOutcome, MachineState)
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        let (outcome, machine) = self.run_until(inputs, Some(deadline), None, None);
+        (outcome, machine.into_state())
+    }
+
+    /// Like [`Interpreter::run_outcome`] but also counts how often each instruction runs and
+    /// times every loop. Noticeably slower than a normal run.
+    #[cfg(feature = "std")]
+    pub fn profile<I>(&self, inputs: I) -> (RunOutcome, Profile)
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        let mut machine = self.machine();
+        machine.profiler = Some(Profiler::new(self.bytecode.len()));
+        let outcome = Self::run_machine(&mut machine, inputs);
+
+        let profiler = machine
+            .profiler
+            .take()
+            .expect("profiler was installed above");
+        (outcome, profiler.finish(self.bytecode.clone()))
+    }
+
+    /// Like [`Interpreter::run_with_streams`] but also records every read and the output, so the
+    /// run can be checked again later with [`Interpreter::replay`]. Always runs on the bytecode
+    /// interpreter so iteration counts match between runs. The recording covers everything up to
+    /// an error.
+    #[cfg(feature = "std")]
+    pub fn record<R, W>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+    ) -> (Result<(), RunTimeError>, Recording)
+    where
+        R: Read,
+        W: Write,
+    {
+        let mut machine = self.machine();
+        machine.recording = Some(Recording::default());
+
+        let mut io = StreamIo { input, output };
+        let result = machine
+            .execute(&mut io)
+            .and_then(|()| io.output.flush().map_err(RunTimeError::Io));
+
+        let recording = machine
+            .recording
+            .take()
+            .expect("recording was installed above");
+        (result, recording)
+    }
+
+    /// Runs the program again on `recording`'s input, checking that every read happens at the
+    /// same iteration and the output is the same. Returns the output when it matches.
+    pub fn replay(&self, recording: &Recording) -> Result<Vec<u8>, ReplayError> {
+        let mut machine = self.machine();
+        machine.recording = Some(Recording::default());
+
+        let mut io = BufferIo {
+            input: recording.input(),
+            output: vec![],
+        };
+        // An error is fine as long as the recorded run hit it at the same point, the comparison
+        // below will tell
+        let _ = machine.execute(&mut io);
+
+        let replayed = machine
+            .recording
+            .take()
+            .expect("recording was installed above");
+        recording.compare(&replayed)?;
+        Ok(replayed.output)
+    }
+
+    /// Run to completion, giving up with `RunTimeError::TimedOut` once `deadline` passes.
+    /// `max_iterations` and `max_output` override the configured limits. Also returns the machine
+    /// that ran the program.
+    #[cfg(feature = "std")]
+    pub(crate) fn run_until<I>(
+        &self,
+        inputs: I,
+        deadline: Option<Instant>,
+        max_iterations: Option<NonZeroU64>,
+        max_output: Option<usize>,
+    ) -> (RunOutcome, Machine)
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        let mut machine = self.machine();
+        let outcome = Self::run_limited(&mut machine, inputs, deadline, max_iterations, max_output);
+        (outcome, machine)
     }
+
+    /// Like [`Interpreter::run_until`] but on `machine`, which usually has just been
+    /// [reset](Machine::reset) to run another input
+    #[cfg(feature = "std")]
+    pub(crate) fn run_limited<I>(
+        machine: &mut Machine,
+        inputs: I,
+        deadline: Option<Instant>,
+        max_iterations: Option<NonZeroU64>,
+        max_output: Option<usize>,
+    ) -> RunOutcome
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        machine.deadline = deadline;
+        if max_iterations.is_some() {
+            machine.max_iterations = max_iterations;
+        }
+        if max_output.is_some() {
+            machine.max_output = max_output;
+        }
+
+        Self::run_machine(machine, inputs)
+    }
+
+    /// Run `machine` to completion on `inputs`
+    fn run_machine<I>(machine: &mut Machine, inputs: I) -> RunOutcome
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        // Everything is available up front so skip the channels and buffer output directly
+        let mut io = BufferIo {
+            input: inputs.into_iter(),
+            output: vec![],
+        };
+
+        let result = machine.execute(&mut io);
+        RunOutcome {
+            output: io.output,
+            error: result.err(),
+            iterations: machine.iterations(),
+            pointer: machine.pointer(),
+        }
+    }
+
+    /// Run to completion by walking the tree or with native code when either is available,
+    /// otherwise on a fresh machine
+    fn execute<IO: Io>(&self, io: &mut IO) -> Result<(), RunTimeError> {
+        self.execute_counted(io).0
+    }
+
+    /// Like [`Interpreter::execute`] but also returns the iterations run and where the pointer
+    /// finished
+    fn execute_counted<IO: Io>(&self, io: &mut IO) -> (Result<(), RunTimeError>, u64, isize) {
+        if let Some(tree) = &self.tree {
+            let (result, context) = tree.execute(io);
+            return (result, context.iterations, context.pointer);
+        }
+
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &self.jit {
+            let (result, context) = jit.execute(io);
+            return (result, context.iterations, context.pointer);
+        }
+
+        let mut machine = self.machine();
+        let result = machine.execute(io);
+        let (iterations, pointer) = (machine.iterations(), machine.pointer());
+        self.recycle(machine);
+        (result, iterations, pointer)
+    }
+
+    /// Create a machine to step through the program by hand
+    pub fn machine(&self) -> Machine {
+        #[cfg(feature = "std")]
+        let tape = self.tapes.take();
+        #[cfg(not(feature = "std"))]
+        let tape = Vec::new();
+
+        Machine::new(self.bytecode.clone(), &self.config, tape)
+    }
+
+    /// Keeps the tape of a machine that's done for the next one to reuse
+    pub(crate) fn recycle(&self, machine: Machine) {
+        #[cfg(feature = "std")]
+        self.tapes.give(machine.into_tape());
+        #[cfg(not(feature = "std"))]
+        drop(machine);
+    }
+
+    /// Create a machine that picks up where `snapshot` left off. Returns `None` if the snapshot
+    /// was taken from a different program.
+    pub fn restore(&self, snapshot: Snapshot) -> Option<Machine> {
+        let mut machine = self.machine();
+        machine.restore(snapshot).then_some(machine)
+    }
+}
+
+/// Controls an interpreter started with [`Interpreter::spawn`]
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct SpawnHandle {
+    handle: JoinHandle<(Result<(), RunTimeError>, ExecutionStats)>,
+    control: Arc<Control>,
 }
 
+#[cfg(feature = "std")]
+impl SpawnHandle {
+    /// Stop the machine. Output produced so far is still delivered, followed by
+    /// `RunTimeError::Cancelled`.
+    pub fn cancel(&self) {
+        self.control.cancelled.store(true, Ordering::Relaxed);
+        self.control.resumed.notify_all();
+    }
+
+    /// Suspend the machine until [`SpawnHandle::resume`] is called
+    pub fn pause(&self) {
+        *self.control.paused.lock().unwrap() = true;
+    }
+
+    pub fn resume(&self) {
+        *self.control.paused.lock().unwrap() = false;
+        self.control.resumed.notify_all();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Wait for the machine's thread to exit, returning the same error it sent on the output
+    /// channel if it failed. Panics are reported as `RunTimeError::Internal`.
+    pub fn join(self) -> Result<(), RunTimeError> {
+        self.join_with_stats().0
+    }
+
+    /// Like [`SpawnHandle::join`] but also returns what the machine did, unless its thread
+    /// panicked
+    pub fn join_with_stats(self) -> (Result<(), RunTimeError>, Option<ExecutionStats>) {
+        match self.handle.join() {
+            Ok((result, stats)) => (result, Some(stats)),
+            Err(payload) => (Err(RunTimeError::Internal(panic_message(&*payload))), None),
+        }
+    }
+}
+
+/// The message a panic was raised with, if it was a string
+#[cfg(feature = "std")]
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
+/// Tapes left over from finished runs. Handing them to new machines saves allocating and zeroing
+/// a fresh 30,000 cells for every test case.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+struct TapePool {
+    tapes: Mutex<Vec<Vec<Wrapping<u32>>>>,
+}
+
+/// The most tapes a pool keeps, enough for every thread of a parallel test run
+#[cfg(feature = "std")]
+const POOLED_TAPES: usize = 16;
+
+#[cfg(feature = "std")]
+impl TapePool {
+    fn take(&self) -> Vec<Wrapping<u32>> {
+        self.tapes.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    fn give(&self, tape: Vec<Wrapping<u32>>) {
+        let mut tapes = self.tapes.lock().unwrap();
+        if tapes.len() < POOLED_TAPES {
+            tapes.push(tape);
+        }
+    }
+}
+
+/// Flags shared between a spawned machine and its [`SpawnHandle`]
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub(crate) struct Control {
+    cancelled: AtomicBool,
+    paused: Mutex<bool>,
+    resumed: Condvar,
+}
+
+#[cfg(feature = "std")]
+impl Control {
+    /// Blocks while paused and fails once cancelled
+    pub(crate) fn checkpoint(&self) -> Result<(), RunTimeError> {
+        let mut paused = self.paused.lock().unwrap();
+        while *paused && !self.cancelled.load(Ordering::Relaxed) {
+            paused = self.resumed.wait(paused).unwrap();
+        }
+
+        if self.cancelled.load(Ordering::Relaxed) {
+            Err(RunTimeError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// How often a machine blocked on input checks whether it has been cancelled
+#[cfg(feature = "std")]
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[cfg(feature = "std")]
 pub type InputTx = Sender<Wrapping<u8>>;
+#[cfg(feature = "std")]
 pub type InputRx = Receiver<Wrapping<u8>>;
+#[cfg(feature = "std")]
 pub type OutputTx = Sender<Result<Wrapping<u8>, RunTimeError>>;
+#[cfg(feature = "std")]
 pub type OutputRx = Receiver<Result<Wrapping<u8>, RunTimeError>>;
 
-/// Interpreter that's receives inputs and sends outputs down channels
-struct InterpreterInner {
-    instructions: Arc<Vec<AstNode>>,
-    max_iterations: u64,
-    memory: Vec<Wrapping<u8>>,
-    memory_pointer: isize,
-    iterations: u64,
+/// The sending end of a spawned machine's output channel, see
+/// [`InterpreterBuilder::output_capacity`]
+#[cfg(feature = "std")]
+enum OutputSender {
+    Unbounded(OutputTx),
+    /// Blocks while the channel is full
+    Bounded(SyncSender<Result<Wrapping<u8>, RunTimeError>>),
+}
+
+#[cfg(feature = "std")]
+impl OutputSender {
+    fn send(&self, output: Result<Wrapping<u8>, RunTimeError>) -> Result<(), RunTimeError> {
+        match self {
+            OutputSender::Unbounded(outputs) => outputs.send(output),
+            OutputSender::Bounded(outputs) => outputs.send(output),
+        }
+        .map_err(|_| RunTimeError::OutputClosed)
+    }
+}
 
+/// Receives inputs and sends outputs down channels
+#[cfg(feature = "std")]
+struct ChannelIo {
     inputs: InputRx,
-    outputs: OutputTx,
+    outputs: OutputSender,
+    control: Arc<Control>,
 }
 
-impl InterpreterInner {
-    fn run(mut self) -> thread::JoinHandle<()> {
-        thread::spawn(move || {
-            let _ = self.run_body(&self.instructions.clone());
-        })
+#[cfg(feature = "std")]
+impl Io for ChannelIo {
+    fn read(&mut self) -> Result<Option<u8>, RunTimeError> {
+        // Wake up every so often to check for cancellation
+        loop {
+            match self.inputs.recv_timeout(POLL_INTERVAL) {
+                Ok(input) => return Ok(Some(input.0)),
+                Err(RecvTimeoutError::Disconnected) => return Ok(None),
+                Err(RecvTimeoutError::Timeout) => self.control.checkpoint()?,
+            }
+        }
     }
 
-    fn run_blocking(mut self) {
-        let _ = self.run_body(&self.instructions.clone());
+    fn write(&mut self, output: u8) -> Result<(), RunTimeError> {
+        self.outputs.send(Ok(Wrapping(output)))
     }
+}
 
-    fn run_body(&mut self, body: &[AstNode]) -> Result<(), ()> {
-        for instruction in body {
-            self.iterations += 1;
-            if self.iterations > self.max_iterations {
-                self.outputs
-                    .send(Err(RunTimeError::MaxIterationsExceeded))
-                    .unwrap();
-                return Err(());
-            }
+/// Reads from an iterator and collects output into a buffer
+struct BufferIo<I> {
+    input: I,
+    output: Vec<u8>,
+}
+
+impl<I: Iterator<Item = u8>> Io for BufferIo<I> {
+    fn read(&mut self) -> Result<Option<u8>, RunTimeError> {
+        Ok(self.input.next())
+    }
+
+    fn write(&mut self, output: u8) -> Result<(), RunTimeError> {
+        self.output.push(output);
+        Ok(())
+    }
+}
 
-            match instruction {
-                AstNode::Increment { amount, offset, .. } => {
-                    let index = self.memory_pointer.checked_add(*offset).ok_or_else(|| {
-                        self.outputs
-                            .send(Err(RunTimeError::OutOfBoundsRight))
-                            .unwrap()
-                    })?;
-
-                    // Convert isize to usize
-                    let index = match index.cmp(&0) {
-                        Ordering::Greater => index as usize,
-                        Ordering::Equal => 0,
-                        Ordering::Less => {
-                            self.outputs
-                                .send(Err(RunTimeError::OutOfBoundsLeft))
-                                .unwrap();
-                            return Err(());
-                        }
-                    };
-
-                    // Check if the index is out of bounds
-                    if index >= self.memory.len() {
-                        self.outputs
-                            .send(Err(RunTimeError::OutOfBoundsRight))
-                            .unwrap();
-                        return Err(());
-                    }
-
-                    match amount.0.cmp(&0) {
-                        Ordering::Less => self.memory[index] -= amount.0.unsigned_abs(),
-                        Ordering::Equal => {}
-                        Ordering::Greater => self.memory[index] += amount.0.unsigned_abs(),
-                    }
-                }
-                AstNode::PointerIncrement { amount, .. } => {
-                    self.memory_pointer += amount;
-
-                    if self.memory_pointer < 0 {
-                        self.outputs
-                            .send(Err(RunTimeError::OutOfBoundsLeft))
-                            .unwrap();
-                        return Err(());
-                    } else if self.memory_pointer.unsigned_abs() > self.memory.len() {
-                        self.outputs
-                            .send(Err(RunTimeError::OutOfBoundsRight))
-                            .unwrap();
-                        return Err(());
-                    }
-                }
-                AstNode::Read { .. } => {
-                    self.memory[self.memory_pointer as usize] = self.inputs.recv().unwrap();
-                }
-                AstNode::Write { .. } => {
-                    self.outputs
-                        .send(Ok(self.memory[self.memory_pointer as usize]))
-                        .unwrap();
-                }
-                AstNode::Loop { body, .. } => {
-                    while self.memory[self.memory_pointer as usize] != Wrapping(0) {
-                        self.run_body(body)?;
-                    }
-                }
-                AstNode::Set { amount, offset, .. } => {
-                    let index = self.memory_pointer.checked_add(*offset).ok_or_else(|| {
-                        self.outputs
-                            .send(Err(RunTimeError::OutOfBoundsRight))
-                            .unwrap()
-                    })?;
-
-                    // Convert isize to usize
-                    let index = match index.cmp(&0) {
-                        Ordering::Greater => index as usize,
-                        Ordering::Equal => 0,
-                        Ordering::Less => {
-                            self.outputs
-                                .send(Err(RunTimeError::OutOfBoundsLeft))
-                                .unwrap();
-                            return Err(());
-                        }
-                    };
-
-                    // Check if the index is out of bounds
-                    if index >= self.memory.len() {
-                        self.outputs
-                            .send(Err(RunTimeError::OutOfBoundsRight))
-                            .unwrap();
-                        return Err(());
-                    }
-
-                    // Convert the i8 to Wrapped u8
-                    self.memory[index] = match amount.0.cmp(&0) {
-                        Ordering::Less => -Wrapping(amount.0.unsigned_abs()),
-                        Ordering::Equal => Wrapping(0),
-                        Ordering::Greater => Wrapping(amount.0.unsigned_abs()),
-                    };
-                }
-                AstNode::MultiplyMove { changes, .. } => {
-                    let current = self.memory[self.memory_pointer as usize];
-
-                    if current != Wrapping(0) {
-                        for (offset, factor) in changes.iter() {
-                            let index =
-                                self.memory_pointer.checked_add(*offset).ok_or_else(|| {
-                                    self.outputs
-                                        .send(Err(RunTimeError::OutOfBoundsRight))
-                                        .unwrap()
-                                })?;
-
-                            // Convert isize to usize
-                            let index = match index.cmp(&0) {
-                                Ordering::Greater => index as usize,
-                                Ordering::Equal => 0,
-                                Ordering::Less => {
-                                    self.outputs
-                                        .send(Err(RunTimeError::OutOfBoundsLeft))
-                                        .unwrap();
-                                    return Err(());
-                                }
-                            };
-
-                            // Check if the index is out of bounds
-                            if index >= self.memory.len() {
-                                self.outputs
-                                    .send(Err(RunTimeError::OutOfBoundsRight))
-                                    .unwrap();
-                                return Err(());
-                            }
-
-                            self.memory[index] += current
-                                * match factor.0.cmp(&0) {
-                                    Ordering::Less => -Wrapping(factor.0.unsigned_abs()),
-                                    Ordering::Equal => Wrapping(0),
-                                    Ordering::Greater => Wrapping(factor.0.unsigned_abs()),
-                                };
-                        }
-
-                        self.memory[self.memory_pointer as usize] = Wrapping(0);
-                    }
-                }
+/// Reads and writes through `std::io`
+#[cfg(feature = "std")]
+struct StreamIo<'a, R, W> {
+    input: &'a mut R,
+    output: &'a mut W,
+}
+
+#[cfg(feature = "std")]
+impl<R: Read, W: Write> Io for StreamIo<'_, R, W> {
+    fn read(&mut self) -> Result<Option<u8>, RunTimeError> {
+        let mut buf = [0];
+        loop {
+            match self.input.read(&mut buf) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(buf[0])),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(RunTimeError::Io(err)),
             }
         }
+    }
+
+    fn write(&mut self, output: u8) -> Result<(), RunTimeError> {
+        self.output.write_all(&[output]).map_err(RunTimeError::Io)
+    }
+}
+
+/// Calls user supplied closures
+struct FnIo<I, O> {
+    input: I,
+    output: O,
+}
+
+impl<I, O> Io for FnIo<I, O>
+where
+    I: FnMut() -> Option<u8>,
+    O: FnMut(u8),
+{
+    fn read(&mut self) -> Result<Option<u8>, RunTimeError> {
+        Ok((self.input)())
+    }
 
+    fn write(&mut self, output: u8) -> Result<(), RunTimeError> {
+        (self.output)(output);
         Ok(())
     }
 }
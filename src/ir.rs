@@ -0,0 +1,97 @@
+use std::fmt::Write;
+
+use bfc_ir::{AstNode, Position};
+
+use crate::Location;
+
+/// The column source positions are lined up at
+const POSITION_COLUMN: usize = 40;
+
+/// Lists `instructions` one per line with loop bodies indented, each followed by the part of
+/// `program` it came from. Offsets are relative to the pointer and positions are
+/// `line:column`, or a range of them. `+[->+<]` before optimizing is
+///
+/// ```text
+/// add 1                                   1:1
+/// loop                                    1:2-1:7
+///     add -1                              1:3
+///     move +1                             1:4
+///     add 1                               1:5
+///     move -1                             1:6
+/// ```
+pub fn format_ir(instructions: &[AstNode], program: &str) -> String {
+    let mut formatted = String::new();
+    write_block(&mut formatted, instructions, program, 0);
+    formatted
+}
+
+fn write_block(formatted: &mut String, instructions: &[AstNode], program: &str, depth: usize) {
+    for instruction in instructions {
+        let (text, position) = match instruction {
+            AstNode::Increment {
+                amount,
+                offset,
+                position,
+            } => (format!("add {}{}", amount.0, at(*offset)), position),
+            AstNode::PointerIncrement { amount, position } => {
+                (format!("move {:+}", amount), position)
+            }
+            AstNode::Read { position } => ("read".to_string(), position),
+            AstNode::Write { position } => ("write".to_string(), position),
+            AstNode::Set {
+                amount,
+                offset,
+                position,
+            } => (format!("set {}{}", amount.0 as u8, at(*offset)), position),
+            AstNode::MultiplyMove { changes, position } => {
+                let mut changes: Vec<_> = changes.iter().collect();
+                changes.sort_by_key(|(offset, _)| **offset);
+                let changes: Vec<String> = changes
+                    .iter()
+                    .map(|(offset, factor)| format!("{:+} x{}", offset, factor.0))
+                    .collect();
+                (format!("multiply-move {}", changes.join(", ")), position)
+            }
+            AstNode::Loop { position, .. } => ("loop".to_string(), position),
+        };
+
+        let line = format!("{}{}", "    ".repeat(depth), text);
+        match position {
+            Some(position) => writeln!(
+                formatted,
+                "{:width$} {}",
+                line,
+                span(program, position),
+                width = POSITION_COLUMN - 1
+            )
+            .unwrap(),
+            None => writeln!(formatted, "{}", line).unwrap(),
+        }
+
+        if let AstNode::Loop { body, .. } = instruction {
+            write_block(formatted, body, program, depth + 1);
+        }
+    }
+}
+
+/// Describes a cell offset, nothing for the current cell
+fn at(offset: isize) -> String {
+    if offset == 0 {
+        String::new()
+    } else {
+        format!(" at {:+}", offset)
+    }
+}
+
+fn span(program: &str, position: &Position) -> String {
+    let start = Location::new(program, position.start);
+    if position.end == position.start {
+        return format!("{}:{}", start.line, start.column);
+    }
+
+    let end = Location::new(program, position.end);
+    format!(
+        "{}:{}-{}:{}",
+        start.line, start.column, end.line, end.column
+    )
+}
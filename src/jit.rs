@@ -0,0 +1,574 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt, mem,
+    num::NonZeroU64,
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+};
+
+use cranelift_codegen::{
+    ir::{condcodes::IntCC, types, AbiParam, Block, FuncRef, InstBuilder, MemFlags, Type, Value},
+    settings::{self, Configurable},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+
+use crate::{
+    bytecode::{Bytecode, Op},
+    interpreter::{
+        BoundsCheck, CellWidth, Config, EofBehavior, ErrorContext, IterationCost, NegativeCells,
+        OverflowBehavior, RunTimeError,
+    },
+    machine::Io,
+};
+
+// Status codes returned by compiled programs
+const OK: i64 = 0;
+const OUT_OF_BOUNDS_LEFT: i64 = 1;
+const OUT_OF_BOUNDS_RIGHT: i64 = 2;
+const MAX_ITERATIONS_EXCEEDED: i64 = 3;
+const IO_FAILED: i64 = 4;
+
+// Special results of `jit_read`, anything else is the byte to store
+const LEAVE_UNCHANGED: i64 = -1;
+const READ_FAILED: i64 = -2;
+
+/// State shared between compiled code and the I/O callbacks. Compiled code writes the first
+/// three fields before returning so they must stay at the start of the struct.
+#[repr(C)]
+struct JitContext<'a> {
+    pointer: i64,
+    iterations: u64,
+    pc: u64,
+    io: &'a mut dyn Io,
+    eof_behavior: EofBehavior,
+    error: Option<RunTimeError>,
+    /// A panic from `io`, which can't unwind through compiled code. It's resumed once the
+    /// compiled code has returned.
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+unsafe extern "C" fn jit_read(ctx: *mut u8) -> i32 {
+    let ctx = &mut *(ctx as *mut JitContext<'_>);
+    let read = match panic::catch_unwind(AssertUnwindSafe(|| ctx.io.read())) {
+        Ok(read) => read,
+        Err(payload) => {
+            ctx.panic = Some(payload);
+            return READ_FAILED as i32;
+        }
+    };
+
+    match read {
+        Ok(Some(input)) => input as i32,
+        Ok(None) => match ctx.eof_behavior {
+            EofBehavior::Zero => 0,
+            EofBehavior::MinusOne => u8::MAX as i32,
+            EofBehavior::Unchanged => LEAVE_UNCHANGED as i32,
+            EofBehavior::Error => READ_FAILED as i32,
+        },
+        Err(err) => {
+            ctx.error = Some(err);
+            READ_FAILED as i32
+        }
+    }
+}
+
+unsafe extern "C" fn jit_write(ctx: *mut u8, output: i32) -> i32 {
+    let ctx = &mut *(ctx as *mut JitContext<'_>);
+    match panic::catch_unwind(AssertUnwindSafe(|| ctx.io.write(output as u8))) {
+        Ok(Ok(())) => 0,
+        Ok(Err(err)) => {
+            ctx.error = Some(err);
+            1
+        }
+        Err(payload) => {
+            ctx.panic = Some(payload);
+            1
+        }
+    }
+}
+
+/// A program compiled to native code
+pub(crate) struct JitProgram {
+    // Owns the executable memory `function` points into
+    _module: JITModule,
+    function: unsafe extern "C" fn(*mut u8, *mut u8) -> i32,
+    bytecode: Arc<Bytecode>,
+    tape_size: usize,
+    initial_memory: Vec<u8>,
+    eof_behavior: EofBehavior,
+}
+
+// SAFETY: the module is only used to keep the finalized code alive, which is never written to
+// again, and each call to `function` works on its own tape and context
+unsafe impl Send for JitProgram {}
+unsafe impl Sync for JitProgram {}
+
+impl fmt::Debug for JitProgram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JitProgram")
+            .field("tape_size", &self.tape_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl JitProgram {
+    /// Whether the compiled code can honour every setting in `config`
+    pub(crate) fn supports(config: &Config) -> bool {
+        config.cell_width == CellWidth::U8
+            && config.overflow == OverflowBehavior::Wrap
+            && config.max_output.is_none()
+            && config.iteration_cost == IterationCost::Instruction
+            && config.fuel.is_none()
+            && config.max_tape_size.is_none()
+            && !config.circular_tape
+            && config.negative_cells == NegativeCells::Error
+            && config.bounds_check == BoundsCheck::Strict
+            && config.dump.is_none()
+            && config.extensions.is_empty()
+    }
+
+    pub(crate) fn compile(bytecode: Arc<Bytecode>, config: &Config) -> Result<Self, String> {
+        if bytecode.needs_interpreter() {
+            return Err("procedures and threads aren't compiled".to_string());
+        }
+
+        let mut flags = settings::builder();
+        flags
+            .set("opt_level", "speed")
+            .map_err(|err| err.to_string())?;
+        let isa = cranelift_native::builder()
+            .map_err(|err| err.to_string())?
+            .finish(settings::Flags::new(flags))
+            .map_err(|err| err.to_string())?;
+
+        let mut jit_builder = JITBuilder::with_isa(isa, default_libcall_names());
+        jit_builder.symbol("bfi_jit_read", jit_read as *const u8);
+        jit_builder.symbol("bfi_jit_write", jit_write as *const u8);
+        let mut module = JITModule::new(jit_builder);
+        let pointer_type = module.target_config().pointer_type();
+
+        let mut read_signature = module.make_signature();
+        read_signature.params.push(AbiParam::new(pointer_type));
+        read_signature.returns.push(AbiParam::new(types::I32));
+        let read = module
+            .declare_function("bfi_jit_read", Linkage::Import, &read_signature)
+            .map_err(|err| err.to_string())?;
+
+        let mut write_signature = module.make_signature();
+        write_signature.params.push(AbiParam::new(pointer_type));
+        write_signature.params.push(AbiParam::new(types::I32));
+        write_signature.returns.push(AbiParam::new(types::I32));
+        let write = module
+            .declare_function("bfi_jit_write", Linkage::Import, &write_signature)
+            .map_err(|err| err.to_string())?;
+
+        let tape_size = config.tape_size.max(config.initial_memory.len());
+
+        let mut ctx = module.make_context();
+        ctx.func.signature.params.push(AbiParam::new(pointer_type));
+        ctx.func.signature.params.push(AbiParam::new(pointer_type));
+        ctx.func.signature.returns.push(AbiParam::new(types::I32));
+
+        let mut builder_context = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut ctx.func, &mut builder_context);
+        let read = module.declare_func_in_func(read, builder.func);
+        let write = module.declare_func_in_func(write, builder.func);
+
+        Codegen::new(
+            builder,
+            pointer_type,
+            read,
+            write,
+            tape_size,
+            config.max_iterations.map_or(u64::MAX, NonZeroU64::get),
+        )
+        .emit(bytecode.ops());
+
+        let main = module
+            .declare_function("bfi_main", Linkage::Local, &ctx.func.signature)
+            .map_err(|err| err.to_string())?;
+        module
+            .define_function(main, &mut ctx)
+            .map_err(|err| err.to_string())?;
+        module.clear_context(&mut ctx);
+        module
+            .finalize_definitions()
+            .map_err(|err| err.to_string())?;
+
+        let code = module.get_finalized_function(main);
+        // SAFETY: `bfi_main` was declared with exactly this signature
+        let function = unsafe {
+            mem::transmute::<*const u8, unsafe extern "C" fn(*mut u8, *mut u8) -> i32>(code)
+        };
+
+        Ok(Self {
+            _module: module,
+            function,
+            bytecode,
+            tape_size,
+            initial_memory: config.initial_memory.clone(),
+            eof_behavior: config.eof_behavior,
+        })
+    }
+
+    /// Runs the program to completion, also returning where it stopped
+    pub(crate) fn execute(&self, io: &mut dyn Io) -> (Result<(), RunTimeError>, ErrorContext) {
+        let mut tape = vec![0u8; self.tape_size];
+        tape[..self.initial_memory.len()].copy_from_slice(&self.initial_memory);
+
+        let mut ctx = JitContext {
+            pointer: 0,
+            iterations: 0,
+            pc: 0,
+            io,
+            eof_behavior: self.eof_behavior,
+            error: None,
+            panic: None,
+        };
+
+        // SAFETY: the compiled code only touches `tape[..tape_size]` and the context's leading
+        // fields, both of which outlive the call
+        let status = unsafe {
+            (self.function)(
+                &mut ctx as *mut JitContext<'_> as *mut u8,
+                tape.as_mut_ptr(),
+            )
+        } as i64;
+
+        if let Some(payload) = ctx.panic.take() {
+            panic::resume_unwind(payload);
+        }
+
+        let context = ErrorContext {
+            position: self.bytecode.position(ctx.pc as usize),
+            pointer: ctx.pointer as isize,
+            iterations: ctx.iterations,
+        };
+
+        let result = match status {
+            OK => Ok(()),
+            OUT_OF_BOUNDS_LEFT => Err(RunTimeError::OutOfBoundsLeft(context)),
+            OUT_OF_BOUNDS_RIGHT => Err(RunTimeError::OutOfBoundsRight(context)),
+            MAX_ITERATIONS_EXCEEDED => Err(RunTimeError::MaxIterationsExceeded(context)),
+            IO_FAILED => Err(ctx
+                .error
+                .take()
+                .unwrap_or(RunTimeError::InputExhausted(context))),
+            _ => unreachable!("unknown jit status {}", status),
+        };
+        (result, context)
+    }
+}
+
+/// Translates bytecode into cranelift IR
+struct Codegen<'a> {
+    builder: FunctionBuilder<'a>,
+    read: FuncRef,
+    write: FuncRef,
+    tape_size: usize,
+    max_iterations: u64,
+
+    ctx: Value,
+    tape: Value,
+    pointer: Variable,
+    iterations: Variable,
+    /// Every failure jumps here with a status and the failing pc
+    exit: Block,
+}
+
+impl<'a> Codegen<'a> {
+    fn new(
+        mut builder: FunctionBuilder<'a>,
+        pointer_type: Type,
+        read: FuncRef,
+        write: FuncRef,
+        tape_size: usize,
+        max_iterations: u64,
+    ) -> Self {
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let ctx = builder.block_params(entry)[0];
+        let tape = builder.block_params(entry)[1];
+
+        let pointer = Variable::new(0);
+        builder.declare_var(pointer, pointer_type);
+        let zero = builder.ins().iconst(pointer_type, 0);
+        builder.def_var(pointer, zero);
+
+        let iterations = Variable::new(1);
+        builder.declare_var(iterations, types::I64);
+        let zero = builder.ins().iconst(types::I64, 0);
+        builder.def_var(iterations, zero);
+
+        let exit = builder.create_block();
+        builder.append_block_param(exit, types::I32);
+        builder.append_block_param(exit, types::I64);
+
+        Self {
+            builder,
+            read,
+            write,
+            tape_size,
+            max_iterations,
+            ctx,
+            tape,
+            pointer,
+            iterations,
+            exit,
+        }
+    }
+
+    fn emit(mut self, ops: &[Op]) {
+        // Every jump target starts a new block
+        let mut blocks = HashMap::new();
+        for (pc, op) in ops.iter().enumerate() {
+            if let Op::JumpIfZero { target } | Op::JumpIfNonZero { target } = op {
+                for start in [*target, pc + 1] {
+                    blocks
+                        .entry(start)
+                        .or_insert_with(|| self.builder.create_block());
+                }
+            }
+        }
+
+        let mut terminated = false;
+        for pc in 0..=ops.len() {
+            if let Some(block) = blocks.get(&pc) {
+                if !terminated {
+                    self.builder.ins().jump(*block, &[]);
+                }
+                self.builder.switch_to_block(*block);
+                terminated = false;
+            }
+
+            let op = match ops.get(pc) {
+                Some(op) => op,
+                None => break,
+            };
+
+            // Dumps need the interpreter, compiled programs skip them
+            if let Op::Dump = op {
+                continue;
+            }
+
+            self.count_iteration(pc);
+
+            match op {
+                Op::Add { amount, offset } => {
+                    let address = self.index(*offset, pc);
+                    let cell = self.load(address);
+                    let cell = self.builder.ins().iadd_imm(cell, amount.0 as u8 as i64);
+                    self.store(cell, address);
+                }
+                Op::Set { amount, offset } => {
+                    let address = self.index(*offset, pc);
+                    let value = self.builder.ins().iconst(types::I8, amount.0 as u8 as i64);
+                    self.store(value, address);
+                }
+                Op::Move { amount } => {
+                    let pointer = self.builder.use_var(self.pointer);
+                    let pointer = self.builder.ins().iadd_imm(pointer, *amount as i64);
+                    self.builder.def_var(self.pointer, pointer);
+
+                    let left = self
+                        .builder
+                        .ins()
+                        .icmp_imm(IntCC::SignedLessThan, pointer, 0);
+                    self.fail_if(left, OUT_OF_BOUNDS_LEFT, pc);
+
+                    let right = self.builder.ins().icmp_imm(
+                        IntCC::SignedGreaterThanOrEqual,
+                        pointer,
+                        self.tape_size as i64,
+                    );
+                    self.fail_if(right, OUT_OF_BOUNDS_RIGHT, pc);
+                }
+                Op::Read => {
+                    let call = self.builder.ins().call(self.read, &[self.ctx]);
+                    let input = self.builder.inst_results(call)[0];
+
+                    let failed = self
+                        .builder
+                        .ins()
+                        .icmp_imm(IntCC::Equal, input, READ_FAILED);
+                    self.fail_if(failed, IO_FAILED, pc);
+
+                    let store = self.builder.create_block();
+                    let done = self.builder.create_block();
+                    let unchanged =
+                        self.builder
+                            .ins()
+                            .icmp_imm(IntCC::Equal, input, LEAVE_UNCHANGED);
+                    self.builder.ins().brif(unchanged, done, &[], store, &[]);
+
+                    self.builder.switch_to_block(store);
+                    let address = self.current();
+                    let input = self.builder.ins().ireduce(types::I8, input);
+                    self.store(input, address);
+                    self.builder.ins().jump(done, &[]);
+
+                    self.builder.switch_to_block(done);
+                }
+                Op::Write => {
+                    let address = self.current();
+                    let cell = self.load(address);
+                    let cell = self.builder.ins().uextend(types::I32, cell);
+                    let call = self.builder.ins().call(self.write, &[self.ctx, cell]);
+                    let result = self.builder.inst_results(call)[0];
+
+                    let failed = self.builder.ins().icmp_imm(IntCC::NotEqual, result, 0);
+                    self.fail_if(failed, IO_FAILED, pc);
+                }
+                Op::JumpIfZero { target } => {
+                    let address = self.current();
+                    let cell = self.load(address);
+                    self.builder
+                        .ins()
+                        .brif(cell, blocks[&(pc + 1)], &[], blocks[target], &[]);
+                    terminated = true;
+                }
+                Op::JumpIfNonZero { target } => {
+                    let address = self.current();
+                    let cell = self.load(address);
+                    self.builder
+                        .ins()
+                        .brif(cell, blocks[target], &[], blocks[&(pc + 1)], &[]);
+                    terminated = true;
+                }
+                Op::MultiplyMove { changes } => {
+                    let current = self.current();
+                    let value = self.load(current);
+
+                    let apply = self.builder.create_block();
+                    let done = self.builder.create_block();
+                    self.builder.ins().brif(value, apply, &[], done, &[]);
+
+                    self.builder.switch_to_block(apply);
+                    for (offset, factor) in changes.iter() {
+                        let address = self.index(*offset, pc);
+                        let cell = self.load(address);
+                        let product = self.builder.ins().imul_imm(value, factor.0 as u8 as i64);
+                        let cell = self.builder.ins().iadd(cell, product);
+                        self.store(cell, address);
+                    }
+                    let zero = self.builder.ins().iconst(types::I8, 0);
+                    self.store(zero, current);
+                    self.builder.ins().jump(done, &[]);
+
+                    self.builder.switch_to_block(done);
+                }
+                Op::Scan { stride } => {
+                    let head = self.builder.create_block();
+                    let step = self.builder.create_block();
+                    let done = self.builder.create_block();
+                    self.builder.ins().jump(head, &[]);
+
+                    self.builder.switch_to_block(head);
+                    let address = self.index(0, pc);
+                    let cell = self.load(address);
+                    self.builder.ins().brif(cell, step, &[], done, &[]);
+
+                    self.builder.switch_to_block(step);
+                    let pointer = self.builder.use_var(self.pointer);
+                    let pointer = self.builder.ins().iadd_imm(pointer, *stride as i64);
+                    self.builder.def_var(self.pointer, pointer);
+                    self.builder.ins().jump(head, &[]);
+
+                    self.builder.switch_to_block(done);
+                }
+                Op::Dump => unreachable!(),
+                Op::Procedure { .. } | Op::Return | Op::Call | Op::Fork => {
+                    unreachable!("programs with procedures or threads aren't compiled")
+                }
+                // Only programs without handlers are compiled, so extensions do nothing
+                Op::Extension { .. } => {}
+            }
+        }
+
+        let ok = self.builder.ins().iconst(types::I32, OK);
+        let pc = self.builder.ins().iconst(types::I64, ops.len() as i64);
+        self.builder.ins().jump(self.exit, &[ok, pc]);
+
+        // Save the machine state for error reporting and return the status
+        self.builder.switch_to_block(self.exit);
+        let status = self.builder.block_params(self.exit)[0];
+        let pc = self.builder.block_params(self.exit)[1];
+        let pointer = self.builder.use_var(self.pointer);
+        let iterations = self.builder.use_var(self.iterations);
+        let flags = MemFlags::trusted();
+        self.builder.ins().store(flags, pointer, self.ctx, 0);
+        self.builder.ins().store(flags, iterations, self.ctx, 8);
+        self.builder.ins().store(flags, pc, self.ctx, 16);
+        self.builder.ins().return_(&[status]);
+
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+    }
+
+    fn count_iteration(&mut self, pc: usize) {
+        let iterations = self.builder.use_var(self.iterations);
+        let iterations = self.builder.ins().iadd_imm(iterations, 1);
+        self.builder.def_var(self.iterations, iterations);
+
+        if self.max_iterations != u64::MAX {
+            let exceeded = self.builder.ins().icmp_imm(
+                IntCC::UnsignedGreaterThan,
+                iterations,
+                self.max_iterations as i64,
+            );
+            self.fail_if(exceeded, MAX_ITERATIONS_EXCEEDED, pc);
+        }
+    }
+
+    /// Leaves the function with `status` when `condition` holds
+    fn fail_if(&mut self, condition: Value, status: i64, pc: usize) {
+        let status = self.builder.ins().iconst(types::I32, status);
+        let pc = self.builder.ins().iconst(types::I64, pc as i64);
+
+        let next = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(condition, self.exit, &[status, pc], next, &[]);
+        self.builder.switch_to_block(next);
+    }
+
+    /// Address of the current cell
+    fn current(&mut self) -> Value {
+        let pointer = self.builder.use_var(self.pointer);
+        self.builder.ins().iadd(self.tape, pointer)
+    }
+
+    /// Bounds checked address of `pointer + offset`
+    fn index(&mut self, offset: isize, pc: usize) -> Value {
+        let pointer = self.builder.use_var(self.pointer);
+        let index = self.builder.ins().iadd_imm(pointer, offset as i64);
+
+        let left = self.builder.ins().icmp_imm(IntCC::SignedLessThan, index, 0);
+        self.fail_if(left, OUT_OF_BOUNDS_LEFT, pc);
+
+        let right = self.builder.ins().icmp_imm(
+            IntCC::SignedGreaterThanOrEqual,
+            index,
+            self.tape_size as i64,
+        );
+        self.fail_if(right, OUT_OF_BOUNDS_RIGHT, pc);
+
+        self.builder.ins().iadd(self.tape, index)
+    }
+
+    fn load(&mut self, address: Value) -> Value {
+        self.builder
+            .ins()
+            .load(types::I8, MemFlags::trusted(), address, 0)
+    }
+
+    fn store(&mut self, value: Value, address: Value) {
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), value, address, 0);
+    }
+}
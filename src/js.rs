@@ -0,0 +1,291 @@
+//! JavaScript bindings, built with wasm-bindgen for running programs in the browser
+//!
+//! There are no threads on the web so everything here runs synchronously, either to completion
+//! or a few steps at a time with a [`Stepper`].
+//!
+//! ```js
+//! import { execute, Stepper } from "bfi";
+//!
+//! const output = execute(",[.,]", new TextEncoder().encode("hi"), 1000000n);
+//!
+//! const stepper = new Stepper("+[.+]");
+//! while (stepper.step(1000) === "executed") {
+//!     console.log(stepper.takeOutput());
+//! }
+//! ```
+
+use std::num::NonZeroU64;
+
+use bfc_ir::ParseError;
+use js_sys::Function;
+use wasm_bindgen::prelude::*;
+
+use crate::{compile, report::describe, CellWidth, Error, Interpreter, Machine, Program, TestCase};
+
+/// Runs a program to completion with all of its input up front, returning its output. Iteration
+/// limits of `0` mean unlimited here and below, JavaScript has no `Option<NonZeroU64>`.
+#[wasm_bindgen]
+pub fn execute(program: &str, input: &[u8], max_iterations: u64) -> Result<Vec<u8>, JsError> {
+    run(program, input, max_iterations).map_err(|err| JsError::new(&err))
+}
+
+// The bindings only wrap what's below in JavaScript types, which can't be made off wasm, so this
+// is the part the tests reach
+
+fn run(program: &str, input: &[u8], max_iterations: u64) -> Result<Vec<u8>, String> {
+    Program::compile(program)
+        .map_err(Error::ParseError)
+        .and_then(|program| program.run(input.iter().copied(), NonZeroU64::new(max_iterations)))
+        .map_err(|err| err.to_string())
+}
+
+/// Parse errors read the same as every other error that reaches JavaScript
+fn parse_error(err: ParseError) -> String {
+    Error::ParseError(err).to_string()
+}
+
+/// What a `read` callback returned: a byte, or anything that isn't a number for EOF
+fn read_byte(value: Option<f64>) -> Option<u8> {
+    value.map(|byte| byte as u8)
+}
+
+/// Runs a program to completion, calling `read` whenever it reads and `write` with each byte it
+/// writes. `read` returns a byte, or `null` once the input is exhausted.
+#[wasm_bindgen(js_name = executeWithCallbacks)]
+pub fn execute_with_callbacks(
+    program: &str,
+    read: &Function,
+    write: &Function,
+    max_iterations: u64,
+) -> Result<(), JsError> {
+    let interpreter = compile(program, NonZeroU64::new(max_iterations), CellWidth::U8)
+        .map_err(|err| JsError::new(&parse_error(err)))?;
+
+    interpreter
+        .run_with_io(
+            || {
+                read_byte(
+                    read.call0(&JsValue::NULL)
+                        .ok()
+                        .and_then(|byte| byte.as_f64()),
+                )
+            },
+            |byte| {
+                // Nothing useful to do if the callback throws, the program carries on
+                let _ = write.call1(&JsValue::NULL, &JsValue::from(byte));
+            },
+        )
+        .map_err(|err| JsError::new(&err.to_string()))
+}
+
+/// Runs a program against `input` and checks it writes `expected`. Returns `undefined` if it
+/// does, otherwise why it didn't.
+#[wasm_bindgen]
+pub fn test(program: &str, input: &[u8], expected: &[u8], max_iterations: u64) -> Option<String> {
+    let case =
+        TestCase::new("test", input, expected).iteration_limit(NonZeroU64::new(max_iterations));
+    let report = crate::run_tests(program, &[case]);
+    report
+        .results
+        .first()
+        .and_then(|(_, result)| describe(&result.outcome))
+}
+
+/// A program run a few instructions at a time, for showing the tape as it changes. The program
+/// isn't optimized so every step is one command.
+#[wasm_bindgen]
+pub struct Stepper {
+    machine: Machine,
+    output: Vec<u8>,
+    error: Option<String>,
+}
+
+#[wasm_bindgen]
+impl Stepper {
+    #[wasm_bindgen(constructor)]
+    pub fn new(program: &str) -> Result<Stepper, JsError> {
+        Stepper::parse(program).map_err(|err| JsError::new(&err))
+    }
+
+    #[wasm_bindgen(js_name = pushInput)]
+    pub fn push_input(&mut self, input: &[u8]) {
+        self.machine.push_input(input.iter().copied());
+    }
+
+    /// Once the queued input is read, reads follow the EOF behavior instead of waiting
+    #[wasm_bindgen(js_name = closeInput)]
+    pub fn close_input(&mut self) {
+        self.machine.close_input();
+    }
+
+    /// Stop before any instruction compiled from source offset `offset`
+    #[wasm_bindgen(js_name = addBreakpoint)]
+    pub fn add_breakpoint(&mut self, offset: usize) {
+        self.machine.add_breakpoint(offset);
+    }
+
+    #[wasm_bindgen(js_name = removeBreakpoint)]
+    pub fn remove_breakpoint(&mut self, offset: usize) -> bool {
+        self.machine.remove_breakpoint(offset)
+    }
+
+    /// Limit the program to `fuel` more instructions, stepping stops with `"fuel-exhausted"` once
+    /// they're spent
+    #[wasm_bindgen(js_name = addFuel)]
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.machine.add_fuel(fuel);
+    }
+
+    /// Runs up to `n` instructions, output is collected for [`Stepper::take_output`]. Returns
+    /// what stopped it: `"executed"` after all `n`, or `"needs-input"`, `"breakpoint"`,
+    /// `"halted"`, `"fuel-exhausted"` or `"error"`.
+    pub fn step(&mut self, n: usize) -> String {
+        for _ in 0..n {
+            match self.machine.step() {
+                crate::Event::Executed | crate::Event::Watchpoint { .. } => {}
+                crate::Event::Output(output) => self.output.push(output),
+                crate::Event::NeedsInput => return "needs-input".to_string(),
+                crate::Event::Breakpoint(_) => return "breakpoint".to_string(),
+                crate::Event::Halted => return "halted".to_string(),
+                crate::Event::FuelExhausted => return "fuel-exhausted".to_string(),
+                crate::Event::Error(err) => {
+                    self.error = Some(err.to_string());
+                    return "error".to_string();
+                }
+            }
+        }
+        "executed".to_string()
+    }
+
+    /// Output written since the last call
+    #[wasm_bindgen(js_name = takeOutput)]
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Why the program failed, once `step` has returned `"error"`
+    #[wasm_bindgen(getter)]
+    pub fn error(&self) -> Option<String> {
+        self.error.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn pc(&self) -> usize {
+        self.machine.pc()
+    }
+
+    /// The source offset of the next instruction, `undefined` at the end of the program
+    #[wasm_bindgen(getter)]
+    pub fn offset(&self) -> Option<usize> {
+        let position = self.machine.bytecode().position(self.machine.pc())?;
+        Some(position.start)
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn pointer(&self) -> isize {
+        self.machine.pointer()
+    }
+
+    /// Instructions run so far, as a number rather than a `BigInt` since it's only for display
+    #[wasm_bindgen(getter)]
+    pub fn iterations(&self) -> f64 {
+        self.machine.iterations() as f64
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn tape(&self) -> Vec<u32> {
+        self.machine.tape().iter().map(|cell| cell.0).collect()
+    }
+}
+
+impl Stepper {
+    fn parse(program: &str) -> Result<Stepper, String> {
+        let instructions = bfc_ir::parse(program).map_err(parse_error)?;
+
+        Ok(Stepper {
+            machine: Interpreter::from_instructions(instructions, None).machine(),
+            output: Vec::new(),
+            error: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs() {
+        assert_eq!(run(",.,.", b"hi", 0), Ok(b"hi".to_vec()));
+        assert_eq!(run("+[-]", b"", 3), Ok(Vec::new()));
+        assert_eq!(
+            run("+[]", b"", 10).unwrap_err(),
+            "runtime error after 0 bytes of output: exceeded the maximum number of iterations at \
+             offset 1 (pointer 0, after 11 iterations)"
+        );
+    }
+
+    #[test]
+    fn errors_are_messages() {
+        let expected = parse_error(bfc_ir::parse("+[").unwrap_err());
+        assert!(
+            expected.starts_with("parse error at offset 1: "),
+            "{}",
+            expected
+        );
+        assert_eq!(run("+[", b"", 0), Err(expected.clone()));
+        assert_eq!(Stepper::parse("+[").err(), Some(expected));
+    }
+
+    #[test]
+    fn read_callbacks() {
+        assert_eq!(read_byte(Some(104.0)), Some(b'h'));
+        assert_eq!(read_byte(None), None);
+    }
+
+    #[test]
+    fn stepping() {
+        let mut stepper = Stepper::parse("+>++<").unwrap();
+        assert_eq!(stepper.step(3), "executed");
+        assert_eq!(
+            (stepper.pc(), stepper.offset(), stepper.pointer()),
+            (3, Some(3), 1)
+        );
+        assert_eq!(&stepper.tape()[..2], &[1, 1]);
+        assert_eq!(stepper.iterations(), 3.0);
+        assert_eq!(stepper.step(10), "halted");
+        assert_eq!(stepper.offset(), None);
+
+        let mut stepper = Stepper::parse(",[.,]").unwrap();
+        assert_eq!(stepper.step(10), "needs-input");
+        stepper.push_input(b"ab");
+        assert_eq!(stepper.step(100), "needs-input");
+        assert_eq!(stepper.take_output(), b"ab");
+        assert!(stepper.take_output().is_empty());
+        stepper.close_input();
+        assert_eq!(stepper.step(10), "error");
+        assert!(stepper
+            .error()
+            .unwrap()
+            .starts_with("read past the end of the input"));
+    }
+
+    #[test]
+    fn breakpoints_and_fuel() {
+        let mut stepper = Stepper::parse("+++.").unwrap();
+        stepper.add_breakpoint(2);
+        assert_eq!(stepper.step(10), "breakpoint");
+        assert_eq!(stepper.offset(), Some(2));
+        assert!(stepper.remove_breakpoint(2));
+        assert!(!stepper.remove_breakpoint(2));
+        stepper.add_fuel(1);
+        assert_eq!(stepper.step(10), "fuel-exhausted");
+        assert_eq!(stepper.offset(), Some(3));
+    }
+
+    #[test]
+    fn tests() {
+        assert_eq!(test(",.", b"a", b"a", 0), None);
+        assert!(test(",.", b"a", b"b", 0).is_some());
+    }
+}
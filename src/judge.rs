@@ -0,0 +1,165 @@
+use std::{
+    fmt::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    interpreter::DEFAULT_TAPE_SIZE, optimized, CellWidth, Interpreter, OverflowBehavior,
+    RunTimeError,
+};
+
+/// What a submission may use before [`judge`] stops it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Wall-clock time
+    pub time: Duration,
+    /// Tape cells, the tape starts at up to 30,000 and grows to this on demand
+    pub memory: usize,
+    /// Bytes of output
+    pub output: usize,
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        ResourceLimits {
+            time: Duration::from_secs(1),
+            memory: DEFAULT_TAPE_SIZE,
+            output: 1 << 20,
+        }
+    }
+}
+
+/// The usual online judge verdicts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accepted,
+    WrongAnswer,
+    TimeLimitExceeded,
+    /// The pointer moved past the memory limit
+    MemoryLimitExceeded,
+    OutputLimitExceeded,
+    RuntimeError,
+    /// The program doesn't parse
+    CompileError,
+}
+
+impl Verdict {
+    /// The abbreviation judges show, e.g. `AC`
+    pub fn code(self) -> &'static str {
+        match self {
+            Verdict::Accepted => "AC",
+            Verdict::WrongAnswer => "WA",
+            Verdict::TimeLimitExceeded => "TLE",
+            Verdict::MemoryLimitExceeded => "MLE",
+            Verdict::OutputLimitExceeded => "OLE",
+            Verdict::RuntimeError => "RE",
+            Verdict::CompileError => "CE",
+        }
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// The verdict on a submission along with what it used
+#[derive(Debug, Clone)]
+pub struct Judgement {
+    pub verdict: Verdict,
+    /// Why the program failed, for runtime and compile errors
+    pub message: Option<String>,
+    /// Output up to when the program stopped, cut off at the output limit
+    pub output: Vec<u8>,
+    pub iterations: u64,
+    pub elapsed: Duration,
+    /// Tape cells allocated when the program stopped
+    pub memory: usize,
+}
+
+impl Judgement {
+    /// A single line JSON object for grading systems to read
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        write!(json, r#"{{"verdict":"{}","message":"#, self.verdict).unwrap();
+        match &self.message {
+            Some(message) => json.push_str(&json_string(message)),
+            None => json.push_str("null"),
+        }
+        write!(
+            json,
+            r#","time_ms":{},"memory":{},"output_bytes":{},"iterations":{}}}"#,
+            self.elapsed.as_millis(),
+            self.memory,
+            self.output.len(),
+            self.iterations
+        )
+        .unwrap();
+        json
+    }
+}
+
+/// Runs `program` on `input` within `limits` and compares its output to `expected` exactly
+pub fn judge(program: &str, input: &[u8], expected: &[u8], limits: &ResourceLimits) -> Judgement {
+    let bytecode = match optimized(program, CellWidth::U8, OverflowBehavior::Wrap) {
+        Ok(bytecode) => bytecode,
+        Err(err) => {
+            return Judgement {
+                verdict: Verdict::CompileError,
+                message: Some(crate::Error::ParseError(err).to_string()),
+                output: Vec::new(),
+                iterations: 0,
+                elapsed: Duration::ZERO,
+                memory: 0,
+            }
+        }
+    };
+    let interpreter = Interpreter::builder()
+        .tape_size(limits.memory.min(DEFAULT_TAPE_SIZE))
+        .growable_tape(limits.memory)
+        .max_output(limits.output)
+        .build_bytecode(bytecode);
+
+    let start = Instant::now();
+    let (outcome, machine) =
+        interpreter.run_until(input.iter().copied(), Some(start + limits.time), None, None);
+    let elapsed = start.elapsed();
+
+    let (verdict, message) = match outcome.error {
+        None if outcome.output == expected => (Verdict::Accepted, None),
+        None => (Verdict::WrongAnswer, None),
+        Some(RunTimeError::TimedOut) => (Verdict::TimeLimitExceeded, None),
+        Some(RunTimeError::OutOfBoundsRight(_)) => (Verdict::MemoryLimitExceeded, None),
+        Some(RunTimeError::OutputLimitExceeded(_)) => (Verdict::OutputLimitExceeded, None),
+        Some(err) => (Verdict::RuntimeError, Some(err.to_string())),
+    };
+
+    Judgement {
+        verdict,
+        message,
+        output: outcome.output,
+        iterations: outcome.iterations,
+        elapsed,
+        memory: machine.tape().len(),
+    }
+}
+
+/// `text` as a quoted JSON string
+pub(crate) fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
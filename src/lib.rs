@@ -1,80 +1,289 @@
+// Without `std` only the interpreter itself is available, running on the current thread with
+// buffered or callback I/O
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+// Lets `bf!` expansions name `::bfi` from this crate's own tests
+#[cfg(all(test, feature = "macros"))]
+extern crate self as bfi;
+
+#[cfg(feature = "std")]
+pub mod bench;
+mod bytecode;
+#[cfg(feature = "capi")]
+mod capi;
+#[cfg(feature = "std")]
+mod codegen;
+#[cfg(feature = "std")]
+mod diagnostics;
+#[cfg(feature = "std")]
+mod dialect;
+#[cfg(feature = "std")]
+mod diff;
+mod extension;
+#[cfg(feature = "std")]
+mod format;
+#[cfg(feature = "std")]
+pub mod fuzz;
+mod history;
 mod interpreter;
+#[cfg(feature = "std")]
+mod ir;
+#[cfg(feature = "jit")]
+mod jit;
+#[cfg(feature = "wasm")]
+mod js;
+#[cfg(feature = "std")]
+mod judge;
+#[cfg(feature = "std")]
+mod lint;
+mod machine;
+mod passes;
+#[cfg(feature = "std")]
+mod preprocess;
+#[cfg(feature = "std")]
+mod profile;
+mod program;
+mod record;
+#[cfg(feature = "std")]
+mod report;
+#[cfg(feature = "std")]
+mod script;
+mod source;
+#[cfg(feature = "proptest")]
+pub mod strategy;
+#[cfg(feature = "trace")]
+mod trace;
+mod tree;
+#[cfg(feature = "std")]
+mod verify;
+#[cfg(feature = "std")]
+mod viz;
 
+use alloc::{
+    string::{FromUtf8Error, String},
+    vec::Vec,
+};
 use bfc_ir::ParseError;
+use core::{fmt, num::NonZeroU64};
+#[cfg(feature = "std")]
 use interpreter::{InputTx, OutputRx};
-use std::thread::JoinHandle;
+#[cfg(feature = "std")]
+use std::{
+    cmp::Reverse,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::channel,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 use Error::*;
 
-pub use bfc_ir::{optimize, parse, OptimisationsFlags};
-pub use interpreter::{Interpreter, RunTimeError};
+pub use bfc_ir::{optimize, parse, OptimisationsFlags, Position};
+#[cfg(feature = "macros")]
+pub use bfi_macros::bf;
+pub use bytecode::{Bytecode, Op};
+#[cfg(feature = "std")]
+pub use codegen::{
+    print_string, to_brainfuck, to_c, to_rust, to_wasm, CodegenOptions, UnsupportedOp,
+};
+#[cfg(feature = "std")]
+pub use diagnostics::{Diagnostic, Note};
+#[cfg(feature = "std")]
+pub use dialect::{parse_with_alphabet, Alphabet, Dialect};
+#[cfg(feature = "std")]
+pub use diff::{hexdiff, linediff};
+pub use extension::{ExtensionContext, ExtensionHandler};
+#[cfg(feature = "std")]
+pub use format::{format, format_with, minify, strip_comments, FormatOptions};
+pub use interpreter::{
+    BoundsCheck, CellWidth, EofBehavior, ErrorContext, ExecutionStats, Interpreter,
+    InterpreterBuilder, IterationCost, Limit, NegativeCells, OverflowBehavior, RunOutcome,
+    RunTimeError, RunTimeErrorKind, DEFAULT_TAPE_SIZE,
+};
+#[cfg(feature = "std")]
+pub use interpreter::{DumpTarget, SpawnHandle};
+#[cfg(feature = "std")]
+pub use ir::format_ir;
+#[cfg(feature = "std")]
+pub use judge::{judge, Judgement, ResourceLimits, Verdict};
+#[cfg(feature = "std")]
+pub use lint::{lint, Lint, LintKind, Severity};
+pub use machine::{Event, Machine, MachineState, Snapshot};
+pub use passes::{Optimisations, Pass};
+#[cfg(feature = "std")]
+pub use preprocess::{preprocess, preprocess_file, PreprocessError};
+#[cfg(feature = "std")]
+pub use profile::{LoopProfile, MemoryHeatmap, Profile};
+pub use program::Program;
+pub use record::{InputRead, Recording, ReplayError};
+#[cfg(feature = "std")]
+pub use script::{Script, ScriptError, Step};
+pub use source::{annotate, split_input, Location};
+#[cfg(feature = "std")]
+pub use verify::{verify, Divergence, RunResult};
+#[cfg(feature = "std")]
+pub use viz::to_dot;
 
+#[derive(Debug)]
 pub enum Error {
     ParseError(bfc_ir::ParseError),
     RunTimeError((Vec<u8>, interpreter::RunTimeError)),
+    /// The program ran but its output isn't UTF-8, see [`execute_str`]
+    InvalidUtf8(FromUtf8Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError(err) => write!(
+                f,
+                "parse error at offset {}: {}",
+                err.position.start, err.message
+            ),
+            RunTimeError((output, err)) => write!(
+                f,
+                "runtime error after {} bytes of output: {}",
+                output.len(),
+                err
+            ),
+            InvalidUtf8(err) => write!(f, "output isn't valid UTF-8: {}", err),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError(_) => None,
+            RunTimeError((_, err)) => Some(err),
+            InvalidUtf8(err) => Some(err),
+        }
+    }
 }
 
+#[cfg(feature = "std")]
 pub enum TestResults {
     OutputsDontMatchInputs,
     ParseError(bfc_ir::ParseError),
     Results(Vec<TestResult>),
 }
 
-pub enum TestResult {
+/// The outcome of a single test case along with how much work it took
+#[cfg(feature = "std")]
+pub struct TestResult {
+    pub outcome: TestOutcome,
+    pub iterations: u64,
+    pub elapsed: Duration,
+}
+
+#[cfg(feature = "std")]
+pub enum TestOutcome {
     Ok,
-    RunTimeError((Vec<u8>, interpreter::RunTimeError)),
-    UnexpectedOutput { expected: Vec<u8>, output: Vec<u8> },
+    /// The program failed, with everything the run produced up to the error
+    RunTimeError(RunOutcome),
+    UnexpectedOutput {
+        expected: Vec<u8>,
+        output: Vec<u8>,
+    },
+    /// The case ran past its wall-clock timeout, output is whatever was produced so far
+    TimedOut(Vec<u8>),
+    /// The case expected an error but the program halted normally with this output
+    MissingError(Vec<u8>),
+    /// The program failed to parse but the case expected it to run
+    ParseError(String),
 }
 
-/// Executes a Brainfuck program to completion
-pub fn execute<I>(program: &str, input: I, max_iterations: u64) -> Result<Vec<u8>, Error>
+/// Executes a Brainfuck program to completion, `max_iterations` of 0 means unlimited
+#[deprecated(
+    since = "0.1.7",
+    note = "use `execute_outcome`, it takes `None` for no limit and `RunOutcome::into_result` \
+            gives the same result"
+)]
+pub fn execute<I>(
+    program: &str,
+    input: I,
+    max_iterations: u64,
+    cell_width: CellWidth,
+) -> Result<Vec<u8>, Error>
 where
     I: IntoIterator<Item = u8>,
 {
-    let mut instructions = bfc_ir::parse(program).map_err(ParseError)?;
-
-    let flags = OptimisationsFlags::all();
-    (instructions, _) = bfc_ir::optimize(instructions, flags);
+    execute_outcome(program, input, NonZeroU64::new(max_iterations), cell_width)
+        .map_err(ParseError)?
+        .into_result()
+        .map_err(RunTimeError)
+}
 
-    let interpreter = Interpreter::new(instructions, max_iterations);
+/// Executes a Brainfuck program to completion, reporting everything the run produced whether or
+/// not it halted normally, see [`RunOutcome`]
+pub fn execute_outcome<I>(
+    program: &str,
+    input: I,
+    max_iterations: Option<NonZeroU64>,
+    cell_width: CellWidth,
+) -> Result<RunOutcome, bfc_ir::ParseError>
+where
+    I: IntoIterator<Item = u8>,
+{
+    Ok(Program::compile_with(program, cell_width)?.run_outcome(input, max_iterations))
+}
 
-    let results = interpreter.run(input).map_err(Error::RunTimeError)?;
+/// Executes a program that reads and writes text, failing with [`Error::InvalidUtf8`] if what it
+/// writes isn't UTF-8. There's no iteration limit and cells are bytes.
+pub fn execute_str(program: &str, input: &str) -> Result<String, Error> {
+    let output = Program::compile(program)
+        .map_err(ParseError)?
+        .run(input.bytes(), None)?;
+    String::from_utf8(output).map_err(InvalidUtf8)
+}
 
-    Ok(results)
+/// Like [`execute_str`] but replaces invalid UTF-8 in the output with `U+FFFD`
+pub fn execute_str_lossy(program: &str, input: &str) -> Result<String, Error> {
+    let output = Program::compile(program)
+        .map_err(ParseError)?
+        .run(input.bytes(), None)?;
+    Ok(String::from_utf8_lossy(&output).into_owned())
 }
 
+#[cfg(feature = "std")]
 pub fn spawn(
     program: &str,
-    max_iterations: u64,
-) -> Result<(InputTx, OutputRx, JoinHandle<()>), ParseError> {
-    let mut instructions = bfc_ir::parse(program)?;
-
-    let flags = OptimisationsFlags::all();
-    (instructions, _) = bfc_ir::optimize(instructions, flags);
-
-    let interpreter = Interpreter::new(instructions, max_iterations);
+    max_iterations: Option<NonZeroU64>,
+) -> Result<(InputTx, OutputRx, SpawnHandle), ParseError> {
+    let interpreter = compile(program, max_iterations, CellWidth::U8)?;
 
     Ok(interpreter.spawn())
 }
 
+#[cfg(feature = "std")]
 pub fn test_blocking(
     program: &str,
     input: Vec<u8>,
     expected: Vec<u8>,
-    max_iterations: u64,
+    max_iterations: Option<NonZeroU64>,
+    cell_width: CellWidth,
 ) -> TestResults {
     tests_blocking(
         program,
         std::iter::once(input),
         std::iter::once(expected),
         max_iterations,
+        cell_width,
     )
 }
 
+#[cfg(feature = "std")]
 pub fn tests_blocking<I, O>(
     program: &str,
     inputs: I,
     outputs: O,
-    max_iterations: u64,
+    max_iterations: Option<NonZeroU64>,
+    cell_width: CellWidth,
 ) -> TestResults
 where
     I: Iterator<Item = Vec<u8>> + ExactSizeIterator,
@@ -84,32 +293,486 @@ where
         return TestResults::OutputsDontMatchInputs;
     }
 
-    let instructions = match bfc_ir::parse(program) {
-        Ok(instructions) => {
-            let (inst, _) = bfc_ir::optimize(instructions, OptimisationsFlags::all());
-            inst
-        }
+    match Program::compile_with(program, cell_width) {
+        Ok(program) => program.tests(inputs, outputs, max_iterations),
+        Err(err) => TestResults::ParseError(err),
+    }
+}
+
+/// Like [`tests_blocking`] but runs up to `concurrency` cases at once, each limited to `timeout`
+/// of wall-clock time. Results are returned in the same order as the inputs.
+#[cfg(feature = "std")]
+pub fn tests_parallel<I, O>(
+    program: &str,
+    inputs: I,
+    outputs: O,
+    max_iterations: Option<NonZeroU64>,
+    cell_width: CellWidth,
+    concurrency: usize,
+    timeout: Option<Duration>,
+) -> TestResults
+where
+    I: Iterator<Item = Vec<u8>> + ExactSizeIterator,
+    O: Iterator<Item = Vec<u8>> + ExactSizeIterator,
+{
+    if inputs.len() != outputs.len() {
+        return TestResults::OutputsDontMatchInputs;
+    }
+
+    let interpreter = match compile(program, max_iterations, cell_width) {
+        Ok(interpreter) => interpreter,
         Err(err) => return TestResults::ParseError(err),
     };
 
-    let interpreter = Interpreter::new(instructions, max_iterations);
-    let mut results: Vec<TestResult> = Vec::with_capacity(inputs.len());
+    let cases: Vec<(Vec<u8>, Vec<u8>)> = inputs.zip(outputs).collect();
+    let mut results: Vec<Option<TestResult>> = cases.iter().map(|_| None).collect();
+
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = channel();
+    thread::scope(|scope| {
+        for _ in 0..concurrency.clamp(1, cases.len().max(1)) {
+            let (cases, next, interpreter, tx) = (&cases, &next, &interpreter, tx.clone());
+
+            scope.spawn(move || {
+                let mut machine = interpreter.machine();
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let (input, expected) = match cases.get(i) {
+                        Some(case) => case.clone(),
+                        None => break,
+                    };
+
+                    tx.send((
+                        i,
+                        run_test(
+                            &mut machine,
+                            input,
+                            &Expectation::Output(expected),
+                            timeout,
+                            None,
+                            None,
+                        ),
+                    ))
+                    .unwrap();
+                }
+                interpreter.recycle(machine);
+            });
+        }
+    });
+    drop(tx);
+
+    for (i, result) in rx {
+        results[i] = Some(result);
+    }
+
+    TestResults::Results(results.into_iter().map(Option::unwrap).collect())
+}
+
+/// What a [`TestCase`] expects the program to do
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expectation {
+    /// Halt normally with exactly this output
+    Output(Vec<u8>),
+    /// Fail at runtime with this kind of error, wherever it happens
+    RunTimeError(RunTimeErrorKind),
+    /// Fail to parse
+    ParseError,
+}
+
+/// A named input and expected outcome for [`run_tests`]
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestCase {
+    pub name: String,
+    pub input: Vec<u8>,
+    pub expected: Expectation,
+    /// Instructions the case may run, unlimited for `None`
+    pub max_iterations: Option<NonZeroU64>,
+    /// Wall-clock limit for the case, it fails with [`TestOutcome::TimedOut`] when exceeded
+    pub timeout: Option<Duration>,
+    /// Bytes of output, see [`InterpreterBuilder::max_output`]
+    pub max_output: Option<usize>,
+}
+
+#[cfg(feature = "std")]
+impl TestCase {
+    /// A case expecting `expected` as output, with no iteration or time limit
+    pub fn new<N, I, O>(name: N, input: I, expected: O) -> Self
+    where
+        N: Into<String>,
+        I: Into<Vec<u8>>,
+        O: Into<Vec<u8>>,
+    {
+        TestCase::expecting(name, input, Expectation::Output(expected.into()))
+    }
+
+    /// A case expecting the program to fail with `kind`
+    pub fn error<N, I>(name: N, input: I, kind: RunTimeErrorKind) -> Self
+    where
+        N: Into<String>,
+        I: Into<Vec<u8>>,
+    {
+        TestCase::expecting(name, input, Expectation::RunTimeError(kind))
+    }
+
+    /// A case expecting the program not to parse
+    pub fn parse_error<N: Into<String>>(name: N) -> Self {
+        TestCase::expecting(name, vec![], Expectation::ParseError)
+    }
+
+    pub fn expecting<N, I>(name: N, input: I, expected: Expectation) -> Self
+    where
+        N: Into<String>,
+        I: Into<Vec<u8>>,
+    {
+        TestCase {
+            name: name.into(),
+            input: input.into(),
+            expected,
+            max_iterations: None,
+            timeout: None,
+            max_output: None,
+        }
+    }
+
+    pub fn iteration_limit(mut self, max_iterations: Option<NonZeroU64>) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    /// Limit the case to `max_iterations` instructions, `0` means unlimited
+    #[deprecated(since = "0.1.7", note = "use `iteration_limit`")]
+    pub fn max_iterations(self, max_iterations: u64) -> Self {
+        self.iteration_limit(NonZeroU64::new(max_iterations))
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn max_output(mut self, bytes: usize) -> Self {
+        self.max_output = Some(bytes);
+        self
+    }
+}
+
+/// The results of [`run_tests`], in the same order as the cases
+#[cfg(feature = "std")]
+pub struct TestReport {
+    pub results: Vec<(String, TestResult)>,
+}
+
+#[cfg(feature = "std")]
+impl TestReport {
+    /// Whether every case did what it expected
+    pub fn passed(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    /// The name and result of every case that didn't pass
+    pub fn failures(&self) -> impl Iterator<Item = (&str, &TestResult)> {
+        self.results
+            .iter()
+            .filter(|(_, result)| !matches!(result.outcome, TestOutcome::Ok))
+            .map(|(name, result)| (name.as_str(), result))
+    }
+
+    /// The `n` cases that took the longest wall-clock time, slowest first
+    pub fn slowest(&self, n: usize) -> Vec<(&str, &TestResult)> {
+        let mut results: Vec<_> = self
+            .results
+            .iter()
+            .map(|(name, result)| (name.as_str(), result))
+            .collect();
+        results.sort_by_key(|(_, result)| Reverse(result.elapsed));
+        results.truncate(n);
+        results
+    }
+
+    /// Wall-clock time spent running every case, summed
+    pub fn elapsed(&self) -> Duration {
+        self.results.iter().map(|(_, result)| result.elapsed).sum()
+    }
+}
+
+/// Runs every case against `program`, each limited to its own `max_iterations`, `timeout` and
+/// `max_output`. If the program doesn't parse only cases expecting [`Expectation::ParseError`]
+/// pass.
+#[cfg(feature = "std")]
+pub fn run_tests(program: &str, cases: &[TestCase]) -> TestReport {
+    match Program::compile(program) {
+        Ok(program) => program.run_tests(cases),
+        Err(err) => TestReport {
+            results: unparsed_cases(cases, err),
+        },
+    }
+}
+
+/// Like [`run_tests`] but spreads the cases over rayon's thread pool
+#[cfg(feature = "rayon")]
+pub fn run_tests_parallel(program: &str, cases: &[TestCase]) -> TestReport {
+    use rayon::prelude::*;
+
+    let results = match compile(program, None, CellWidth::U8) {
+        Ok(interpreter) => cases
+            .par_iter()
+            .map_init(|| interpreter.machine(), run_case)
+            .collect(),
+        Err(err) => unparsed_cases(cases, err),
+    };
+
+    TestReport { results }
+}
+
+/// A program found by [`test_dir`] and how it did against its `.bf.out` file
+#[cfg(feature = "std")]
+pub struct FileTest {
+    pub program: PathBuf,
+    pub result: Result<TestResult, ParseError>,
+}
+
+/// Runs every `*.bf` and `*.ook` file in `dir` that has a matching `.out` file, e.g.
+/// `*.bf.out`, feeding it the matching `.in` file when one exists. Programs without an expected
+/// output are skipped, results are sorted by path.
+#[cfg(feature = "std")]
+pub fn test_dir<P: AsRef<Path>>(dir: P) -> io::Result<Vec<FileTest>> {
+    let mut programs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "bf" || extension == "ook")
+        {
+            programs.push(path);
+        }
+    }
+    programs.sort();
+
+    let mut tests = Vec::new();
+    for program in programs {
+        tests.extend(test_file(program)?);
+    }
+
+    Ok(tests)
+}
+
+/// Runs one program the way [`test_dir`] does, `None` if it has no `.out` file
+#[cfg(feature = "std")]
+pub fn test_file<P: Into<PathBuf>>(program: P) -> io::Result<Option<FileTest>> {
+    let program = program.into();
+    let with_extension = |extension: &str| {
+        let mut path = program.as_os_str().to_owned();
+        path.push(extension);
+        PathBuf::from(path)
+    };
+
+    let expected = match fs::read(with_extension(".out")) {
+        Ok(expected) => expected,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let input = match fs::read(with_extension(".in")) {
+        Ok(input) => input,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err),
+    };
+
+    let source = fs::read_to_string(&program)?;
+    let result = Dialect::from_path(&program)
+        .translate(&source)
+        .and_then(|source| compile(&source, None, CellWidth::U8))
+        .map(|interpreter| {
+            run_test(
+                &mut interpreter.machine(),
+                input,
+                &Expectation::Output(expected),
+                None,
+                None,
+                None,
+            )
+        });
+
+    Ok(Some(FileTest { program, result }))
+}
+
+/// Panics unless `program` passes [`test_file`], which includes having a `.out` file. This is
+/// what [`bf_test!`] runs.
+#[cfg(feature = "std")]
+pub fn assert_test_file<P: Into<PathBuf>>(program: P) {
+    let program = program.into();
+    let name = program.display().to_string();
+
+    let test = match test_file(program) {
+        Ok(Some(test)) => test,
+        Ok(None) => panic!("{} has no .out file", name),
+        Err(err) => panic!("failed to read {}: {}", name, err),
+    };
+    let result = test
+        .result
+        .unwrap_or_else(|err| panic!("{}: {}", name, Error::ParseError(err)));
+
+    match result.outcome {
+        TestOutcome::Ok => {}
+        TestOutcome::UnexpectedOutput { expected, output } => panic!(
+            "{}: output didn't match\n{}",
+            name,
+            hexdiff(&expected, &output)
+        ),
+        TestOutcome::RunTimeError(run) => panic!("{}: {}", name, run.error.unwrap()),
+        TestOutcome::TimedOut(_) | TestOutcome::MissingError(_) | TestOutcome::ParseError(_) => {
+            unreachable!("only output was expected, without a timeout")
+        }
+    }
+}
 
-    for (input, expected) in inputs.into_iter().zip(outputs) {
-        match interpreter.run(input) {
-            Ok(output) => {
-                if expected != output {
-                    results.push(TestResult::UnexpectedOutput { expected, output });
-                } else {
-                    results.push(TestResult::Ok);
+/// Defines a `#[test]` for each program that checks it writes what its `.out` file says, given
+/// its `.in` file if there is one. Paths are relative to the crate being tested.
+///
+/// ```ignore
+/// bfi::bf_test! {
+///     hello_world => "sample_programs/hello_world.bf",
+///     hello_world_ook => "sample_programs/hello_world.ook",
+/// }
+/// ```
+#[cfg(feature = "std")]
+#[macro_export]
+macro_rules! bf_test {
+    ($($name:ident => $program:expr),+ $(,)?) => {
+        $(
+            #[test]
+            fn $name() {
+                $crate::assert_test_file(::std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join($program));
+            }
+        )+
+    };
+}
+
+/// Parses and optimizes a program, see [`optimized`]
+#[cfg(feature = "std")]
+fn compile(
+    program: &str,
+    max_iterations: Option<NonZeroU64>,
+    cell_width: CellWidth,
+) -> Result<Interpreter, ParseError> {
+    let program = Program::compile_with(program, cell_width)?;
+    Ok(Interpreter::from_program(&program, max_iterations))
+}
+
+/// Parses and optimizes a program. Wider cells skip `bfc_ir`'s optimizer since it folds
+/// constants as wrapping 8-bit values, and so does any `overflow` but wrapping. bfi's own passes
+/// work at any width.
+fn optimized(
+    program: &str,
+    cell_width: CellWidth,
+    overflow: OverflowBehavior,
+) -> Result<Bytecode, ParseError> {
+    let mut instructions = bfc_ir::parse(program)?;
+
+    if cell_width == CellWidth::U8 && overflow == OverflowBehavior::Wrap {
+        (instructions, _) = bfc_ir::optimize(instructions, OptimisationsFlags::all());
+    }
+
+    Ok(Bytecode::compile(&instructions).optimize_for(Pass::ALL, overflow))
+}
+
+#[cfg(feature = "std")]
+fn run_case(machine: &mut Machine, case: &TestCase) -> (String, TestResult) {
+    let result = run_test(
+        machine,
+        case.input.clone(),
+        &case.expected,
+        case.timeout,
+        case.max_iterations,
+        case.max_output,
+    );
+    (case.name.clone(), result)
+}
+
+/// Results for cases whose program failed to parse, nothing ran so they took no time
+#[cfg(feature = "std")]
+fn unparsed_cases(cases: &[TestCase], err: ParseError) -> Vec<(String, TestResult)> {
+    let message = Error::ParseError(err).to_string();
+
+    cases
+        .iter()
+        .map(|case| {
+            let outcome = match case.expected {
+                Expectation::ParseError => TestOutcome::Ok,
+                _ => TestOutcome::ParseError(message.clone()),
+            };
+            let result = TestResult {
+                outcome,
+                iterations: 0,
+                elapsed: Duration::ZERO,
+            };
+            (case.name.clone(), result)
+        })
+        .collect()
+}
+
+/// Runs one case on `machine` after resetting it, so a single machine can run every case
+#[cfg(feature = "std")]
+fn run_test(
+    machine: &mut Machine,
+    input: Vec<u8>,
+    expected: &Expectation,
+    timeout: Option<Duration>,
+    max_iterations: Option<NonZeroU64>,
+    max_output: Option<usize>,
+) -> TestResult {
+    machine.reset();
+    let start = Instant::now();
+    let deadline = timeout.map(|t| start + t);
+    let run = Interpreter::run_limited(machine, input, deadline, max_iterations, max_output);
+    let elapsed = start.elapsed();
+    let iterations = run.iterations;
+
+    let outcome = match (&run.error, expected) {
+        (None, Expectation::Output(expected)) => {
+            if *expected != run.output {
+                TestOutcome::UnexpectedOutput {
+                    expected: expected.clone(),
+                    output: run.output,
                 }
+            } else {
+                TestOutcome::Ok
             }
-            Err(e) => results.push(TestResult::RunTimeError(e)),
         }
+        (None, _) => TestOutcome::MissingError(run.output),
+        (Some(err), Expectation::RunTimeError(kind)) if err.kind() == *kind => TestOutcome::Ok,
+        (Some(RunTimeError::TimedOut), _) => TestOutcome::TimedOut(run.output),
+        (Some(_), _) => TestOutcome::RunTimeError(run),
+    };
+
+    TestResult {
+        outcome,
+        iterations,
+        elapsed,
     }
+}
+
+/// What code expanded from [`bf!`] refers to, not part of the public API
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub mod __private {
+    pub use alloc::vec;
+    pub use bfc_ir::AstNode;
 
-    TestResults::Results(results)
+    use alloc::{sync::Arc, vec::Vec};
+
+    use crate::{Bytecode, CellWidth, Pass, Program};
+
+    /// A program from `instructions` already parsed and optimized by `bfc_ir`
+    pub fn program(source: &str, instructions: Vec<AstNode>) -> Program {
+        Program {
+            bytecode: Arc::new(Bytecode::compile(&instructions).optimize(Pass::ALL)),
+            source: source.into(),
+            cell_width: CellWidth::U8,
+        }
+    }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests;
@@ -0,0 +1,306 @@
+//! Finds mistakes in a program without running it
+//!
+//! The linter follows the program from the start of the tape, keeping track of the cells and
+//! pointer while it can be sure of them. Everything it reports is certain to happen, it never
+//! guesses about input or loops it can't see through.
+//!
+//! ```ignore
+//! for lint in bfi::lint(&program) {
+//!     println!("{}\n", lint.render(&program));
+//! }
+//! ```
+
+use std::{collections::HashMap, fmt, ops::Range};
+
+use crate::source::annotate_span;
+
+/// Something wrong with a program
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Lint {
+    pub kind: LintKind,
+    /// The byte range in the source the lint is about
+    pub span: Range<usize>,
+}
+
+impl Lint {
+    pub fn severity(&self) -> Severity {
+        match self.kind {
+            LintKind::UnmatchedOpen | LintKind::UnmatchedClose | LintKind::PointerUnderflow => {
+                Severity::Error
+            }
+            LintKind::InfiniteLoop | LintKind::Unreachable | LintKind::DeadLoop => {
+                Severity::Warning
+            }
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self.kind {
+            LintKind::UnmatchedOpen => "this `[` is never closed",
+            LintKind::UnmatchedClose => "this `]` has no matching `[`",
+            LintKind::PointerUnderflow => "this moves the pointer off the left end of the tape",
+            LintKind::InfiniteLoop => "this loop never exits",
+            LintKind::Unreachable => "this code is never reached, the loop before it never exits",
+            LintKind::DeadLoop => "this loop never runs, the current cell is always zero here",
+        }
+    }
+
+    /// Formats the lint like rustc does, with the offending source underlined
+    ///
+    /// ```text
+    /// warning: this loop never exits
+    ///  --> line 1, column 2
+    ///   |
+    /// 1 | +[].
+    ///   |  ^^
+    /// ```
+    pub fn render(&self, program: &str) -> String {
+        let message = format!("{}: {}", self.severity(), self.message());
+        annotate_span(program, self.span.clone(), &message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LintKind {
+    UnmatchedOpen,
+    UnmatchedClose,
+    /// A `<` that's certain to leave the tape
+    PointerUnderflow,
+    /// A loop that's entered with a non-zero cell it never changes
+    InfiniteLoop,
+    /// Code after an infinite loop
+    Unreachable,
+    /// A loop that's only reached when the current cell is zero
+    DeadLoop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// Lints a program, returning what it found in source order. Unmatched brackets are reported
+/// alone since the rest of the analysis needs the loops to make sense.
+pub fn lint(program: &str) -> Vec<Lint> {
+    let commands: Vec<(usize, u8)> = program
+        .char_indices()
+        .filter(|(_, c)| matches!(c, '+' | '-' | '<' | '>' | '.' | ',' | '[' | ']'))
+        .map(|(offset, c)| (offset, c as u8))
+        .collect();
+
+    let mut lints = Vec::new();
+    let mut matching = vec![0; commands.len()];
+    let mut open = Vec::new();
+    for (i, (offset, c)) in commands.iter().enumerate() {
+        match c {
+            b'[' => open.push(i),
+            b']' => match open.pop() {
+                Some(start) => {
+                    matching[start] = i;
+                    matching[i] = start;
+                }
+                None => lints.push(Lint {
+                    kind: LintKind::UnmatchedClose,
+                    span: *offset..offset + 1,
+                }),
+            },
+            _ => {}
+        }
+    }
+    lints.extend(open.into_iter().map(|i| Lint {
+        kind: LintKind::UnmatchedOpen,
+        span: commands[i].0..commands[i].0 + 1,
+    }));
+
+    if lints.is_empty() {
+        let mut linter = Linter {
+            commands: &commands,
+            matching: &matching,
+            lints: &mut lints,
+        };
+        let mut state = State {
+            pointer: 0,
+            anchored: true,
+            cells: HashMap::new(),
+            zeroed: true,
+        };
+        linter.block(0..commands.len(), &mut state);
+    }
+
+    lints.sort_by_key(|lint| lint.span.start);
+    lints
+}
+
+/// What's known about the machine at some point in the program
+#[derive(Clone)]
+struct State {
+    /// The pointer's position on the tape when `anchored`, otherwise relative to where the
+    /// linter lost track of it
+    pointer: isize,
+    anchored: bool,
+    /// Cells that have been changed, with their values if they're known
+    cells: HashMap<isize, Option<u8>>,
+    /// Whether every cell missing from `cells` is zero
+    zeroed: bool,
+}
+
+impl State {
+    fn get(&self) -> Option<u8> {
+        match self.cells.get(&self.pointer) {
+            Some(value) => *value,
+            None if self.zeroed => Some(0),
+            None => None,
+        }
+    }
+
+    fn set(&mut self, value: Option<u8>) {
+        self.cells.insert(self.pointer, value);
+    }
+
+    fn add(&mut self, amount: u8) {
+        self.set(self.get().map(|value| value.wrapping_add(amount)));
+    }
+
+    fn forget(&mut self) {
+        self.cells.clear();
+        self.zeroed = false;
+    }
+}
+
+struct Linter<'a> {
+    /// Each command with its offset in the source
+    commands: &'a [(usize, u8)],
+    /// The index of the bracket matching each bracket
+    matching: &'a [usize],
+    lints: &'a mut Vec<Lint>,
+}
+
+impl Linter<'_> {
+    /// Follows the commands in `range` from `state`, returning whether they certainly never finish
+    fn block(&mut self, range: Range<usize>, state: &mut State) -> bool {
+        let mut i = range.start;
+        while i < range.end {
+            let (offset, c) = self.commands[i];
+            match c {
+                b'+' => state.add(1),
+                b'-' => state.add(u8::MAX),
+                b'>' => state.pointer += 1,
+                b'<' => {
+                    state.pointer -= 1;
+                    if state.anchored && state.pointer < 0 {
+                        self.push(LintKind::PointerUnderflow, offset..offset + 1);
+                        // Only the first is certain, the program stops there
+                        state.anchored = false;
+                    }
+                }
+                b',' => state.set(None),
+                b'[' => {
+                    let end = self.matching[i];
+                    let span = offset..self.commands[end].0 + 1;
+                    let value = state.get();
+                    if value == Some(0) {
+                        self.push(LintKind::DeadLoop, span);
+                        i = end + 1;
+                        continue;
+                    }
+
+                    // The loop runs at least once only if the cell is known to be non-zero
+                    let entered = value.is_some();
+                    if entered && self.never_exits(i + 1..end) {
+                        self.push(LintKind::InfiniteLoop, span);
+                        self.unreachable(end + 1..range.end);
+                        return true;
+                    }
+
+                    // Nothing carries over between iterations, and where the pointer starts is
+                    // only certain for the first
+                    let mut body = state.clone();
+                    body.forget();
+                    body.anchored = state.anchored && entered;
+                    if self.block(i + 1..end, &mut body) && entered {
+                        self.unreachable(end + 1..range.end);
+                        return true;
+                    }
+
+                    if !self.balanced(i + 1..end) {
+                        state.pointer = 0;
+                        state.anchored = false;
+                    }
+                    state.forget();
+                    state.set(Some(0));
+                    i = end;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+
+        false
+    }
+
+    /// Whether a loop body without inner loops or input leaves the pointer and current cell where
+    /// they started
+    fn never_exits(&self, body: Range<usize>) -> bool {
+        let mut pointer = 0isize;
+        let mut change = 0u8;
+        for (_, c) in &self.commands[body] {
+            match c {
+                b'>' => pointer += 1,
+                b'<' => pointer -= 1,
+                b'+' if pointer == 0 => change = change.wrapping_add(1),
+                b'-' if pointer == 0 => change = change.wrapping_sub(1),
+                b',' | b'[' => return false,
+                _ => {}
+            }
+        }
+
+        pointer == 0 && change == 0
+    }
+
+    /// Whether the commands in `range`, and every loop in them, move the pointer as far left as
+    /// right
+    fn balanced(&self, range: Range<usize>) -> bool {
+        let mut pointer = 0isize;
+        let mut i = range.start;
+        while i < range.end {
+            match self.commands[i].1 {
+                b'>' => pointer += 1,
+                b'<' => pointer -= 1,
+                b'[' => {
+                    let end = self.matching[i];
+                    if !self.balanced(i + 1..end) {
+                        return false;
+                    }
+                    i = end;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+
+        pointer == 0
+    }
+
+    fn unreachable(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+
+        let span = self.commands[range.start].0..self.commands[range.end - 1].0 + 1;
+        self.push(LintKind::Unreachable, span);
+    }
+
+    fn push(&mut self, kind: LintKind, span: Range<usize>) {
+        self.lints.push(Lint { kind, span });
+    }
+}
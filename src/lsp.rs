@@ -0,0 +1,484 @@
+//! `bfi lsp`, a Language Server Protocol server on stdin and stdout
+//!
+//! Open documents get diagnostics from the linter and the optimizer, hovering over a loop shows
+//! what the optimizer turned it into, and formatting is `bfi fmt` with comments kept.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    ops::Range,
+    slice,
+};
+
+use bfc_ir::{AstNode, OptimisationsFlags, Position};
+use bfi::{Diagnostic, FormatOptions, Severity};
+use serde_json::{json, Value};
+
+use crate::rpc::{read_message, write_message};
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// Serves until the editor sends `exit`
+pub fn serve() -> io::Result<()> {
+    let mut server = Server {
+        writer: io::stdout(),
+        documents: HashMap::new(),
+    };
+
+    let mut stdin = io::stdin().lock();
+    while let Some(message) = read_message(&mut stdin)? {
+        if !server.message(&message)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Answers requests with responses and notifications written to `writer`
+struct Server<W> {
+    writer: W,
+    /// The text of every open document by URI
+    documents: HashMap<String, String>,
+}
+
+impl<W: Write> Server<W> {
+    /// Handles a request or notification, returning `false` once the editor wants to exit
+    fn message(&mut self, message: &Value) -> io::Result<bool> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    // The whole document is sent on every change
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "documentFormattingProvider": true,
+                },
+                "serverInfo": { "name": "bfi", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "shutdown" => Ok(Value::Null),
+            "exit" => return Ok(false),
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.update(uri, text.to_string())?;
+                Ok(Value::Null)
+            }
+            "textDocument/didChange" => {
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes.and_then(|changes| changes.last()) {
+                    let text = text["text"].as_str().unwrap_or_default();
+                    self.update(uri, text.to_string())?;
+                }
+                Ok(Value::Null)
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.publish(&uri, Vec::new())?;
+                Ok(Value::Null)
+            }
+            "textDocument/hover" => self.hover(&uri, &params["position"]),
+            "textDocument/formatting" => self.formatting(&uri),
+            _ => Err((METHOD_NOT_FOUND, format!("unsupported method `{}`", method))),
+        };
+
+        // Notifications don't get a response, even when they aren't understood
+        if let Some(id) = message.get("id") {
+            let response = match result {
+                Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                Err((code, message)) => json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": code, "message": message },
+                }),
+            };
+            write_message(&mut self.writer, &response)?;
+        }
+        Ok(true)
+    }
+
+    fn document(&self, uri: &str) -> Result<&str, (i64, String)> {
+        self.documents
+            .get(uri)
+            .map(String::as_str)
+            .ok_or_else(|| (INVALID_PARAMS, format!("{} isn't open", uri)))
+    }
+
+    fn update(&mut self, uri: String, text: String) -> io::Result<()> {
+        let diagnostics = diagnostics(&text);
+        self.publish(&uri, diagnostics)?;
+        self.documents.insert(uri, text);
+        Ok(())
+    }
+
+    fn publish(&mut self, uri: &str, diagnostics: Vec<Value>) -> io::Result<()> {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        });
+        write_message(&mut self.writer, &notification)
+    }
+
+    /// The optimized instructions for the innermost loop under the cursor
+    fn hover(&self, uri: &str, position: &Value) -> Result<Value, (i64, String)> {
+        let text = self.document(uri)?;
+        let line = position["line"].as_u64().unwrap_or(0) as usize;
+        let character = position["character"].as_u64().unwrap_or(0) as usize;
+        let offset = offset(text, line, character);
+
+        let instructions = match bfc_ir::parse(text) {
+            Ok(instructions) => instructions,
+            Err(_) => return Ok(Value::Null),
+        };
+        let (instructions, _) = bfc_ir::optimize(instructions, OptimisationsFlags::all());
+
+        Ok(match innermost_loop(&instructions, text, offset) {
+            Some((node, span)) => json!({
+                "contents": {
+                    "kind": "markdown",
+                    "value": format!("```\n{}```", bfi::format_ir(slice::from_ref(node), text)),
+                },
+                "range": range(text, span.start..span.end + 1),
+            }),
+            None => Value::Null,
+        })
+    }
+
+    /// Replaces the whole document when formatting changes anything
+    fn formatting(&self, uri: &str) -> Result<Value, (i64, String)> {
+        let text = self.document(uri)?;
+        let options = FormatOptions {
+            comments: true,
+            ..FormatOptions::default()
+        };
+        let formatted = bfi::format_with(text, &options);
+
+        if formatted == text {
+            return Ok(json!([]));
+        }
+        Ok(json!([{ "range": range(text, 0..text.len()), "newText": formatted }]))
+    }
+}
+
+/// Lints, then optimizer warnings for programs that parse. The linter reports unmatched
+/// brackets itself.
+fn diagnostics(text: &str) -> Vec<Value> {
+    let mut diagnostics: Vec<Value> = bfi::lint(text)
+        .iter()
+        .map(|lint| {
+            let severity = match lint.severity() {
+                Severity::Error => 1,
+                Severity::Warning => 2,
+            };
+            diagnostic(text, lint.span.clone(), severity, lint.message())
+        })
+        .collect();
+
+    if let Ok(instructions) = bfc_ir::parse(text) {
+        let (_, warnings) = bfc_ir::optimize(instructions, OptimisationsFlags::all());
+        for warning in warnings.into_iter().map(Diagnostic::from) {
+            if let Some(span) = warning.span {
+                diagnostics.push(diagnostic(text, span, 2, &warning.message));
+            }
+        }
+    }
+
+    diagnostics
+}
+
+fn diagnostic(text: &str, span: Range<usize>, severity: u8, message: &str) -> Value {
+    json!({
+        "range": range(text, span),
+        "severity": severity,
+        "source": "bfi",
+        "message": message,
+    })
+}
+
+/// The innermost instruction around `offset` that the optimizer made from a loop, with its span
+fn innermost_loop<'a>(
+    instructions: &'a [AstNode],
+    text: &str,
+    offset: usize,
+) -> Option<(&'a AstNode, Position)> {
+    for node in instructions {
+        let span = match span(node) {
+            Some(span) if span.start <= offset && offset <= span.end => span,
+            _ => continue,
+        };
+        // Loops often become sets and multiply-moves, they still start with a bracket
+        if !text[span.start..].starts_with('[') {
+            continue;
+        }
+
+        if let AstNode::Loop { body, .. } = node {
+            if let Some(inner) = innermost_loop(body, text, offset) {
+                return Some(inner);
+            }
+        }
+        return Some((node, span));
+    }
+    None
+}
+
+fn span(node: &AstNode) -> Option<Position> {
+    match node {
+        AstNode::Increment { position, .. }
+        | AstNode::PointerIncrement { position, .. }
+        | AstNode::Read { position }
+        | AstNode::Write { position }
+        | AstNode::Set { position, .. }
+        | AstNode::MultiplyMove { position, .. }
+        | AstNode::Loop { position, .. } => *position,
+    }
+}
+
+fn range(text: &str, span: Range<usize>) -> Value {
+    json!({ "start": position(text, span.start), "end": position(text, span.end) })
+}
+
+/// An LSP position, lines from 0 and characters in UTF-16 code units
+fn position(text: &str, offset: usize) -> Value {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    json!({
+        "line": before.matches('\n').count(),
+        "character": before[line_start..].encode_utf16().count(),
+    })
+}
+
+/// The byte offset of an LSP position, clamped to the end of its line
+fn offset(text: &str, line: usize, character: usize) -> usize {
+    let start: usize = text.split_inclusive('\n').take(line).map(str::len).sum();
+
+    let mut units = 0;
+    for (offset, c) in text[start..].char_indices() {
+        if units >= character || c == '\n' {
+            return start + offset;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::read_message;
+
+    const URI: &str = "file:///test.bf";
+
+    fn server() -> Server<Vec<u8>> {
+        Server {
+            writer: Vec::new(),
+            documents: HashMap::new(),
+        }
+    }
+
+    /// Handles a message, returning everything the server wrote in reply
+    fn send(server: &mut Server<Vec<u8>>, message: Value) -> Vec<Value> {
+        assert!(server.message(&message).unwrap());
+
+        let written = std::mem::take(&mut server.writer);
+        let mut reader = &written[..];
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn open(server: &mut Server<Vec<u8>>, text: &str) -> Vec<Value> {
+        send(
+            server,
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didOpen",
+                "params": { "textDocument": { "uri": URI, "text": text } },
+            }),
+        )
+    }
+
+    fn request(server: &mut Server<Vec<u8>>, method: &str, params: Value) -> Value {
+        let mut messages = send(
+            server,
+            json!({ "jsonrpc": "2.0", "id": 7, "method": method, "params": params }),
+        );
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["id"], 7);
+        messages.remove(0)
+    }
+
+    #[test]
+    fn positions() {
+        let text = "+\n\u{1F600}>";
+
+        assert_eq!(position(text, 0), json!({ "line": 0, "character": 0 }));
+        assert_eq!(position(text, 2), json!({ "line": 1, "character": 0 }));
+        // Characters outside the BMP are two UTF-16 code units
+        assert_eq!(position(text, 6), json!({ "line": 1, "character": 2 }));
+        assert_eq!(position(text, 4), json!({ "line": 1, "character": 0 }));
+        assert_eq!(position(text, 100), json!({ "line": 1, "character": 3 }));
+
+        assert_eq!(offset(text, 0, 0), 0);
+        assert_eq!(offset(text, 1, 2), 6);
+        assert_eq!(offset(text, 0, 10), 1);
+        assert_eq!(offset(text, 5, 0), text.len());
+    }
+
+    #[test]
+    fn diagnostics_are_published() {
+        let messages = open(&mut server(), "+[");
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["method"], "textDocument/publishDiagnostics");
+        assert_eq!(messages[0]["params"]["uri"], URI);
+        assert_eq!(
+            messages[0]["params"]["diagnostics"],
+            json!([{
+                "range": {
+                    "start": { "line": 0, "character": 1 },
+                    "end": { "line": 0, "character": 2 },
+                },
+                "severity": 1,
+                "source": "bfi",
+                "message": "this `[` is never closed",
+            }])
+        );
+    }
+
+    #[test]
+    fn documents_change_and_close() {
+        let mut server = server();
+        open(&mut server, "+[");
+
+        let messages = send(
+            &mut server,
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didChange",
+                "params": {
+                    "textDocument": { "uri": URI },
+                    "contentChanges": [{ "text": "+[" }, { "text": "+[-]" }],
+                },
+            }),
+        );
+        assert_eq!(messages[0]["params"]["diagnostics"], json!([]));
+        assert_eq!(server.documents[URI], "+[-]");
+
+        let messages = send(
+            &mut server,
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didClose",
+                "params": { "textDocument": { "uri": URI } },
+            }),
+        );
+        assert_eq!(messages[0]["params"]["diagnostics"], json!([]));
+        assert!(server.documents.is_empty());
+    }
+
+    #[test]
+    fn hover() {
+        let mut server = server();
+        open(&mut server, "+[>+<-]");
+        let hover = |server: &mut Server<Vec<u8>>, character| {
+            request(
+                server,
+                "textDocument/hover",
+                json!({
+                    "textDocument": { "uri": URI },
+                    "position": { "line": 0, "character": character },
+                }),
+            )["result"]
+                .clone()
+        };
+
+        let result = hover(&mut server, 3);
+        assert_eq!(result["contents"]["kind"], "markdown");
+        assert!(result["contents"]["value"]
+            .as_str()
+            .unwrap()
+            .starts_with("```\n"));
+        assert_eq!(
+            result["range"],
+            json!({
+                "start": { "line": 0, "character": 1 },
+                "end": { "line": 0, "character": 7 },
+            })
+        );
+        assert_eq!(hover(&mut server, 0), Value::Null);
+
+        // Nothing to show until the program parses
+        open(&mut server, "+[>+<-");
+        assert_eq!(hover(&mut server, 3), Value::Null);
+    }
+
+    #[test]
+    fn formatting() {
+        let mut server = server();
+        open(&mut server, "+ +\n.");
+        let format = |server: &mut Server<Vec<u8>>| {
+            request(
+                server,
+                "textDocument/formatting",
+                json!({ "textDocument": { "uri": URI } }),
+            )["result"]
+                .clone()
+        };
+
+        assert_eq!(
+            format(&mut server),
+            json!([{
+                "range": {
+                    "start": { "line": 0, "character": 0 },
+                    "end": { "line": 1, "character": 1 },
+                },
+                "newText": "++.\n",
+            }])
+        );
+        open(&mut server, "++.\n");
+        assert_eq!(format(&mut server), json!([]));
+    }
+
+    #[test]
+    fn errors() {
+        let mut server = server();
+
+        let response = request(
+            &mut server,
+            "textDocument/formatting",
+            json!({ "textDocument": { "uri": URI } }),
+        );
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+
+        let response = request(&mut server, "workspace/symbol", json!({}));
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(
+            response["error"]["message"],
+            "unsupported method `workspace/symbol`"
+        );
+
+        // Notifications are never answered
+        let messages = send(
+            &mut server,
+            json!({ "jsonrpc": "2.0", "method": "$/cancelRequest", "params": {} }),
+        );
+        assert!(messages.is_empty());
+
+        assert!(!server
+            .message(&json!({ "jsonrpc": "2.0", "method": "exit" }))
+            .unwrap());
+    }
+}
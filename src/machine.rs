@@ -0,0 +1,1215 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    iter, mem,
+    num::{NonZeroU64, Wrapping},
+};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use crate::{
+    bytecode::{Bytecode, Op},
+    extension::{ExtensionContext, Extensions},
+    history::{Checkpoint, History},
+    interpreter::{
+        BoundsCheck, Config, EofBehavior, ErrorContext, IterationCost, NegativeCells,
+        OverflowBehavior, RunTimeError,
+    },
+    record::{InputRead, Recording},
+};
+#[cfg(feature = "std")]
+use crate::{
+    interpreter::{Control, DumpTarget, ExecutionStats, Limit},
+    profile::Profiler,
+};
+
+/// Where a machine gets its input from and sends its output to
+pub(crate) trait Io {
+    /// Waits for the next input byte, returning `None` once the input has been closed
+    fn read(&mut self) -> Result<Option<u8>, RunTimeError>;
+
+    fn write(&mut self, output: u8) -> Result<(), RunTimeError>;
+}
+
+/// What happened during a call to [`Machine::step`]
+#[derive(Debug)]
+pub enum Event {
+    /// An instruction ran without any I/O
+    Executed,
+    /// The program wrote a byte
+    Output(u8),
+    /// The program wants to read but no input is queued, see [`Machine::push_input`]
+    NeedsInput,
+    /// The program has finished, stepping again does nothing
+    Halted,
+    /// The program failed, the machine is halted
+    Error(RunTimeError),
+    /// The machine is out of fuel, stepping again does nothing until [`Machine::add_fuel`]
+    FuelExhausted,
+    /// Stopped before an instruction compiled from this breakpoint's source offset, stepping
+    /// again runs it
+    Breakpoint(usize),
+    /// The instruction changed a watched cell
+    Watchpoint {
+        index: usize,
+        old: Wrapping<u32>,
+        new: Wrapping<u32>,
+    },
+}
+
+/// A machine's memory at the end of a run, see [`crate::Interpreter::run_with_state`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachineState {
+    pub tape: Vec<Wrapping<u32>>,
+    /// Negative if the program failed by moving off the left of the tape
+    pub pointer: isize,
+    /// Index of the starting cell in `tape`
+    pub origin: usize,
+    pub iterations: u64,
+}
+
+/// Everything needed to resume a [`Machine`] later, created by [`Machine::snapshot`] and resumed
+/// with [`crate::Interpreter::restore`]. Serializable with the `serde` feature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot {
+    pub tape: Vec<u32>,
+    pub pointer: isize,
+    /// Index of the starting cell in `tape`, missing from snapshots taken before there could be
+    /// cells left of it
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub origin: usize,
+    pub pc: usize,
+    pub iterations: u64,
+    pub halted: bool,
+    /// Input queued with [`Machine::push_input`] that hasn't been read yet
+    pub input: Vec<u8>,
+    pub input_closed: bool,
+}
+
+/// A single run of a program that can be driven one instruction at a time
+///
+/// ```ignore
+/// let mut machine = interpreter.machine();
+/// machine.push_input(b"input".iter().copied());
+/// loop {
+///     match machine.step() {
+///         Event::Output(b) => print!("{}", b as char),
+///         Event::NeedsInput => machine.close_input(),
+///         Event::Halted | Event::Error(_) => break,
+///         Event::Executed => {}
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Machine {
+    bytecode: Arc<Bytecode>,
+    /// The settings the machine started with, for [`Machine::reset`]
+    config: Arc<Config>,
+    pub(crate) max_iterations: Option<NonZeroU64>,
+    iteration_cost: IterationCost,
+    /// Instructions left to run, `None` when unmetered
+    fuel: Option<u64>,
+    max_tape_size: Option<usize>,
+    circular_tape: bool,
+    negative_cells: NegativeCells,
+    bounds_check: BoundsCheck,
+    /// Index of the starting cell in `memory`
+    origin: usize,
+    mask: Wrapping<u32>,
+    eof_behavior: EofBehavior,
+    overflow: OverflowBehavior,
+    pub(crate) max_output: Option<usize>,
+    #[cfg(feature = "std")]
+    dump: Option<DumpTarget>,
+    extensions: Extensions,
+    memory: Vec<Wrapping<u32>>,
+    memory_pointer: isize,
+    pc: usize,
+    pub(crate) iterations: u64,
+    /// Bytes of output written so far
+    written: usize,
+    /// Bytes of input read so far
+    read: usize,
+    /// The furthest right of `origin` the pointer has been
+    peak_pointer: isize,
+    #[cfg(feature = "std")]
+    pub(crate) deadline: Option<Instant>,
+    #[cfg(feature = "std")]
+    pub(crate) control: Option<Arc<Control>>,
+    #[cfg(feature = "std")]
+    pub(crate) profiler: Option<Profiler>,
+    pub(crate) recording: Option<Recording>,
+    /// Checkpoints for stepping backwards, only kept when stepping
+    history: Option<History>,
+    halted: bool,
+
+    /// Where each pbrain procedure starts, by number
+    procedures: BTreeMap<Wrapping<u32>, usize>,
+    /// Where to return to from each procedure call, innermost last
+    calls: Vec<usize>,
+    /// Threads forked by Brainfork's `Y` that haven't been scheduled yet
+    forked: Vec<Machine>,
+
+    // Input queued through `push_input`, only used when stepping
+    input: VecDeque<u8>,
+    input_closed: bool,
+
+    // Debugging aids, only checked when stepping
+    breakpoints: BTreeSet<usize>,
+    watchpoints: BTreeSet<usize>,
+    /// The pc of the breakpoint the machine last stopped at, so the next step runs it
+    stopped_at: Option<usize>,
+
+    /// Spans of the loops the machine is inside, innermost last
+    #[cfg(feature = "trace")]
+    loops: Vec<tracing::Span>,
+}
+
+impl Machine {
+    /// A machine at the start of `bytecode`, reusing `tape`'s allocation for its memory.
+    /// Whatever `tape` holds is cleared.
+    pub(crate) fn new(
+        bytecode: Arc<Bytecode>,
+        config: &Arc<Config>,
+        tape: Vec<Wrapping<u32>>,
+    ) -> Self {
+        // A circular tape needs a cell to wrap around to
+        let len = config
+            .tape_size
+            .max(config.initial_memory.len())
+            .max(config.circular_tape as usize);
+        let origin = match config.negative_cells {
+            NegativeCells::Offset(cells) => cells,
+            NegativeCells::Error | NegativeCells::Growable(_) => 0,
+        };
+        let mut memory = tape;
+        memory.clear();
+        memory.resize(origin + len, Wrapping(0));
+        for (cell, value) in memory[origin..].iter_mut().zip(&config.initial_memory) {
+            *cell = Wrapping(*value as u32);
+        }
+
+        Self {
+            bytecode,
+            config: config.clone(),
+            max_iterations: config.max_iterations,
+            iteration_cost: config.iteration_cost,
+            fuel: config.fuel,
+            max_tape_size: config.max_tape_size,
+            circular_tape: config.circular_tape,
+            negative_cells: config.negative_cells,
+            bounds_check: config.bounds_check,
+            origin,
+            mask: config.cell_width.mask(),
+            eof_behavior: config.eof_behavior,
+            overflow: config.overflow,
+            max_output: config.max_output,
+            #[cfg(feature = "std")]
+            dump: config.dump.clone(),
+            extensions: config.extensions.clone(),
+            memory,
+            memory_pointer: origin as isize,
+            pc: 0,
+            iterations: 0,
+            written: 0,
+            read: 0,
+            peak_pointer: 0,
+            #[cfg(feature = "std")]
+            deadline: None,
+            #[cfg(feature = "std")]
+            control: None,
+            #[cfg(feature = "std")]
+            profiler: None,
+            recording: None,
+            history: None,
+            halted: false,
+            procedures: BTreeMap::new(),
+            calls: Vec::new(),
+            forked: Vec::new(),
+            input: VecDeque::new(),
+            input_closed: false,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+            stopped_at: None,
+            #[cfg(feature = "trace")]
+            loops: Vec::new(),
+        }
+    }
+
+    /// Queue input for the program to read
+    pub fn push_input<I>(&mut self, input: I)
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        let queued = self.input.len();
+        self.input.extend(input);
+        if let Some(history) = &mut self.history {
+            history.input.extend(self.input.range(queued..));
+        }
+    }
+
+    /// Mark the input as finished, once the queue is drained reads follow the EOF behavior
+    pub fn close_input(&mut self) {
+        self.input_closed = true;
+    }
+
+    /// Input queued with [`Machine::push_input`] that the program hasn't read yet
+    pub fn pending_input(&self) -> impl Iterator<Item = u8> + '_ {
+        self.input.iter().copied()
+    }
+
+    /// Execute a single instruction. Stepping follows only the original thread, threads forked by
+    /// Brainfork's `Y` are dropped.
+    pub fn step(&mut self) -> Event {
+        if self.halted || self.pc >= self.bytecode.len() {
+            return Event::Halted;
+        }
+        if self.fuel == Some(0) {
+            return Event::FuelExhausted;
+        }
+
+        if let Some(offset) = self.breakpoint() {
+            if self.stopped_at != Some(self.pc) {
+                self.stopped_at = Some(self.pc);
+                return Event::Breakpoint(offset);
+            }
+        }
+
+        let bytecode = self.bytecode.clone();
+        let op = &bytecode.ops()[self.pc];
+        if matches!(op, Op::Read) && self.input.is_empty() && !self.input_closed {
+            return Event::NeedsInput;
+        }
+        self.stopped_at = None;
+
+        let watched: Vec<_> = self
+            .watchpoints
+            .iter()
+            .map(|&index| (index, self.cell(index)))
+            .collect();
+
+        let mut io = QueueIo {
+            input: mem::take(&mut self.input),
+            output: None,
+        };
+        let result = self.execute_op(op, &mut io);
+        self.input = io.input;
+        self.forked.clear();
+
+        let event = match (result, io.output) {
+            (Err(err), _) => {
+                self.halt(&err);
+                Event::Error(err)
+            }
+            (Ok(()), Some(output)) => Event::Output(output),
+            (Ok(()), None) => watched
+                .into_iter()
+                .find(|&(index, old)| self.cell(index) != old)
+                .map_or(Event::Executed, |(index, old)| Event::Watchpoint {
+                    index,
+                    old,
+                    new: self.cell(index),
+                }),
+        };
+
+        let due = self.history.as_mut().is_some_and(History::step);
+        if due {
+            let snapshot = self.snapshot();
+            let unread = self.input.len();
+            if let Some(history) = &mut self.history {
+                history.checkpoint(snapshot, unread);
+            }
+        }
+
+        event
+    }
+
+    /// Put the machine back at the start of the program so it can run again on new input without
+    /// allocating another tape. The tape, pointer, counters, limits, queued input and pbrain
+    /// procedures all go back to how the interpreter set them up. Breakpoints, watchpoints, a
+    /// deadline and the handle of a spawned run are kept, and history starts over if it was kept.
+    pub fn reset(&mut self) {
+        let tape = mem::take(&mut self.memory);
+        let fresh = Machine::new(self.bytecode.clone(), &self.config, tape);
+        let old = mem::replace(self, fresh);
+
+        self.breakpoints = old.breakpoints;
+        self.watchpoints = old.watchpoints;
+        #[cfg(feature = "std")]
+        {
+            self.deadline = old.deadline;
+            self.control = old.control;
+        }
+        if old.history.is_some() {
+            self.keep_history();
+        }
+    }
+
+    /// Start keeping checkpoints so [`Machine::step_back`] can return to any point from here on.
+    /// Only [`Machine::step`] is tracked, and like snapshots pbrain procedure calls aren't
+    /// captured.
+    pub fn keep_history(&mut self) {
+        self.history = Some(History::new(self.snapshot()));
+    }
+
+    /// Undo up to `n` steps by going back to the nearest checkpoint and stepping forward again.
+    /// Returns how many steps were undone, fewer than `n` at the point
+    /// [`Machine::keep_history`] was called. Output isn't taken back and isn't repeated.
+    pub fn step_back(&mut self, n: u64) -> u64 {
+        match &self.history {
+            Some(history) => self.rewind(history.steps.saturating_sub(n)),
+            None => 0,
+        }
+    }
+
+    /// Go back to the last place stepping stopped at a breakpoint or watchpoint, or to where
+    /// history starts if there isn't one. Returns how many steps were undone.
+    pub fn reverse_continue(&mut self) -> u64 {
+        let history = match self.history.take() {
+            Some(history) => history,
+            None => return 0,
+        };
+
+        // Search between checkpoints from the latest back, most stretches never need running
+        let mut target = 0;
+        let mut end = history.steps;
+        for checkpoint in history.before(history.steps) {
+            let stops = self.replay(checkpoint, &history.input, end);
+            if let Some(&stop) = stops.iter().rev().find(|&&stop| stop < history.steps) {
+                target = stop;
+                break;
+            }
+            end = checkpoint.steps;
+        }
+
+        self.history = Some(history);
+        self.rewind(target)
+    }
+
+    /// Goes back to `steps` steps after history started, returning how many were undone
+    fn rewind(&mut self, steps: u64) -> u64 {
+        let mut history = match self.history.take() {
+            Some(history) => history,
+            None => return 0,
+        };
+
+        let undone = history.steps - steps;
+        let checkpoint = history.rewind(steps);
+        self.replay(&checkpoint, &history.input, steps);
+        // Already here, the next step shouldn't stop at a breakpoint again
+        self.stopped_at = Some(self.pc);
+
+        self.history = Some(history);
+        undone
+    }
+
+    /// Restores `checkpoint` and steps forward until `steps` steps after history started,
+    /// returning the step counts where breakpoints and watchpoints stopped along the way.
+    /// History must be taken out while replaying so the steps aren't counted twice. Fuel is set
+    /// aside too, the steps already paid for it.
+    fn replay(&mut self, checkpoint: &Checkpoint, input: &[u8], steps: u64) -> Vec<u64> {
+        let fuel = self.fuel.take();
+        let input_closed = self.input_closed;
+        self.restore(checkpoint.snapshot.clone());
+        self.input = input[checkpoint.read..].iter().copied().collect();
+        self.input_closed = input_closed;
+        self.stopped_at = None;
+
+        let mut stops = Vec::new();
+        let mut current = checkpoint.steps;
+        while current < steps {
+            match self.step() {
+                Event::Breakpoint(_) => stops.push(current),
+                Event::Watchpoint { .. } => {
+                    current += 1;
+                    stops.push(current);
+                }
+                // Stepping made it here before, this only happens if the input changed
+                Event::NeedsInput | Event::Halted | Event::FuelExhausted => break,
+                Event::Executed | Event::Output(_) | Event::Error(_) => current += 1,
+            }
+        }
+        self.fuel = fuel;
+        stops
+    }
+
+    /// Make [`Machine::step`] stop with [`Event::Breakpoint`] before running any instruction
+    /// compiled from source offset `offset`. Running a whole program ignores breakpoints.
+    pub fn add_breakpoint(&mut self, offset: usize) {
+        self.breakpoints.insert(offset);
+    }
+
+    /// Returns whether there was a breakpoint at `offset`
+    pub fn remove_breakpoint(&mut self, offset: usize) -> bool {
+        self.breakpoints.remove(&offset)
+    }
+
+    /// Source offsets with a breakpoint, in order
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Make [`Machine::step`] report [`Event::Watchpoint`] whenever the cell at `index` changes
+    pub fn add_watchpoint(&mut self, index: usize) {
+        self.watchpoints.insert(index);
+    }
+
+    /// Returns whether the cell at `index` was being watched
+    pub fn remove_watchpoint(&mut self, index: usize) -> bool {
+        self.watchpoints.remove(&index)
+    }
+
+    /// Indices of watched cells, in order
+    pub fn watchpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.watchpoints.iter().copied()
+    }
+
+    /// The breakpoint on the next instruction, if any
+    fn breakpoint(&self) -> Option<usize> {
+        if self.breakpoints.is_empty() {
+            return None;
+        }
+
+        let range = self.bytecode.source_range(self.pc)?;
+        self.breakpoints.range(range).next().copied()
+    }
+
+    /// A cell's value, cells past the end of a growable tape are zero
+    fn cell(&self, index: usize) -> Wrapping<u32> {
+        self.memory.get(index).copied().unwrap_or_default()
+    }
+
+    /// Execute up to `n` instructions, stopping early at the first event other than
+    /// [`Event::Executed`]
+    pub fn step_n(&mut self, n: usize) -> Event {
+        for _ in 0..n {
+            match self.step() {
+                Event::Executed => {}
+                event => return event,
+            }
+        }
+
+        Event::Executed
+    }
+
+    /// Give the machine `fuel` more instructions to run, metering it from now on if it wasn't
+    /// already, see [`InterpreterBuilder::fuel`](crate::InterpreterBuilder::fuel)
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(self.fuel.unwrap_or(0).saturating_add(fuel));
+    }
+
+    /// Instructions the machine can run before it needs more fuel, `None` when unmetered
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Index of the next instruction in [`Machine::bytecode`]
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn bytecode(&self) -> &Bytecode {
+        &self.bytecode
+    }
+
+    /// The tape, cells are truncated to the configured cell width
+    pub fn tape(&self) -> &[Wrapping<u32>] {
+        &self.memory
+    }
+
+    /// Index of the current cell in [`Machine::tape`]
+    pub fn pointer(&self) -> isize {
+        self.memory_pointer
+    }
+
+    /// Index of the cell the program started on in [`Machine::tape`], nonzero when there are
+    /// cells left of it, see [`NegativeCells`](crate::NegativeCells)
+    pub fn origin(&self) -> usize {
+        self.origin
+    }
+
+    /// Number of instructions executed so far
+    pub fn iterations(&self) -> u64 {
+        self.iterations
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted || self.pc >= self.bytecode.len()
+    }
+
+    /// Capture the machine's state so it can be resumed later. pbrain procedures aren't captured.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            tape: self.memory.iter().map(|cell| cell.0).collect(),
+            pointer: self.memory_pointer,
+            origin: self.origin,
+            pc: self.pc,
+            iterations: self.iterations,
+            halted: self.halted,
+            input: self.input.iter().copied().collect(),
+            input_closed: self.input_closed,
+        }
+    }
+
+    /// Overwrite this machine's state with `snapshot`. Returns `false`, leaving the machine
+    /// untouched, if the snapshot's program counter doesn't fit this machine's program.
+    pub(crate) fn restore(&mut self, snapshot: Snapshot) -> bool {
+        if snapshot.pc > self.bytecode.len() {
+            return false;
+        }
+
+        self.memory = snapshot
+            .tape
+            .into_iter()
+            .map(|cell| Wrapping(cell) & self.mask)
+            .collect();
+        self.memory_pointer = snapshot.pointer;
+        self.origin = snapshot.origin;
+        self.pc = snapshot.pc;
+        self.iterations = snapshot.iterations;
+        self.halted = snapshot.halted;
+        self.input = snapshot.input.into();
+        self.input_closed = snapshot.input_closed;
+        true
+    }
+
+    /// The machine's memory, for the next machine to reuse
+    #[cfg(feature = "std")]
+    pub(crate) fn into_tape(self) -> Vec<Wrapping<u32>> {
+        self.memory
+    }
+
+    /// Consumes the machine, keeping its tape, pointer and iteration count
+    pub fn into_state(self) -> MachineState {
+        MachineState {
+            pointer: self.pointer(),
+            origin: self.origin,
+            iterations: self.iterations,
+            tape: self.memory,
+        }
+    }
+
+    /// Executes the bytecode until the program counter runs off the end
+    pub(crate) fn execute<IO: Io>(&mut self, io: &mut IO) -> Result<(), RunTimeError> {
+        let bytecode = self.bytecode.clone();
+        let ops = bytecode.ops();
+
+        while let Some(op) = ops.get(self.pc) {
+            if let Err(err) = self.execute_op(op, io) {
+                self.halt(&err);
+                return Err(err);
+            }
+
+            if !self.forked.is_empty() {
+                return self.execute_threads(io);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs this machine and every thread forked from it one instruction at a time, taking turns
+    /// in the order they were forked so their output interleaves the same way on every run.
+    /// Threads share the iteration and output counts and the fuel, and the first error stops all of
+    /// them.
+    fn execute_threads<IO: Io>(&mut self, io: &mut IO) -> Result<(), RunTimeError> {
+        let bytecode = self.bytecode.clone();
+        let ops = bytecode.ops();
+        let mut threads = mem::take(&mut self.forked);
+
+        while !self.is_halted() || !threads.is_empty() {
+            if let Some(op) = ops.get(self.pc) {
+                if let Err(err) = self.execute_op(op, io) {
+                    self.halt(&err);
+                    return Err(err);
+                }
+                threads.append(&mut self.forked);
+            }
+
+            // Threads forked during this turn get their first turn in this same round
+            let mut i = 0;
+            while i < threads.len() {
+                let thread = &mut threads[i];
+                if let Some(op) = ops.get(thread.pc) {
+                    (thread.iterations, thread.written) = (self.iterations, self.written);
+                    (thread.read, thread.fuel) = (self.read, self.fuel);
+                    let result = thread.execute_op(op, io);
+                    (self.iterations, self.written) = (thread.iterations, thread.written);
+                    (self.read, self.fuel) = (thread.read, thread.fuel);
+                    self.peak_pointer = self.peak_pointer.max(thread.peak_pointer);
+
+                    if let Err(err) = result {
+                        self.halt(&err);
+                        return Err(err);
+                    }
+                    let mut forked = mem::take(&mut thread.forked);
+                    threads.append(&mut forked);
+                }
+                i += 1;
+            }
+
+            threads.retain(|thread| !thread.is_halted());
+        }
+
+        Ok(())
+    }
+
+    /// A new thread for Brainfork's `Y`, a copy of this machine without its debugging state
+    fn fork(&self) -> Machine {
+        Machine {
+            bytecode: self.bytecode.clone(),
+            config: self.config.clone(),
+            max_iterations: self.max_iterations,
+            iteration_cost: self.iteration_cost,
+            fuel: self.fuel,
+            max_tape_size: self.max_tape_size,
+            circular_tape: self.circular_tape,
+            negative_cells: self.negative_cells,
+            bounds_check: self.bounds_check,
+            origin: self.origin,
+            mask: self.mask,
+            eof_behavior: self.eof_behavior,
+            overflow: self.overflow,
+            max_output: self.max_output,
+            #[cfg(feature = "std")]
+            dump: self.dump.clone(),
+            extensions: self.extensions.clone(),
+            memory: self.memory.clone(),
+            memory_pointer: self.memory_pointer,
+            pc: self.pc,
+            iterations: self.iterations,
+            written: self.written,
+            read: self.read,
+            peak_pointer: self.peak_pointer,
+            #[cfg(feature = "std")]
+            deadline: self.deadline,
+            #[cfg(feature = "std")]
+            control: self.control.clone(),
+            #[cfg(feature = "std")]
+            profiler: None,
+            recording: None,
+            history: None,
+            halted: false,
+            procedures: self.procedures.clone(),
+            calls: self.calls.clone(),
+            forked: Vec::new(),
+            input: VecDeque::new(),
+            input_closed: false,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+            stopped_at: None,
+            #[cfg(feature = "trace")]
+            loops: Vec::new(),
+        }
+    }
+
+    /// What running `op`, the instruction at `pc`, adds to the iteration count
+    #[inline]
+    fn cost(&self, op: &Op) -> u64 {
+        if self.iteration_cost == IterationCost::Instruction {
+            return 1;
+        }
+
+        match (op, self.bytecode.position(self.pc)) {
+            (Op::JumpIfZero { .. } | Op::JumpIfNonZero { .. }, _) | (_, None) => 1,
+            // bfc_ir only extracts loops that count their cell down by one, the loop would have
+            // run its body and closing bracket once per unit in the cell after the opening test
+            (Op::MultiplyMove { .. }, Some(position)) => {
+                let trips = self.cell(self.memory_pointer as usize).0 as u64;
+                1 + trips * (position.end - position.start) as u64
+            }
+            (_, Some(position)) => (position.end - position.start + 1) as u64,
+        }
+    }
+
+    /// Executes `op`, which must be the instruction at `pc`, and moves `pc` along
+    #[inline]
+    fn execute_op<IO: Io>(&mut self, op: &Op, io: &mut IO) -> Result<(), RunTimeError> {
+        if let Op::Dump = op {
+            #[cfg(feature = "std")]
+            self.dump();
+            self.pc += 1;
+            return Ok(());
+        }
+
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel == 0 {
+                return Err(RunTimeError::FuelExhausted(self.context()));
+            }
+            *fuel -= 1;
+        }
+
+        #[cfg(feature = "std")]
+        let before = self.iterations;
+        self.iterations += self.cost(op);
+        if let Some(max_iterations) = self.max_iterations {
+            if self.iterations > max_iterations.get() {
+                return Err(RunTimeError::MaxIterationsExceeded(self.context()));
+            }
+        }
+
+        // Reading the clock and locking are expensive, only check the deadline and the spawn
+        // handle every 4096 iterations
+        #[cfg(feature = "std")]
+        if before >> 12 != self.iterations >> 12 {
+            if let Some(deadline) = self.deadline {
+                if Instant::now() >= deadline {
+                    return Err(RunTimeError::TimedOut);
+                }
+            }
+
+            if let Some(control) = &self.control {
+                control.checkpoint()?;
+            }
+        }
+
+        #[cfg(feature = "std")]
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(self.pc, op, self.memory_pointer, &self.memory);
+        }
+
+        #[cfg(feature = "trace")]
+        tracing::trace!(pc = self.pc, ?op, pointer = self.memory_pointer, "execute");
+
+        // Every cell is reached through `index`, so a pointer left off the tape by lenient bounds
+        // or an extension is an error rather than a panic
+        match op {
+            Op::Add { amount, offset } => {
+                let index = self.index(*offset)?;
+                self.memory[index] = self.add(self.memory[index], amount.0 as i32 as i64)?;
+            }
+            Op::Set { amount, offset } => {
+                // A set is an add to a cleared cell, so negative amounts overflow too
+                let index = self.index(*offset)?;
+                self.memory[index] = self.add(Wrapping(0), amount.0 as i32 as i64)?;
+            }
+            Op::Move { amount } => {
+                self.memory_pointer += amount;
+                self.wrap_pointer();
+
+                // Lenient bounds leave the pointer wherever it lands until a cell is used there
+                if self.bounds_check == BoundsCheck::Strict {
+                    self.index(0)?;
+                }
+            }
+            Op::Read => {
+                let pointer = self.index(0)?;
+                let input = io.read()?;
+
+                #[cfg(feature = "trace")]
+                tracing::debug!(?input, "read");
+
+                if let Some(recording) = &mut self.recording {
+                    recording.reads.push(InputRead {
+                        iteration: self.iterations,
+                        input,
+                    });
+                }
+
+                match input {
+                    Some(input) => {
+                        self.memory[pointer] = Wrapping(input as u32);
+                        self.read += 1;
+                    }
+                    None => match self.eof_behavior {
+                        EofBehavior::Zero => self.memory[pointer] = Wrapping(0),
+                        EofBehavior::MinusOne => self.memory[pointer] = self.mask,
+                        EofBehavior::Unchanged => {}
+                        EofBehavior::Error => {
+                            return Err(RunTimeError::InputExhausted(self.context()))
+                        }
+                    },
+                }
+            }
+            Op::Write => {
+                let output = self.current()?.0 as u8;
+                if matches!(self.max_output, Some(max) if self.written >= max) {
+                    return Err(RunTimeError::OutputLimitExceeded(self.context()));
+                }
+
+                #[cfg(feature = "trace")]
+                tracing::debug!(output, "write");
+
+                io.write(output)?;
+                self.written += 1;
+
+                if let Some(recording) = &mut self.recording {
+                    recording.output.push(output);
+                }
+            }
+            Op::JumpIfZero { target } => {
+                if self.current()? == Wrapping(0) {
+                    self.pc = *target;
+                    return Ok(());
+                }
+
+                #[cfg(feature = "std")]
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.enter_loop(self.pc);
+                }
+
+                #[cfg(feature = "trace")]
+                self.enter_loop();
+            }
+            Op::JumpIfNonZero { target } => {
+                // `target` is just past the loop's opening instruction
+                if self.current()? != Wrapping(0) {
+                    #[cfg(feature = "std")]
+                    if let Some(profiler) = &mut self.profiler {
+                        profiler.loop_iteration(*target - 1);
+                    }
+
+                    self.pc = *target;
+                    return Ok(());
+                }
+
+                #[cfg(feature = "std")]
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.exit_loop(*target - 1);
+                }
+
+                #[cfg(feature = "trace")]
+                self.exit_loop();
+            }
+            Op::MultiplyMove { changes } => {
+                let current = self.current()?;
+
+                if current != Wrapping(0) {
+                    for (offset, factor) in changes.iter() {
+                        let index = self.index(*offset)?;
+                        let delta = current.0 as i64 * factor.0 as i32 as i64;
+                        self.memory[index] = self.add(self.memory[index], delta)?;
+                    }
+
+                    // Growing the tape leftwards moves the current cell
+                    let pointer = self.index(0)?;
+                    self.memory[pointer] = Wrapping(0);
+                }
+            }
+            Op::Scan { stride } => {
+                #[cfg(feature = "std")]
+                let start = self.memory_pointer;
+                let found = self.scan(*stride)?;
+
+                #[cfg(feature = "std")]
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.record_scan(start, self.memory_pointer, *stride);
+                }
+
+                // Every cell the scan can reach on a circular tape is nonzero, it spins forever.
+                // Run it again so the spinning still counts towards the limits.
+                if !found {
+                    return Ok(());
+                }
+            }
+            Op::Procedure { end } => {
+                let number = self.current()?;
+                self.procedures.insert(number, self.pc + 1);
+                self.pc = *end;
+                return Ok(());
+            }
+            Op::Return => {
+                // Snapshots don't record calls, a restored machine halts if it has nowhere to go
+                self.pc = self.calls.pop().unwrap_or(self.bytecode.len());
+                return Ok(());
+            }
+            Op::Call => {
+                let number = self.current()?;
+                let start = match self.procedures.get(&number) {
+                    Some(start) => *start,
+                    None => return Err(RunTimeError::UndefinedProcedure(self.context())),
+                };
+
+                self.calls.push(self.pc + 1);
+                self.pc = start;
+                return Ok(());
+            }
+            Op::Extension { command } => {
+                if let Some(handler) = self.extensions.get(*command) {
+                    let mut context = ExtensionContext {
+                        command: *command,
+                        tape: &mut self.memory,
+                        pointer: &mut self.memory_pointer,
+                        mask: self.mask,
+                        io,
+                    };
+                    handler.call(&mut context)?;
+
+                    // The handler may have moved the pointer anywhere
+                    self.wrap_pointer();
+                    if self.bounds_check == BoundsCheck::Strict {
+                        self.index(0)?;
+                    }
+                }
+            }
+            Op::Fork => {
+                let mut child = self.fork();
+                child.pc += 1;
+                child.memory_pointer += 1;
+                child.wrap_pointer();
+                let index = child.index(0)?;
+                child.memory[index] = Wrapping(1);
+
+                let pointer = self.index(0)?;
+                self.memory[pointer] = Wrapping(0);
+                self.forked.push(child);
+            }
+            Op::Dump => unreachable!("dumps are handled before counting the iteration"),
+        }
+
+        self.peak_pointer = self
+            .peak_pointer
+            .max(self.memory_pointer - self.origin as isize);
+        self.pc += 1;
+        Ok(())
+    }
+
+    /// What the machine has done so far, for a run that took `elapsed`
+    #[cfg(feature = "std")]
+    pub(crate) fn stats(&self, elapsed: Duration) -> ExecutionStats {
+        let near = |used: u64, max: u64| used.saturating_mul(10) >= max.saturating_mul(9);
+        let cells = self.max_tape_size.unwrap_or(self.memory.len());
+        let furthest = self.origin as isize + self.peak_pointer + 1;
+
+        let mut limits_approached = Vec::new();
+        if matches!(self.max_iterations, Some(max) if near(self.iterations, max.get())) {
+            limits_approached.push(Limit::Iterations);
+        }
+        if matches!(self.max_output, Some(max) if near(self.written as u64, max as u64)) {
+            limits_approached.push(Limit::Output);
+        }
+        if !self.circular_tape && near(furthest as u64, cells as u64) {
+            limits_approached.push(Limit::Tape);
+        }
+
+        ExecutionStats {
+            iterations: self.iterations,
+            bytes_read: self.read,
+            bytes_written: self.written,
+            peak_pointer: self.peak_pointer,
+            elapsed,
+            limits_approached,
+        }
+    }
+
+    /// Moves the pointer by `stride` until it lands on a zero cell. Returns false if a circular
+    /// tape was searched all the way around without finding one.
+    fn scan(&mut self, stride: isize) -> Result<bool, RunTimeError> {
+        if self.circular_tape {
+            for _ in 0..self.memory.len() {
+                if self.current()?.0 == 0 {
+                    return Ok(true);
+                }
+                self.memory_pointer += stride;
+                self.wrap_pointer();
+            }
+            return Ok(false);
+        }
+
+        loop {
+            let index = self.index(0)?;
+            let step = stride.unsigned_abs();
+
+            // Search the tape a slice at a time rather than moving one cell per iteration
+            let (found, visited) = if stride > 0 {
+                let cells = &self.memory[index..];
+                let found = cells.iter().step_by(step).position(|cell| cell.0 == 0);
+                (found, cells.len().div_ceil(step))
+            } else {
+                let cells = &self.memory[..=index];
+                let found = cells
+                    .iter()
+                    .rev()
+                    .step_by(step)
+                    .position(|cell| cell.0 == 0);
+                (found, cells.len().div_ceil(step))
+            };
+
+            match found {
+                Some(moves) => {
+                    self.memory_pointer += moves as isize * stride;
+                    return Ok(true);
+                }
+                // Ran off the end, `index` either grows the tape or reports the error
+                None => self.memory_pointer += visited as isize * stride,
+            }
+        }
+    }
+
+    /// Stops the machine after `err`
+    #[cfg_attr(not(feature = "trace"), allow(unused_variables))]
+    fn halt(&mut self, err: &RunTimeError) {
+        self.halted = true;
+
+        #[cfg(feature = "trace")]
+        {
+            tracing::warn!(error = %err, pc = self.pc, pointer = self.memory_pointer, "runtime error");
+            while !self.loops.is_empty() {
+                self.exit_loop();
+            }
+        }
+    }
+
+    #[cfg(feature = "trace")]
+    fn enter_loop(&mut self) {
+        let position = self.bytecode.position(self.pc);
+        let span = tracing::debug_span!("loop", pc = self.pc, ?position);
+        crate::trace::enter(&span);
+        self.loops.push(span);
+    }
+
+    #[cfg(feature = "trace")]
+    fn exit_loop(&mut self) {
+        // Machines restored from a snapshot can leave loops they never entered
+        if let Some(span) = self.loops.pop() {
+            crate::trace::exit(&span);
+        }
+    }
+
+    /// Reports the pointer, the cells around it and the iteration count to the dump target
+    #[cfg(feature = "std")]
+    fn dump(&self) {
+        let target = match &self.dump {
+            Some(target) => target,
+            None => return,
+        };
+
+        let start = (self.memory_pointer - 8).max(0) as usize;
+        let end = ((self.memory_pointer + 9).max(0) as usize).min(self.memory.len());
+        let cells: Vec<String> = (start..end)
+            .map(|i| {
+                if i as isize == self.memory_pointer {
+                    format!("[{}]", self.memory[i])
+                } else {
+                    self.memory[i].to_string()
+                }
+            })
+            .collect();
+
+        let dump = format!(
+            "# pointer {}, {} iterations\n# {}: {}",
+            self.memory_pointer,
+            self.iterations,
+            start,
+            cells.join(" ")
+        );
+
+        match target {
+            DumpTarget::Stderr => eprintln!("{}", dump),
+            // Dumps are diagnostics, a dropped receiver shouldn't stop the program
+            DumpTarget::Channel(tx) => {
+                let _ = tx.send(dump);
+            }
+        }
+    }
+
+    /// `cell + delta` at the configured cell width and overflow behavior
+    fn add(&self, cell: Wrapping<u32>, delta: i64) -> Result<Wrapping<u32>, RunTimeError> {
+        self.overflow
+            .apply(cell, delta, self.mask)
+            .ok_or_else(|| RunTimeError::CellOverflow(self.context()))
+    }
+
+    /// Describes where the machine is, for error reporting
+    fn context(&self) -> ErrorContext {
+        ErrorContext {
+            position: self.bytecode.position(self.pc),
+            pointer: self.memory_pointer,
+            iterations: self.iterations,
+        }
+    }
+
+    /// Brings the pointer back onto a circular tape
+    fn wrap_pointer(&mut self) {
+        if self.circular_tape {
+            self.memory_pointer = self.memory_pointer.rem_euclid(self.memory.len() as isize);
+        }
+    }
+
+    /// The current cell, see [`Machine::index`]
+    fn current(&mut self) -> Result<Wrapping<u32>, RunTimeError> {
+        let index = self.index(0)?;
+        Ok(self.memory[index])
+    }
+
+    /// Resolves `pointer + offset` into an index into memory. Every access to the tape goes
+    /// through here, it fails with `OutOfBoundsLeft` or `OutOfBoundsRight` rather than letting
+    /// the access panic, growing the tape instead if it's allowed to.
+    fn index(&mut self, offset: isize) -> Result<usize, RunTimeError> {
+        if self.circular_tape {
+            let len = self.memory.len() as isize;
+            return Ok((self.memory_pointer + offset.rem_euclid(len)).rem_euclid(len) as usize);
+        }
+
+        let index = self
+            .memory_pointer
+            .checked_add(offset)
+            .ok_or_else(|| RunTimeError::OutOfBoundsRight(self.context()))?;
+
+        let index = match usize::try_from(index) {
+            Ok(index) => index,
+            Err(_) => self.grow_left(index)?,
+        };
+
+        // Check if the index is out of bounds
+        if index >= self.memory.len() {
+            self.grow(index)?;
+        }
+
+        Ok(index)
+    }
+
+    /// Grows the tape so that `index` is addressable, if the tape is allowed to grow
+    fn grow(&mut self, index: usize) -> Result<(), RunTimeError> {
+        match self.max_tape_size {
+            Some(max_tape_size) if index < self.origin + max_tape_size => {
+                let len = (self.memory.len() * 2)
+                    .max(index + 1)
+                    .min(self.origin + max_tape_size);
+                self.memory.resize(len, Wrapping(0));
+                Ok(())
+            }
+            _ => Err(RunTimeError::OutOfBoundsRight(self.context())),
+        }
+    }
+
+    /// Adds cells to the left of the tape so that the negative `index` is addressable, if the
+    /// tape is allowed to grow that way. Returns where `index` is once everything has shifted.
+    fn grow_left(&mut self, index: isize) -> Result<usize, RunTimeError> {
+        let needed = index.unsigned_abs();
+        let added = match self.negative_cells {
+            NegativeCells::Growable(max) if self.origin + needed <= max => {
+                (self.origin * 2).max(self.origin + needed).min(max) - self.origin
+            }
+            _ => return Err(RunTimeError::OutOfBoundsLeft(self.context())),
+        };
+
+        self.memory.splice(0..0, iter::repeat_n(Wrapping(0), added));
+        self.origin += added;
+        self.memory_pointer += added as isize;
+        self.watchpoints = self.watchpoints.iter().map(|index| index + added).collect();
+
+        Ok(added - needed)
+    }
+}
+
+/// Serves reads from the machine's input queue and captures a single output
+struct QueueIo {
+    input: VecDeque<u8>,
+    output: Option<u8>,
+}
+
+impl Io for QueueIo {
+    fn read(&mut self) -> Result<Option<u8>, RunTimeError> {
+        Ok(self.input.pop_front())
+    }
+
+    fn write(&mut self, output: u8) -> Result<(), RunTimeError> {
+        self.output = Some(output);
+        Ok(())
+    }
+}
@@ -1,135 +1,2087 @@
 use std::{
-    fs,
-    io::{self, BufRead, Write},
-    num::Wrapping,
+    fmt,
+    fs::{self, File},
+    io::{self, BufRead, BufWriter, IsTerminal, Read, Write},
+    mem,
+    num::{NonZeroU64, Wrapping},
+    path::{Path, PathBuf},
     process::exit,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
     thread,
+    time::{Duration, Instant},
 };
 
-use bfi::{Interpreter, OptimisationsFlags};
-use clap::Parser;
+use bfi::{
+    Alphabet, BoundsCheck, Bytecode, CellWidth, CodegenOptions, Diagnostic, Dialect, DumpTarget,
+    EofBehavior, Event, ExecutionStats, FormatOptions, Interpreter, InterpreterBuilder,
+    IterationCost, Machine, NegativeCells, Optimisations, OptimisationsFlags, OverflowBehavior,
+    Pass, Recording, ResourceLimits, RunTimeError, Severity, Snapshot, TestCase, Verdict,
+};
+use clap::{ArgAction, Args, Parser, Subcommand};
+
+#[cfg(feature = "dap")]
+mod dap;
+#[cfg(feature = "lsp")]
+mod lsp;
+#[cfg(any(feature = "dap", feature = "lsp"))]
+mod rpc;
+#[cfg(feature = "serve")]
+mod serve;
+#[cfg(feature = "tui")]
+mod watch;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
-struct Args {
+#[clap(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Running a program is the default, `bfi <program>` is short for `bfi run <program>`
+    #[clap(flatten)]
+    run: RunArgs,
+
+    /// Color errors: auto, when stderr is a terminal and NO_COLOR isn't set, always or never
+    #[clap(long, global = true, value_parser, default_value = "auto")]
+    color: ColorChoice,
+}
+
+/// When to color errors
+#[derive(Debug, Clone, Copy)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => {
+                io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!(
+                "unknown color choice `{}`, expected auto, always or never",
+                s
+            )),
+        }
+    }
+}
+
+/// Whether errors are colored, decided once from `--color`
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+#[derive(Args)]
+struct RunArgs {
+    /// A file to run, or the program itself. Prefer --file or --eval in scripts.
     #[clap(value_parser)]
     brainfuck: Option<String>,
 
-    #[clap(short, long, value_parser, default_value = "true")]
+    /// Run the program in this file
+    #[clap(short, long, value_parser, conflicts_with_all = &["brainfuck", "eval"])]
+    file: Option<PathBuf>,
+
+    /// Run this program text
+    #[clap(short, long, value_parser, conflicts_with = "brainfuck")]
+    eval: Option<String>,
+
+    /// Input for the program instead of reading stdin. Takes priority over input after a `!`.
+    #[clap(short, long, value_parser)]
+    input: Option<String>,
+
+    /// Run anything after the first `!` outside a loop as the program's input, a convention
+    /// many collections of programs follow. Always on for programs read from stdin
+    #[clap(long, value_parser, default_value = "false")]
+    split_input: bool,
+
+    /// Read the program's input from this file instead of stdin
+    #[clap(long, value_parser, conflicts_with = "input")]
+    input_file: Option<PathBuf>,
+
+    #[clap(flatten)]
+    language: Language,
+
+    /// Run the program exactly as written, skipping the optimizer
+    #[clap(long = "no-optimize", action = ArgAction::SetFalse)]
     optimize: bool,
 
+    /// The optimizations to run instead of all or none of them: all, none or a comma separated
+    /// list like combine-increments,multiply-move,clear-loops
+    #[clap(long, value_parser, conflicts_with = "optimize")]
+    opt: Option<Optimisations>,
+
+    /// Write the program's output to this file instead of stdout
+    #[clap(short, long, value_parser)]
+    output: Option<PathBuf>,
+
     #[clap(short, long, value_parser, default_value = "false")]
     raw: bool,
 
-    #[clap(long, value_parser, default_value = "18446744073709551615")]
-    max_iterations: u64,
+    /// Stop after this many instructions, there's no limit when it's left out
+    #[clap(long, value_parser)]
+    max_iterations: Option<NonZeroU64>,
+
+    /// What counts as an iteration: instruction, each optimized instruction, or source, each
+    /// source command it was compiled from, so limits mean the same with and without --no-optimize
+    #[clap(long, value_parser, default_value = "instruction")]
+    iteration_cost: IterationCost,
+
+    #[clap(long, value_parser, default_value = "30000")]
+    tape_size: usize,
+
+    /// Bits per cell: 8, 16 or 32. Wider cells disable the optimizer
+    #[clap(long, value_parser, default_value = "8")]
+    cell_width: CellWidth,
+
+    /// What `,` does at the end of input: zero, minus-one, unchanged or error
+    #[clap(long, value_parser, default_value = "error")]
+    eof: EofBehavior,
+
+    /// What arithmetic past a cell's maximum or below zero does: wrap, saturate or trap.
+    /// Saturating and trapping skip bfc_ir's optimizer, which folds with wrapping arithmetic
+    #[clap(long, value_parser, default_value = "wrap")]
+    overflow: OverflowBehavior,
+
+    /// Grow the tape on demand, up to this many cells
+    #[clap(long, value_parser)]
+    grow_tape: Option<usize>,
+
+    /// Stop with an error once the program writes more than this many bytes
+    #[clap(long, value_parser)]
+    max_output: Option<usize>,
+
+    /// When the pointer leaving the tape is an error: strict, as soon as it moves off, or
+    /// lenient, once a cell is used there
+    #[clap(long, value_parser, default_value = "strict")]
+    bounds: BoundsCheck,
+
+    /// Reserve this many cells left of the starting cell
+    #[clap(long, value_parser, conflicts_with = "grow_left")]
+    left_cells: Option<usize>,
+
+    /// Add cells left of the starting cell on demand, up to this many
+    #[clap(long, value_parser)]
+    grow_left: Option<usize>,
+
+    /// Wrap the pointer around the ends of the tape instead of stopping with an error
+    #[clap(
+        long,
+        value_parser,
+        default_value = "false",
+        conflicts_with = "grow_tape"
+    )]
+    circular_tape: bool,
+
+    /// Treat `#` as an instruction that prints the pointer, nearby cells and iteration count to
+    /// stderr
+    #[clap(long, value_parser, default_value = "false")]
+    dump: bool,
+
+    /// Print the instructions the optimizer produced to stderr before running
+    #[clap(long, value_parser, default_value = "false")]
+    dump_ir: bool,
+
+    /// Print how many instructions ran, the bytes read and written, how far right the pointer
+    /// went, the time taken and any limits the run came close to to stderr afterwards
+    #[clap(long, value_parser, default_value = "false")]
+    stats: bool,
+
+    /// Print how long parsing, optimizing and running the program each took to stderr afterwards
+    #[clap(long, value_parser, default_value = "false")]
+    time: bool,
+
+    /// Print --time's durations as a JSON object in nanoseconds
+    #[clap(long, value_parser, default_value = "false", requires = "time")]
+    json: bool,
+
+    /// How to print the optimizer's warnings to stderr: human, with the source underlined, or
+    /// json, one object per line
+    #[clap(long, value_parser, default_value = "human")]
+    warnings_format: WarningsFormat,
+
+    /// Read all of stdin up front, then print the hottest loops to stderr after the run
+    #[clap(long, value_parser, default_value = "false")]
+    profile: bool,
+
+    /// Like --profile but also print how often each cell was read and written
+    #[clap(long, value_parser, default_value = "false")]
+    heatmap: bool,
+
+    /// Save every byte the program reads, when it reads it, and its output to this file so
+    /// `bfi replay` can run it again exactly
+    #[clap(long, value_parser, conflicts_with = "raw")]
+    record: Option<PathBuf>,
+
+    /// Set by `bfi replay`, the recording to run against
+    #[clap(skip)]
+    replay: Option<PathBuf>,
+}
+
+/// How to read a program written in something other than plain Brainfuck
+#[derive(Args)]
+struct Language {
+    /// The language the program is written in: brainfuck, ook, pbrain or brainfork. Defaults to
+    /// ook for `.ook` files and brainfuck otherwise. Only `bfi run` supports pbrain and brainfork
+    #[clap(long, value_parser)]
+    dialect: Option<Dialect>,
+
+    /// Spell the commands with other tokens, either alphuck, reversefuck or eight whitespace
+    /// separated tokens standing for `> < + - . , [ ]`
+    #[clap(long, value_parser, conflicts_with = "dialect")]
+    alphabet: Option<Alphabet>,
+
+    /// Expand `%define` macros, `*n` repetitions and `%include`s before loading the program
+    #[clap(long, value_parser)]
+    preprocess: bool,
+}
+
+impl Language {
+    /// Reads, expands and translates the program in `path`
+    fn load(&self, path: &Path) -> String {
+        self.translate(&self.expand(read_file(path), Some(path)), Some(path))
+    }
+
+    /// Expands macros when `--preprocess` is given, exiting if that fails. Includes are relative
+    /// to `path`.
+    fn expand(&self, source: String, path: Option<&Path>) -> String {
+        if !self.preprocess {
+            return source;
+        }
+
+        bfi::preprocess(&source, path).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            exit(1)
+        })
+    }
+
+    /// Translates a program into Brainfuck, exiting if it's malformed. Without a dialect or
+    /// alphabet the dialect is picked from `path`'s extension.
+    fn translate(&self, source: &str, path: Option<&Path>) -> String {
+        if let Some(alphabet) = &self.alphabet {
+            return alphabet.translate(source);
+        }
+
+        let dialect = self
+            .dialect
+            .unwrap_or_else(|| path.map_or(Dialect::Brainfuck, Dialect::from_path));
+        match dialect.translate(source) {
+            Ok(program) => program.into_owned(),
+            Err(err) => {
+                report_parse_error(source, &err);
+                exit(1)
+            }
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a program
+    Run(RunArgs),
+    /// Run a program on the input saved by `bfi run --record`, checking it reads at the same
+    /// points and writes the same output. Pass the same options the recorded run used.
+    Replay {
+        /// The file `--record` wrote
+        #[clap(value_parser)]
+        recording: PathBuf,
+
+        #[clap(flatten)]
+        run: RunArgs,
+    },
+    /// Check a program's output against `<program>.out`, feeding it `<program>.in` if it exists
+    Test {
+        #[clap(value_parser)]
+        program: PathBuf,
+
+        /// The expected output, defaults to `<program>.out`
+        #[clap(long, value_parser)]
+        expect: Option<PathBuf>,
+
+        /// Input for the program, defaults to `<program>.in` when that file exists
+        #[clap(long, value_parser)]
+        input: Option<PathBuf>,
+
+        /// Stop after this many instructions, there's no limit when it's left out
+        #[clap(long, value_parser)]
+        max_iterations: Option<NonZeroU64>,
+
+        /// How to print the result: human, junit or tap
+        #[clap(long, value_parser, default_value = "human")]
+        report_format: ReportFormat,
+
+        #[clap(flatten)]
+        language: Language,
+    },
+    /// Grade a program like an online judge, printing a JSON verdict: AC, WA, TLE, MLE, OLE, RE
+    /// or CE
+    Judge {
+        #[clap(value_parser)]
+        program: PathBuf,
+
+        /// Input for the program, it gets none otherwise
+        #[clap(long, value_parser)]
+        input: Option<PathBuf>,
+
+        /// The exact output expected
+        #[clap(long, value_parser)]
+        expect: PathBuf,
+
+        /// Wall-clock time allowed, in milliseconds
+        #[clap(long, value_parser, default_value = "1000")]
+        time_limit_ms: u64,
+
+        /// Tape cells allowed
+        #[clap(long, value_parser, default_value = "30000")]
+        memory_limit: usize,
+
+        /// Bytes of output allowed
+        #[clap(long, value_parser, default_value = "1048576")]
+        output_limit: usize,
+
+        #[clap(flatten)]
+        language: Language,
+    },
+    /// Step through a program interactively
+    Debug {
+        /// Path to the program
+        #[clap(value_parser)]
+        file: String,
+
+        #[clap(long, value_parser, default_value = "30000")]
+        tape_size: usize,
+    },
+    /// Run a program in a terminal UI that shows the tape, the current instruction and I/O live
+    #[cfg(feature = "tui")]
+    Watch {
+        #[clap(value_parser)]
+        program: PathBuf,
+
+        /// Input for the program, otherwise it's typed in when the program reads
+        #[clap(short, long, value_parser)]
+        input: Option<String>,
+
+        /// Instructions per second to start at, `+` and `-` change it while running
+        #[clap(long, value_parser, default_value = "20")]
+        speed: u32,
+
+        #[clap(long, value_parser, default_value = "30000")]
+        tape_size: usize,
+
+        #[clap(flatten)]
+        language: Language,
+    },
+    /// Serve the Debug Adapter Protocol on stdin and stdout, for debugging in editors
+    #[cfg(feature = "dap")]
+    Dap,
+    /// Serve the Language Server Protocol on stdin and stdout, for diagnostics, hovers and
+    /// formatting in editors
+    #[cfg(feature = "lsp")]
+    Lsp,
+    /// Serve an HTTP API for running programs, `POST /run` with JSON or form data
+    #[cfg(feature = "serve")]
+    Serve {
+        #[clap(long, value_parser, default_value = "127.0.0.1:8080")]
+        address: String,
+
+        /// Requests run at once
+        #[clap(long, value_parser, default_value = "4")]
+        threads: usize,
+
+        /// The most iterations a request can ask for
+        #[clap(long, value_parser, default_value = "100000000")]
+        max_iterations: NonZeroU64,
+
+        /// The most wall-clock time a request can ask for, in milliseconds
+        #[clap(long, value_parser, default_value = "5000")]
+        timeout_ms: u64,
+
+        /// The largest tape a request can ask for
+        #[clap(long, value_parser, default_value = "30000")]
+        tape_size: usize,
+
+        /// The most output a request can ask for, in bytes
+        #[clap(long, value_parser, default_value = "1048576")]
+        max_output: usize,
+
+        /// Also accept interactive sessions over WebSockets on this address
+        #[clap(long, value_parser)]
+        sessions: Option<String>,
+    },
+    /// Run snippets one line at a time against a persistent tape
+    Repl {
+        #[clap(long, value_parser, default_value = "30000")]
+        tape_size: usize,
+    },
+    /// Check that a program behaves the same with and without optimizations
+    Verify {
+        #[clap(value_parser)]
+        program: PathBuf,
+
+        /// Input for the program
+        #[clap(long, value_parser)]
+        input: Option<PathBuf>,
+
+        #[clap(long, value_parser, default_value = "100000000")]
+        max_iterations: NonZeroU64,
+
+        #[clap(flatten)]
+        language: Language,
+    },
+    /// Print the bytecode a program compiles to, or translate it into another language
+    Compile {
+        #[clap(value_parser)]
+        program: PathBuf,
+
+        /// Compile the program exactly as written, skipping the optimizer
+        #[clap(long = "no-optimize", action = ArgAction::SetFalse)]
+        optimize: bool,
+
+        /// What to print: bytecode, rust, c or wasm
+        #[clap(long, value_parser, default_value = "bytecode")]
+        target: Target,
+
+        /// Write to this file instead of stdout
+        #[clap(short, long, value_parser)]
+        output: Option<PathBuf>,
+
+        #[clap(long, value_parser, default_value = "30000")]
+        tape_size: usize,
+
+        /// Bits per cell: 8, 16 or 32. Wider cells disable the optimizer
+        #[clap(long, value_parser, default_value = "8")]
+        cell_width: CellWidth,
+
+        /// What `,` does at the end of input: zero, minus-one, unchanged or error
+        #[clap(long, value_parser, default_value = "error")]
+        eof: EofBehavior,
+
+        /// Stop after this many instructions, there's no limit when it's left out
+        #[clap(long, value_parser)]
+        max_iterations: Option<NonZeroU64>,
+
+        #[clap(flatten)]
+        language: Language,
+    },
+    /// Write out a program as optimized Brainfuck, for running on other interpreters
+    Optimize {
+        #[clap(value_parser)]
+        program: PathBuf,
+
+        /// Write to this file instead of stdout
+        #[clap(short, long, value_parser)]
+        output: Option<PathBuf>,
+
+        #[clap(flatten)]
+        language: Language,
+    },
+    /// Write out a Brainfuck program that prints the given text
+    Generate {
+        #[clap(long, value_parser)]
+        text: String,
+
+        /// Treat the text as whitespace separated byte values
+        #[clap(long, value_parser)]
+        raw: bool,
+
+        /// Write to this file instead of stdout
+        #[clap(short, long, value_parser)]
+        output: Option<PathBuf>,
+    },
+    /// Print a program with its macros, repetitions and includes expanded
+    Expand {
+        #[clap(value_parser)]
+        program: PathBuf,
+
+        /// Write to this file instead of stdout
+        #[clap(short, long, value_parser)]
+        output: Option<PathBuf>,
+    },
+    /// Write out a GraphViz graph of the program's loops and optimized instructions
+    Viz {
+        #[clap(value_parser)]
+        program: PathBuf,
+
+        /// Write to this file instead of stdout
+        #[clap(short, long, value_parser)]
+        output: Option<PathBuf>,
+
+        /// Run the program first and label the graph with how often each part ran
+        #[clap(long, value_parser)]
+        profile: bool,
+
+        /// Input for the profiled run, it gets none otherwise
+        #[clap(long, value_parser)]
+        input: Option<PathBuf>,
+
+        /// Stop after this many instructions, there's no limit when it's left out
+        #[clap(long, value_parser)]
+        max_iterations: Option<NonZeroU64>,
+
+        #[clap(flatten)]
+        language: Language,
+    },
+    /// Report mistakes that are certain to happen without running the program
+    Lint {
+        #[clap(value_parser)]
+        program: PathBuf,
+    },
+    /// Write out a program with its comments stripped and cancelling commands like `+-` removed
+    Minify {
+        #[clap(value_parser)]
+        program: PathBuf,
+
+        /// Only strip comments, leave the commands as they are
+        #[clap(long, value_parser)]
+        strip_only: bool,
+
+        /// Write to this file instead of stdout
+        #[clap(short, long, value_parser)]
+        output: Option<PathBuf>,
+    },
+    /// Print a program reformatted with one loop per line, indented by nesting depth
+    Fmt {
+        #[clap(value_parser)]
+        program: PathBuf,
+
+        /// Wrap runs of commands to fit in this many columns
+        #[clap(long, value_parser, default_value = "80")]
+        width: usize,
+
+        /// Keep comments instead of dropping everything but commands
+        #[clap(long, value_parser)]
+        comments: bool,
+
+        /// Don't print anything, exit with status 1 if the program isn't already formatted
+        #[clap(long, value_parser)]
+        check: bool,
+    },
 }
 
 fn main() {
-    let args = Args::parse();
+    let cli = Cli::parse();
+    COLOR.store(cli.color.enabled(), Ordering::Relaxed);
+
+    match cli.command {
+        None => run(cli.run),
+        Some(Command::Run(args)) => run(args),
+        Some(Command::Replay {
+            recording,
+            run: args,
+        }) => run(RunArgs {
+            replay: Some(recording),
+            ..args
+        }),
+        Some(Command::Test {
+            program,
+            expect,
+            input,
+            max_iterations,
+            report_format,
+            language,
+        }) => test(
+            &program,
+            expect,
+            input,
+            max_iterations,
+            report_format,
+            &language,
+        ),
+        Some(Command::Judge {
+            program,
+            input,
+            expect,
+            time_limit_ms,
+            memory_limit,
+            output_limit,
+            language,
+        }) => {
+            let limits = ResourceLimits {
+                time: std::time::Duration::from_millis(time_limit_ms),
+                memory: memory_limit,
+                output: output_limit,
+            };
+            judge(&program, input, &expect, &limits, &language)
+        }
+        Some(Command::Debug { file, tape_size }) => debug(&file, tape_size),
+        #[cfg(feature = "tui")]
+        Some(Command::Watch {
+            program,
+            input,
+            speed,
+            tape_size,
+            language,
+        }) => watch(&program, input, speed, tape_size, &language),
+        #[cfg(feature = "dap")]
+        Some(Command::Dap) => {
+            if let Err(err) = dap::serve() {
+                eprintln!("debug adapter error: {}", err);
+                exit(1)
+            }
+        }
+        #[cfg(feature = "lsp")]
+        Some(Command::Lsp) => {
+            if let Err(err) = lsp::serve() {
+                eprintln!("language server error: {}", err);
+                exit(1)
+            }
+        }
+        #[cfg(feature = "serve")]
+        Some(Command::Serve {
+            address,
+            threads,
+            max_iterations,
+            timeout_ms,
+            tape_size,
+            max_output,
+            sessions,
+        }) => {
+            let limits = serve::Limits {
+                max_iterations,
+                timeout: std::time::Duration::from_millis(timeout_ms),
+                tape_size,
+                max_output,
+            };
+            if let Some(sessions) = sessions {
+                thread::spawn(move || {
+                    if let Err(err) = serve::serve_sessions(&sessions, limits) {
+                        eprintln!("failed to serve sessions on {}: {}", sessions, err);
+                        exit(1)
+                    }
+                });
+            }
+            if let Err(err) = serve::serve(&address, threads, limits) {
+                eprintln!("failed to serve on {}: {}", address, err);
+                exit(1)
+            }
+        }
+        Some(Command::Repl { tape_size }) => repl(tape_size),
+        Some(Command::Verify {
+            program,
+            input,
+            max_iterations,
+            language,
+        }) => verify(&program, input, max_iterations, &language),
+        Some(Command::Compile {
+            program,
+            optimize,
+            target,
+            output,
+            tape_size,
+            cell_width,
+            eof,
+            max_iterations,
+            language,
+        }) => {
+            let options = CodegenOptions {
+                tape_size,
+                cell_width,
+                eof_behavior: eof,
+                max_iterations,
+            };
+            compile(
+                &program,
+                optimize,
+                target,
+                output.as_deref(),
+                &options,
+                &language,
+            )
+        }
+        Some(Command::Optimize {
+            program,
+            output,
+            language,
+        }) => optimize(&program, output.as_deref(), &language),
+        Some(Command::Generate { text, raw, output }) => {
+            let text = if raw {
+                parse_raw(text.as_bytes())
+            } else {
+                text.into_bytes()
+            };
+            generate(&text, output.as_deref())
+        }
+        Some(Command::Expand { program, output }) => expand(&program, output.as_deref()),
+        Some(Command::Viz {
+            program,
+            output,
+            profile,
+            input,
+            max_iterations,
+            language,
+        }) => viz(
+            &program,
+            output.as_deref(),
+            profile.then_some(input),
+            max_iterations,
+            &language,
+        ),
+        Some(Command::Lint { program }) => lint(&program),
+        Some(Command::Minify {
+            program,
+            strip_only,
+            output,
+        }) => minify(&program, strip_only, output.as_deref()),
+        Some(Command::Fmt {
+            program,
+            width,
+            comments,
+            check,
+        }) => fmt(&program, &FormatOptions { width, comments }, check),
+    }
+}
 
-    // Attempt to parse the file as a path
-    let program = match &args.brainfuck {
-        Some(input) => match fs::read_to_string(input) {
-            Ok(program) => program,
-            Err(_) => input.to_string(),
+fn run(args: RunArgs) {
+    let (mut program, mut input) = match (&args.file, &args.eval, &args.brainfuck) {
+        (Some(file), _, _) => (read_file(file), None),
+        (_, Some(program), _) => (program.clone(), None),
+        // Attempt to parse the file as a path
+        (_, _, Some(input)) => match fs::read_to_string(input) {
+            Ok(program) => (program, None),
+            Err(err) if looks_like_path(input) => {
+                eprintln!("failed to read {}: {}", input, err);
+                exit(1)
+            }
+            Err(_) => (input.to_string(), None),
         },
+        (None, None, None) => {
+            let mut program = String::new();
+            io::stdin().read_to_string(&mut program).unwrap();
+
+            // Stdin is used up so the program can't read from it
+            (program, Some(vec![]))
+        }
+    };
+    // Anything after a `!` is input for the program rather than code, always for programs read
+    // from stdin. Ook! spells its commands with `!` so it never carries input this way.
+    if (args.split_input || input.is_some()) && args.language.dialect != Some(Dialect::Ook) {
+        if let (code, Some(inline)) = bfi::split_input(&program) {
+            input = Some(inline.as_bytes().to_vec());
+            program = code.to_string();
+        }
+    }
+    let input = match (&args.input, &args.input_file) {
+        (Some(input), _) => Some(input.as_bytes().to_vec()),
+        (_, Some(path)) => Some(fs::read(path).unwrap_or_else(|err| {
+            eprintln!("failed to read {}: {}", path.display(), err);
+            exit(1)
+        })),
+        (None, None) => input,
+    };
+    let input = input.map(|input| if args.raw { parse_raw(&input) } else { input });
+
+    let path = match (&args.file, &args.brainfuck) {
+        (Some(file), _) => Some(file.as_path()),
+        (_, Some(brainfuck)) => Some(Path::new(brainfuck)),
+        (None, None) => None,
+    };
+    let optimisations = match (&args.opt, args.optimize) {
+        (Some(optimisations), _) => optimisations.clone(),
+        (None, true) => Optimisations::all(),
+        (None, false) => Optimisations::none(),
+    };
+    // Only parsing and the optimizers are timed, not reporting what they found
+    let mut timings = Timings::default();
+    let start = Instant::now();
+    // Errors point into the source as written, everything else works on the translation. Macros
+    // are expanded first so positions point into the expansion.
+    let source = args.language.expand(program, path);
+    let (program, mut bytecode) =
+        if let Some(dialect @ (Dialect::Pbrain | Dialect::Brainfork)) = args.language.dialect {
+            // Procedures and threads have no Brainfuck equivalent, and bfc_ir's optimizer would
+            // move code across them
+            match dialect.compile(&source) {
+                Ok(bytecode) => {
+                    timings.parse = start.elapsed();
+                    (source.clone(), bytecode)
+                }
+                Err(err) => {
+                    report_parse_error(&source, &err);
+                    exit(1)
+                }
+            }
+        } else {
+            let program = args.language.translate(&source, path);
+            let mut instructions = parse_or_exit(&program, &source);
+            timings.parse = start.elapsed();
+
+            if !optimisations.flags.is_empty()
+                && args.cell_width == CellWidth::U8
+                && args.overflow == OverflowBehavior::Wrap
+            {
+                let start = Instant::now();
+                let warnings;
+                (instructions, warnings) = bfc_ir::optimize(instructions, optimisations.flags);
+                timings.optimize = start.elapsed();
+
+                for warning in warnings.into_iter().map(Diagnostic::from) {
+                    match args.warnings_format {
+                        WarningsFormat::Human => eprintln!("{}\n", warning.render(&program)),
+                        WarningsFormat::Json => eprintln!("{}", warning.to_json(&program)),
+                    }
+                }
+            }
+            if args.dump_ir {
+                eprint!("{}", bfi::format_ir(&instructions, &source));
+            }
+
+            let start = Instant::now();
+            let bytecode = Bytecode::compile(&instructions);
+            timings.parse += start.elapsed();
+            (program, bytecode)
+        };
+
+    let mut builder = Interpreter::builder()
+        .iteration_limit(args.max_iterations)
+        .iteration_cost(args.iteration_cost)
+        .tape_size(args.tape_size)
+        .cell_width(args.cell_width)
+        .eof_behavior(args.eof)
+        .overflow(args.overflow)
+        .circular_tape(args.circular_tape)
+        .bounds_check(args.bounds);
+    if let Some(max_tape_size) = args.grow_tape {
+        builder = builder.growable_tape(max_tape_size);
+    }
+    if let Some(max_output) = args.max_output {
+        builder = builder.max_output(max_output);
+    }
+    match (args.left_cells, args.grow_left) {
+        (Some(cells), _) => builder = builder.negative_cells(NegativeCells::Offset(cells)),
+        (_, Some(max)) => builder = builder.negative_cells(NegativeCells::Growable(max)),
+        (None, None) => {}
+    }
+
+    let start = Instant::now();
+    bytecode = bytecode.optimize_for(&optimisations.passes, args.overflow);
+    timings.optimize += start.elapsed();
+    if args.dump {
+        bytecode = bytecode.with_dumps(&program);
+        builder = builder.dump_state(DumpTarget::Stderr);
+    }
+
+    // Slow consumers like a pager hold the machine back instead of buffering everything
+    let interpreter = builder.output_capacity(1 << 16).build_bytecode(bytecode);
+    let mut writer = open_output(args.output.as_deref());
+
+    if args.profile || args.heatmap {
+        profile(
+            &interpreter,
+            &program,
+            input,
+            writer,
+            args.raw,
+            args.heatmap,
+        );
+        return;
+    }
+    if let Some(recording) = &args.replay {
+        replay(&interpreter, recording, writer);
+        return;
+    }
+    if let Some(recording) = &args.record {
+        record(&interpreter, &source, input, writer, recording);
+        return;
+    }
+
+    let started = Instant::now();
+    let (tx, rx, handle) = interpreter.spawn();
+    let raw = args.raw;
+
+    match input {
+        // Everything is known up front, dropping `tx` afterwards signals the end of input
+        Some(input) => {
+            for b in input {
+                if tx.send(Wrapping(b)).is_err() {
+                    break;
+                }
+            }
+        }
+        // On one thread read from stdin
         None => {
-            let mut buf = String::new();
-            io::stdin().read_line(&mut buf).unwrap();
-            buf
+            thread::spawn(move || {
+                // lock stdin
+                let mut stdin = io::stdin().lock();
+
+                loop {
+                    let mut buffer = String::new();
+                    // Dropping `tx` at end of input lets the interpreter apply its EOF behavior
+                    if stdin.read_line(&mut buffer).unwrap() == 0 {
+                        break;
+                    }
+
+                    let bytes = if raw {
+                        parse_raw(buffer.as_bytes())
+                    } else {
+                        buffer.into_bytes()
+                    };
+                    // The machine stops listening once it finishes, there's no point reading more
+                    if bytes.into_iter().any(|b| tx.send(Wrapping(b)).is_err()) {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    // On the another write the output, returning whether the program failed
+    let output = thread::spawn(move || {
+        while let Ok(b) = rx.recv() {
+            match b {
+                Ok(b) if raw => write!(writer, "{} ", b).unwrap(),
+                Ok(b) => writer.write_all(&[b.0]).unwrap(),
+                Err(err) => {
+                    writer.flush().unwrap();
+                    report_runtime_error(&source, &err);
+                    return true;
+                }
+            }
         }
+        writer.flush().unwrap();
+        false
+    });
+
+    // Join the the VM, its error (if any) was already sent to the output thread
+    let (_, stats) = handle.join_with_stats();
+    let failed = output.join().unwrap();
+    timings.execute = started.elapsed();
+    if let (true, Some(stats)) = (args.stats, stats) {
+        print_stats(&stats);
+    }
+    if args.time && args.json {
+        eprintln!("{}", timings.to_json());
+    } else if args.time {
+        eprintln!("{}", timings);
+    }
+    if failed {
+        exit(1);
+    }
+}
+
+/// How long each stage of `bfi run` took, for --time
+#[derive(Debug, Default)]
+struct Timings {
+    /// Reading the source into unoptimized bytecode
+    parse: Duration,
+    /// `bfc_ir`'s optimizer and bfi's passes
+    optimize: Duration,
+    execute: Duration,
+}
+
+impl Timings {
+    /// The durations in nanoseconds as a single line of JSON, for --time --json
+    fn to_json(&self) -> String {
+        format!(
+            r#"{{"parse_ns":{},"optimize_ns":{},"execute_ns":{}}}"#,
+            self.parse.as_nanos(),
+            self.optimize.as_nanos(),
+            self.execute.as_nanos()
+        )
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "parse:    {:?}", self.parse)?;
+        writeln!(f, "optimize: {:?}", self.optimize)?;
+        write!(f, "execute:  {:?}", self.execute)
+    }
+}
+
+fn print_stats(stats: &ExecutionStats) {
+    let limits: Vec<String> = stats
+        .limits_approached
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+
+    eprintln!("iterations:        {}", stats.iterations);
+    eprintln!("bytes read:        {}", stats.bytes_read);
+    eprintln!("bytes written:     {}", stats.bytes_written);
+    eprintln!("peak pointer:      {}", stats.peak_pointer);
+    eprintln!("time:              {:?}", stats.elapsed);
+    if limits.is_empty() {
+        eprintln!("limits approached: none");
+    } else {
+        eprintln!("limits approached: {}", limits.join(", "));
+    }
+}
+
+/// How `bfi test` prints its result
+#[derive(Debug, Clone, Copy)]
+enum ReportFormat {
+    Human,
+    Junit,
+    Tap,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(ReportFormat::Human),
+            "junit" => Ok(ReportFormat::Junit),
+            "tap" => Ok(ReportFormat::Tap),
+            _ => Err(format!(
+                "unknown report format `{}`, expected human, junit or tap",
+                s
+            )),
+        }
+    }
+}
+
+/// How `bfi run` prints the optimizer's warnings
+#[derive(Debug, Clone, Copy)]
+enum WarningsFormat {
+    Human,
+    Json,
+}
+
+impl FromStr for WarningsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(WarningsFormat::Human),
+            "json" => Ok(WarningsFormat::Json),
+            _ => Err(format!(
+                "unknown warnings format `{}`, expected human or json",
+                s
+            )),
+        }
+    }
+}
+
+/// Runs `program` against the `.in` and `.out` files next to it, exiting non-zero on failure
+fn test(
+    program: &Path,
+    expect: Option<PathBuf>,
+    input: Option<PathBuf>,
+    max_iterations: Option<NonZeroU64>,
+    format: ReportFormat,
+    language: &Language,
+) {
+    let source = language.expand(read_file(program), Some(program));
+    let translated = language.translate(&source, Some(program));
+    let with_extension = |extension: &str| {
+        let mut path = program.as_os_str().to_owned();
+        path.push(extension);
+        PathBuf::from(path)
+    };
+
+    let expect = expect.unwrap_or_else(|| with_extension(".out"));
+    let expected = fs::read(&expect).unwrap_or_else(|err| {
+        eprintln!("failed to read {}: {}", expect.display(), err);
+        exit(1)
+    });
+    // An explicit input file has to exist, the conventional one is optional
+    let input = match input {
+        Some(input) => fs::read(&input).unwrap_or_else(|err| {
+            eprintln!("failed to read {}: {}", input.display(), err);
+            exit(1)
+        }),
+        None => fs::read(with_extension(".in")).unwrap_or_default(),
+    };
+
+    let name = program.display().to_string();
+    let case = TestCase::new(name, input, expected).iteration_limit(max_iterations);
+    let report = bfi::run_tests(&translated, &[case]);
+
+    match format {
+        ReportFormat::Human => print!("{}", report.human(&source)),
+        ReportFormat::Junit => print!("{}", report.junit("bfi")),
+        ReportFormat::Tap => print!("{}", report.tap()),
+    }
+    if !report.passed() {
+        exit(1)
+    }
+}
+
+/// Prints the judgement as JSON, exiting with status 1 unless it was accepted
+fn judge(
+    program: &Path,
+    input: Option<PathBuf>,
+    expect: &Path,
+    limits: &ResourceLimits,
+    language: &Language,
+) {
+    let program = language.load(program);
+    let read = |path: &Path| {
+        fs::read(path).unwrap_or_else(|err| {
+            eprintln!("failed to read {}: {}", path.display(), err);
+            exit(1)
+        })
+    };
+    let input = input.map(|input| read(&input)).unwrap_or_default();
+    let expected = read(expect);
+
+    let judgement = bfi::judge(&program, &input, &expected, limits);
+    println!("{}", judgement.to_json());
+    if judgement.verdict != Verdict::Accepted {
+        exit(1)
+    }
+}
+
+/// Prints a line diff between the expected and actual output, `-` for expected lines that are
+/// missing and `+` for unexpected ones
+fn verify(program: &Path, input: Option<PathBuf>, max_iterations: NonZeroU64, language: &Language) {
+    let source = language.load(program);
+    let input = match input {
+        Some(input) => fs::read(&input).unwrap_or_else(|err| {
+            eprintln!("failed to read {}: {}", input.display(), err);
+            exit(1)
+        }),
+        None => vec![],
     };
 
-    let mut instructions = match bfc_ir::parse(&program) {
-        Ok(instructions) => instructions,
+    match bfi::verify(&source, &input, Some(max_iterations)) {
+        Ok(None) => println!("{}: ok", program.display()),
+        Ok(Some(divergence)) => {
+            println!(
+                "{}: optimized and unoptimized runs differ",
+                program.display()
+            );
+            println!("{}", divergence);
+            let (optimized, unoptimized) = match (&divergence.optimized, &divergence.unoptimized) {
+                (Ok(a) | Err((a, _)), Ok(b) | Err((b, _))) => (a, b),
+            };
+            print!("{}", bfi::hexdiff(unoptimized, optimized));
+            exit(1)
+        }
         Err(err) => {
-            eprintln!("{:?}", err);
+            report_parse_error(&source, &err);
             exit(1)
         }
+    }
+}
+
+/// What `bfi compile` prints
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Bytecode,
+    Rust,
+    C,
+    Wasm,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bytecode" => Ok(Target::Bytecode),
+            "rust" => Ok(Target::Rust),
+            "c" => Ok(Target::C),
+            "wasm" => Ok(Target::Wasm),
+            _ => Err(format!(
+                "unknown target `{}`, expected bytecode, rust, c or wasm",
+                s
+            )),
+        }
+    }
+}
+
+/// Prints the bytecode for `program` one instruction per line, or the program translated to
+/// `target`
+fn compile(
+    program: &Path,
+    optimize: bool,
+    target: Target,
+    output: Option<&Path>,
+    options: &CodegenOptions,
+    language: &Language,
+) {
+    let source = language.load(program);
+    let mut instructions = parse_or_exit(&source, &source);
+
+    let bytecode = if optimize {
+        if options.cell_width == CellWidth::U8 {
+            (instructions, _) = bfc_ir::optimize(instructions, OptimisationsFlags::all());
+        }
+        Bytecode::compile(&instructions).optimize(Pass::ALL)
+    } else {
+        Bytecode::compile(&instructions)
     };
 
-    if args.optimize {
-        let flags = OptimisationsFlags::all();
-        let warnings;
-        (instructions, warnings) = bfc_ir::optimize(instructions, flags);
+    let compiled = match target {
+        Target::Bytecode => Ok(bytecode
+            .ops()
+            .iter()
+            .enumerate()
+            .map(|(pc, op)| format!("{:>6}  {:?}\n", pc, op))
+            .collect::<String>()
+            .into_bytes()),
+        Target::Rust => bfi::to_rust(&bytecode, options).map(String::into_bytes),
+        Target::C => bfi::to_c(&bytecode, options).map(String::into_bytes),
+        Target::Wasm => bfi::to_wasm(&bytecode, options),
+    };
+    let compiled = compiled.unwrap_or_else(|err| {
+        eprintln!("{}: {}", program.display(), err);
+        exit(1)
+    });
+
+    let mut writer = open_output(output);
+    if let Err(err) = writer.write_all(&compiled).and_then(|_| writer.flush()) {
+        eprintln!("failed to write output: {}", err);
+        exit(1)
+    }
+}
+
+fn optimize(program: &Path, output: Option<&Path>, language: &Language) {
+    let source = language.load(program);
+    let instructions = parse_or_exit(&source, &source);
+
+    let (instructions, _) = bfc_ir::optimize(instructions, OptimisationsFlags::all());
+    let bytecode = Bytecode::compile(&instructions).optimize(Pass::ALL);
+
+    let mut writer = open_output(output);
+    let optimized = bfi::to_brainfuck(&bytecode);
+    if let Err(err) = writer
+        .write_all(optimized.as_bytes())
+        .and_then(|_| writer.flush())
+    {
+        eprintln!("failed to write output: {}", err);
+        exit(1)
+    }
+}
+
+fn fmt(program: &Path, options: &FormatOptions, check: bool) {
+    let source = read_file(program);
+    let formatted = bfi::format_with(&source, options);
+
+    if !check {
+        print!("{}", formatted);
+    } else if formatted != source {
+        eprintln!("{} isn't formatted", program.display());
+        exit(1)
+    }
+}
+
+/// Draws `program`, profiling a run with `profile`'s input first if it's given
+fn viz(
+    program: &Path,
+    output: Option<&Path>,
+    profile: Option<Option<PathBuf>>,
+    max_iterations: Option<NonZeroU64>,
+    language: &Language,
+) {
+    let source = language.load(program);
+    let instructions = parse_or_exit(&source, &source);
+
+    let (instructions, _) = bfc_ir::optimize(instructions, OptimisationsFlags::all());
+    let bytecode = Bytecode::compile(&instructions).optimize(Pass::ALL);
+
+    let profile = profile.map(|input| {
+        let input = input.map_or_else(Vec::new, |path| {
+            fs::read(&path).unwrap_or_else(|err| {
+                eprintln!("failed to read {}: {}", path.display(), err);
+                exit(1)
+            })
+        });
+
+        let interpreter = Interpreter::from_bytecode(bytecode.clone(), max_iterations);
+        let (outcome, profile) = interpreter.profile(input);
+        // The graph is still useful for a run that failed part way
+        if let Some(err) = outcome.error {
+            report_runtime_error(&source, &err);
+        }
+        profile
+    });
+
+    let mut writer = open_output(output);
+    let dot = bfi::to_dot(&bytecode, &source, profile.as_ref());
+    if let Err(err) = writer
+        .write_all(dot.as_bytes())
+        .and_then(|_| writer.flush())
+    {
+        eprintln!("failed to write output: {}", err);
+        exit(1)
+    }
+}
+
+fn lint(program: &Path) {
+    let source = read_file(program);
+    let lints = bfi::lint(&source);
+    for lint in &lints {
+        println!("{}\n", lint.render(&source));
+    }
+
+    if lints.iter().any(|lint| lint.severity() == Severity::Error) {
+        exit(1)
+    }
+}
 
-        if !warnings.is_empty() {
-            for err in warnings {
-                eprintln!("{:?}", err);
+fn minify(program: &Path, strip_only: bool, output: Option<&Path>) {
+    let source = read_file(program);
+    let minified = if strip_only {
+        bfi::strip_comments(&source)
+    } else {
+        bfi::minify(&source)
+    };
+
+    let mut writer = open_output(output);
+    if let Err(err) = writer
+        .write_all(minified.as_bytes())
+        .and_then(|_| writer.flush())
+    {
+        eprintln!("failed to write output: {}", err);
+        exit(1)
+    }
+}
+
+fn generate(text: &[u8], output: Option<&Path>) {
+    let mut writer = open_output(output);
+    if let Err(err) = writer
+        .write_all(bfi::print_string(text).as_bytes())
+        .and_then(|_| writer.flush())
+    {
+        eprintln!("failed to write output: {}", err);
+        exit(1)
+    }
+}
+
+fn expand(program: &Path, output: Option<&Path>) {
+    let expanded = bfi::preprocess_file(program).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        exit(1)
+    });
+
+    let mut writer = open_output(output);
+    if let Err(err) = writer
+        .write_all(expanded.as_bytes())
+        .and_then(|_| writer.flush())
+    {
+        eprintln!("failed to write output: {}", err);
+        exit(1)
+    }
+}
+
+/// Where program output goes, stdout unless `path` is given
+fn open_output(path: Option<&Path>) -> Box<dyn Write + Send> {
+    match path {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(err) => {
+                eprintln!("failed to create {}: {}", path.display(), err);
+                exit(1)
             }
+        },
+        None => Box::new(io::stdout()),
+    }
+}
+
+fn read_file(path: &Path) -> String {
+    fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("failed to read {}: {}", path.display(), err);
+        exit(1)
+    })
+}
+
+/// Whether a positional argument that couldn't be read was meant to be a file rather than code,
+/// so a typo in a filename isn't silently run as a program
+fn looks_like_path(argument: &str) -> bool {
+    // `.` is an instruction but also starts every extension
+    let has_code = argument.contains(['+', '-', '<', '>', '[', ']', ',']);
+    let has_path_parts =
+        argument.contains(['/', '\\']) || Path::new(argument).extension().is_some();
+
+    !has_code && has_path_parts
+}
+
+/// Parses whitespace separated numbers, ignoring anything that isn't a byte
+fn parse_raw(input: &[u8]) -> Vec<u8> {
+    String::from_utf8_lossy(input)
+        .split_whitespace()
+        .filter_map(|b| b.parse().ok())
+        .collect()
+}
+
+/// Runs the whole program, on stdin unless `input` was already given, and reports where the time
+/// went
+fn profile(
+    interpreter: &Interpreter,
+    program: &str,
+    input: Option<Vec<u8>>,
+    mut writer: Box<dyn Write + Send>,
+    raw: bool,
+    heatmap: bool,
+) {
+    let input = input.unwrap_or_else(|| {
+        let mut input = Vec::new();
+        io::stdin().read_to_end(&mut input).unwrap();
+        if raw {
+            parse_raw(&input)
+        } else {
+            input
         }
+    });
+
+    let (outcome, profile) = interpreter.profile(input);
+    let (output, err) = (outcome.output, outcome.error);
+
+    if raw {
+        output
+            .iter()
+            .for_each(|b| write!(writer, "{} ", b).unwrap());
+    } else {
+        writer.write_all(&output).unwrap();
     }
+    writer.flush().unwrap();
 
-    let interpreter = Interpreter::new(instructions, args.max_iterations);
-    let (tx, rx, handle) = interpreter.spawn();
+    eprint!("\n{}", profile.report(program, 10));
+    if heatmap {
+        eprint!("cell usage:\n{}", profile.heatmap().render(40));
+    }
+    if let Some(err) = err {
+        report_runtime_error(program, &err);
+        exit(1);
+    }
+}
 
-    if args.raw {
-        // On one thread read from stdin
-        thread::spawn(move || {
-            // lock stdin
-            let mut stdin = io::stdin().lock();
+/// Runs the program on this thread, streaming stdin when there's no other input, then saves what
+/// it read and wrote to `path`. The recording is saved even if the program fails.
+fn record(
+    interpreter: &Interpreter,
+    program: &str,
+    input: Option<Vec<u8>>,
+    mut writer: Box<dyn Write + Send>,
+    path: &Path,
+) {
+    let (result, recording) = match input {
+        Some(input) => interpreter.record(&mut input.as_slice(), &mut writer),
+        None => interpreter.record(&mut io::stdin().lock(), &mut writer),
+    };
 
-            loop {
-                let mut buffer = String::new();
-                stdin.read_line(&mut buffer).unwrap();
-                buffer
-                    .split_whitespace()
-                    .map(|s| s.parse())
-                    .filter(|b| b.is_ok())
-                    .for_each(|b| tx.send(Wrapping(b.unwrap())).unwrap())
+    if let Err(err) = fs::write(path, recording.to_string()) {
+        eprintln!("failed to write {}: {}", path.display(), err);
+        exit(1)
+    }
+    if let Err(err) = result {
+        report_runtime_error(program, &err);
+        exit(1);
+    }
+}
+
+fn replay(interpreter: &Interpreter, path: &Path, mut writer: Box<dyn Write + Send>) {
+    let recording: Recording = read_file(path).parse().unwrap_or_else(|err| {
+        eprintln!("failed to read {}: {}", path.display(), err);
+        exit(1)
+    });
+
+    match interpreter.replay(&recording) {
+        Ok(output) => {
+            if let Err(err) = writer.write_all(&output).and_then(|_| writer.flush()) {
+                eprintln!("failed to write output: {}", err);
+                exit(1)
             }
-        });
+        }
+        Err(err) => {
+            eprintln!("replay diverged from {}: {}", path.display(), err);
+            exit(1)
+        }
+    }
+}
+
+/// Parses `program`, or exits pointing out the error in `source`. They're the same unless
+/// `program` was translated from `source`, translations keep offsets.
+fn parse_or_exit(program: &str, source: &str) -> Vec<bfc_ir::AstNode> {
+    bfc_ir::parse(program).unwrap_or_else(|err| {
+        report_parse_error(source, &err);
+        exit(1)
+    })
+}
+
+/// Prints `err` rustc-style, underlining where it happened in `source`
+fn report_parse_error(source: &str, err: &bfc_ir::ParseError) {
+    let diagnostic = Diagnostic::parse_error(source, err);
+    if COLOR.load(Ordering::Relaxed) {
+        eprintln!("{}", diagnostic.render_color(source));
+    } else {
+        eprintln!("{}", diagnostic.render(source));
+    }
+}
+
+/// Prints `err`, pointing at the instruction that failed when it's known
+fn report_runtime_error(program: &str, err: &RunTimeError) {
+    let message = format!("runtime error: {}", err);
+    match err.context().and_then(|context| context.position) {
+        Some(position) => eprintln!("{}", bfi::annotate(program, position.start, &message)),
+        None => eprintln!("{}", message),
+    }
+}
+
+const DEBUG_HELP: &str = "\
+commands:
+  s, step [n]         execute the next n instructions (default 1)
+  c, continue         run until a breakpoint, input is needed or the program stops
+  rs, reverse-step [n]
+                      undo the last n instructions (default 1)
+  rc, reverse-continue
+                      go back to the last breakpoint or watchpoint that stopped the program
+  b, break <offset>   stop before the instruction at this source offset
+  d, delete <offset>  remove a breakpoint
+  w, watch <cell>     stop whenever the cell at this index changes
+  unwatch <cell>      remove a watchpoint
+  t, tape [radius]    show the cells around the pointer (default 8)
+  i, info             show the program counter, pointer, iterations, breakpoints and
+                      watchpoints
+  input <text>        queue a line of input for the program
+  eof                 close the program's input
+  q, quit             exit the debugger";
+
+/// Steps through the program in the terminal UI, starting at `speed` instructions a second
+#[cfg(feature = "tui")]
+fn watch(program: &Path, input: Option<String>, speed: u32, tape_size: usize, language: &Language) {
+    let source = language.load(program);
+
+    // Unoptimized so every step is a single command
+    let instructions = parse_or_exit(&source, &source);
 
-        // On the another write to stdout
-        thread::spawn(move || {
-            let mut stdout = io::stdout().lock();
-            while let Ok(b) = rx.recv() {
-                match b {
-                    Ok(b) => {
-                        write!(stdout, "{} ", b).unwrap();
+    let interpreter = Interpreter::builder()
+        .tape_size(tape_size)
+        .build(instructions);
+    let mut machine = interpreter.machine();
+    if let Some(input) = input {
+        machine.push_input(input.into_bytes());
+        machine.close_input();
+    }
+
+    if let Err(err) = watch::watch(machine, &source, speed) {
+        eprintln!("terminal error: {}", err);
+        exit(1)
+    }
+}
+
+/// A small REPL around [`Machine::step`]. The program isn't optimized so every instruction can be
+/// stepped through and breakpoints line up with the source.
+fn debug(file: &str, tape_size: usize) {
+    let program = read_file(Path::new(file));
+
+    let instructions = parse_or_exit(&program, &program);
+
+    let interpreter = Interpreter::builder()
+        .tape_size(tape_size)
+        .build(instructions);
+    let mut machine = interpreter.machine();
+    machine.keep_history();
+
+    println!("debugging {}, type `help` for commands", file);
+    let mut stdin = io::stdin().lock();
+    loop {
+        print!("(bfi) ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if stdin.read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+
+        let command = match DebugCommand::parse(&line) {
+            Some(command) => command,
+            None => continue,
+        };
+
+        match command {
+            DebugCommand::Step(n) => {
+                for _ in 0..n {
+                    if !report(machine.step()) {
+                        break;
                     }
-                    Err(err) => {
-                        eprintln!("Runtime Error {:?}", err);
-                        exit(1);
+                }
+                print_location(&machine, &program);
+            }
+            DebugCommand::Continue => {
+                while report(machine.step()) {}
+                print_location(&machine, &program);
+            }
+            DebugCommand::ReverseStep(n) => {
+                let undone = machine.step_back(n);
+                report_rewind(undone);
+                print_location(&machine, &program);
+            }
+            DebugCommand::ReverseContinue => {
+                let undone = machine.reverse_continue();
+                report_rewind(undone);
+                print_location(&machine, &program);
+            }
+            DebugCommand::Break(offset) => {
+                machine.add_breakpoint(offset);
+                println!("breakpoint set at offset {}", offset);
+            }
+            DebugCommand::Delete(offset) if machine.remove_breakpoint(offset) => {
+                println!("breakpoint at offset {} removed", offset)
+            }
+            DebugCommand::Delete(offset) => println!("no breakpoint at offset {}", offset),
+            DebugCommand::Watch(index) => {
+                machine.add_watchpoint(index);
+                println!("watching cell {}", index);
+            }
+            DebugCommand::Unwatch(index) if machine.remove_watchpoint(index) => {
+                println!("stopped watching cell {}", index)
+            }
+            DebugCommand::Unwatch(index) => println!("cell {} isn't watched", index),
+            DebugCommand::Tape(radius) => print_tape(&machine, radius),
+            DebugCommand::Info => {
+                print_location(&machine, &program);
+                println!(
+                    "breakpoints: {:?}",
+                    machine.breakpoints().collect::<Vec<_>>()
+                );
+                println!(
+                    "watchpoints: {:?}",
+                    machine.watchpoints().collect::<Vec<_>>()
+                );
+            }
+            DebugCommand::Input(text) => machine.push_input(text.bytes().chain(Some(b'\n'))),
+            DebugCommand::Eof => machine.close_input(),
+            DebugCommand::Quit => break,
+            DebugCommand::Help => println!("{}", DEBUG_HELP),
+            DebugCommand::Usage(usage) => println!("usage: {}", usage),
+            DebugCommand::Unknown(command) => {
+                println!("unknown command `{}`, type `help` for commands", command)
+            }
+        }
+    }
+}
+
+/// A line typed at `bfi debug`, see [`DEBUG_HELP`]
+#[derive(Debug, PartialEq, Eq)]
+enum DebugCommand {
+    Step(usize),
+    Continue,
+    ReverseStep(u64),
+    ReverseContinue,
+    Break(usize),
+    Delete(usize),
+    Watch(usize),
+    Unwatch(usize),
+    Tape(usize),
+    Info,
+    /// A line of input, without its newline
+    Input(String),
+    Eof,
+    Quit,
+    Help,
+    /// A command missing its argument, with how it should have been used
+    Usage(&'static str),
+    Unknown(String),
+}
+
+impl DebugCommand {
+    /// Parses a line, `None` if it's blank. Arguments that aren't numbers count as missing.
+    fn parse(line: &str) -> Option<Self> {
+        let mut words = line.split_whitespace();
+        let command = words.next()?;
+        let argument = words
+            .next()
+            .and_then(|argument| argument.parse::<usize>().ok());
+        let required = |command: fn(usize) -> Self, usage| match argument {
+            Some(argument) => command(argument),
+            None => DebugCommand::Usage(usage),
+        };
+
+        Some(match command {
+            "s" | "step" => DebugCommand::Step(argument.unwrap_or(1)),
+            "c" | "continue" => DebugCommand::Continue,
+            "rs" | "reverse-step" => DebugCommand::ReverseStep(argument.unwrap_or(1) as u64),
+            "rc" | "reverse-continue" => DebugCommand::ReverseContinue,
+            "b" | "break" => required(DebugCommand::Break, "break <offset>"),
+            "d" | "delete" => required(DebugCommand::Delete, "delete <offset>"),
+            "w" | "watch" => required(DebugCommand::Watch, "watch <cell>"),
+            "unwatch" => required(DebugCommand::Unwatch, "unwatch <cell>"),
+            "t" | "tape" => DebugCommand::Tape(argument.unwrap_or(8)),
+            "i" | "info" => DebugCommand::Info,
+            "input" => DebugCommand::Input(line.trim_start()["input".len()..].trim().to_string()),
+            "eof" => DebugCommand::Eof,
+            "q" | "quit" => DebugCommand::Quit,
+            "h" | "help" => DebugCommand::Help,
+            _ => DebugCommand::Unknown(command.to_string()),
+        })
+    }
+}
+
+const REPL_HELP: &str = "\
+enter brainfuck to run it against the current tape, or one of:
+  :mem [radius]  show the cells around the pointer (default 8)
+  :reset         start over with an empty tape
+  :load <file>   run a file against the current tape
+  :help          show this message
+  :quit          exit the repl";
+
+/// Runs each line against the tape left behind by the previous one
+fn repl(tape_size: usize) {
+    let mut repl = Repl::new(Interpreter::builder().tape_size(tape_size));
+
+    println!("bfi repl, type `:help` for commands");
+    let mut stdin = io::stdin().lock();
+    loop {
+        print!("bf> ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if stdin.read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+
+        let snippet = match ReplCommand::parse(&line) {
+            None => continue,
+            Some(ReplCommand::Mem(radius)) => {
+                print_tape(&repl.machine, radius);
+                continue;
+            }
+            Some(ReplCommand::Reset) => {
+                repl.reset();
+                continue;
+            }
+            Some(ReplCommand::Load(path)) => match fs::read_to_string(&path) {
+                Ok(program) => program,
+                Err(err) => {
+                    println!("failed to read {}: {}", path, err);
+                    continue;
+                }
+            },
+            Some(ReplCommand::Help) => {
+                println!("{}", REPL_HELP);
+                continue;
+            }
+            Some(ReplCommand::Quit) => break,
+            Some(ReplCommand::Unknown(command)) => {
+                println!("unknown command `{}`, type `:help` for commands", command);
+                continue;
+            }
+            Some(ReplCommand::Run(snippet)) => snippet,
+        };
+
+        let previous = match repl.start(&snippet) {
+            Ok(previous) => previous,
+            Err(err) => {
+                println!(
+                    "parse error at offset {}: {}",
+                    err.position.start, err.message
+                );
+                continue;
+            }
+        };
+
+        let mut wrote = false;
+        loop {
+            match repl.machine.step() {
+                Event::Executed => {}
+                Event::Output(output) => {
+                    print!("{}", output as char);
+                    io::stdout().flush().unwrap();
+                    wrote = true;
+                }
+                Event::NeedsInput => {
+                    print!("input> ");
+                    io::stdout().flush().unwrap();
+
+                    let mut input = String::new();
+                    if stdin.read_line(&mut input).unwrap() == 0 {
+                        repl.machine.close_input();
                     }
+                    repl.machine.push_input(input.into_bytes());
+                }
+                Event::Halted | Event::FuelExhausted => break,
+                Event::Error(err) => {
+                    // Don't leave the pointer somewhere later snippets can't use
+                    println!("runtime error {}, the tape is unchanged", err);
+                    repl.machine = previous;
+                    break;
                 }
+                Event::Breakpoint(_) | Event::Watchpoint { .. } => {}
             }
-            stdout.flush().unwrap();
-        });
+        }
+
+        if wrote {
+            println!();
+        }
+    }
+}
+
+/// A line typed at `bfi repl`, see [`REPL_HELP`]
+#[derive(Debug, PartialEq, Eq)]
+enum ReplCommand {
+    Mem(usize),
+    Reset,
+    /// Run the file at this path
+    Load(String),
+    Help,
+    Quit,
+    Unknown(String),
+    /// Brainfuck to run
+    Run(String),
+}
+
+impl ReplCommand {
+    /// Parses a line, `None` if it's blank
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+
+        Some(match command {
+            "" => return None,
+            ":mem" => ReplCommand::Mem(argument.parse().unwrap_or(8)),
+            ":reset" => ReplCommand::Reset,
+            ":load" => ReplCommand::Load(argument.to_string()),
+            ":help" => ReplCommand::Help,
+            ":quit" => ReplCommand::Quit,
+            _ if command.starts_with(':') => ReplCommand::Unknown(command.to_string()),
+            _ => ReplCommand::Run(line.to_string()),
+        })
+    }
+}
+
+/// The machine each REPL snippet runs on, carrying the tape and pointer from one to the next
+struct Repl {
+    builder: InterpreterBuilder,
+    machine: Machine,
+}
+
+impl Repl {
+    fn new(builder: InterpreterBuilder) -> Self {
+        let machine = builder.clone().build(vec![]).machine();
+        Repl { builder, machine }
+    }
+
+    /// Starts over with an empty tape
+    fn reset(&mut self) {
+        self.machine = self.builder.clone().build(vec![]).machine();
+    }
+
+    /// Swaps in a machine that runs `snippet` from the tape and pointer the last one left,
+    /// returning the last one to go back to if the snippet fails
+    fn start(&mut self, snippet: &str) -> Result<Machine, bfc_ir::ParseError> {
+        let interpreter = self.builder.clone().build(bfc_ir::parse(snippet)?);
+        let snapshot = Snapshot {
+            pc: 0,
+            halted: false,
+            input: vec![],
+            input_closed: false,
+            ..self.machine.snapshot()
+        };
+
+        Ok(mem::replace(
+            &mut self.machine,
+            interpreter
+                .restore(snapshot)
+                .expect("every program has a start"),
+        ))
+    }
+}
+
+/// Prints what a step did. Returns whether the machine can keep running.
+fn report(event: Event) -> bool {
+    match event {
+        Event::Executed => true,
+        Event::Output(output) => {
+            print!("{}", output as char);
+            io::stdout().flush().unwrap();
+            true
+        }
+        Event::NeedsInput => {
+            println!("\nprogram is waiting for input, use `input <text>` or `eof`");
+            false
+        }
+        Event::Halted => {
+            println!("\nprogram finished");
+            false
+        }
+        Event::FuelExhausted => {
+            println!("\nprogram is out of fuel");
+            false
+        }
+        Event::Error(err) => {
+            println!("\nruntime error {}", err);
+            false
+        }
+        Event::Breakpoint(offset) => {
+            println!("\nhit breakpoint at offset {}", offset);
+            false
+        }
+        Event::Watchpoint { index, old, new } => {
+            println!("\ncell {} changed from {} to {}", index, old, new);
+            false
+        }
+    }
+}
+
+fn report_rewind(undone: u64) {
+    if undone == 0 {
+        println!("already at the start of the program");
     } else {
-        // On one thread read from stdin
-        thread::spawn(move || {
-            // lock stdin
-            let mut stdin = io::stdin().lock();
+        println!("went back {} instructions", undone);
+    }
+}
 
-            loop {
-                let mut buffer = String::new();
-                stdin.read_line(&mut buffer).unwrap();
-                buffer.bytes().for_each(|b| tx.send(Wrapping(b)).unwrap())
+fn print_location(machine: &Machine, program: &str) {
+    let source = machine
+        .bytecode()
+        .position(machine.pc())
+        .map(|position| {
+            let instruction = program.get(position.start..=position.end).unwrap_or("");
+            format!("offset {} `{}`", position.start, instruction)
+        })
+        .unwrap_or_else(|| "end of program".to_string());
+
+    println!(
+        "pc {} at {}, pointer {}, {} iterations",
+        machine.pc(),
+        source,
+        machine.pointer(),
+        machine.iterations()
+    );
+}
+
+/// Prints the cells within `radius` of the pointer, with the current cell in brackets
+fn print_tape(machine: &Machine, radius: usize) {
+    let tape = machine.tape();
+    let pointer = machine.pointer();
+    let start = (pointer - radius as isize).max(0) as usize;
+    let end = ((pointer + radius as isize + 1).max(0) as usize).min(tape.len());
+
+    let cells: Vec<String> = (start..end)
+        .map(|i| {
+            if i as isize == pointer {
+                format!("[{}]", tape[i])
+            } else {
+                tape[i].to_string()
             }
-        });
+        })
+        .collect();
 
-        // On the another write to stdout
-        thread::spawn(move || {
-            let mut stdout = io::stdout().lock();
-            let mut buf: [u8; 1] = [0];
-            while let Ok(b) = rx.recv() {
-                match b {
-                    Ok(b) => {
-                        buf[0] = b.0;
-                        stdout.write_all(&buf).unwrap();
-                    }
-                    Err(err) => {
-                        eprintln!("Runtime Error {:?}", err);
-                        exit(1);
+    println!("{}: {}", start, cells.join(" "));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timings() {
+        let timings = Timings {
+            parse: Duration::from_millis(1),
+            optimize: Duration::from_micros(2),
+            execute: Duration::from_nanos(3),
+        };
+
+        assert_eq!(
+            timings.to_json(),
+            r#"{"parse_ns":1000000,"optimize_ns":2000,"execute_ns":3}"#
+        );
+        assert_eq!(
+            timings.to_string(),
+            "parse:    1ms\noptimize: 2µs\nexecute:  3ns"
+        );
+    }
+
+    #[test]
+    fn debug_commands() {
+        let parse = |line: &str| DebugCommand::parse(line);
+
+        assert_eq!(parse("  \n"), None);
+        assert_eq!(parse("s\n"), Some(DebugCommand::Step(1)));
+        assert_eq!(parse("step 5"), Some(DebugCommand::Step(5)));
+        assert_eq!(parse("rs 3"), Some(DebugCommand::ReverseStep(3)));
+        assert_eq!(parse("c"), Some(DebugCommand::Continue));
+        assert_eq!(parse("b 12"), Some(DebugCommand::Break(12)));
+        assert_eq!(
+            parse("break here"),
+            Some(DebugCommand::Usage("break <offset>"))
+        );
+        assert_eq!(
+            parse("unwatch"),
+            Some(DebugCommand::Usage("unwatch <cell>"))
+        );
+        assert_eq!(parse("t"), Some(DebugCommand::Tape(8)));
+        assert_eq!(
+            parse("  input hello world \n"),
+            Some(DebugCommand::Input("hello world".to_string()))
+        );
+        assert_eq!(parse("q"), Some(DebugCommand::Quit));
+        assert_eq!(
+            parse("jump 3"),
+            Some(DebugCommand::Unknown("jump".to_string()))
+        );
+    }
+
+    #[test]
+    fn repl_commands() {
+        let parse = |line: &str| ReplCommand::parse(line);
+
+        assert_eq!(parse(" \n"), None);
+        assert_eq!(parse(":mem\n"), Some(ReplCommand::Mem(8)));
+        assert_eq!(parse(":mem 2"), Some(ReplCommand::Mem(2)));
+        assert_eq!(parse(":reset"), Some(ReplCommand::Reset));
+        assert_eq!(
+            parse(":load  a b.bf \n"),
+            Some(ReplCommand::Load("a b.bf".to_string()))
+        );
+        assert_eq!(
+            parse(":frobnicate"),
+            Some(ReplCommand::Unknown(":frobnicate".to_string()))
+        );
+        assert_eq!(
+            parse(" +> ++ \n"),
+            Some(ReplCommand::Run("+> ++".to_string()))
+        );
+    }
+
+    #[test]
+    fn repl_snippets_share_the_tape() {
+        let run = |repl: &mut Repl, snippet: &str| {
+            let previous = repl.start(snippet).unwrap();
+            loop {
+                match repl.machine.step() {
+                    Event::Executed => {}
+                    Event::Halted => return Ok(()),
+                    Event::Error(err) => {
+                        repl.machine = previous;
+                        return Err(err);
                     }
+                    event => panic!("unexpected {:?}", event),
                 }
             }
-            stdout.flush().unwrap();
-        });
-    }
+        };
+        let cells = |repl: &Repl| {
+            repl.machine.tape()[..3]
+                .iter()
+                .map(|cell| cell.0)
+                .collect::<Vec<_>>()
+        };
+        let mut repl = Repl::new(Interpreter::builder().tape_size(8));
 
-    // Join the the VM
-    handle.join().unwrap();
+        run(&mut repl, "+>++").unwrap();
+        run(&mut repl, "+>+").unwrap();
+        assert_eq!(cells(&repl), [1, 3, 1]);
+        assert_eq!(repl.machine.pointer(), 2);
+
+        // A snippet that fails leaves the tape as the last one did
+        assert!(run(&mut repl, "+<<<").is_err());
+        assert_eq!(cells(&repl), [1, 3, 1]);
+        assert_eq!(repl.machine.pointer(), 2);
+        assert!(repl.start("[").is_err());
+
+        repl.reset();
+        assert_eq!(cells(&repl), [0, 0, 0]);
+        assert_eq!(repl.machine.pointer(), 0);
+    }
 }
@@ -0,0 +1,241 @@
+use alloc::{format, string::String, vec::Vec};
+use core::str::FromStr;
+
+use bfc_ir::{OptimisationsFlags, Position};
+
+use crate::{bytecode::Op, OverflowBehavior};
+
+/// A rewrite applied to [`Bytecode`](crate::Bytecode) after it is lowered, on top of whatever
+/// [`bfc_ir::optimize`] already did. Passes ignore jump targets, which are recomputed afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pass {
+    /// Replace `[-]` and `[+]` with a `Set` to zero. Only when cells wrap, `[+]` never reaches
+    /// zero otherwise.
+    ClearLoops,
+    /// Replace `[>]`, `[<<]` and similar with a single `Scan`
+    ScanLoops,
+    /// Merge neighbouring `Set`s and `Add`s that touch the same cell, turning `[-]+++` into a
+    /// single `Set`. Unless cells wrap only a `Set` folds into what came before it, adding up
+    /// amounts would skip a saturation or trap in between.
+    FoldSets,
+}
+
+impl Pass {
+    /// Every pass, in the order they work best
+    pub const ALL: &'static [Pass] = &[Pass::ClearLoops, Pass::ScanLoops, Pass::FoldSets];
+
+    pub(crate) fn run(
+        self,
+        ops: Vec<(Op, Option<Position>)>,
+        overflow: OverflowBehavior,
+    ) -> Vec<(Op, Option<Position>)> {
+        let wraps = overflow == OverflowBehavior::Wrap;
+        match self {
+            Pass::ClearLoops if !wraps => ops,
+            Pass::ClearLoops => replace_loops(ops, |body| match body {
+                // Any odd step eventually wraps around to zero
+                Op::Add { amount, offset: 0 } if amount.0 & 1 == 1 => Some(Op::Set {
+                    amount: Default::default(),
+                    offset: 0,
+                }),
+                _ => None,
+            }),
+            Pass::ScanLoops => replace_loops(ops, |body| match body {
+                Op::Move { amount } if *amount != 0 => Some(Op::Scan { stride: *amount }),
+                _ => None,
+            }),
+            Pass::FoldSets => {
+                let mut folded: Vec<(Op, Option<Position>)> = Vec::with_capacity(ops.len());
+                for (op, position) in ops {
+                    if let Some((last, _)) = folded.last_mut() {
+                        if let Some(op) = fold(last, &op, wraps) {
+                            *last = op;
+                            continue;
+                        }
+                    }
+
+                    folded.push((op, position));
+                }
+                folded
+            }
+        }
+    }
+}
+
+impl FromStr for Pass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clear-loops" => Ok(Pass::ClearLoops),
+            "scan-loops" => Ok(Pass::ScanLoops),
+            "fold-sets" => Ok(Pass::FoldSets),
+            _ => Err(format!(
+                "unknown pass `{}`, expected clear-loops, scan-loops or fold-sets",
+                s
+            )),
+        }
+    }
+}
+
+/// Which of [`bfc_ir::optimize`]'s optimizations and bfi's own [`Pass`]es to run, parsed from
+/// `all`, `none` or a comma separated list of their names
+///
+/// ```ignore
+/// let optimisations: Optimisations = "combine-increments,multiply-move,clear-loops".parse()?;
+/// let (instructions, _) = bfi::optimize(instructions, optimisations.flags);
+/// let bytecode = Bytecode::compile(&instructions).optimize(&optimisations.passes);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Optimisations {
+    pub flags: OptimisationsFlags,
+    pub passes: Vec<Pass>,
+}
+
+/// The names of `bfc_ir`'s optimizations, after the peephole passes that implement them
+const FLAGS: &[(&str, OptimisationsFlags)] = &[
+    ("combine-increments", OptimisationsFlags::COMBINE_INCREMENTS),
+    (
+        "combine-pointer-increments",
+        OptimisationsFlags::COMBINE_PTR_INCREMENTS,
+    ),
+    (
+        "annotate-known-zero",
+        OptimisationsFlags::ANNOTATE_KNOWN_ZERO,
+    ),
+    ("multiply-move", OptimisationsFlags::EXTRACT_MULTIPLY),
+    ("simplify-loops", OptimisationsFlags::SIMPLIFY_LOOPS),
+    (
+        "combine-set-and-increments",
+        OptimisationsFlags::COMBINE_SET_AND_INCREMENTS,
+    ),
+    ("remove-dead-loops", OptimisationsFlags::REMOVE_DEAD_LOOPS),
+    (
+        "remove-redundant-sets",
+        OptimisationsFlags::REMOVE_REDUNDANT_SETS,
+    ),
+    (
+        "remove-read-clobber",
+        OptimisationsFlags::REMOVE_READ_CLOBBER,
+    ),
+    ("remove-pure-code", OptimisationsFlags::REMOVE_PURE_CODE),
+    ("sort-by-offset", OptimisationsFlags::SORT_BY_OFFSET),
+    (
+        "combine-before-read",
+        OptimisationsFlags::COMBINE_BEFORE_READ,
+    ),
+];
+
+impl Optimisations {
+    /// Everything, what [`Program::compile`](crate::Program::compile) runs
+    pub fn all() -> Self {
+        Optimisations {
+            flags: OptimisationsFlags::all(),
+            passes: Pass::ALL.to_vec(),
+        }
+    }
+
+    pub fn none() -> Self {
+        Optimisations {
+            flags: OptimisationsFlags::empty(),
+            passes: Vec::new(),
+        }
+    }
+}
+
+impl FromStr for Optimisations {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => return Ok(Optimisations::all()),
+            "none" => return Ok(Optimisations::none()),
+            _ => {}
+        }
+
+        let mut flags = OptimisationsFlags::empty();
+        let mut passes = Vec::new();
+        for name in s.split(',').map(str::trim) {
+            match FLAGS.iter().find(|(flag, _)| *flag == name) {
+                Some((_, flag)) => flags |= *flag,
+                None => passes.push(name.parse::<Pass>().map_err(|_| {
+                    let names: Vec<&str> = FLAGS.iter().map(|(name, _)| *name).collect();
+                    format!(
+                        "unknown optimisation `{}`, expected all, none or a list of {}, \
+                         clear-loops, scan-loops or fold-sets",
+                        name,
+                        names.join(", ")
+                    )
+                })?),
+            }
+        }
+
+        Ok(Optimisations {
+            flags,
+            // In the order they work best, however they were listed
+            passes: Pass::ALL
+                .iter()
+                .copied()
+                .filter(|pass| passes.contains(pass))
+                .collect(),
+        })
+    }
+}
+
+/// Replaces every loop whose body is a single op with `replace(body)`, when it returns `Some`
+fn replace_loops<F>(ops: Vec<(Op, Option<Position>)>, replace: F) -> Vec<(Op, Option<Position>)>
+where
+    F: Fn(&Op) -> Option<Op>,
+{
+    let mut replaced = Vec::with_capacity(ops.len());
+    let mut i = 0;
+    while i < ops.len() {
+        if let [(Op::JumpIfZero { .. }, position), (body, _), (Op::JumpIfNonZero { .. }, _), ..] =
+            &ops[i..]
+        {
+            if let Some(op) = replace(body) {
+                replaced.push((op, *position));
+                i += 3;
+                continue;
+            }
+        }
+
+        replaced.push(ops[i].clone());
+        i += 1;
+    }
+    replaced
+}
+
+/// Combines two ops that run back to back into one, if they touch the same cell. Amounts are
+/// only added up when cells `wraps`.
+fn fold(first: &Op, second: &Op, wraps: bool) -> Option<Op> {
+    match (first, second) {
+        (
+            Op::Set { amount, offset },
+            Op::Add {
+                amount: add,
+                offset: other,
+            },
+        ) if offset == other && wraps => Some(Op::Set {
+            amount: amount + add,
+            offset: *offset,
+        }),
+        (
+            Op::Add { amount, offset },
+            Op::Add {
+                amount: add,
+                offset: other,
+            },
+        ) if offset == other && wraps => Some(Op::Add {
+            amount: amount + add,
+            offset: *offset,
+        }),
+        // A set overwrites whatever happened to the cell before it
+        (Op::Add { offset, .. } | Op::Set { offset, .. }, Op::Set { offset: other, .. })
+            if offset == other =>
+        {
+            Some(second.clone())
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,288 @@
+//! A small macro layer that expands to plain Brainfuck before parsing
+//!
+//! ```text
+//! %include "lib/print.bf"
+//! %define clear { [-] }
+//! %define double { [->++<]>[-<+>]< }
+//!
+//! +*10 %double %clear >*3
+//! ```
+//!
+//! - `%define name { body }` defines a macro, the body can use macros defined before it
+//! - `%name` expands to the macro's body
+//! - A command or macro followed by `*n` is repeated `n` times
+//! - `%include "path"` expands another file in place, relative to the including file
+//!
+//! Everything else, comments included, is copied through unchanged.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::Location;
+
+/// Why a program couldn't be expanded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreprocessError {
+    pub message: String,
+    /// The file the error is in, `None` for source passed to [`preprocess`] directly
+    pub file: Option<PathBuf>,
+    /// Byte offset of the error in that file's source
+    pub offset: usize,
+    /// The source the error is in, to report the line and column
+    source: String,
+}
+
+impl PreprocessError {
+    /// The line and column of the error
+    pub fn location(&self) -> Location {
+        Location::new(&self.source, self.offset)
+    }
+}
+
+impl fmt::Display for PreprocessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file.display())?;
+        }
+        write!(f, "{}: {}", self.location(), self.message)
+    }
+}
+
+impl std::error::Error for PreprocessError {}
+
+/// Expands macros, repetitions and includes in `source`, the contents of `file` if it came from
+/// one. Includes are relative to `file`, or the current directory without one.
+pub fn preprocess(source: &str, file: Option<&Path>) -> Result<String, PreprocessError> {
+    let mut preprocessor = Preprocessor::default();
+    preprocessor.including.extend(file.map(Path::to_path_buf));
+    preprocessor.expand(source, file)
+}
+
+/// Reads and expands the program in `path`
+pub fn preprocess_file<P: AsRef<Path>>(path: P) -> Result<String, PreprocessError> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|err| PreprocessError {
+        message: format!("failed to read {}: {}", path.display(), err),
+        file: Some(path.to_path_buf()),
+        offset: 0,
+        source: String::new(),
+    })?;
+
+    preprocess(&source, Some(path))
+}
+
+#[derive(Default)]
+struct Preprocessor {
+    /// Expanded macro bodies by name
+    macros: HashMap<String, String>,
+    /// Files currently being expanded, to catch include cycles
+    including: Vec<PathBuf>,
+}
+
+impl Preprocessor {
+    fn expand(&mut self, source: &str, file: Option<&Path>) -> Result<String, PreprocessError> {
+        let mut cursor = Cursor {
+            source,
+            file,
+            offset: 0,
+        };
+        let mut expanded = String::with_capacity(source.len());
+
+        while let Some(c) = cursor.peek() {
+            let start = cursor.offset;
+            let unit = match c {
+                '%' => {
+                    cursor.bump();
+                    let name = cursor.identifier();
+                    match name {
+                        "" => {
+                            expanded.push('%');
+                            continue;
+                        }
+                        "define" => {
+                            self.define(&mut cursor)?;
+                            continue;
+                        }
+                        "include" => {
+                            let included = self.include(&mut cursor)?;
+                            expanded.push_str(&included);
+                            continue;
+                        }
+                        name => match self.macros.get(name) {
+                            Some(body) => body.clone(),
+                            None => {
+                                return Err(cursor.error(format!("`{}` isn't defined", name), start))
+                            }
+                        },
+                    }
+                }
+                '+' | '-' | '<' | '>' | '[' | ']' | ',' | '.' => {
+                    cursor.bump();
+                    c.to_string()
+                }
+                _ => {
+                    cursor.bump();
+                    expanded.push(c);
+                    continue;
+                }
+            };
+
+            let count = cursor.repetition()?;
+            expanded.push_str(&unit.repeat(count));
+        }
+
+        Ok(expanded)
+    }
+
+    /// Reads `name { body }` after `%define`
+    fn define(&mut self, cursor: &mut Cursor) -> Result<(), PreprocessError> {
+        cursor.skip_whitespace();
+        let start = cursor.offset;
+        let name = cursor.identifier().to_string();
+        if name.is_empty() {
+            return Err(cursor.error("expected a macro name after `%define`", start));
+        }
+        if matches!(name.as_str(), "define" | "include") {
+            return Err(cursor.error(format!("`{}` can't be redefined", name), start));
+        }
+
+        cursor.skip_whitespace();
+        if cursor.peek() != Some('{') {
+            return Err(cursor.error("expected `{` to start the macro body", cursor.offset));
+        }
+        cursor.bump();
+
+        let body_start = cursor.offset;
+        let mut depth = 1;
+        while depth > 0 {
+            match cursor.peek() {
+                Some('{') => depth += 1,
+                Some('}') => depth -= 1,
+                Some(_) => {}
+                None => return Err(cursor.error("the macro body is never closed", body_start - 1)),
+            }
+            cursor.bump();
+        }
+        let body = &cursor.source[body_start..cursor.offset - 1];
+
+        // Macros used in the body are expanded now, so a macro can't refer to itself. Errors in
+        // the body itself are reported relative to the whole source.
+        let body = self.expand(body, cursor.file).map_err(|mut err| {
+            if err.file.as_deref() == cursor.file {
+                err.offset += body_start;
+                err.source = cursor.source.to_string();
+            }
+            err
+        })?;
+        self.macros.insert(name, body);
+        Ok(())
+    }
+
+    /// Reads `"path"` after `%include` and expands that file
+    fn include(&mut self, cursor: &mut Cursor) -> Result<String, PreprocessError> {
+        cursor.skip_whitespace();
+        let start = cursor.offset;
+        if cursor.peek() != Some('"') {
+            return Err(cursor.error("expected a quoted path after `%include`", start));
+        }
+        cursor.bump();
+
+        let path_start = cursor.offset;
+        loop {
+            match cursor.peek() {
+                Some('"') => break,
+                Some('\n') | None => {
+                    return Err(cursor.error("the include path is never closed", start))
+                }
+                Some(_) => cursor.bump(),
+            }
+        }
+        let source = cursor.source;
+        let relative = Path::new(&source[path_start..cursor.offset]);
+        cursor.bump();
+
+        let path = match cursor.file.and_then(Path::parent) {
+            Some(dir) => dir.join(relative),
+            None => relative.to_path_buf(),
+        };
+        if self.including.contains(&path) {
+            return Err(cursor.error(format!("{} includes itself", path.display()), start));
+        }
+
+        let source = fs::read_to_string(&path).map_err(|err| {
+            cursor.error(format!("failed to read {}: {}", path.display(), err), start)
+        })?;
+
+        self.including.push(path.clone());
+        let expanded = self.expand(&source, Some(&path));
+        self.including.pop();
+        expanded
+    }
+}
+
+/// A position in the source being expanded
+struct Cursor<'a> {
+    source: &'a str,
+    file: Option<&'a Path>,
+    offset: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> Option<char> {
+        self.source[self.offset..].chars().next()
+    }
+
+    fn bump(&mut self) {
+        if let Some(c) = self.peek() {
+            self.offset += c.len_utf8();
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.bump();
+        }
+    }
+
+    /// Reads a macro name, empty if there isn't one here
+    fn identifier(&mut self) -> &'a str {
+        let start = self.offset;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            self.bump();
+        }
+        &self.source[start..self.offset]
+    }
+
+    /// Reads `*n` after a command or macro, returning how many times to repeat it
+    fn repetition(&mut self) -> Result<usize, PreprocessError> {
+        if self.peek() != Some('*') {
+            return Ok(1);
+        }
+
+        let start = self.offset;
+        self.bump();
+        let digits = self.offset;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.bump();
+        }
+
+        self.source[digits..self.offset]
+            .parse()
+            .map_err(|_| self.error("expected a count after `*`", start))
+    }
+
+    fn error<M: Into<String>>(&self, message: M, offset: usize) -> PreprocessError {
+        PreprocessError {
+            message: message.into(),
+            file: self.file.map(Path::to_path_buf),
+            offset,
+            source: self.source.to_string(),
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    num::Wrapping,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bfc_ir::Position;
+
+use crate::bytecode::{Bytecode, Op};
+
+/// How often each instruction ran and how long was spent in each loop, created by
+/// [`crate::Interpreter::profile`]
+#[derive(Debug, Clone)]
+pub struct Profile {
+    bytecode: Arc<Bytecode>,
+    counts: Vec<u64>,
+    loops: BTreeMap<usize, LoopProfile>,
+    heatmap: MemoryHeatmap,
+}
+
+/// Statistics for a single loop
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopProfile {
+    /// Index of the loop's opening instruction
+    pub pc: usize,
+    /// Source span of the whole loop, if the parser recorded one
+    pub position: Option<Position>,
+    /// How many times the loop was entered with a non-zero cell
+    pub entries: u64,
+    /// How many times the body ran, across every entry
+    pub iterations: u64,
+    /// Wall-clock time spent inside the loop, including nested loops
+    pub time: Duration,
+}
+
+impl Profile {
+    /// How many times the instruction at `pc` ran
+    pub fn count(&self, pc: usize) -> u64 {
+        self.counts.get(pc).copied().unwrap_or(0)
+    }
+
+    /// Execution counts for every instruction, indexed by pc
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Every loop that was entered at least once, in program order
+    pub fn loops(&self) -> impl Iterator<Item = &LoopProfile> {
+        self.loops.values()
+    }
+
+    /// The `n` loops that took the longest, slowest first
+    pub fn hottest_loops(&self, n: usize) -> Vec<&LoopProfile> {
+        let mut loops: Vec<_> = self.loops.values().collect();
+        loops.sort_by(|a, b| b.time.cmp(&a.time).then(a.pc.cmp(&b.pc)));
+        loops.truncate(n);
+        loops
+    }
+
+    /// The program the profile was taken from
+    pub fn bytecode(&self) -> &Bytecode {
+        &self.bytecode
+    }
+
+    /// How often each cell was read and written
+    pub fn heatmap(&self) -> &MemoryHeatmap {
+        &self.heatmap
+    }
+
+    /// A human readable summary of the `n` hottest loops with excerpts from `program`, the
+    /// source the profiled bytecode was compiled from
+    pub fn report(&self, program: &str, n: usize) -> String {
+        let mut report = String::new();
+        let total: u64 = self.counts.iter().sum();
+        writeln!(report, "{} instructions executed", total).unwrap();
+
+        let loops = self.hottest_loops(n);
+        if loops.is_empty() {
+            return report;
+        }
+
+        writeln!(report, "hottest loops:").unwrap();
+        for (rank, profile) in loops.into_iter().enumerate() {
+            let location = match profile.position {
+                Some(position) => format!("offset {}", position.start),
+                None => format!("pc {}", profile.pc),
+            };
+
+            writeln!(
+                report,
+                "{:>3}. {}: {:?}, {} iterations over {} entries",
+                rank + 1,
+                location,
+                profile.time,
+                profile.iterations,
+                profile.entries
+            )
+            .unwrap();
+
+            if let Some(position) = profile.position {
+                writeln!(report, "     {}", excerpt(program, position)).unwrap();
+            }
+        }
+
+        report
+    }
+}
+
+/// How many times each tape cell was read and written during a profiled run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryHeatmap {
+    reads: Vec<u64>,
+    writes: Vec<u64>,
+}
+
+impl MemoryHeatmap {
+    pub fn reads(&self, index: usize) -> u64 {
+        self.reads.get(index).copied().unwrap_or(0)
+    }
+
+    pub fn writes(&self, index: usize) -> u64 {
+        self.writes.get(index).copied().unwrap_or(0)
+    }
+
+    /// One past the highest cell that was touched
+    pub fn len(&self) -> usize {
+        self.reads.len().max(self.writes.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// An ASCII histogram with a row per touched cell, bars are scaled to at most `width`
+    /// characters
+    ///
+    /// ```text
+    ///      0 | ####################  1200 (600 reads, 600 writes)
+    ///      1 | ###                    180 (90 reads, 90 writes)
+    /// ```
+    pub fn render(&self, width: usize) -> String {
+        let totals: Vec<u64> = (0..self.len())
+            .map(|index| self.reads(index) + self.writes(index))
+            .collect();
+        let max = totals.iter().copied().max().unwrap_or(0).max(1);
+
+        let mut rendered = String::new();
+        for (index, total) in totals.into_iter().enumerate() {
+            if total == 0 {
+                continue;
+            }
+
+            // Every touched cell gets at least one mark
+            let bar = (total as u128 * width as u128).div_ceil(max as u128) as usize;
+            writeln!(
+                rendered,
+                "{:>6} | {:<width$} {:>5} ({} reads, {} writes)",
+                index,
+                "#".repeat(bar),
+                total,
+                self.reads(index),
+                self.writes(index),
+                width = width
+            )
+            .unwrap();
+        }
+        rendered
+    }
+
+    fn read(&mut self, index: Option<usize>) {
+        if let Some(index) = index {
+            bump(&mut self.reads, index);
+        }
+    }
+
+    fn write(&mut self, index: Option<usize>) {
+        if let Some(index) = index {
+            bump(&mut self.writes, index);
+        }
+    }
+}
+
+fn bump(counts: &mut Vec<u64>, index: usize) {
+    if index >= counts.len() {
+        counts.resize(index + 1, 0);
+    }
+    counts[index] += 1;
+}
+
+/// The source of `position` on a single line, shortened if it's long
+pub(crate) fn excerpt(program: &str, position: Position) -> String {
+    const MAX_LEN: usize = 60;
+
+    let source = program.get(position.start..=position.end).unwrap_or("");
+    let mut excerpt: String = source
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .take(MAX_LEN)
+        .collect();
+    if source.chars().filter(|c| !c.is_whitespace()).count() > MAX_LEN {
+        excerpt.push_str("...");
+    }
+    excerpt
+}
+
+/// Collects a [`Profile`] while a machine runs
+#[derive(Debug)]
+pub(crate) struct Profiler {
+    counts: Vec<u64>,
+    loops: BTreeMap<usize, LoopProfile>,
+    /// Loops currently running, innermost last, with when they were entered
+    open: Vec<(usize, Instant)>,
+    heatmap: MemoryHeatmap,
+}
+
+impl Profiler {
+    pub(crate) fn new(len: usize) -> Self {
+        Self {
+            counts: vec![0; len],
+            loops: BTreeMap::new(),
+            open: Vec::new(),
+            heatmap: MemoryHeatmap::default(),
+        }
+    }
+
+    /// Counts the instruction at `pc` and the cells it's about to touch. Scans are recorded
+    /// separately with [`Profiler::record_scan`] once they know where they stopped.
+    pub(crate) fn record(&mut self, pc: usize, op: &Op, pointer: isize, memory: &[Wrapping<u32>]) {
+        self.counts[pc] += 1;
+
+        let cell = |offset: isize| usize::try_from(pointer + offset).ok();
+        let heatmap = &mut self.heatmap;
+        match op {
+            Op::Add { offset, .. } => {
+                heatmap.read(cell(*offset));
+                heatmap.write(cell(*offset));
+            }
+            Op::Set { offset, .. } => heatmap.write(cell(*offset)),
+            Op::Read => heatmap.write(cell(0)),
+            Op::Write
+            | Op::JumpIfZero { .. }
+            | Op::JumpIfNonZero { .. }
+            | Op::Procedure { .. }
+            | Op::Call => heatmap.read(cell(0)),
+            Op::Fork => {
+                heatmap.write(cell(0));
+                heatmap.write(cell(1));
+            }
+            Op::MultiplyMove { changes } => {
+                heatmap.read(cell(0));
+
+                let current = cell(0).and_then(|index| memory.get(index));
+                if current.is_some_and(|current| current.0 != 0) {
+                    for (offset, _) in changes.iter() {
+                        heatmap.read(cell(*offset));
+                        heatmap.write(cell(*offset));
+                    }
+                    heatmap.write(cell(0));
+                }
+            }
+            // Extensions can touch any cell, only the handler knows which
+            Op::Move { .. } | Op::Scan { .. } | Op::Dump | Op::Return | Op::Extension { .. } => {}
+        }
+    }
+
+    /// Records the cells a scan looked at on its way from `start` to `end`
+    pub(crate) fn record_scan(&mut self, start: isize, end: isize, stride: isize) {
+        let mut pointer = start;
+        loop {
+            self.heatmap.read(usize::try_from(pointer).ok());
+            if pointer == end {
+                break;
+            }
+            pointer += stride;
+        }
+    }
+
+    /// The loop opening at `pc` was entered
+    pub(crate) fn enter_loop(&mut self, pc: usize) {
+        self.open.push((pc, Instant::now()));
+        self.stats(pc).entries += 1;
+    }
+
+    /// The body of the loop opening at `pc` finished once
+    pub(crate) fn loop_iteration(&mut self, pc: usize) {
+        self.stats(pc).iterations += 1;
+    }
+
+    /// The loop opening at `pc` finished
+    pub(crate) fn exit_loop(&mut self, pc: usize) {
+        self.loop_iteration(pc);
+
+        // Machines restored from a snapshot can leave loops they never entered
+        if let Some((_, start)) = self.open.pop() {
+            self.stats(pc).time += start.elapsed();
+        }
+    }
+
+    fn stats(&mut self, pc: usize) -> &mut LoopProfile {
+        self.loops.entry(pc).or_insert_with(|| LoopProfile {
+            pc,
+            position: None,
+            entries: 0,
+            iterations: 0,
+            time: Duration::ZERO,
+        })
+    }
+
+    pub(crate) fn finish(self, bytecode: Arc<Bytecode>) -> Profile {
+        let mut loops = self.loops;
+        for profile in loops.values_mut() {
+            profile.position = bytecode.position(profile.pc);
+        }
+
+        Profile {
+            bytecode,
+            counts: self.counts,
+            loops,
+            heatmap: self.heatmap,
+        }
+    }
+}
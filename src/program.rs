@@ -0,0 +1,144 @@
+use alloc::{sync::Arc, vec::Vec};
+use core::num::NonZeroU64;
+
+use bfc_ir::ParseError;
+
+use crate::{
+    bytecode::Bytecode, optimized, source::Location, CellWidth, Error, Interpreter,
+    OverflowBehavior, RunOutcome,
+};
+#[cfg(feature = "std")]
+use crate::{
+    run_case, run_test, Diagnostic, Expectation, OptimisationsFlags, TestCase, TestReport,
+    TestResults,
+};
+
+/// A parsed and optimized program along with the source it came from. Compile it once and run
+/// it, or build interpreters from it, any number of times.
+///
+/// ```ignore
+/// let program = Program::compile(",[.,]")?;
+/// assert_eq!(program.run(*b"hi", None)?, b"hi");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub(crate) bytecode: Arc<Bytecode>,
+    pub(crate) source: Arc<str>,
+    pub(crate) cell_width: CellWidth,
+}
+
+impl Program {
+    /// Parses and optimizes `source` for 8-bit cells
+    pub fn compile(source: &str) -> Result<Self, ParseError> {
+        Self::compile_with(source, CellWidth::U8)
+    }
+
+    /// Parses and optimizes `source` for wrapping cells of `cell_width`. Constants are folded at
+    /// that width so the program should only be run with it, [`Interpreter::from_program`] does
+    /// so.
+    pub fn compile_with(source: &str, cell_width: CellWidth) -> Result<Self, ParseError> {
+        Ok(Program {
+            bytecode: Arc::new(optimized(source, cell_width, OverflowBehavior::Wrap)?),
+            source: source.into(),
+            cell_width,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn bytecode(&self) -> &Bytecode {
+        &self.bytecode
+    }
+
+    /// The cell width the program was optimized for
+    pub fn cell_width(&self) -> CellWidth {
+        self.cell_width
+    }
+
+    /// What `bfc_ir`'s optimizer warned about while compiling the program. Programs for wider
+    /// cells skip that optimizer so they never have any.
+    #[cfg(feature = "std")]
+    pub fn warnings(&self) -> Vec<Diagnostic> {
+        if self.cell_width != CellWidth::U8 {
+            return Vec::new();
+        }
+
+        let instructions = bfc_ir::parse(&self.source).expect("the program parsed when compiled");
+        let (_, warnings) = bfc_ir::optimize(instructions, OptimisationsFlags::all());
+        warnings.into_iter().map(Diagnostic::from).collect()
+    }
+
+    /// Where in the source the instruction at `pc` came from
+    pub fn location(&self, pc: usize) -> Option<Location> {
+        let position = self.bytecode.position(pc)?;
+        Some(Location::new(&self.source, position.start))
+    }
+
+    /// Runs the program to completion with all of its input up front
+    pub fn run<I>(&self, input: I, max_iterations: Option<NonZeroU64>) -> Result<Vec<u8>, Error>
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        self.run_outcome(input, max_iterations)
+            .into_result()
+            .map_err(Error::RunTimeError)
+    }
+
+    /// Like [`Program::run`] but also reports how many iterations ran and where the pointer
+    /// finished, see [`Interpreter::run_outcome`]
+    pub fn run_outcome<I>(&self, input: I, max_iterations: Option<NonZeroU64>) -> RunOutcome
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        Interpreter::from_program(self, max_iterations).run_outcome(input)
+    }
+
+    /// Runs the program once for each input and checks it writes the matching output, like
+    /// [`tests_blocking`](crate::tests_blocking) without parsing again
+    #[cfg(feature = "std")]
+    pub fn tests<I, O>(
+        &self,
+        inputs: I,
+        outputs: O,
+        max_iterations: Option<NonZeroU64>,
+    ) -> TestResults
+    where
+        I: Iterator<Item = Vec<u8>> + ExactSizeIterator,
+        O: Iterator<Item = Vec<u8>> + ExactSizeIterator,
+    {
+        if inputs.len() != outputs.len() {
+            return TestResults::OutputsDontMatchInputs;
+        }
+
+        let mut machine = Interpreter::from_program(self, max_iterations).machine();
+        let results = inputs
+            .zip(outputs)
+            .map(|(input, expected)| {
+                run_test(
+                    &mut machine,
+                    input,
+                    &Expectation::Output(expected),
+                    None,
+                    None,
+                    None,
+                )
+            })
+            .collect();
+
+        TestResults::Results(results)
+    }
+
+    /// Runs every case, like [`run_tests`](crate::run_tests) without parsing again
+    #[cfg(feature = "std")]
+    pub fn run_tests(&self, cases: &[TestCase]) -> TestReport {
+        let mut machine = Interpreter::from_program(self, None).machine();
+        let results = cases
+            .iter()
+            .map(|case| run_case(&mut machine, case))
+            .collect();
+
+        TestReport { results }
+    }
+}
@@ -0,0 +1,185 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{fmt, str::FromStr};
+
+/// Every byte a run read, when it read it, and everything it wrote. Made by
+/// [`Interpreter::record`], [`Interpreter::replay`] runs the program again with the same input
+/// and checks it behaves the same way.
+///
+/// Recordings are saved as text, a line for each read with the iteration it happened at and the
+/// byte or `eof`, then the output in hex:
+///
+/// ```text
+/// bfi recording
+/// read 1 104
+/// read 3 eof
+/// output 68
+/// ```
+///
+/// [`Interpreter::record`]: crate::Interpreter::record
+/// [`Interpreter::replay`]: crate::Interpreter::replay
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recording {
+    pub reads: Vec<InputRead>,
+    pub output: Vec<u8>,
+}
+
+/// A single `,`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputRead {
+    /// The iteration count when the `,` ran, the first instruction is iteration 1
+    pub iteration: u64,
+    /// The byte read, `None` once the input was closed
+    pub input: Option<u8>,
+}
+
+impl Recording {
+    /// The bytes the program read, in order
+    pub fn input(&self) -> impl Iterator<Item = u8> + '_ {
+        self.reads.iter().filter_map(|read| read.input)
+    }
+
+    /// Checks that `replayed` did everything this recording did at the same points
+    pub fn compare(&self, replayed: &Recording) -> Result<(), ReplayError> {
+        let reads = self.reads.len().max(replayed.reads.len());
+        for index in 0..reads {
+            let expected = self.reads.get(index).copied();
+            let actual = replayed.reads.get(index).copied();
+            if expected != actual {
+                return Err(ReplayError::Read {
+                    index,
+                    expected,
+                    actual,
+                });
+            }
+        }
+
+        if self.output != replayed.output {
+            let offset = self
+                .output
+                .iter()
+                .zip(&replayed.output)
+                .take_while(|(expected, actual)| expected == actual)
+                .count();
+            return Err(ReplayError::Output { offset });
+        }
+
+        Ok(())
+    }
+}
+
+const HEADER: &str = "bfi recording";
+
+impl fmt::Display for Recording {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        for read in &self.reads {
+            match read.input {
+                Some(input) => writeln!(f, "read {} {}", read.iteration, input)?,
+                None => writeln!(f, "read {} eof", read.iteration)?,
+            }
+        }
+
+        write!(f, "output ")?;
+        for byte in &self.output {
+            write!(f, "{:02x}", byte)?;
+        }
+        writeln!(f)
+    }
+}
+
+impl FromStr for Recording {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines().enumerate();
+        match lines.next() {
+            Some((_, HEADER)) => {}
+            _ => return Err(format!("expected a recording to start with `{}`", HEADER)),
+        }
+
+        let mut recording = Recording::default();
+        for (index, line) in lines {
+            let error = |message: &str| format!("line {}: {}", index + 1, message);
+            let words: Vec<&str> = line.split_whitespace().collect();
+
+            match words.as_slice() {
+                [] => {}
+                ["read", iteration, input] => {
+                    let iteration = iteration
+                        .parse()
+                        .map_err(|_| error("expected an iteration count"))?;
+                    let input = match *input {
+                        "eof" => None,
+                        input => Some(input.parse().map_err(|_| error("expected a byte or eof"))?),
+                    };
+                    recording.reads.push(InputRead { iteration, input });
+                }
+                ["output"] => {}
+                ["output", hex] => {
+                    if hex.len() % 2 != 0 {
+                        return Err(error("output has an odd number of hex digits"));
+                    }
+                    recording.output = (0..hex.len())
+                        .step_by(2)
+                        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| error("expected hex output"))?;
+                }
+                _ => {
+                    return Err(error(
+                        "expected `read <iteration> <byte>` or `output <hex>`",
+                    ))
+                }
+            }
+        }
+
+        Ok(recording)
+    }
+}
+
+/// Where a replay stopped matching its recording
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The `index`th read differed, `None` if one run read fewer times
+    Read {
+        index: usize,
+        expected: Option<InputRead>,
+        actual: Option<InputRead>,
+    },
+    /// The output differed starting at byte `offset`
+    Output { offset: usize },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Read {
+                index,
+                expected,
+                actual,
+            } => {
+                let describe = |read: &Option<InputRead>| match read {
+                    Some(read) => format!("at iteration {}", read.iteration),
+                    None => "never".to_string(),
+                };
+                write!(
+                    f,
+                    "read {} happened {} but was recorded {}",
+                    index + 1,
+                    describe(actual),
+                    describe(expected)
+                )
+            }
+            ReplayError::Output { offset } => {
+                write!(f, "output differs from the recording at byte {}", offset)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ReplayError {}
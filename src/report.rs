@@ -0,0 +1,176 @@
+use std::fmt::Write;
+
+use crate::{annotate, hexdiff, linediff, TestOutcome, TestReport, TestResult};
+
+impl TestReport {
+    /// Formats the report as a JUnit XML `<testsuite>` named `suite`, failures are cases that
+    /// produced the wrong output and errors are cases that didn't finish as expected
+    pub fn junit(&self, suite: &str) -> String {
+        let failures = self
+            .failures()
+            .filter(|(_, result)| !is_error(result))
+            .count();
+        let errors = self
+            .failures()
+            .filter(|(_, result)| is_error(result))
+            .count();
+
+        let mut xml = String::new();
+        writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+        writeln!(
+            xml,
+            r#"<testsuite name="{}" tests="{}" failures="{}" errors="{}" time="{:.6}">"#,
+            escape(suite),
+            self.results.len(),
+            failures,
+            errors,
+            self.elapsed().as_secs_f64()
+        )
+        .unwrap();
+
+        for (name, result) in &self.results {
+            write!(
+                xml,
+                r#"  <testcase name="{}" time="{:.6}""#,
+                escape(name),
+                result.elapsed.as_secs_f64()
+            )
+            .unwrap();
+
+            match describe(&result.outcome) {
+                None => writeln!(xml, "/>").unwrap(),
+                Some(message) => {
+                    let tag = if is_error(result) { "error" } else { "failure" };
+                    writeln!(
+                        xml,
+                        r#"><{} message="{}">{}</{}></testcase>"#,
+                        tag,
+                        escape(&message),
+                        escape(&details(&result.outcome)),
+                        tag
+                    )
+                    .unwrap();
+                }
+            }
+        }
+
+        writeln!(xml, "</testsuite>").unwrap();
+        xml
+    }
+
+    /// Formats the report for a terminal, a line for each case. Cases with the wrong output are
+    /// followed by a line diff and a hexdump of the first difference, runtime errors point into
+    /// `source`, the program the cases ran.
+    pub fn human(&self, source: &str) -> String {
+        let mut text = String::new();
+        for (name, result) in &self.results {
+            match &result.outcome {
+                TestOutcome::Ok => writeln!(
+                    text,
+                    "{}: ok ({} iterations in {:?})",
+                    name, result.iterations, result.elapsed
+                ),
+                // Both diffs end in a newline already
+                TestOutcome::UnexpectedOutput { expected, output } => write!(
+                    text,
+                    "{}: FAILED, output didn't match\n{}\n{}",
+                    name,
+                    linediff(expected, output),
+                    hexdiff(expected, output)
+                ),
+                TestOutcome::RunTimeError(run) => {
+                    let error = run.error.as_ref().expect("failed runs have an error");
+                    let message = format!("runtime error: {}", error);
+                    match error.context().and_then(|context| context.position) {
+                        Some(position) => writeln!(
+                            text,
+                            "{}: FAILED\n{}",
+                            name,
+                            annotate(source, position.start, &message)
+                        ),
+                        None => writeln!(text, "{}: FAILED\n{}", name, message),
+                    }
+                }
+                outcome => writeln!(
+                    text,
+                    "{}: FAILED, {}",
+                    name,
+                    describe(outcome).expect("only passing cases have no description")
+                ),
+            }
+            .unwrap();
+        }
+
+        text
+    }
+
+    /// Formats the report in the Test Anything Protocol, with failure details as comments
+    pub fn tap(&self) -> String {
+        let mut tap = String::new();
+        writeln!(tap, "TAP version 13").unwrap();
+        writeln!(tap, "1..{}", self.results.len()).unwrap();
+
+        for (i, (name, result)) in self.results.iter().enumerate() {
+            match describe(&result.outcome) {
+                None => writeln!(tap, "ok {} - {}", i + 1, name).unwrap(),
+                Some(message) => {
+                    writeln!(tap, "not ok {} - {}", i + 1, name).unwrap();
+                    writeln!(tap, "# {}", message).unwrap();
+                    for line in details(&result.outcome).lines() {
+                        writeln!(tap, "# {}", line).unwrap();
+                    }
+                }
+            }
+        }
+
+        tap
+    }
+}
+
+/// Whether a case failed to finish as expected rather than producing the wrong output
+fn is_error(result: &TestResult) -> bool {
+    matches!(
+        result.outcome,
+        TestOutcome::RunTimeError(_) | TestOutcome::TimedOut(_) | TestOutcome::ParseError(_)
+    )
+}
+
+/// A one line summary of why a case failed, `None` if it passed
+pub(crate) fn describe(outcome: &TestOutcome) -> Option<String> {
+    match outcome {
+        TestOutcome::Ok => None,
+        TestOutcome::UnexpectedOutput { .. } => Some("output didn't match".to_string()),
+        TestOutcome::RunTimeError(run) => run.error.as_ref().map(ToString::to_string),
+        TestOutcome::TimedOut(_) => Some("timed out".to_string()),
+        TestOutcome::MissingError(_) => {
+            Some("expected an error but the program halted".to_string())
+        }
+        TestOutcome::ParseError(message) => Some(message.clone()),
+    }
+}
+
+/// Longer, multi-line details for a failed case
+fn details(outcome: &TestOutcome) -> String {
+    match outcome {
+        TestOutcome::UnexpectedOutput { expected, output } => hexdiff(expected, output),
+        _ => String::new(),
+    }
+}
+
+/// Escapes text for use in XML attributes and element content
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // XML 1.0 has no way to represent most control characters, even escaped
+            c if c.is_control() && !matches!(c, '\n' | '\r' | '\t') => escaped.push('?'),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
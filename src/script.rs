@@ -0,0 +1,186 @@
+use std::{
+    fmt,
+    num::Wrapping,
+    sync::mpsc::RecvTimeoutError,
+    time::{Duration, Instant},
+};
+
+use crate::{interpreter::OutputRx, Interpreter, RunTimeError};
+
+/// One step of a [`Script`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Write these bytes to the program's input
+    Send(Vec<u8>),
+    /// Wait for the program to write exactly these bytes next
+    Expect(Vec<u8>),
+}
+
+/// An expect-style conversation with a running program, for testing programs that respond to
+/// input as it arrives rather than reading everything up front
+///
+/// ```ignore
+/// Script::new()
+///     .expect("name? ")
+///     .send("bfi\n")
+///     .expect("hello bfi\n")
+///     .run(&interpreter, Duration::from_secs(1))?;
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script {
+    steps: Vec<Step>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn send<B: Into<Vec<u8>>>(mut self, bytes: B) -> Self {
+        self.steps.push(Step::Send(bytes.into()));
+        self
+    }
+
+    pub fn expect<B: Into<Vec<u8>>>(mut self, bytes: B) -> Self {
+        self.steps.push(Step::Expect(bytes.into()));
+        self
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// Runs the script against a newly spawned machine, waiting up to `timeout` for each expected
+    /// output. The machine is cancelled once the last step passes, whatever it was doing.
+    pub fn run(&self, interpreter: &Interpreter, timeout: Duration) -> Result<(), ScriptError> {
+        let (input, output, handle) = interpreter.spawn();
+
+        let mut result = Ok(());
+        for (step, action) in self.steps.iter().enumerate() {
+            match action {
+                Step::Send(bytes) => {
+                    // A program that already stopped is reported by the next expect
+                    for &byte in bytes {
+                        let _ = input.send(Wrapping(byte));
+                    }
+                }
+                Step::Expect(expected) => {
+                    result = expect(&output, step, expected, Instant::now() + timeout);
+                    if result.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        handle.cancel();
+        result
+    }
+}
+
+/// Receives output until it matches `expected`, failing on the first byte that doesn't
+fn expect(
+    output: &OutputRx,
+    step: usize,
+    expected: &[u8],
+    deadline: Instant,
+) -> Result<(), ScriptError> {
+    let mut received = Vec::with_capacity(expected.len());
+
+    while received.len() < expected.len() {
+        match output.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Ok(byte)) => received.push(byte.0),
+            Ok(Err(error)) => {
+                return Err(ScriptError::RunTimeError {
+                    step,
+                    output: received,
+                    error,
+                })
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(ScriptError::Halted {
+                    step,
+                    output: received,
+                })
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(ScriptError::TimedOut {
+                    step,
+                    output: received,
+                })
+            }
+        }
+
+        if !expected.starts_with(&received) {
+            return Err(ScriptError::UnexpectedOutput {
+                step,
+                expected: expected.to_vec(),
+                output: received,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Why a [`Script`] failed. `step` is the index of the step that was running and `output` is
+/// what the program wrote during it.
+#[derive(Debug)]
+pub enum ScriptError {
+    /// The program wrote something other than what the step expected
+    UnexpectedOutput {
+        step: usize,
+        expected: Vec<u8>,
+        output: Vec<u8>,
+    },
+    /// The program didn't finish writing the expected output in time
+    TimedOut { step: usize, output: Vec<u8> },
+    /// The program halted before writing the expected output
+    Halted { step: usize, output: Vec<u8> },
+    /// The program failed before writing the expected output
+    RunTimeError {
+        step: usize,
+        output: Vec<u8>,
+        error: RunTimeError,
+    },
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::UnexpectedOutput {
+                step,
+                expected,
+                output,
+            } => write!(
+                f,
+                "step {}: expected {:?} but got {:?}",
+                step,
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(output)
+            ),
+            ScriptError::TimedOut { step, output } => write!(
+                f,
+                "step {}: timed out after {:?}",
+                step,
+                String::from_utf8_lossy(output)
+            ),
+            ScriptError::Halted { step, output } => write!(
+                f,
+                "step {}: program halted after {:?}",
+                step,
+                String::from_utf8_lossy(output)
+            ),
+            ScriptError::RunTimeError { step, error, .. } => write!(f, "step {}: {}", step, error),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScriptError::RunTimeError { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
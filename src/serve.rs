@@ -0,0 +1,803 @@
+//! `bfi serve`, an HTTP API for running programs
+//!
+//! `POST /run` takes a program and its input, either as JSON or as `multipart/form-data` fields
+//! with the same names:
+//!
+//! ```text
+//! {"program": ",[.,]", "input": "hi", "max_iterations": 1000, "timeout_ms": 500, "tape_size": 100,
+//!  "max_output": 1024}
+//! ```
+//!
+//! The limits are optional and can only lower the ones the server was started with, `0` is the
+//! same as leaving one out. The response is always JSON, runs that fail still return what they
+//! wrote:
+//!
+//! ```text
+//! {"output": "hi", "iterations": 9, "elapsed_ms": 0, "error": null}
+//! {"output": "", "iterations": 3, "elapsed_ms": 0, "error": {"kind": "OutOfBoundsLeft", "message": "..."}}
+//! ```
+//!
+//! Interactive sessions are served over WebSockets on a separate address, see [`serve_sessions`].
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read},
+    net::{TcpListener, TcpStream},
+    num::{NonZeroU64, Wrapping},
+    sync::{
+        mpsc::{Receiver, Sender, TryRecvError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use bfi::{Bytecode, Interpreter, Location, OptimisationsFlags, Pass, RunTimeError, SpawnHandle};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+use tungstenite::Message;
+
+/// Requests with larger bodies are refused
+const MAX_BODY: usize = 1 << 20;
+/// How long a session waits for input before checking for output
+const POLL: Duration = Duration::from_millis(10);
+
+/// The most any request is allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_iterations: NonZeroU64,
+    pub timeout: Duration,
+    /// Cells on the tape
+    pub tape_size: usize,
+    /// Bytes of output
+    pub max_output: usize,
+}
+
+/// Serves requests on `address` with `threads` workers until the process is killed
+pub fn serve(address: &str, threads: usize, limits: Limits) -> io::Result<()> {
+    let server = Server::http(address).map_err(|err| io::Error::other(err.to_string()))?;
+    let server = Arc::new(server);
+    eprintln!("listening on http://{}", address);
+
+    let workers: Vec<_> = (0..threads.max(1))
+        .map(|_| {
+            let server = server.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    respond(request, &limits);
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+fn respond(mut request: Request, limits: &Limits) {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    let content_type = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Content-Type"))
+        .map(|header| header.value.as_str().to_string())
+        .unwrap_or_default();
+    let (status, body) = handle(&method, &url, &content_type, request.as_reader(), limits);
+
+    let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(header);
+    // The client may have gone away, there's nobody left to tell
+    let _ = request.respond(response);
+}
+
+/// Answers a request for `url`, returning its status code and JSON body
+fn handle(
+    method: &Method,
+    url: &str,
+    content_type: &str,
+    body: &mut dyn Read,
+    limits: &Limits,
+) -> (u16, Value) {
+    match (method, url) {
+        (Method::Post, "/run") => match read_run(content_type, body) {
+            Ok(run) => (200, run.execute(limits)),
+            Err(message) => (400, json!({ "error": message })),
+        },
+        (_, "/run") => (405, json!({ "error": "use POST" })),
+        _ => (404, json!({ "error": "not found" })),
+    }
+}
+
+/// A program to run and how far the client wants to let it go
+#[derive(Debug)]
+struct Run {
+    program: String,
+    input: Vec<u8>,
+    max_iterations: Option<u64>,
+    timeout: Option<Duration>,
+    tape_size: Option<usize>,
+    max_output: Option<usize>,
+}
+
+impl Run {
+    /// Compiles the program within `limits`, also returning how long it may run. Parse errors are
+    /// returned ready to send as a response's `error`.
+    fn compile(&self, limits: &Limits) -> Result<(Interpreter, Duration), Value> {
+        let mut instructions = bfc_ir::parse(&self.program).map_err(|err| {
+            let message = format!(
+                "{} at {}",
+                err.message,
+                Location::new(&self.program, err.position.start)
+            );
+            json!({ "kind": "ParseError", "message": message })
+        })?;
+        (instructions, _) = bfc_ir::optimize(instructions, OptimisationsFlags::all());
+
+        let limits = self.limits(limits);
+        let interpreter = Interpreter::builder()
+            .iteration_limit(Some(limits.max_iterations))
+            .tape_size(limits.tape_size)
+            .max_output(limits.max_output)
+            .build_bytecode(Bytecode::compile(&instructions).optimize(Pass::ALL));
+        Ok((interpreter, limits.timeout))
+    }
+
+    /// The server's `limits` lowered to whatever the client asked for. Asking for 0, like leaving
+    /// a limit out, gets as much as the server allows.
+    fn limits(&self, limits: &Limits) -> Limits {
+        fn lower<T: Ord + Default + Copy>(requested: Option<T>, limit: T) -> T {
+            requested
+                .filter(|requested| *requested != T::default())
+                .map_or(limit, |requested| requested.min(limit))
+        }
+
+        Limits {
+            max_iterations: self
+                .max_iterations
+                .and_then(NonZeroU64::new)
+                .map_or(limits.max_iterations, |max| max.min(limits.max_iterations)),
+            timeout: lower(self.timeout, limits.timeout),
+            tape_size: lower(self.tape_size, limits.tape_size),
+            max_output: lower(self.max_output, limits.max_output),
+        }
+    }
+
+    fn execute(&self, limits: &Limits) -> Value {
+        let start = Instant::now();
+        let (interpreter, timeout) = match self.compile(limits) {
+            Ok(compiled) => compiled,
+            Err(error) => {
+                return json!({ "output": "", "iterations": 0, "elapsed_ms": 0, "error": error })
+            }
+        };
+
+        let (outcome, state) = interpreter.run_with_deadline(self.input.clone(), start + timeout);
+        let error = outcome.error.as_ref().map_or(Value::Null, runtime_error);
+
+        json!({
+            "output": String::from_utf8_lossy(&outcome.output),
+            "iterations": state.iterations,
+            "elapsed_ms": start.elapsed().as_millis() as u64,
+            "error": error,
+        })
+    }
+}
+
+fn runtime_error(err: &RunTimeError) -> Value {
+    json!({ "kind": format!("{:?}", err.kind()), "message": err.to_string() })
+}
+
+/// Accepts WebSocket connections on `address`, each an interactive session on its own thread
+///
+/// The client's first message is a JSON object like a `/run` request, its `input` is queued
+/// before anything else. After that every message is input, an empty binary message closes the
+/// input so the program sees EOF, and closing the socket stops the program. Output is sent as
+/// binary messages as it's written, then a final `{"done": true, "error": ...}` before the server
+/// closes the socket.
+pub fn serve_sessions(address: &str, limits: Limits) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    eprintln!("accepting sessions on ws://{}", address);
+
+    for stream in listener.incoming().flatten() {
+        thread::spawn(move || {
+            // However the session ends there's nobody left to report errors to
+            let _ = session(stream, &limits);
+        });
+    }
+    Ok(())
+}
+
+fn session(stream: TcpStream, limits: &Limits) -> tungstenite::Result<()> {
+    let mut socket = match tungstenite::accept(stream) {
+        Ok(socket) => socket,
+        // Not a WebSocket client
+        Err(_) => return Ok(()),
+    };
+
+    // Dropping the session stops the program however this returns
+    let mut session = Session::new(*limits);
+    while !session.is_finished() {
+        let mut replies = match socket.read() {
+            Ok(Message::Close(_)) => return socket.flush(),
+            Ok(message) => session.receive(message),
+            Err(tungstenite::Error::Io(err))
+                if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                Vec::new()
+            }
+            Err(err) => return Err(err),
+        };
+        if session.is_running() {
+            // Reads time out so output can be forwarded while the client is quiet
+            socket.get_ref().set_read_timeout(Some(POLL))?;
+            replies.extend(session.poll());
+        }
+        for reply in replies {
+            socket.send(reply)?;
+        }
+    }
+
+    socket.close(None)?;
+    socket.flush()
+}
+
+/// The server's side of an interactive session, everything but the socket
+struct Session {
+    limits: Limits,
+    state: State,
+}
+
+enum State {
+    /// Waiting for the first message, the run's configuration
+    Configuring,
+    Running(Running),
+    Finished,
+}
+
+/// The program a session started
+struct Running {
+    /// Dropped once the client closes the input
+    input: Option<Sender<Wrapping<u8>>>,
+    output: Receiver<Result<Wrapping<u8>, RunTimeError>>,
+    handle: SpawnHandle,
+    deadline: Instant,
+    timed_out: bool,
+}
+
+impl Session {
+    fn new(limits: Limits) -> Self {
+        Session {
+            limits,
+            state: State::Configuring,
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        matches!(self.state, State::Running(_))
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(self.state, State::Finished)
+    }
+
+    /// Handles a message from the client, returning what to send back. The first text message
+    /// configures the run, every message after it is input.
+    fn receive(&mut self, message: Message) -> Vec<Message> {
+        match (&mut self.state, message) {
+            (State::Configuring, Message::Text(text)) => {
+                let run = match from_json(text.as_bytes()) {
+                    Ok(run) => run,
+                    Err(message) => {
+                        return vec![
+                            self.finish(json!({ "kind": "BadRequest", "message": message }))
+                        ]
+                    }
+                };
+                let (interpreter, timeout) = match run.compile(&self.limits) {
+                    Ok(compiled) => compiled,
+                    Err(error) => return vec![self.finish(error)],
+                };
+
+                let deadline = Instant::now() + timeout;
+                let (input, output, handle) = interpreter.spawn();
+                let input = Some(input);
+                send_input(&input, &run.input);
+                self.state = State::Running(Running {
+                    input,
+                    output,
+                    handle,
+                    deadline,
+                    timed_out: false,
+                });
+            }
+            (State::Running(running), Message::Binary(bytes)) => running.receive(&bytes),
+            (State::Running(running), Message::Text(text)) => running.receive(text.as_bytes()),
+            // Pings, and anything but text before the configuration
+            _ => {}
+        }
+        Vec::new()
+    }
+
+    /// Output written since the last poll, followed by how the session ended once the program
+    /// has finished
+    fn poll(&mut self) -> Vec<Message> {
+        let running = match &mut self.state {
+            State::Running(running) => running,
+            _ => return Vec::new(),
+        };
+
+        let mut output = Vec::new();
+        let finished = loop {
+            match running.output.try_recv() {
+                Ok(Ok(byte)) => output.push(byte.0),
+                Ok(Err(err)) => break Some(Some(err)),
+                Err(TryRecvError::Empty) => break None,
+                Err(TryRecvError::Disconnected) => break Some(None),
+            }
+        };
+        let error = finished.map(|err| match err {
+            // Cancelled below
+            Some(_) if running.timed_out => {
+                json!({ "kind": "TimedOut", "message": "the session ran out of time" })
+            }
+            Some(err) => runtime_error(&err),
+            None => Value::Null,
+        });
+        if !running.timed_out && Instant::now() >= running.deadline {
+            running.timed_out = true;
+            running.handle.cancel();
+        }
+
+        let mut messages = Vec::new();
+        if !output.is_empty() {
+            messages.push(Message::Binary(output));
+        }
+        if let Some(error) = error {
+            messages.push(self.finish(error));
+        }
+        messages
+    }
+
+    /// Ends the session, returning the message that tells the client how
+    fn finish(&mut self, error: Value) -> Message {
+        self.state = State::Finished;
+        Message::Text(json!({ "done": true, "error": error }).to_string())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let State::Running(running) = &self.state {
+            running.handle.cancel();
+        }
+    }
+}
+
+impl Running {
+    /// Queues input for the program, an empty message closes the input so it sees EOF
+    fn receive(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            self.input = None;
+        } else {
+            send_input(&self.input, bytes);
+        }
+    }
+}
+
+fn send_input(input: &Option<Sender<Wrapping<u8>>>, bytes: &[u8]) {
+    if let Some(input) = input {
+        for &byte in bytes {
+            // The program has finished, what's left is never read
+            if input.send(Wrapping(byte)).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+fn read_run(content_type: &str, reader: &mut dyn Read) -> Result<Run, String> {
+    let mut body = Vec::new();
+    reader
+        .take(MAX_BODY as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(|err| format!("failed to read the request: {}", err))?;
+    if body.len() > MAX_BODY {
+        return Err(format!("requests are limited to {} bytes", MAX_BODY));
+    }
+
+    match content_type.split_once("boundary=") {
+        Some((kind, boundary)) if kind.starts_with("multipart/form-data") => {
+            from_fields(multipart(&body, boundary.trim_matches('"'))?)
+        }
+        _ => from_json(&body),
+    }
+}
+
+fn from_json(body: &[u8]) -> Result<Run, String> {
+    let body: Value =
+        serde_json::from_slice(body).map_err(|err| format!("invalid JSON: {}", err))?;
+
+    let number = |name: &str| match &body[name] {
+        Value::Null => Ok(None),
+        value => value
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| format!("`{}` must be a non-negative integer", name)),
+    };
+    Ok(Run {
+        program: body["program"]
+            .as_str()
+            .ok_or("`program` must be a string")?
+            .to_string(),
+        input: match &body["input"] {
+            Value::Null => Vec::new(),
+            input => input
+                .as_str()
+                .ok_or("`input` must be a string")?
+                .as_bytes()
+                .to_vec(),
+        },
+        max_iterations: number("max_iterations")?,
+        timeout: number("timeout_ms")?.map(Duration::from_millis),
+        tape_size: number("tape_size")?.map(|tape_size| tape_size as usize),
+        max_output: number("max_output")?.map(|max| max as usize),
+    })
+}
+
+fn from_fields(mut fields: HashMap<String, Vec<u8>>) -> Result<Run, String> {
+    let mut number = |name: &str| {
+        fields
+            .remove(name)
+            .map(|value| {
+                String::from_utf8_lossy(&value)
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|_| format!("`{}` must be a non-negative integer", name))
+            })
+            .transpose()
+    };
+    let max_iterations = number("max_iterations")?;
+    let timeout = number("timeout_ms")?.map(Duration::from_millis);
+    let tape_size = number("tape_size")?.map(|tape_size| tape_size as usize);
+    let max_output = number("max_output")?.map(|max| max as usize);
+
+    let program = fields
+        .remove("program")
+        .ok_or("missing the `program` field")?;
+    Ok(Run {
+        program: String::from_utf8(program).map_err(|_| "`program` must be UTF-8")?,
+        input: fields.remove("input").unwrap_or_default(),
+        max_iterations,
+        timeout,
+        tape_size,
+        max_output,
+    })
+}
+
+/// Splits a `multipart/form-data` body into its fields by name
+fn multipart(body: &[u8], boundary: &str) -> Result<HashMap<String, Vec<u8>>, String> {
+    let delimiter = format!("--{}", boundary);
+    let mut fields = HashMap::new();
+
+    for part in split(body, delimiter.as_bytes()).skip(1) {
+        // The closing delimiter is followed by `--`
+        if part.starts_with(b"--") {
+            break;
+        }
+        let part = part.strip_prefix(b"\r\n").unwrap_or(part);
+        let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+
+        let end = find(part, b"\r\n\r\n").ok_or("a multipart field has no headers")?;
+        let headers = String::from_utf8_lossy(&part[..end]);
+        let name = headers
+            .lines()
+            .filter(|line| {
+                line.to_ascii_lowercase()
+                    .starts_with("content-disposition:")
+            })
+            .flat_map(|line| line.split(';'))
+            .find_map(|parameter| parameter.trim().strip_prefix("name="))
+            .map(|name| name.trim_matches('"').to_string())
+            .ok_or("a multipart field has no name")?;
+        fields.insert(name, part[end + 4..].to_vec());
+    }
+
+    Ok(fields)
+}
+
+fn split<'a>(haystack: &'a [u8], needle: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+    let mut rest = Some(haystack);
+    std::iter::from_fn(move || {
+        let current = rest?;
+        match find(current, needle) {
+            Some(at) => {
+                rest = Some(&current[at + needle.len()..]);
+                Some(&current[..at])
+            }
+            None => {
+                rest = None;
+                Some(current)
+            }
+        }
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits {
+            max_iterations: NonZeroU64::new(10_000).unwrap(),
+            timeout: Duration::from_secs(5),
+            tape_size: 100,
+            max_output: 64,
+        }
+    }
+
+    fn post(content_type: &str, body: &[u8]) -> (u16, Value) {
+        handle(
+            &Method::Post,
+            "/run",
+            content_type,
+            &mut &body[..],
+            &limits(),
+        )
+    }
+
+    fn run(request: Value) -> Value {
+        let (status, body) = post("application/json", request.to_string().as_bytes());
+        assert_eq!(status, 200, "{}", body);
+        body
+    }
+
+    #[test]
+    fn limits_only_lower() {
+        let requested = |request: Value| {
+            from_json(request.to_string().as_bytes())
+                .unwrap()
+                .limits(&limits())
+        };
+
+        assert_eq!(requested(json!({ "program": "" })), limits());
+        assert_eq!(
+            requested(json!({
+                "program": "",
+                "max_iterations": 50,
+                "timeout_ms": 100,
+                "tape_size": 10,
+                "max_output": 8,
+            })),
+            Limits {
+                max_iterations: NonZeroU64::new(50).unwrap(),
+                timeout: Duration::from_millis(100),
+                tape_size: 10,
+                max_output: 8,
+            }
+        );
+        assert_eq!(
+            requested(json!({
+                "program": "",
+                "max_iterations": 1_000_000,
+                "timeout_ms": 60_000,
+                "tape_size": 1_000_000,
+                "max_output": 1_000_000,
+            })),
+            limits()
+        );
+        // 0 is the server's limit, not none at all
+        assert_eq!(
+            requested(json!({
+                "program": "",
+                "max_iterations": 0,
+                "timeout_ms": 0,
+                "tape_size": 0,
+                "max_output": 0,
+            })),
+            limits()
+        );
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let body = run(json!({ "program": "+[]", "max_iterations": 1_000_000 }));
+        assert_eq!(body["error"]["kind"], "MaxIterationsExceeded");
+        assert!(body["iterations"].as_u64().unwrap() <= 10_001);
+
+        let body = run(json!({ "program": ">>>", "tape_size": 2 }));
+        assert_eq!(body["error"]["kind"], "OutOfBoundsRight");
+
+        let body = run(json!({ "program": "+[.]", "max_output": 4 }));
+        assert_eq!(body["error"]["kind"], "OutputLimitExceeded");
+        assert_eq!(body["output"], "\u{1}\u{1}\u{1}\u{1}");
+    }
+
+    #[test]
+    fn runs() {
+        let body = run(json!({ "program": ",.,.", "input": "hi" }));
+        assert_eq!(body["output"], "hi");
+        assert_eq!(body["error"], Value::Null);
+
+        // Failed runs keep what they wrote
+        let body = run(json!({ "program": ",.<", "input": "a" }));
+        assert_eq!(body["output"], "a");
+        assert_eq!(body["error"]["kind"], "OutOfBoundsLeft");
+        assert!(body["error"]["message"].is_string());
+
+        let body = run(json!({ "program": "+[" }));
+        assert_eq!(body["output"], "");
+        assert_eq!(body["iterations"], 0);
+        assert_eq!(body["error"]["kind"], "ParseError");
+        assert!(body["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("line 1"));
+    }
+
+    #[test]
+    fn multipart() {
+        let body = "--XYZ\r\n\
+                    Content-Disposition: form-data; name=\"program\"\r\n\r\n\
+                    ,[.,]\r\n\
+                    --XYZ\r\n\
+                    Content-Disposition: form-data; name=\"input\"\r\n\r\n\
+                    hey\r\n\
+                    --XYZ\r\n\
+                    Content-Disposition: form-data; name=\"max_output\"\r\n\r\n\
+                    2\r\n\
+                    --XYZ--\r\n";
+        let (status, body) = post("multipart/form-data; boundary=XYZ", body.as_bytes());
+        assert_eq!(status, 200);
+        assert_eq!(body["output"], "he");
+        assert_eq!(body["error"]["kind"], "OutputLimitExceeded");
+
+        let (status, body) = post("multipart/form-data; boundary=XYZ", b"--XYZ--\r\n");
+        assert_eq!(status, 400);
+        assert_eq!(body, json!({ "error": "missing the `program` field" }));
+    }
+
+    #[test]
+    fn bad_requests() {
+        let (status, body) = post("application/json", b"{");
+        assert_eq!(status, 400);
+        assert!(body["error"].as_str().unwrap().starts_with("invalid JSON"));
+
+        let (status, body) = post("application/json", br#"{"program": 1}"#);
+        assert_eq!(status, 400);
+        assert_eq!(body, json!({ "error": "`program` must be a string" }));
+
+        let (status, body) = post(
+            "application/json",
+            br#"{"program": "", "max_iterations": -1}"#,
+        );
+        assert_eq!(status, 400);
+        assert_eq!(
+            body,
+            json!({ "error": "`max_iterations` must be a non-negative integer" })
+        );
+
+        let (status, body) = post("application/json", &vec![b' '; MAX_BODY + 1]);
+        assert_eq!(status, 400);
+        assert_eq!(
+            body,
+            json!({ "error": format!("requests are limited to {} bytes", MAX_BODY) })
+        );
+    }
+
+    #[test]
+    fn routing() {
+        let request = |method, url: &str| handle(&method, url, "", &mut io::empty(), &limits());
+
+        assert_eq!(
+            request(Method::Get, "/run"),
+            (405, json!({ "error": "use POST" }))
+        );
+        assert_eq!(
+            request(Method::Post, "/walk"),
+            (404, json!({ "error": "not found" }))
+        );
+        assert_eq!(
+            request(Method::Get, "/"),
+            (404, json!({ "error": "not found" }))
+        );
+    }
+
+    /// A session configured with `config`, and what it replied
+    fn configured(config: Value) -> (Session, Vec<Message>) {
+        let mut session = Session::new(limits());
+        let replies = session.receive(Message::Text(config.to_string()));
+        (session, replies)
+    }
+
+    /// Polls until the session finishes, returning everything the program wrote and the final
+    /// message's `error`
+    fn finish(session: &mut Session) -> (Vec<u8>, Value) {
+        let mut output = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            for message in session.poll() {
+                match message {
+                    Message::Binary(bytes) => output.extend(bytes),
+                    Message::Text(text) => {
+                        assert!(session.is_finished());
+                        let done: Value = serde_json::from_str(&text).unwrap();
+                        assert_eq!(done["done"], true);
+                        return (output, done["error"].clone());
+                    }
+                    message => panic!("unexpected {:?}", message),
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("the session never finished");
+    }
+
+    /// The `error` of a session's only reply, which has to be the final one
+    fn done(session: &Session, replies: &[Message]) -> Value {
+        assert!(session.is_finished());
+        match replies {
+            [Message::Text(text)] => {
+                let done: Value = serde_json::from_str(text).unwrap();
+                assert_eq!(done["done"], true);
+                done["error"].clone()
+            }
+            replies => panic!("expected a single text message, got {:?}", replies),
+        }
+    }
+
+    #[test]
+    fn session_configuration() {
+        // Only a text message configures the session
+        let mut session = Session::new(limits());
+        assert!(session.receive(Message::Binary(b"hi".to_vec())).is_empty());
+        assert!(session.poll().is_empty());
+        assert!(!session.is_running());
+
+        let replies = session.receive(Message::Text("{".to_string()));
+        assert_eq!(done(&session, &replies)["kind"], "BadRequest");
+
+        let (session, replies) = configured(json!({ "program": "[" }));
+        assert_eq!(done(&session, &replies)["kind"], "ParseError");
+    }
+
+    #[test]
+    fn session_io() {
+        let (mut session, replies) = configured(json!({ "program": ",.,.", "input": "a" }));
+        assert!(replies.is_empty());
+        assert!(session.is_running());
+
+        assert!(session.receive(Message::Binary(b"b".to_vec())).is_empty());
+        assert_eq!(finish(&mut session), (b"ab".to_vec(), Value::Null));
+        assert!(session.poll().is_empty());
+    }
+
+    #[test]
+    fn session_eof() {
+        // An empty binary message closes the input, the program reads past its end
+        let (mut session, _) = configured(json!({ "program": ",[.,]", "input": "hi" }));
+        session.receive(Message::Binary(Vec::new()));
+
+        let (output, error) = finish(&mut session);
+        assert_eq!(output, b"hi");
+        assert_eq!(error["kind"], "InputExhausted");
+    }
+
+    #[test]
+    fn session_timeout() {
+        let (mut session, _) = configured(json!({ "program": ".,", "timeout_ms": 20 }));
+
+        let (output, error) = finish(&mut session);
+        assert_eq!(output, [0]);
+        assert_eq!(error["kind"], "TimedOut");
+    }
+}
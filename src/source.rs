@@ -0,0 +1,148 @@
+use alloc::string::{String, ToString};
+use core::{
+    fmt::{self, Write},
+    ops::Range,
+};
+
+/// A 1-based line and column in a program's source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Location {
+    pub line: usize,
+    /// Counted in characters, not bytes
+    pub column: usize,
+}
+
+impl Location {
+    /// Finds the line and column of byte `offset` in `program`. Offsets past the end are placed
+    /// just after the last character.
+    pub fn new(program: &str, offset: usize) -> Self {
+        let before = &program[..floor_char_boundary(program, offset)];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+
+        Self {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// Formats `message` followed by the source line containing byte `offset` with a caret under it
+///
+/// ```text
+/// runtime error: moved off the left end of the tape at offset 7 (pointer -1, after 8 iterations)
+///  --> line 1, column 8
+///   |
+/// 1 | +++>++<<
+///   |        ^
+/// ```
+pub fn annotate(program: &str, offset: usize, message: &str) -> String {
+    annotate_span(program, offset..offset + 1, message)
+}
+
+/// ANSI escapes for the parts of an annotated snippet, empty for plain text
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Style {
+    /// The caret underline
+    pub(crate) accent: &'static str,
+    /// Line numbers, the arrow and the bars
+    pub(crate) gutter: &'static str,
+    pub(crate) reset: &'static str,
+}
+
+impl Style {
+    pub(crate) const PLAIN: Style = Style {
+        accent: "",
+        gutter: "",
+        reset: "",
+    };
+}
+
+/// Like [`annotate`], underlining the part of `span` on its first line
+pub(crate) fn annotate_span(program: &str, span: Range<usize>, message: &str) -> String {
+    annotate_span_styled(program, span, message, Style::PLAIN)
+}
+
+/// Like [`annotate_span`], coloring the gutter and underline with `style`
+pub(crate) fn annotate_span_styled(
+    program: &str,
+    span: Range<usize>,
+    message: &str,
+    style: Style,
+) -> String {
+    let location = Location::new(program, span.start);
+    let line = program.lines().nth(location.line - 1).unwrap_or("");
+    let gutter = " ".repeat(location.line.to_string().len());
+
+    // Keep tabs so the caret lines up however the terminal renders them
+    let indent: String = line
+        .chars()
+        .take(location.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let underline = program
+        [floor_char_boundary(program, span.start)..floor_char_boundary(program, span.end)]
+        .chars()
+        .take_while(|c| *c != '\n')
+        .count()
+        .max(1);
+
+    let Style {
+        accent,
+        gutter: blue,
+        reset,
+    } = style;
+    let mut annotated = String::new();
+    writeln!(annotated, "{}", message).unwrap();
+    writeln!(annotated, "{}{}-->{} {}", gutter, blue, reset, location).unwrap();
+    writeln!(annotated, "{}{} |{}", blue, gutter, reset).unwrap();
+    writeln!(annotated, "{}{} |{} {}", blue, location.line, reset, line).unwrap();
+    write!(
+        annotated,
+        "{}{} |{} {}{}{}{}",
+        blue,
+        gutter,
+        reset,
+        indent,
+        accent,
+        "^".repeat(underline),
+        reset
+    )
+    .unwrap();
+    annotated
+}
+
+/// Splits a program that carries its own input, the convention of putting it after a `!`. Only
+/// a `!` outside every loop counts, returning the program before it and the input after it, or
+/// `None` if there's no such `!`.
+///
+/// ```ignore
+/// assert_eq!(bfi::split_input(",[.,]!hello"), (",[.,]", Some("hello")));
+/// ```
+pub fn split_input(source: &str) -> (&str, Option<&str>) {
+    let mut depth = 0usize;
+    for (offset, c) in source.char_indices() {
+        match c {
+            '[' => depth += 1,
+            // Unbalanced programs are left for the parser to report
+            ']' => depth = depth.saturating_sub(1),
+            '!' if depth == 0 => return (&source[..offset], Some(&source[offset + 1..])),
+            _ => {}
+        }
+    }
+
+    (source, None)
+}
+
+fn floor_char_boundary(s: &str, offset: usize) -> usize {
+    let mut offset = offset.min(s.len());
+    while !s.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
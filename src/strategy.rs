@@ -0,0 +1,24 @@
+//! [`proptest`] strategies for Brainfuck programs and their inputs
+//!
+//! ```ignore
+//! proptest! {
+//!     #[test]
+//!     fn halts_or_errors(program in bfi::strategy::program(64), input in bfi::strategy::input(8)) {
+//!         let limit = std::num::NonZeroU64::new(10_000);
+//!         let _ = bfi::execute_outcome(&program, input, limit, bfi::CellWidth::U8);
+//!     }
+//! }
+//! ```
+
+use proptest::{collection::vec, prelude::*};
+
+/// Programs of up to `max_len` commands with balanced brackets. Shrinks towards shorter programs
+/// made of earlier commands in `+-<>,.[]`.
+pub fn program(max_len: usize) -> impl Strategy<Value = String> {
+    vec(any::<u8>(), 0..=max_len).prop_map(|bytes| crate::fuzz::program(&bytes))
+}
+
+/// Inputs of up to `max_len` arbitrary bytes
+pub fn input(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 0..=max_len)
+}
@@ -1,69 +1,2199 @@
-use crate::{test_blocking, TestResult, TestResults};
-use std::path::Path;
+use crate::{
+    execute_outcome, execute_str, execute_str_lossy, format_ir, format_with, fuzz, hexdiff, judge,
+    linediff, lint, parse_with_alphabet, preprocess, print_string, run_tests, split_input,
+    strip_comments, test_blocking, tests_parallel, to_brainfuck, to_c, to_dot, to_rust, to_wasm,
+    verify, Alphabet, BoundsCheck, Bytecode, CellWidth, CodegenOptions, Diagnostic, Dialect,
+    DumpTarget, EofBehavior, Event, ExtensionContext, FormatOptions, InputRead, Interpreter,
+    IterationCost, Limit, LintKind, NegativeCells, Op, Optimisations, OptimisationsFlags,
+    OverflowBehavior, Pass, Program, Recording, ReplayError, ResourceLimits, RunOutcome,
+    RunTimeError, RunTimeErrorKind, Script, ScriptError, Severity, TestCase, TestOutcome,
+    TestResults, Verdict,
+};
+use bfc_ir::AstNode;
+use std::{
+    num::{NonZeroU64, Wrapping},
+    time::{Duration, Instant},
+};
 
-fn test_file<P: AsRef<Path>>(program: P, output: P) {
-    // Read the file
-    let program = std::fs::read_to_string(program).unwrap();
-    let expected: Vec<u8> = std::fs::read_to_string(output).unwrap().bytes().collect();
+#[test]
+fn inputs() {
+    test_blocking(",.,.,.", vec![1, 2, 3], vec![1, 2, 3], None, CellWidth::U8);
+}
 
-    match test_blocking(&program, vec![], expected, u64::MAX) {
-        TestResults::OutputsDontMatchInputs => unreachable!(),
-        TestResults::ParseError(e) => panic!("failed to compile program {:?}", e),
-        TestResults::Results(results) => {
-            for r in results {
-                match r {
-                    TestResult::Ok => {}
-                    TestResult::RunTimeError(e) => panic!("RunTimeError {:?}", e),
-                    TestResult::UnexpectedOutput { expected, output } => {
-                        assert_eq!(expected, output)
-                    }
-                }
-            }
+#[test]
+fn parallel_results_keep_input_order() {
+    // Echo the first byte, then the second byte picks the behaviour:
+    // 0 prints a zero, 1 walks off the left of the tape, anything larger spins forever
+    let program = ",.,[-[+[]]<].";
+    let inputs = vec![vec![1, 0], vec![2, 0], vec![3, 1], vec![4, 2], vec![5, 0]];
+    let outputs = vec![vec![1, 0], vec![2], vec![3], vec![4], vec![5, 0]];
+
+    let results = match tests_parallel(
+        program,
+        inputs.into_iter(),
+        outputs.into_iter(),
+        None,
+        CellWidth::U8,
+        2,
+        Some(Duration::from_millis(100)),
+    ) {
+        TestResults::Results(results) => results,
+        _ => panic!("expected results"),
+    };
+
+    assert!(matches!(results[0].outcome, TestOutcome::Ok));
+    assert!(matches!(
+        results[1].outcome,
+        TestOutcome::UnexpectedOutput { .. }
+    ));
+    assert!(matches!(
+        results[2].outcome,
+        TestOutcome::RunTimeError(RunOutcome {
+            error: Some(RunTimeError::OutOfBoundsLeft(_)),
+            ..
+        })
+    ));
+    assert!(matches!(results[3].outcome, TestOutcome::TimedOut(_)));
+    assert!(results[3].elapsed >= Duration::from_millis(100));
+    assert!(matches!(results[4].outcome, TestOutcome::Ok));
+    assert!(results.iter().all(|r| r.iterations > 0));
+}
+
+#[test]
+fn named_test_cases() {
+    let cases = [
+        TestCase::new("echo", "ab", "ab"),
+        TestCase::new("wrong", "ab", "ba"),
+        TestCase::new("limited", "ab", "ab").iteration_limit(NonZeroU64::new(2)),
+    ];
+
+    let report = run_tests(",.,.", &cases);
+    assert!(!report.passed());
+
+    let failures: Vec<&str> = report.failures().map(|(name, _)| name).collect();
+    assert_eq!(failures, ["wrong", "limited"]);
+    // Failures keep what the run produced before the error
+    assert!(matches!(
+        &report.results[2].1.outcome,
+        TestOutcome::RunTimeError(RunOutcome {
+            error: Some(RunTimeError::MaxIterationsExceeded(_)),
+            output,
+            iterations: 3,
+            ..
+        }) if output == b"a"
+    ));
+}
+
+#[test]
+fn test_case_timeout() {
+    let cases = [
+        TestCase::new("spins", "", "").timeout(Duration::from_millis(50)),
+        TestCase::new("quick", "", "").iteration_limit(NonZeroU64::new(100)),
+    ];
+
+    // Never halts, the first case is stopped by the clock and the second by its iteration limit
+    let report = run_tests("+[]", &cases);
+    assert!(matches!(
+        report.results[0].1.outcome,
+        TestOutcome::TimedOut(_)
+    ));
+    assert!(report.results[0].1.elapsed >= Duration::from_millis(50));
+    assert!(report.results[0].1.iterations > 0);
+    assert!(matches!(
+        report.results[1].1.outcome,
+        TestOutcome::RunTimeError(RunOutcome {
+            error: Some(RunTimeError::MaxIterationsExceeded(_)),
+            ..
+        })
+    ));
+
+    assert_eq!(report.slowest(1)[0].0, "spins");
+    assert!(report.elapsed() >= report.results[0].1.elapsed);
+}
+
+#[test]
+fn expected_errors() {
+    let cases = [
+        TestCase::error("left", "", RunTimeErrorKind::OutOfBoundsLeft),
+        TestCase::error("wrong kind", "", RunTimeErrorKind::InputExhausted),
+        TestCase::parse_error("parses"),
+    ];
+
+    let report = run_tests("<.", &cases);
+    assert!(matches!(report.results[0].1.outcome, TestOutcome::Ok));
+    assert!(matches!(
+        report.results[1].1.outcome,
+        TestOutcome::RunTimeError(RunOutcome {
+            error: Some(RunTimeError::OutOfBoundsLeft(_)),
+            ..
+        })
+    ));
+    assert!(matches!(
+        report.results[2].1.outcome,
+        TestOutcome::RunTimeError(_)
+    ));
+
+    let cases = [
+        TestCase::parse_error("unbalanced"),
+        TestCase::new("runs", "", ""),
+    ];
+
+    let report = run_tests("[", &cases);
+    assert!(matches!(report.results[0].1.outcome, TestOutcome::Ok));
+    assert!(matches!(
+        report.results[1].1.outcome,
+        TestOutcome::ParseError(_)
+    ));
+
+    let report = run_tests("+", &[TestCase::error("halts", "", RunTimeErrorKind::Io)]);
+    assert!(matches!(
+        report.results[0].1.outcome,
+        TestOutcome::MissingError(_)
+    ));
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn named_test_cases_in_parallel() {
+    let cases: Vec<TestCase> = (0..32u8)
+        .map(|i| TestCase::new(format!("case {}", i), [i], [i + 1]))
+        .collect();
+
+    let report = crate::run_tests_parallel(",+.", &cases);
+    assert!(report.passed());
+
+    let names: Vec<&str> = report
+        .results
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect();
+    let expected: Vec<String> = (0..32).map(|i| format!("case {}", i)).collect();
+    assert_eq!(names, expected);
+}
+
+#[test]
+fn hexdump_diff() {
+    assert_eq!(hexdiff(b"same", b"same"), "");
+
+    let diff = hexdiff(b"abcdefghijkl", b"abcdefghiJkl");
+    let lines: Vec<&str> = diff.lines().collect();
+    assert_eq!(
+        lines[0],
+        "first difference at byte 9: expected 0x6a, got 0x4a"
+    );
+    assert!(lines[3].starts_with(">000008  69 6a 6b 6c"));
+    assert_eq!(lines[4].find("^^"), Some(12));
+
+    let diff = hexdiff(b"hello\n", b"hello");
+    assert!(diff.starts_with("output ended after 5 bytes, expected 6"));
+}
+
+#[test]
+fn report_formats() {
+    let cases = [
+        TestCase::new("echo", "a", "a"),
+        TestCase::new("<wrong>", "a", "b"),
+        TestCase::error("overflow", "a", RunTimeErrorKind::OutOfBoundsRight),
+        TestCase::new("limited", "a", "a").iteration_limit(NonZeroU64::new(1)),
+    ];
+    let report = run_tests(",.", &cases);
+
+    let junit = report.junit("suite");
+    assert!(junit.contains(r#"<testsuite name="suite" tests="4" failures="2" errors="1""#));
+    assert!(junit.contains(r#"<testcase name="echo""#));
+    assert!(junit.contains(r#"<testcase name="&lt;wrong&gt;""#));
+    assert!(junit.contains(r#"<failure message="output didn&apos;t match">"#));
+    assert!(junit.contains(r#"<failure message="expected an error but the program halted">"#));
+    assert!(junit.contains(r#"<error message="exceeded the maximum number of iterations"#));
+
+    let tap = report.tap();
+    let tap: Vec<&str> = tap.lines().collect();
+    assert_eq!(
+        tap[..4],
+        [
+            "TAP version 13",
+            "1..4",
+            "ok 1 - echo",
+            "not ok 2 - <wrong>"
+        ]
+    );
+    assert_eq!(tap[4], "# output didn't match");
+    assert!(tap.contains(&"not ok 3 - overflow"));
+}
+
+#[test]
+fn human_report() {
+    let cases = [
+        TestCase::new("echo", "abc", "abc"),
+        TestCase::new("wrong", "a\nb", "a\nc"),
+        TestCase::new("unread", "", "a"),
+        TestCase::new("limited", "a", "a").iteration_limit(NonZeroU64::new(1)),
+    ];
+    let report = run_tests(",.,.,.", &cases);
+    assert!(!report.passed());
+
+    let human = report.human(",.,.,.");
+    let lines: Vec<&str> = human.lines().collect();
+    assert!(
+        lines[0].starts_with("echo: ok (6 iterations in "),
+        "{}",
+        human
+    );
+    assert_eq!(
+        lines[1..6],
+        [
+            "wrong: FAILED, output didn't match",
+            "  a",
+            "- c",
+            "+ b",
+            ""
+        ]
+    );
+    assert_eq!(
+        lines[6],
+        "first difference at byte 2: expected 0x63, got 0x62"
+    );
+    assert!(
+        human.contains("unread: FAILED\nruntime error: read past the end of the input at offset 0")
+    );
+    assert!(human.contains(" --> line 1, column 1\n  |\n1 | ,.,.,.\n  | ^\n"));
+    assert!(human.contains("limited: FAILED\nruntime error: exceeded the maximum number"));
+
+    assert!(run_tests(",.,.,.", &cases[..1]).passed());
+}
+
+#[test]
+fn line_diff() {
+    assert_eq!(linediff(b"same\n", b"same\n"), "  same\n  \n");
+    assert_eq!(
+        linediff(b"hello\nworld", b"hello\nWorld\n!"),
+        "  hello\n- world\n+ World\n+ !\n"
+    );
+}
+
+#[test]
+fn judge_verdicts() {
+    let limits = ResourceLimits {
+        time: Duration::from_millis(100),
+        memory: 16,
+        output: 4,
+    };
+    let verdict = |program: &str| judge(program, b"ab", b"ab", &limits).verdict;
+
+    assert_eq!(verdict(",.,."), Verdict::Accepted);
+    assert_eq!(verdict(",.,+."), Verdict::WrongAnswer);
+    assert_eq!(verdict("+[]"), Verdict::TimeLimitExceeded);
+    assert_eq!(verdict("+[>+]"), Verdict::MemoryLimitExceeded);
+    assert_eq!(verdict("+[.]"), Verdict::OutputLimitExceeded);
+    assert_eq!(verdict("<"), Verdict::RuntimeError);
+    assert_eq!(verdict("["), Verdict::CompileError);
+
+    let judgement = judge(",.,.", b"ab", b"ab", &limits);
+    assert_eq!(judgement.memory, 16);
+    assert!(judgement
+        .to_json()
+        .starts_with(r#"{"verdict":"AC","message":null,"time_ms":"#));
+    let judgement = judge("<", b"", b"", &limits);
+    assert!(judgement
+        .to_json()
+        .contains(r#""message":"moved off the left end"#));
+}
+
+#[test]
+fn interactive_script() {
+    let timeout = Duration::from_millis(200);
+    let echo = Interpreter::from_instructions(bfc_ir::parse(",[.,]").unwrap(), None);
+
+    let script = Script::new().send("hi").expect("hi").send("!").expect("!");
+    script.run(&echo, timeout).unwrap();
+
+    let script = Script::new().send("a").expect("b");
+    assert!(matches!(
+        script.run(&echo, timeout),
+        Err(ScriptError::UnexpectedOutput { step: 1, .. })
+    ));
+
+    // Nothing was sent so nothing comes back
+    let script = Script::new().expect("a");
+    assert!(matches!(
+        script.run(&echo, Duration::from_millis(20)),
+        Err(ScriptError::TimedOut { step: 0, .. })
+    ));
+
+    let once = Interpreter::from_instructions(bfc_ir::parse(",.").unwrap(), None);
+    let script = Script::new().send("ab").expect("a").expect("b");
+    match script.run(&once, timeout) {
+        Err(ScriptError::Halted { step: 2, output }) => assert!(output.is_empty()),
+        _ => panic!("expected the program to halt"),
+    }
+}
+
+#[test]
+fn optimizer_equivalence() {
+    let program = std::fs::read_to_string("sample_programs/bottles.bf").unwrap();
+    assert!(verify(&program, b"", None).unwrap().is_none());
+
+    // Out of iterations at different points, but the output so far agrees
+    assert!(verify("+[.+]", b"", NonZeroU64::new(1000))
+        .unwrap()
+        .is_none());
+    assert!(verify("[", b"", NonZeroU64::new(1000)).is_err());
+}
+
+#[test]
+fn fuzz_random_programs() {
+    assert_eq!(fuzz::program(b"\x07\x06\x00"), "[[+]]");
+
+    let mut rng = fuzz::Rng::new(0xbf);
+    for _ in 0..200 {
+        let program = rng.program(32);
+        let input = rng.input(4);
+        assert!(bfc_ir::parse(&program).is_ok(), "{}", program);
+
+        if let Err(fuzz::Failure::Panicked(message)) = fuzz::check(&program, &input) {
+            panic!("{:?} with input {:?} panicked: {}", program, input, message);
         }
     }
 }
 
+#[cfg(feature = "proptest")]
+proptest::proptest! {
+    #[test]
+    fn strategies_generate_runnable_programs(
+        program in crate::strategy::program(32),
+        input in crate::strategy::input(4),
+    ) {
+        proptest::prop_assert!(bfc_ir::parse(&program).is_ok());
+        proptest::prop_assert!(!matches!(
+            fuzz::check(&program, &input),
+            Err(fuzz::Failure::Panicked(_))
+        ));
+    }
+}
+
 #[test]
-fn inputs() {
-    test_blocking(",.,.,.", vec![1, 2, 3], vec![1, 2, 3], u64::MAX);
+fn rust_backend() {
+    let program = std::fs::read_to_string("sample_programs/hello_world.bf").unwrap();
+    let (instructions, _) =
+        bfc_ir::optimize(bfc_ir::parse(&program).unwrap(), OptimisationsFlags::all());
+    let bytecode = Bytecode::compile(&instructions).optimize(Pass::ALL);
+
+    let source = to_rust(&bytecode, &CodegenOptions::default()).unwrap();
+    assert!(source.contains("type Cell = u8;"));
+    assert!(source.contains("const TAPE_SIZE: usize = 30000;"));
+    assert!(source.contains("while m.cell() != 0 {"));
+    assert!(!source.contains("m.step();"));
+    assert_eq!(source.matches('{').count(), source.matches('}').count());
+
+    let options = CodegenOptions {
+        max_iterations: NonZeroU64::new(1000),
+        cell_width: CellWidth::U16,
+        eof_behavior: EofBehavior::Zero,
+        ..Default::default()
+    };
+    let source = to_rust(
+        &Bytecode::compile(&bfc_ir::parse(",[.,]").unwrap()),
+        &options,
+    )
+    .unwrap();
+    assert!(source.contains("type Cell = u16;"));
+    assert!(source.contains("None => self.tape[index] = 0,"));
+    // Every op counts an iteration and loops check their condition at both ends
+    assert_eq!(source.matches("m.step();").count(), 5);
+    assert!(source.contains("if m.cell() == 0 {"));
+    assert_eq!(source.matches('{').count(), source.matches('}').count());
+}
+
+#[test]
+fn c_backend() {
+    let bytecode = Bytecode::compile(&bfc_ir::parse(",[.,]").unwrap());
+    let source = to_c(&bytecode, &CodegenOptions::default()).unwrap();
+    assert!(source.contains("typedef uint8_t cell;"));
+    assert!(source.contains("while (tape[at(0)] != 0) {"));
+    assert!(source.contains(r#"fail("read past the end of the input");"#));
+    assert_eq!(source.matches('{').count(), source.matches('}').count());
+
+    let options = CodegenOptions {
+        max_iterations: NonZeroU64::new(1000),
+        ..Default::default()
+    };
+    let source = to_c(&bytecode, &options).unwrap();
+    assert_eq!(source.matches("step();").count(), 5);
+    assert_eq!(source.matches('{').count(), source.matches('}').count());
+}
+
+#[test]
+fn wasm_backend() {
+    let bytecode = Bytecode::compile(&bfc_ir::parse(",[.,]").unwrap());
+    let module = to_wasm(&bytecode, &CodegenOptions::default()).unwrap();
+    assert_eq!(module[..8], *b"\0asm\x01\0\0\0");
+
+    let contains = |name: &[u8]| module.windows(name.len()).any(|window| window == name);
+    for name in [&b"read"[..], b"write", b"fail", b"run", b"memory"] {
+        assert!(contains(name), "{:?}", String::from_utf8_lossy(name));
+    }
+
+    // A 30000 byte tape fits in a single 64KiB page, 30000 u32s need two
+    let memory = |module: &[u8]| {
+        let section = module.windows(4).position(|w| w == [5, 3, 1, 0]).unwrap();
+        module[section + 4]
+    };
+    assert_eq!(memory(&module), 1);
+
+    let options = CodegenOptions {
+        cell_width: CellWidth::U32,
+        ..Default::default()
+    };
+    assert_eq!(memory(&to_wasm(&bytecode, &options).unwrap()), 2);
 }
 
 #[test]
-fn bang_bang_bf() {
-    test_file(
-        "sample_programs/bangbang.bf",
-        "sample_programs/bangbang.bf.out",
+fn brainfuck_backend() {
+    let bytecode = Bytecode::compile(&bfc_ir::parse("+++[->++>-<<]>[>]#").unwrap())
+        .optimize(Pass::ALL)
+        .with_dumps("+++[->++>-<<]>[>]#");
+    assert_eq!(to_brainfuck(&bytecode), "+++[->++>-<<]>[>]#\n");
+
+    for name in ["hello_world", "bottles"] {
+        let program = std::fs::read_to_string(format!("sample_programs/{}.bf", name)).unwrap();
+        let expected = std::fs::read(format!("sample_programs/{}.bf.out", name)).unwrap();
+
+        let (instructions, _) =
+            bfc_ir::optimize(bfc_ir::parse(&program).unwrap(), OptimisationsFlags::all());
+        let optimized = to_brainfuck(&Bytecode::compile(&instructions).optimize(Pass::ALL));
+        assert_eq!(
+            execute_outcome(&optimized, vec![], None, CellWidth::U8)
+                .unwrap()
+                .into_result()
+                .unwrap(),
+            expected
+        );
+    }
+}
+
+#[test]
+fn ook() {
+    let program = "Ook. Ook. Ook. Ook. Ook! Ook? Ook. Ook? Ook. Ook.\nOok? Ook. Ook! Ook! Ook? Ook!\nOok. Ook? Ook! Ook.";
+    let translated = Dialect::Ook.translate(program).unwrap();
+    assert_eq!(translated.len(), program.len());
+    assert_eq!(
+        translated.split_whitespace().collect::<String>(),
+        "++[>+<-]>."
+    );
+
+    // Commands sit where their tokens started
+    assert_eq!(&translated[20..21], "[");
+    assert_eq!(&program[20..23], "Ook");
+
+    assert_eq!(
+        execute_outcome(&translated, vec![], None, CellWidth::U8)
+            .unwrap()
+            .into_result()
+            .unwrap(),
+        vec![2]
     );
+
+    let err = Dialect::Ook.translate("Ook. Ook. Ook?").unwrap_err();
+    assert_eq!(err.position.start, 10);
+    let err = Dialect::Ook.translate("Ook. Ook. Ook? Ook?").unwrap_err();
+    assert_eq!(err.position.start, 10);
+    assert!(Dialect::Ook.parse("Ook! Ook?").is_err());
+
+    assert_eq!(Dialect::from_path("hello.ook"), Dialect::Ook);
+    assert_eq!(Dialect::from_path("hello.bf"), Dialect::Brainfuck);
+    assert_eq!("ook".parse(), Ok(Dialect::Ook));
 }
 
 #[test]
-fn bottles_bf() {
-    test_file(
-        "sample_programs/bottles.bf",
-        "sample_programs/bottles.bf.out",
+fn alphabets() {
+    let alphabet: Alphabet = "r l inc dec out in loop end".parse().unwrap();
+    // `inc` would otherwise be read as `in` followed by `c`
+    assert_eq!(
+        alphabet.translate("inc inc\nloop r inc l dec end r out"),
+        "+   +  \n[    > +   < -   ]   > .  "
+    );
+    assert!(parse_with_alphabet("loop", &alphabet).is_err());
+
+    assert_eq!(Alphabet::alphuck().translate("eepaeciscaj"), "++[>+<-]<>.");
+    assert_eq!(
+        Alphabet::reversefuck().translate("--]<->+[<>,"),
+        "++[>+<-]><."
     );
+    assert_eq!("alphuck".parse(), Ok(Alphabet::alphuck()));
+
+    assert!("a b c".parse::<Alphabet>().is_err());
+    assert!("a a b c d e f g".parse::<Alphabet>().is_err());
 }
 
 #[test]
-fn hello_world_bf() {
-    test_file(
-        "sample_programs/hello_world.bf",
-        "sample_programs/hello_world.bf.out",
+fn pbrain_procedures() {
+    // Procedure 1 prints and increments the current cell, it's called twice
+    let bytecode = Dialect::Pbrain.compile("+(.+):-:").unwrap();
+    assert!(bytecode.needs_interpreter());
+    // Only the interpreter runs procedures, the backends point at the first one
+    let err = to_c(&bytecode, &CodegenOptions::default()).unwrap_err();
+    assert!(matches!(bytecode.ops()[err.pc], Op::Procedure { .. }));
+    assert!(to_rust(&bytecode, &CodegenOptions::default()).is_err());
+    assert!(to_wasm(&bytecode, &CodegenOptions::default()).is_err());
+    let interpreter = Interpreter::builder().build_bytecode(bytecode.optimize(Pass::ALL));
+    assert_eq!(
+        interpreter.run_outcome(vec![]).into_result().unwrap(),
+        vec![1, 1]
+    );
+
+    // Procedure 2 calls procedure 1, which adds 6 to the current cell and prints it
+    let program = "+(>+++[<++>-]<.)+(-:):";
+    let bytecode = Dialect::Pbrain.compile(program).unwrap();
+    let interpreter = Interpreter::builder().build_bytecode(bytecode);
+    assert_eq!(
+        interpreter.run_outcome(vec![]).into_result().unwrap(),
+        vec![7]
     );
+
+    let interpreter = Interpreter::builder().build_bytecode(Dialect::Pbrain.compile(":").unwrap());
+    let (_, err) = interpreter.run_outcome(vec![]).into_result().unwrap_err();
+    assert_eq!(err.kind(), RunTimeErrorKind::UndefinedProcedure);
+
+    assert_eq!(Dialect::Pbrain.compile("+(").unwrap_err().position.start, 1);
+    assert_eq!(Dialect::Pbrain.compile("+)").unwrap_err().position.start, 1);
+    assert!(Dialect::Pbrain.compile("([)]").is_err());
+    assert!(Dialect::Pbrain.translate("+(-)").is_err());
+    assert_eq!(Dialect::Pbrain.translate("+.").unwrap(), "+.");
 }
 
 #[test]
-fn mandelbrot_bf() {
-    test_file(
-        "sample_programs/mandelbrot.bf",
-        "sample_programs/mandelbrot.bf.out",
+fn inline_input() {
+    assert_eq!(split_input(",[.,]!hello"), (",[.,]", Some("hello")));
+    assert_eq!(split_input(",[.,]"), (",[.,]", None));
+    // Only the first `!` splits, and not one inside a loop
+    assert_eq!(split_input("+[!-]!a!b"), ("+[!-]", Some("a!b")));
+
+    let (program, input) = split_input(",.,.!hi");
+    assert_eq!(
+        execute_outcome(program, input.unwrap().bytes(), None, CellWidth::U8)
+            .unwrap()
+            .into_result()
+            .unwrap(),
+        b"hi"
     );
 }
 
 #[test]
-fn multiply_bf() {
-    test_file(
-        "sample_programs/multiply.bf",
-        "sample_programs/multiply.bf.out",
+fn text_io() {
+    assert_eq!(execute_str(",.,.,.", "hé").unwrap(), "hé");
+    assert!(matches!(
+        execute_str(",.", "é"),
+        Err(crate::Error::InvalidUtf8(_))
+    ));
+    assert_eq!(execute_str_lossy(",.", "é").unwrap(), "\u{FFFD}");
+}
+
+#[test]
+fn extensions() {
+    // `@` doubles the current cell, `%` prints the pointer and `^` jumps back to the start
+    let program = "+++@.>>%^@.";
+    let bytecode = Bytecode::compile(&bfc_ir::parse(program).unwrap())
+        .with_extensions(program, &['@', '%', '^']);
+    assert!(bytecode.ops().contains(&Op::Extension { command: '@' }));
+
+    let interpreter = Interpreter::builder()
+        .extension('@', |context: &mut ExtensionContext| {
+            let doubled = context.cell() * 2;
+            context.set_cell(doubled);
+            Ok(())
+        })
+        .extension('%', |context: &mut ExtensionContext| {
+            context.write(context.pointer() as u8)
+        })
+        .extension('^', |context: &mut ExtensionContext| {
+            context.set_pointer(0);
+            Ok(())
+        })
+        .build_bytecode(bytecode.clone());
+    assert_eq!(
+        interpreter.run_outcome(vec![]).into_result().unwrap(),
+        vec![6, 2, 12]
     );
+
+    // Without handlers extensions do nothing
+    let interpreter = Interpreter::builder().build_bytecode(bytecode);
+    assert_eq!(
+        interpreter.run_outcome(vec![]).into_result().unwrap(),
+        vec![3, 0]
+    );
+
+    let interpreter = Interpreter::builder()
+        .extension('@', |context: &mut ExtensionContext| {
+            context.set_pointer(-1);
+            Ok(())
+        })
+        .build_bytecode(Bytecode::default().with_extensions("@", &['@']));
+    let (_, err) = interpreter.run_outcome(vec![]).into_result().unwrap_err();
+    assert_eq!(err.kind(), RunTimeErrorKind::OutOfBoundsLeft);
+}
+
+#[test]
+fn off_tape_accesses() {
+    // Lenient bounds and extensions can leave the pointer off the tape, every instruction that
+    // uses the cell there fails instead of panicking
+    for program in ["@.", "@,", "@[]", "@+[]", "@[-]", "@[->+<]", "@[>]"] {
+        let bytecode = Bytecode::compile(&bfc_ir::parse(program).unwrap())
+            .optimize(Pass::ALL)
+            .with_extensions(program, &['@']);
+        let interpreter = Interpreter::builder()
+            .tape_size(4)
+            .bounds_check(BoundsCheck::Lenient)
+            .extension('@', |context: &mut ExtensionContext| {
+                context.set_pointer(4);
+                Ok(())
+            })
+            .build_bytecode(bytecode);
+
+        let (_, err) = interpreter.run_outcome(*b"x").into_result().unwrap_err();
+        assert_eq!(
+            err.kind(),
+            RunTimeErrorKind::OutOfBoundsRight,
+            "{}",
+            program
+        );
+    }
+
+    for program in ["Y", "()", ":"] {
+        let dialect = match program {
+            "Y" => Dialect::Brainfork,
+            _ => Dialect::Pbrain,
+        };
+        let bytecode = dialect.compile(&format!("<>>>>>{}", program)).unwrap();
+        let interpreter = Interpreter::builder()
+            .tape_size(4)
+            .bounds_check(BoundsCheck::Lenient)
+            .build_bytecode(bytecode);
+
+        let (_, err) = interpreter.run_outcome(vec![]).into_result().unwrap_err();
+        assert_eq!(
+            err.kind(),
+            RunTimeErrorKind::OutOfBoundsRight,
+            "{}",
+            program
+        );
+    }
+}
+
+#[test]
+fn brainfork_threads() {
+    // After the fork the original counts down from 0 and the new thread from 1, taking turns
+    let bytecode = Dialect::Brainfork.compile("+++Y-.-.").unwrap();
+    assert!(bytecode.ops().contains(&Op::Fork));
+    let interpreter = Interpreter::builder().build_bytecode(bytecode);
+    assert_eq!(
+        interpreter.run_outcome(vec![]).into_result().unwrap(),
+        vec![255, 0, 254, 255]
+    );
+
+    // Each thread forks another further right, the iteration limit covers all of them
+    let bytecode = Dialect::Brainfork.compile("+[Y]").unwrap();
+    let interpreter = Interpreter::builder()
+        .iteration_limit(NonZeroU64::new(1000))
+        .build_bytecode(bytecode);
+    let (_, err) = interpreter.run_outcome(vec![]).into_result().unwrap_err();
+    assert_eq!(err.kind(), RunTimeErrorKind::MaxIterationsExceeded);
+
+    assert!(Dialect::Brainfork.translate("+Y").is_err());
+    assert_eq!(Dialect::Brainfork.translate("+.").unwrap(), "+.");
+}
+
+#[test]
+fn preprocessor() {
+    let source = "%define clear {[-]}\n%define twice {%clear+*2}\n+*3 %twice>*2<";
+    assert_eq!(preprocess(source, None).unwrap(), "\n\n+++ [-]++>><");
+    // Macros can be repeated too, a lone `%` is left alone
+    assert_eq!(
+        preprocess("%define inc {+}%inc*4 100%", None).unwrap(),
+        "++++ 100%"
+    );
+
+    let err = preprocess("+\n  %nope", None).unwrap_err();
+    assert_eq!(err.location().line, 2);
+    assert_eq!(err.location().column, 3);
+    assert_eq!(err.to_string(), "line 2, column 3: `nope` isn't defined");
+
+    // Errors in a macro body point into the whole source
+    let err = preprocess("%define bad {+*}", None).unwrap_err();
+    assert_eq!(err.offset, 14);
+    assert!(preprocess("%include \"does/not/exist.bf\"", None).is_err());
+}
+
+#[test]
+fn generated_text() {
+    for text in [&b"Hello, World!\n"[..], b"", b"\x00\xff\x80\x7f"] {
+        let program = print_string(text);
+        assert_eq!(
+            execute_outcome(&program, vec![], None, CellWidth::U8)
+                .unwrap()
+                .into_result()
+                .unwrap(),
+            text
+        );
+    }
+
+    // Large steps are built with a loop rather than one `+` at a time
+    let program = print_string(b"x");
+    assert!(program.contains('['));
+    assert!(program.len() < 40);
+}
+
+#[test]
+fn lints() {
+    let kinds = |program| {
+        lint(program)
+            .into_iter()
+            .map(|lint| (lint.kind, lint.span))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(kinds("+[-]."), vec![]);
+    assert_eq!(
+        kinds("+[ wait ]."),
+        vec![
+            (LintKind::InfiniteLoop, 1..9),
+            (LintKind::Unreachable, 9..10)
+        ]
+    );
+    assert_eq!(kinds("[comment]+"), vec![(LintKind::DeadLoop, 0..9)]);
+    // A loop always leaves its cell at zero
+    assert_eq!(kinds("+[-][.]"), vec![(LintKind::DeadLoop, 4..7)]);
+    assert_eq!(kinds("><<"), vec![(LintKind::PointerUnderflow, 2..3)]);
+    assert_eq!(kinds("+[<]"), vec![(LintKind::PointerUnderflow, 2..3)]);
+    // Nothing is certain about input
+    assert_eq!(kinds(",[<]"), vec![]);
+    assert_eq!(
+        kinds("][["),
+        vec![
+            (LintKind::UnmatchedClose, 0..1),
+            (LintKind::UnmatchedOpen, 1..2),
+            (LintKind::UnmatchedOpen, 2..3),
+        ]
+    );
+
+    let program = "+\n [ ]\n.";
+    let lints = lint(program);
+    assert_eq!(lints[0].severity(), Severity::Warning);
+    assert!(lints[0]
+        .render(program)
+        .starts_with("warning: this loop never exits\n"));
+    assert!(lints[0].render(program).ends_with("2 |  [ ]\n  |  ^^^"));
+
+    for name in ["hello_world", "bottles", "mandelbrot"] {
+        let program = std::fs::read_to_string(format!("sample_programs/{}.bf", name)).unwrap();
+        assert!(lint(&program).is_empty());
+    }
+}
+
+#[test]
+fn diagnostics() {
+    let program = "+\n[-]";
+    let warning = Diagnostic::from(bfc_ir::Warning {
+        message: "dead code".to_string(),
+        position: Some(bfc_ir::Position { start: 2, end: 4 }),
+    });
+    assert_eq!(warning.span, Some(2..5));
+    assert_eq!(
+        warning.render(program),
+        "warning: dead code\n --> line 2, column 1\n  |\n2 | [-]\n  | ^^^"
+    );
+    assert_eq!(
+        warning.to_json(program),
+        r#"{"severity":"warning","message":"dead code","start":2,"end":5,"line":2,"column":1}"#
+    );
+
+    let warning = Diagnostic {
+        span: None,
+        ..warning
+    };
+    assert_eq!(warning.render(program), "warning: dead code");
+    assert!(warning
+        .to_json(program)
+        .ends_with(r#""line":null,"column":null}"#));
+
+    // The optimizer only runs for byte cells
+    let program = Program::compile_with("+[-]", CellWidth::U16).unwrap();
+    assert!(program.warnings().is_empty());
+
+    let program = "+[-]]";
+    let err = bfc_ir::parse(program).unwrap_err();
+    let diagnostic = Diagnostic::parse_error(program, &err);
+    assert_eq!(diagnostic.severity, Severity::Error);
+    assert_eq!(diagnostic.span, Some(4..5));
+    assert_eq!(diagnostic.notes[0].span, 1..2);
+    let rendered = diagnostic.render(program);
+    assert!(rendered.ends_with(
+        "note: the closest `[` before it is already closed\n --> line 1, column 2\n  |\n1 | +[-]]\n  |  ^"
+    ));
+    assert!(!rendered.contains('\x1b'));
+    assert!(diagnostic
+        .render_color(program)
+        .contains("\x1b[1;31merror\x1b[0m"));
+
+    let program = "[[-]";
+    let diagnostic = Diagnostic::parse_error(program, &bfc_ir::parse(program).unwrap_err());
+    assert_eq!(diagnostic.span, Some(0..1));
+    assert_eq!(diagnostic.notes[0].span, 3..4);
+
+    // Errors that aren't about brackets, like a dialect's, have nothing to pair with
+    let program = "[+](-)";
+    let err = Dialect::Pbrain.translate(program).unwrap_err();
+    let diagnostic = Diagnostic::parse_error(program, &err);
+    assert_eq!(diagnostic.span, Some(3..4));
+    assert!(diagnostic.notes.is_empty());
+}
+
+#[test]
+fn ir_listing() {
+    let program = "+[->+<]";
+    let listing = format_ir(&bfc_ir::parse(program).unwrap(), program);
+    let lines: Vec<&str> = listing.lines().map(str::trim_end).collect();
+    assert_eq!(lines[0], format!("{:39} 1:1", "add 1"));
+    assert_eq!(lines[1], format!("{:39} 1:2-1:7", "loop"));
+    assert_eq!(lines[2], format!("{:39} 1:3", "    add -1"));
+    assert_eq!(lines.len(), 6);
+
+    let program = ",[->+<]>.";
+    let (instructions, _) =
+        crate::optimize(bfc_ir::parse(program).unwrap(), OptimisationsFlags::all());
+    assert!(format_ir(&instructions, program).contains("multiply-move +1 x1"));
+}
+
+#[test]
+fn dot_graph() {
+    let program = "++[->+++<]>[.-]";
+    let (instructions, _) =
+        crate::optimize(bfc_ir::parse(program).unwrap(), OptimisationsFlags::all());
+    let bytecode = Bytecode::compile(&instructions).optimize(Pass::ALL);
+
+    let dot = to_dot(&bytecode, program, None);
+    assert!(dot.starts_with("digraph program {\n"));
+    assert!(dot.trim_end().ends_with('}'));
+    assert!(dot.contains("subgraph cluster_"));
+    assert!(dot.contains("[label=\"non-zero\"]"));
+    assert!(dot.contains("-> end"));
+
+    let interpreter = Interpreter::from_bytecode(bytecode.clone(), None);
+    let (_, profile) = interpreter.profile(vec![]);
+    let dot = to_dot(&bytecode, program, Some(&profile));
+    assert!(dot.contains("1 entries, 6 iterations"));
+}
+
+#[test]
+fn record_replay() {
+    let interpreter = Interpreter::builder()
+        .eof_behavior(EofBehavior::Zero)
+        .build(bfc_ir::parse(",.,.").unwrap());
+
+    let mut output = vec![];
+    let (result, recording) = interpreter.record(&mut &b"a"[..], &mut output);
+    result.unwrap();
+    assert_eq!(output, b"a\0");
+    assert_eq!(
+        recording.reads,
+        vec![
+            InputRead {
+                iteration: 1,
+                input: Some(b'a')
+            },
+            InputRead {
+                iteration: 3,
+                input: None
+            },
+        ]
+    );
+    assert_eq!(recording.output, b"a\0");
+
+    let saved = recording.to_string();
+    assert_eq!(saved, "bfi recording\nread 1 97\nread 3 eof\noutput 6100\n");
+    assert_eq!(saved.parse::<Recording>().unwrap(), recording);
+    assert!("read 1 97\n".parse::<Recording>().is_err());
+
+    assert_eq!(interpreter.replay(&recording).unwrap(), b"a\0");
+
+    let mut tampered = recording.clone();
+    tampered.reads[0].iteration = 2;
+    assert!(matches!(
+        interpreter.replay(&tampered),
+        Err(ReplayError::Read { index: 0, .. })
+    ));
+
+    let mut tampered = recording;
+    tampered.output[1] = b'b';
+    assert_eq!(
+        interpreter.replay(&tampered),
+        Err(ReplayError::Output { offset: 1 })
+    );
+}
+
+#[test]
+fn tape_size() {
+    let fits = Interpreter::builder()
+        .tape_size(4)
+        .build(bfc_ir::parse(">>>+.").unwrap());
+    assert_eq!(fits.run_outcome(vec![]).into_result().unwrap(), vec![1]);
+
+    let overflows = Interpreter::builder()
+        .tape_size(4)
+        .build(bfc_ir::parse(">>>>+.").unwrap());
+    assert!(matches!(
+        overflows.run_outcome(vec![]).into_result(),
+        Err((_, RunTimeError::OutOfBoundsRight(_)))
+    ));
+}
+
+#[test]
+fn growable_tape() {
+    let grows = Interpreter::builder()
+        .tape_size(1)
+        .growable_tape(8)
+        .build(bfc_ir::parse(">>>>>+.").unwrap());
+    assert_eq!(grows.run_outcome(vec![]).into_result().unwrap(), vec![1]);
+
+    let capped = Interpreter::builder()
+        .tape_size(1)
+        .growable_tape(8)
+        .build(bfc_ir::parse(">>>>>>>>+.").unwrap());
+    assert!(matches!(
+        capped.run_outcome(vec![]).into_result(),
+        Err((_, RunTimeError::OutOfBoundsRight(_)))
+    ));
+}
+
+#[test]
+fn cell_width() {
+    // 256 overflows a byte back to zero but not a wider cell
+    let program = format!("{}[>+<[-]]>.", "+".repeat(256));
+
+    for (cell_width, expected) in [(CellWidth::U8, 0), (CellWidth::U16, 1), (CellWidth::U32, 1)] {
+        let output = execute_outcome(&program, vec![], None, cell_width)
+            .unwrap()
+            .into_result()
+            .ok();
+        assert_eq!(output, Some(vec![expected]));
+    }
+}
+
+#[test]
+fn eof_behavior() {
+    let run = |eof_behavior| {
+        Interpreter::builder()
+            .eof_behavior(eof_behavior)
+            .build(bfc_ir::parse("+,.").unwrap())
+            .run_outcome(vec![])
+            .into_result()
+    };
+
+    assert_eq!(run(EofBehavior::Zero).unwrap(), vec![0]);
+    assert_eq!(run(EofBehavior::MinusOne).unwrap(), vec![255]);
+    assert_eq!(run(EofBehavior::Unchanged).unwrap(), vec![1]);
+    assert!(matches!(
+        run(EofBehavior::Error),
+        Err((_, RunTimeError::InputExhausted(_)))
+    ));
+}
+
+#[test]
+fn overflow_behavior() {
+    let run = |overflow, instructions: Vec<AstNode>| {
+        Interpreter::builder()
+            .overflow(overflow)
+            .build_bytecode(Bytecode::compile(&instructions).optimize_for(Pass::ALL, overflow))
+            .run_outcome(vec![])
+            .into_result()
+    };
+    let parsed = |program| bfc_ir::parse(program).unwrap();
+    // 100 times 3 into the next cell
+    let multiply = || {
+        vec![
+            AstNode::Increment {
+                amount: Wrapping(100),
+                offset: 0,
+                position: None,
+            },
+            AstNode::MultiplyMove {
+                changes: [(1, Wrapping(3))].into_iter().collect(),
+                position: None,
+            },
+            AstNode::PointerIncrement {
+                amount: 1,
+                position: None,
+            },
+            AstNode::Write { position: None },
+        ]
+    };
+
+    assert_eq!(
+        run(OverflowBehavior::Wrap, parsed("-.")).unwrap(),
+        vec![255]
+    );
+    assert_eq!(run(OverflowBehavior::Wrap, multiply()).unwrap(), vec![44]);
+    assert_eq!(
+        run(OverflowBehavior::Saturate, parsed("-.")).unwrap(),
+        vec![0]
+    );
+    // `[-]-` is run as a loop and a decrement, not folded into a set to 255
+    assert_eq!(
+        run(OverflowBehavior::Saturate, parsed("+[-]-.")).unwrap(),
+        vec![0]
+    );
+    assert_eq!(
+        run(OverflowBehavior::Saturate, multiply()).unwrap(),
+        vec![255]
+    );
+
+    for instructions in [parsed("-."), parsed("+[-]-."), multiply()] {
+        assert!(matches!(
+            run(OverflowBehavior::Trap, instructions),
+            Err((_, RunTimeError::CellOverflow(_)))
+        ));
+    }
+    assert_eq!(
+        run(OverflowBehavior::Trap, parsed("++++[-].")).unwrap(),
+        vec![0]
+    );
+
+    // `[+]` only clears a cell that wraps, the optimizer mustn't assume it halts otherwise
+    let program = Program::compile("+[+].").unwrap();
+    let limited = |overflow| {
+        Interpreter::builder()
+            .overflow(overflow)
+            .iteration_limit(NonZeroU64::new(10_000))
+            .build_program(&program)
+            .run_outcome(vec![])
+            .into_result()
+    };
+    assert_eq!(limited(OverflowBehavior::Wrap).unwrap(), vec![0]);
+    let (output, err) = limited(OverflowBehavior::Saturate).unwrap_err();
+    assert!(output.is_empty());
+    assert_eq!(err.kind(), RunTimeErrorKind::MaxIterationsExceeded);
+    let (_, err) = limited(OverflowBehavior::Trap).unwrap_err();
+    assert_eq!(err.kind(), RunTimeErrorKind::CellOverflow);
+}
+
+#[test]
+fn circular_tape() {
+    let run = |program| {
+        Interpreter::builder()
+            .tape_size(4)
+            .circular_tape(true)
+            .build_bytecode(Bytecode::compile(&bfc_ir::parse(program).unwrap()).optimize(Pass::ALL))
+            .run_outcome(vec![])
+            .into_result()
+    };
+
+    // Off the left end onto the last cell and back round to the first
+    assert_eq!(run("<+++>.<.").unwrap(), vec![0, 3]);
+    assert_eq!(run(">>>>+.").unwrap(), vec![1]);
+    // Scans wrap around too
+    assert_eq!(run(">+>+>+<<[>]+.").unwrap(), vec![1]);
+    // With every cell set the scan never stops
+    let spin = Interpreter::builder()
+        .tape_size(2)
+        .circular_tape(true)
+        .iteration_limit(NonZeroU64::new(1000))
+        .build_bytecode(Bytecode::compile(&bfc_ir::parse("+>+[>]").unwrap()).optimize(Pass::ALL));
+    assert!(matches!(
+        spin.run_outcome(vec![]).into_result(),
+        Err((_, RunTimeError::MaxIterationsExceeded(_)))
+    ));
+
+    // Offsets wrap the same way as moves
+    let offsets = Bytecode::compile(&[
+        AstNode::Increment {
+            amount: Wrapping(5),
+            offset: -1,
+            position: None,
+        },
+        AstNode::PointerIncrement {
+            amount: 3,
+            position: None,
+        },
+        AstNode::Write { position: None },
+    ]);
+    let interpreter = Interpreter::builder()
+        .tape_size(4)
+        .circular_tape(true)
+        .build_bytecode(offsets);
+    assert_eq!(
+        interpreter.run_outcome(vec![]).into_result().unwrap(),
+        vec![5]
+    );
+}
+
+#[test]
+fn bounds_check() {
+    let run = |bounds_check, program| {
+        Interpreter::builder()
+            .tape_size(4)
+            .bounds_check(bounds_check)
+            .build(bfc_ir::parse(program).unwrap())
+            .run_outcome(vec![])
+            .into_result()
+    };
+    let left = |result| matches!(result, Err((_, RunTimeError::OutOfBoundsLeft(_))));
+    let right = |result| matches!(result, Err((_, RunTimeError::OutOfBoundsRight(_))));
+
+    // Both edge cells are usable
+    for bounds_check in [BoundsCheck::Strict, BoundsCheck::Lenient] {
+        assert_eq!(run(bounds_check, "+.>>>++.").unwrap(), vec![1, 2]);
+    }
+
+    // One past either end is already off the tape
+    assert!(right(run(BoundsCheck::Strict, ">>>>")));
+    assert!(right(run(BoundsCheck::Strict, ">>>><")));
+    assert!(left(run(BoundsCheck::Strict, "<>")));
+
+    // Unless nothing is done there
+    assert_eq!(run(BoundsCheck::Lenient, ">>>><.").unwrap(), vec![0]);
+    assert_eq!(run(BoundsCheck::Lenient, "<<>>+.").unwrap(), vec![1]);
+    assert!(right(run(BoundsCheck::Lenient, ">>>>.")));
+    assert!(right(run(BoundsCheck::Lenient, ">>>>[]")));
+    assert!(left(run(BoundsCheck::Lenient, "<,")));
+    assert!(left(run(BoundsCheck::Lenient, "<-")));
+}
+
+#[test]
+fn negative_cells() {
+    let run = |negative_cells, program| {
+        let (outcome, state) = Interpreter::builder()
+            .tape_size(4)
+            .negative_cells(negative_cells)
+            .build(bfc_ir::parse(program).unwrap())
+            .run_with_state(vec![]);
+        (outcome.into_result(), state)
+    };
+
+    let (result, state) = run(NegativeCells::Offset(2), "<<+>>+[<]>.");
+    assert_eq!(result.unwrap(), vec![1]);
+    assert_eq!(state.origin, 2);
+    assert_eq!(state.pointer, 2);
+    assert_eq!(state.tape.len(), 6);
+    let (result, _) = run(NegativeCells::Offset(2), "<<<");
+    assert!(matches!(result, Err((_, RunTimeError::OutOfBoundsLeft(_)))));
+
+    // Grows by one cell, then doubles to two
+    let (result, state) = run(NegativeCells::Growable(3), "<+<++>>+.<.<.");
+    assert_eq!(result.unwrap(), vec![1, 1, 2]);
+    assert_eq!(state.origin, 2);
+    assert_eq!(state.tape[..3], [Wrapping(2), Wrapping(1), Wrapping(1)]);
+    let (result, state) = run(NegativeCells::Growable(3), "<<<<");
+    assert!(matches!(result, Err((_, RunTimeError::OutOfBoundsLeft(_)))));
+    assert_eq!(state.origin, 3);
+
+    // Offsets left of the start grow the tape too
+    let bytecode = Bytecode::compile(&[AstNode::Increment {
+        amount: Wrapping(7),
+        offset: -3,
+        position: None,
+    }]);
+    let mut machine = Interpreter::builder()
+        .negative_cells(NegativeCells::Growable(8))
+        .build_bytecode(bytecode)
+        .machine();
+    assert!(matches!(machine.step(), Event::Executed));
+    assert_eq!(machine.origin(), 3);
+    assert_eq!(machine.tape()[0], Wrapping(7));
+}
+
+#[test]
+fn streams() {
+    let interpreter = Interpreter::builder()
+        .eof_behavior(EofBehavior::Zero)
+        .build(bfc_ir::parse(",[.,]").unwrap());
+
+    let mut output = vec![];
+    interpreter
+        .run_with_streams(&mut &b"hello"[..], &mut output)
+        .unwrap();
+    assert_eq!(output, b"hello");
+}
+
+#[test]
+fn closures() {
+    let interpreter = Interpreter::from_instructions(bfc_ir::parse(",+.,+.").unwrap(), None);
+
+    let mut input = vec![b'a', b'b'].into_iter();
+    let mut output = vec![];
+    interpreter
+        .run_with_io(|| input.next(), |b| output.push(b))
+        .unwrap();
+    assert_eq!(output, b"bc");
+}
+
+#[test]
+fn step() {
+    let interpreter = Interpreter::from_instructions(bfc_ir::parse(",+.").unwrap(), None);
+    let mut machine = interpreter.machine();
+
+    assert!(matches!(machine.step(), Event::NeedsInput));
+    machine.push_input([7]);
+    assert_eq!(machine.pending_input().collect::<Vec<_>>(), [7]);
+    assert!(matches!(machine.step(), Event::Executed));
+    assert_eq!(machine.pending_input().count(), 0);
+    assert!(matches!(machine.step(), Event::Executed));
+    assert_eq!(machine.tape()[0], Wrapping(8));
+    assert!(matches!(machine.step(), Event::Output(8)));
+    assert!(matches!(machine.step(), Event::Halted));
+    assert_eq!(machine.iterations(), 3);
+}
+
+#[test]
+fn machine_reset() {
+    let interpreter = Interpreter::builder()
+        .initial_memory(vec![2])
+        .fuel(100)
+        .build(bfc_ir::parse(">,<+.").unwrap());
+    let mut machine = interpreter.machine();
+    machine.add_breakpoint(4);
+    machine.push_input([7, 8]);
+
+    assert!(matches!(machine.step_n(10), Event::Breakpoint(4)));
+    assert!(matches!(machine.step(), Event::Output(3)));
+    assert!(matches!(machine.step(), Event::Halted));
+
+    machine.reset();
+    assert_eq!(machine.pc(), 0);
+    assert_eq!(machine.pointer(), 0);
+    assert_eq!(machine.iterations(), 0);
+    assert_eq!(machine.fuel(), Some(100));
+    assert_eq!(machine.tape()[..2], [Wrapping(2), Wrapping(0)]);
+    assert_eq!(machine.pending_input().count(), 0);
+    assert_eq!(machine.breakpoints().collect::<Vec<_>>(), vec![4]);
+
+    machine.push_input([9]);
+    assert!(matches!(machine.step_n(10), Event::Breakpoint(4)));
+    assert_eq!(machine.tape()[1], Wrapping(9));
+}
+
+#[test]
+fn execution_stats() {
+    let interpreter = Interpreter::builder()
+        .tape_size(4)
+        .max_output(1)
+        .iteration_limit(NonZeroU64::new(100))
+        .build(bfc_ir::parse(",>.>>").unwrap());
+
+    let (outcome, stats) = interpreter.run_with_stats([5, 6]);
+    assert!(outcome.error.is_none());
+    assert_eq!(stats.iterations, outcome.iterations);
+    assert_eq!((stats.bytes_read, stats.bytes_written), (1, 1));
+    assert_eq!(stats.peak_pointer, 3);
+    assert_eq!(stats.limits_approached, vec![Limit::Output, Limit::Tape]);
+
+    let (tx, rx, handle) = interpreter.spawn();
+    tx.send(Wrapping(5)).unwrap();
+    assert_eq!(rx.iter().count(), 1);
+    let (result, spawned) = handle.join_with_stats();
+    assert!(result.is_ok());
+    let spawned = spawned.unwrap();
+    assert_eq!(spawned.iterations, stats.iterations);
+    assert_eq!(spawned.limits_approached, stats.limits_approached);
+}
+
+#[test]
+fn fuel() {
+    let interpreter = Interpreter::builder()
+        .fuel(2)
+        .build(bfc_ir::parse("+++.").unwrap());
+    let mut machine = interpreter.machine();
+
+    assert!(matches!(machine.step_n(10), Event::FuelExhausted));
+    assert_eq!(machine.fuel(), Some(0));
+    assert_eq!(machine.iterations(), 2);
+    // Running dry pauses the machine rather than halting it
+    assert!(matches!(machine.step(), Event::FuelExhausted));
+    assert!(!machine.is_halted());
+
+    machine.add_fuel(5);
+    assert!(matches!(machine.step(), Event::Executed));
+    assert!(matches!(machine.step(), Event::Output(3)));
+    assert!(matches!(machine.step(), Event::Halted));
+    assert_eq!(machine.fuel(), Some(3));
+
+    // Machines are unmetered until they're given fuel
+    let mut machine = Interpreter::from_instructions(bfc_ir::parse("+[]").unwrap(), None).machine();
+    assert_eq!(machine.fuel(), None);
+    machine.add_fuel(100);
+    assert!(matches!(machine.step_n(1000), Event::FuelExhausted));
+    assert_eq!(machine.iterations(), 100);
+
+    let outcome = interpreter.run_outcome(vec![]);
+    assert!(matches!(
+        outcome.error,
+        Some(RunTimeError::FuelExhausted(_))
+    ));
+}
+
+#[test]
+fn cancel_spawned() {
+    // One program spins forever and the other waits on input that never arrives
+    for program in ["+[]", ",."] {
+        let interpreter = Interpreter::from_instructions(bfc_ir::parse(program).unwrap(), None);
+        let (_tx, rx, handle) = interpreter.spawn();
+
+        handle.pause();
+        handle.resume();
+        handle.cancel();
+
+        assert!(matches!(rx.recv().unwrap(), Err(RunTimeError::Cancelled)));
+        assert!(matches!(handle.join(), Err(RunTimeError::Cancelled)));
+    }
+}
+
+#[test]
+fn final_state() {
+    let interpreter = Interpreter::from_instructions(bfc_ir::parse("+++>++<<").unwrap(), None);
+    let (outcome, state) = interpreter.run_with_state(vec![]);
+
+    match outcome.error {
+        Some(RunTimeError::OutOfBoundsLeft(context)) => {
+            assert_eq!(context.pointer, -1);
+            assert_eq!(context.iterations, 8);
+            assert_eq!(context.position.map(|p| p.start), Some(7));
+
+            let err = RunTimeError::OutOfBoundsLeft(context);
+            assert_eq!(
+                err.to_string(),
+                "moved off the left end of the tape at offset 7 (pointer -1, after 8 iterations)"
+            );
+
+            // Offsets map onto whichever lines the source is split over
+            let program = "+++>\n++<<";
+            let location = context.location(program).unwrap();
+            assert_eq!((location.line, location.column), (2, 3));
+            assert_eq!(
+                crate::annotate(program, 7, "error"),
+                "error\n --> line 2, column 3\n  |\n2 | ++<<\n  |   ^"
+            );
+        }
+        _ => panic!("expected OutOfBoundsLeft"),
+    }
+    assert_eq!(state.tape[..2], [Wrapping(3), Wrapping(2)]);
+    assert_eq!(state.pointer, -1);
+    assert_eq!(state.iterations, 8);
+
+    // The deadline is only checked every few thousand iterations
+    let forever = Interpreter::from_instructions(bfc_ir::parse("+[]").unwrap(), None);
+    let (outcome, state) = forever.run_with_deadline(vec![], Instant::now());
+    assert!(matches!(outcome.error, Some(RunTimeError::TimedOut)));
+    assert!(state.iterations >= 4096);
+}
+
+#[test]
+fn snapshot_and_restore() {
+    let interpreter = Interpreter::from_instructions(bfc_ir::parse("+.,+.+.").unwrap(), None);
+
+    let mut machine = interpreter.machine();
+    assert!(matches!(machine.step_n(10), Event::Output(1)));
+    assert!(matches!(machine.step_n(10), Event::NeedsInput));
+    machine.push_input([5]);
+    let snapshot = machine.snapshot();
+
+    let mut restored = interpreter.restore(snapshot.clone()).unwrap();
+    assert_eq!(restored.snapshot(), snapshot);
+    assert!(matches!(restored.step_n(10), Event::Output(6)));
+    assert!(matches!(restored.step_n(10), Event::Output(7)));
+    assert!(matches!(restored.step_n(10), Event::Halted));
+}
+
+#[test]
+fn initial_memory() {
+    let interpreter = Interpreter::builder()
+        .tape_size(2)
+        .initial_memory(vec![3, 4, 5])
+        .build(bfc_ir::parse(".>.>.").unwrap());
+
+    assert_eq!(
+        interpreter.run_outcome(vec![]).into_result().unwrap(),
+        vec![3, 4, 5]
+    );
+}
+
+#[test]
+#[allow(deprecated)]
+fn iteration_limit() {
+    let program = bfc_ir::parse("+++[-]").unwrap();
+    let limited = Interpreter::builder()
+        .iteration_limit(NonZeroU64::new(2))
+        .build(program.clone());
+    let (_, err) = limited.run_outcome(vec![]).into_result().unwrap_err();
+    assert_eq!(err.kind(), RunTimeErrorKind::MaxIterationsExceeded);
+
+    // The old `u64` limits took 0 to mean there's no limit
+    let unlimited = Interpreter::builder()
+        .max_iterations(0)
+        .build(program.clone());
+    assert!(unlimited.run_outcome(vec![]).is_ok());
+    assert!(Interpreter::new(program.clone(), 0)
+        .run_outcome(vec![])
+        .is_ok());
+    let (_, err) = Interpreter::new(program, 2)
+        .run_outcome(vec![])
+        .into_result()
+        .unwrap_err();
+    assert_eq!(err.kind(), RunTimeErrorKind::MaxIterationsExceeded);
+
+    assert_eq!(
+        crate::execute("+++[-].", vec![], 0, CellWidth::U8).unwrap(),
+        vec![0]
+    );
+    assert!(matches!(
+        crate::execute("+++[-].", vec![], 1, CellWidth::U8),
+        Err(crate::Error::RunTimeError((
+            _,
+            RunTimeError::MaxIterationsExceeded(_)
+        )))
+    ));
+}
+
+#[test]
+fn optimisations() {
+    assert_eq!("all".parse(), Ok(Optimisations::all()));
+    assert_eq!("none".parse(), Ok(Optimisations::none()));
+
+    let optimisations: Optimisations = "fold-sets, multiply-move,clear-loops".parse().unwrap();
+    assert_eq!(optimisations.flags, OptimisationsFlags::EXTRACT_MULTIPLY);
+    // Passes run in their usual order whatever order they're listed in
+    assert_eq!(optimisations.passes, vec![Pass::ClearLoops, Pass::FoldSets]);
+
+    let err = "combine-increments,unroll"
+        .parse::<Optimisations>()
+        .unwrap_err();
+    assert!(err.starts_with("unknown optimisation `unroll`"));
+}
+
+#[test]
+fn iteration_cost() {
+    let bytecode = Bytecode::compile(&bfc_ir::parse("+[-]").unwrap());
+    let optimized = bytecode.clone().optimize(&[Pass::ClearLoops]);
+    let iterations = |bytecode: &Bytecode, cost| {
+        Interpreter::builder()
+            .iteration_cost(cost)
+            .build_bytecode(bytecode.clone())
+            .run_outcome(vec![])
+            .iterations
+    };
+
+    // The clear loop becomes a single instruction that still costs its three characters
+    assert_eq!(iterations(&bytecode, IterationCost::Instruction), 4);
+    assert_eq!(iterations(&optimized, IterationCost::Instruction), 2);
+    assert_eq!(iterations(&bytecode, IterationCost::Source), 4);
+    assert_eq!(iterations(&optimized, IterationCost::Source), 4);
+
+    let limited = Interpreter::builder()
+        .iteration_limit(NonZeroU64::new(3))
+        .iteration_cost(IterationCost::Source)
+        .build_bytecode(optimized);
+    let (_, err) = limited.run_outcome(vec![]).into_result().unwrap_err();
+    assert_eq!(err.kind(), RunTimeErrorKind::MaxIterationsExceeded);
+
+    // `+++[->+<]` with the loop folded into a multiply is charged what the loop would have run
+    let multiply = Bytecode::compile(&[
+        AstNode::Increment {
+            amount: Wrapping(3),
+            offset: 0,
+            position: Some(bfc_ir::Position { start: 0, end: 2 }),
+        },
+        AstNode::MultiplyMove {
+            changes: [(1, Wrapping(1))].into_iter().collect(),
+            position: Some(bfc_ir::Position { start: 3, end: 8 }),
+        },
+    ]);
+    let unoptimized = Bytecode::compile(&bfc_ir::parse("+++[->+<]").unwrap());
+    assert_eq!(iterations(&unoptimized, IterationCost::Source), 19);
+    assert_eq!(iterations(&multiply, IterationCost::Source), 19);
+    assert_eq!(iterations(&multiply, IterationCost::Instruction), 2);
+
+    // A multiply on a zero cell only pays for the opening test
+    let skipped = Bytecode::compile(&[AstNode::MultiplyMove {
+        changes: [(1, Wrapping(1))].into_iter().collect(),
+        position: Some(bfc_ir::Position { start: 0, end: 5 }),
+    }]);
+    assert_eq!(iterations(&skipped, IterationCost::Source), 1);
+}
+
+#[test]
+fn interpreter_clones_reuse_tapes() {
+    let interpreter = Interpreter::builder()
+        .tape_size(4)
+        .build(bfc_ir::parse("+>++>+++.").unwrap());
+    let clone = interpreter.clone();
+    assert_eq!(interpreter.run_outcome(vec![]).output, vec![3]);
+
+    // The clone shares the tape the first run left behind, it has to start out zeroed again
+    let machine = clone.machine();
+    assert!(machine.tape().iter().all(|cell| cell.0 == 0));
+    assert_eq!(machine.tape().len(), 4);
+    assert_eq!(clone.run_outcome(vec![]).output, vec![3]);
+}
+
+#[test]
+fn output_limit() {
+    let interpreter = Interpreter::builder()
+        .max_output(3)
+        .build(bfc_ir::parse("+[.]").unwrap());
+    let outcome = interpreter.run_outcome(vec![]);
+    assert_eq!(outcome.output, vec![1, 1, 1]);
+    assert!(matches!(
+        outcome.error,
+        Some(RunTimeError::OutputLimitExceeded(_))
+    ));
+
+    // Exactly at the limit is fine
+    let interpreter = Interpreter::builder()
+        .max_output(2)
+        .build(bfc_ir::parse("+..").unwrap());
+    assert_eq!(interpreter.run_outcome(vec![]).output, vec![1, 1]);
+
+    let report = run_tests(
+        "+[.]",
+        &[TestCase::error("forever", "", RunTimeErrorKind::OutputLimitExceeded).max_output(10)],
+    );
+    assert!(report.passed());
+}
+
+#[test]
+fn bounded_output() {
+    let interpreter = Interpreter::builder()
+        .output_capacity(4)
+        .build(bfc_ir::parse("+[.]").unwrap());
+    let (_tx, rx, handle) = interpreter.spawn();
+
+    // The machine blocks once the channel is full instead of running ahead
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(rx.try_iter().count(), 4);
+    assert!(matches!(rx.recv(), Ok(Ok(Wrapping(1)))));
+
+    drop(rx);
+    assert!(matches!(handle.join(), Err(RunTimeError::OutputClosed)));
+}
+
+#[test]
+fn dropped_output_receiver() {
+    let interpreter = Interpreter::from_instructions(bfc_ir::parse("+[.]").unwrap(), None);
+    let (_tx, rx, handle) = interpreter.spawn();
+
+    drop(rx);
+    assert!(matches!(handle.join(), Err(RunTimeError::OutputClosed)));
+}
+
+#[test]
+fn spawned_panic() {
+    let bytecode = Bytecode::compile(&bfc_ir::parse("+@.").unwrap()).with_extensions("+@.", &['@']);
+    let interpreter = Interpreter::builder()
+        .extension('@', |_: &mut ExtensionContext| panic!("boom"))
+        .build_bytecode(bytecode);
+    let (_tx, rx, handle) = interpreter.spawn();
+
+    match rx.recv().unwrap() {
+        Err(RunTimeError::Internal(message)) => assert_eq!(message, "boom"),
+        _ => panic!("expected Internal"),
+    }
+    assert_eq!(
+        handle.join().unwrap_err().kind(),
+        RunTimeErrorKind::Internal
+    );
+}
+
+/// Compiled code can't unwind, panics in callbacks still reach the caller the way they would
+/// from the interpreter
+#[cfg(feature = "jit")]
+#[test]
+fn jit_panic() {
+    let interpreter = Interpreter::builder()
+        .jit(true)
+        .build(bfc_ir::parse(",.").unwrap());
+
+    let panicked = |read_panics: bool| {
+        let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            interpreter.run_with_io(
+                || if read_panics { panic!("boom") } else { Some(1) },
+                |_| panic!("bang"),
+            )
+        }))
+        .unwrap_err();
+        crate::interpreter::panic_message(&*payload)
+    };
+    assert_eq!(panicked(true), "boom");
+    assert_eq!(panicked(false), "bang");
+
+    // The interpreter is still usable afterwards
+    assert_eq!(
+        interpreter.run_outcome(vec![7]).into_result().unwrap(),
+        vec![7]
+    );
+}
+
+#[test]
+fn run_outcome() {
+    let interpreter = Interpreter::from_instructions(bfc_ir::parse(">>+.<").unwrap(), None);
+    let outcome = interpreter.run_outcome(vec![]);
+    assert!(outcome.is_ok());
+    assert_eq!(outcome.output, vec![1]);
+    assert_eq!(outcome.iterations, 5);
+    assert_eq!(outcome.pointer, 1);
+
+    // Failed runs keep their partial output and where they stopped
+    let interpreter = Interpreter::from_instructions(bfc_ir::parse("+.<").unwrap(), None);
+    let outcome = interpreter.run_outcome(vec![]);
+    assert!(matches!(
+        outcome.error,
+        Some(RunTimeError::OutOfBoundsLeft(_))
+    ));
+    assert_eq!(outcome.output, vec![1]);
+    assert_eq!(outcome.pointer, -1);
+
+    #[allow(deprecated)]
+    let result = interpreter.run(vec![]);
+    assert!(matches!(result, Err((output, RunTimeError::OutOfBoundsLeft(_))) if output == [1]));
+}
+
+#[test]
+fn input_starvation() {
+    let interpreter = Interpreter::from_instructions(bfc_ir::parse(",.,.,.").unwrap(), None);
+
+    match interpreter.run_outcome(vec![1, 2]).into_result() {
+        Err((output, RunTimeError::InputExhausted(_))) => assert_eq!(output, vec![1, 2]),
+        _ => panic!("expected InputExhausted"),
+    }
+}
+
+#[test]
+fn deeply_nested_loops() {
+    let depth = 10_000;
+    let program = format!("+{}-{}.", "[".repeat(depth), "]".repeat(depth));
+    let bytecode = Bytecode::compile(&bfc_ir::parse(&program).unwrap());
+
+    // Execution doesn't recurse, so even a tiny stack is enough
+    let output = std::thread::Builder::new()
+        .stack_size(64 * 1024)
+        .spawn(move || {
+            Interpreter::from_bytecode(bytecode, None)
+                .run_outcome(vec![])
+                .into_result()
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+
+    assert_eq!(output.unwrap(), vec![0]);
+}
+
+#[test]
+fn optimization_passes() {
+    let program = bfc_ir::parse(">>+>+>+<<<[-]++[>]<[<]").unwrap();
+    let bytecode = Bytecode::compile(&program).optimize(Pass::ALL);
+
+    assert!(bytecode.ops().contains(&Op::Set {
+        amount: Wrapping(2),
+        offset: 0
+    }));
+    assert!(bytecode.ops().contains(&Op::Scan { stride: 1 }));
+    assert!(bytecode.ops().contains(&Op::Scan { stride: -1 }));
+    assert!(!bytecode
+        .ops()
+        .iter()
+        .any(|op| matches!(op, Op::JumpIfZero { .. })));
+
+    // Scanning right stops past the ones, scanning left stops at the untouched first cell
+    let (outcome, state) = Interpreter::from_bytecode(bytecode, None).run_with_state(vec![]);
+    assert!(outcome.is_ok());
+    assert_eq!(state.pointer, 0);
+
+    // Scanning off the end of the tape is an error like moving there one step at a time
+    let program = bfc_ir::parse("+>+>+>+[>]").unwrap();
+    let bytecode = Bytecode::compile(&program).optimize(&[Pass::ScanLoops]);
+    let interpreter = Interpreter::builder().tape_size(4).build_bytecode(bytecode);
+    assert!(matches!(
+        interpreter.run_outcome(vec![]).into_result(),
+        Err((_, RunTimeError::OutOfBoundsRight(_)))
+    ));
+}
+
+#[test]
+fn breakpoints_and_watchpoints() {
+    let interpreter = Interpreter::from_instructions(bfc_ir::parse("++[>+<-]>.").unwrap(), None);
+    let mut machine = interpreter.machine();
+    machine.add_breakpoint(4);
+    machine.add_watchpoint(1);
+
+    assert!(matches!(machine.step_n(10), Event::Breakpoint(4)));
+    assert_eq!(machine.pointer(), 1);
+
+    // Resuming runs the instruction under the breakpoint, which changes the watched cell
+    match machine.step_n(10) {
+        Event::Watchpoint { index, old, new } => {
+            assert_eq!(index, 1);
+            assert_eq!((old, new), (Wrapping(0), Wrapping(1)));
+        }
+        event => panic!("expected a watchpoint, got {:?}", event),
+    }
+
+    machine.remove_watchpoint(1);
+    assert!(matches!(machine.step_n(10), Event::Breakpoint(4)));
+    assert!(machine.remove_breakpoint(4));
+    assert!(matches!(machine.step_n(100), Event::Output(2)));
+}
+
+#[test]
+fn step_back() {
+    let interpreter = Interpreter::from_instructions(bfc_ir::parse("+>,[-<+>]<.").unwrap(), None);
+    let mut machine = interpreter.machine();
+    machine.keep_history();
+    machine.add_breakpoint(4);
+
+    let mut stops = vec![];
+    loop {
+        match machine.step_n(100) {
+            Event::Breakpoint(_) => stops.push(machine.snapshot()),
+            Event::NeedsInput => machine.push_input([3]),
+            Event::Halted => break,
+            _ => {}
+        }
+    }
+    assert_eq!(stops.len(), 3);
+    let end = machine.snapshot();
+
+    for stop in stops.iter().rev() {
+        assert!(machine.reverse_continue() > 0);
+        assert_eq!(&machine.snapshot(), stop);
+    }
+    assert_eq!(machine.reverse_continue(), 4);
+    assert_eq!(machine.iterations(), 0);
+    assert_eq!(machine.step_back(1), 0);
+
+    // Input queued along the way is read again
+    while !matches!(machine.step_n(100), Event::Halted) {}
+    assert_eq!(machine.snapshot(), end);
+
+    assert_eq!(machine.step_back(1), 1);
+    assert_eq!(machine.iterations(), end.iterations - 1);
+    assert!(matches!(machine.step(), Event::Output(4)));
+}
+
+#[test]
+fn profile() {
+    let program = "++[>+++[>+<-]<-]";
+    let interpreter = Interpreter::from_instructions(bfc_ir::parse(program).unwrap(), None);
+    let (outcome, profile) = interpreter.profile(vec![]);
+    assert!(outcome.is_ok());
+
+    // `+` at offset 4 runs once per outer iteration
+    assert_eq!(profile.count(4), 2);
+
+    let loops: Vec<_> = profile.loops().collect();
+    assert_eq!(loops.len(), 2);
+    assert_eq!((loops[0].entries, loops[0].iterations), (1, 2));
+    assert_eq!((loops[1].entries, loops[1].iterations), (2, 6));
+    assert_eq!(loops[1].position.map(|p| p.start), Some(7));
+
+    assert!(profile.report(program, 1).contains("[>+<-]"));
+}
+
+#[test]
+fn memory_heatmap() {
+    let interpreter = Interpreter::from_instructions(bfc_ir::parse("++>+<[-]>.").unwrap(), None);
+    let (outcome, profile) = interpreter.profile(vec![]);
+    assert!(outcome.is_ok());
+
+    let heatmap = profile.heatmap();
+    assert_eq!(heatmap.len(), 2);
+    // Two `+`, the loop check, `-` twice and the loop close twice
+    assert_eq!((heatmap.reads(0), heatmap.writes(0)), (7, 4));
+    // One `+` and the `.`
+    assert_eq!((heatmap.reads(1), heatmap.writes(1)), (2, 1));
+
+    let rendered = heatmap.render(10);
+    assert_eq!(rendered.lines().count(), 2);
+    assert!(rendered.starts_with("     0 | ##########    11"));
+}
+
+#[test]
+fn dump_instruction() {
+    let program = "+++>++#<[-#]";
+    let bytecode = Bytecode::compile(&bfc_ir::parse(program).unwrap()).with_dumps(program);
+    assert_eq!(bytecode.ops()[6], Op::Dump);
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let interpreter = Interpreter::builder()
+        .dump_state(DumpTarget::Channel(tx))
+        .build_bytecode(bytecode);
+    let (outcome, state) = interpreter.run_with_state(vec![]);
+    assert!(outcome.is_ok());
+
+    // Dumps don't count as iterations
+    assert_eq!(state.iterations, 6 + 2 + 3 * 2);
+
+    let dumps: Vec<String> = rx.try_iter().collect();
+    assert_eq!(dumps.len(), 4);
+    assert_eq!(
+        dumps[0],
+        "# pointer 1, 6 iterations\n# 0: 3 [2] 0 0 0 0 0 0 0 0"
+    );
+}
+
+#[test]
+fn format() {
+    assert_eq!(
+        crate::format("+[->[ comment +<]]."),
+        "+\n[\n    ->\n    [\n        +<\n    ]\n]\n.\n"
+    );
+
+    let options = FormatOptions {
+        width: 10,
+        comments: true,
+    };
+    let formatted = format_with("add two ++ then\nloop [->+<]\n+++++++++++++++", &options);
+    assert_eq!(
+        formatted,
+        "add two\n++\nthen\nloop\n[\n    ->+<\n]\n++++++++++\n+++++\n"
+    );
+    // Formatting is stable
+    assert_eq!(format_with(&formatted, &options), formatted);
+}
+
+#[test]
+fn minify() {
+    assert_eq!(strip_comments("add two ++ and print ."), "++.");
+    assert_eq!(crate::minify("++- >>< [-+]"), "+>[]");
+    assert_eq!(crate::minify("+<>-.<<>"), ".<");
+    // Loops and IO stop cancellation
+    assert_eq!(crate::minify("+[]-+.-"), "+[].-");
+
+    let program = std::fs::read_to_string("sample_programs/hello_world.bf").unwrap();
+    assert_eq!(
+        execute_outcome(&crate::minify(&program), vec![], None, CellWidth::U8)
+            .unwrap()
+            .into_result()
+            .unwrap(),
+        execute_outcome(&program, vec![], None, CellWidth::U8)
+            .unwrap()
+            .into_result()
+            .unwrap()
+    );
+}
+
+#[test]
+fn bench_timings() {
+    let program = std::fs::read_to_string("sample_programs/hello_world.bf").unwrap();
+    let timings = crate::bench::time(&program, b"").unwrap();
+
+    assert!(timings.iterations > 0);
+    assert!(timings.total() >= timings.execute);
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn jit_matches_interpreter() {
+    let program = std::fs::read_to_string("sample_programs/hello_world.bf").unwrap();
+    let (instructions, _) = crate::optimize(
+        bfc_ir::parse(&program).unwrap(),
+        crate::OptimisationsFlags::all(),
+    );
+    let bytecode = Bytecode::compile(&instructions);
+
+    let interpreted = Interpreter::builder().build_bytecode(bytecode.clone());
+    let compiled = Interpreter::builder().jit(true).build_bytecode(bytecode);
+    assert_eq!(
+        compiled.run_outcome(vec![]).into_result().unwrap(),
+        interpreted.run_outcome(vec![]).into_result().unwrap()
+    );
+
+    // Errors carry the same context
+    let interpreted = Interpreter::from_instructions(bfc_ir::parse("+++>++<<").unwrap(), None);
+    let compiled = Interpreter::builder()
+        .jit(true)
+        .build(bfc_ir::parse("+++>++<<").unwrap());
+    match (
+        interpreted.run_outcome(vec![]).into_result(),
+        compiled.run_outcome(vec![]).into_result(),
+    ) {
+        (
+            Err((_, RunTimeError::OutOfBoundsLeft(expected))),
+            Err((_, RunTimeError::OutOfBoundsLeft(context))),
+        ) => assert_eq!(expected, context),
+        _ => panic!("expected OutOfBoundsLeft"),
+    }
+}
+
+#[test]
+fn tree_walker_matches_bytecode() {
+    let hello_world = std::fs::read_to_string("sample_programs/hello_world.bf").unwrap();
+    let programs = [
+        (hello_world.as_str(), &b""[..]),
+        (",[.,]", b"echo"),
+        ("+[]", b""),
+        ("+++>++<<", b""),
+        ("+[>+]", b""),
+        (",.,.", b"a"),
+    ];
+
+    for (program, input) in programs {
+        let builder = Interpreter::builder()
+            .iteration_limit(NonZeroU64::new(10_000))
+            .tape_size(64);
+        let interpreted = builder.clone().build(bfc_ir::parse(program).unwrap());
+        let walked = builder
+            .tree_walker(true)
+            .build(bfc_ir::parse(program).unwrap());
+
+        let expected = interpreted.run_outcome(input.iter().copied());
+        let outcome = walked.run_outcome(input.iter().copied());
+        assert_eq!(outcome.output, expected.output, "{}", program);
+        assert_eq!(outcome.iterations, expected.iterations, "{}", program);
+        assert_eq!(outcome.pointer, expected.pointer, "{}", program);
+        assert_eq!(
+            outcome
+                .error
+                .as_ref()
+                .map(|err| (err.kind(), err.context().copied())),
+            expected
+                .error
+                .as_ref()
+                .map(|err| (err.kind(), err.context().copied())),
+            "{}",
+            program
+        );
+    }
+
+    // Walking doesn't recurse either, the tree is handed back to be dropped on a full stack
+    let depth = 10_000;
+    let program = format!("+{}-{}.", "[".repeat(depth), "]".repeat(depth));
+    let walked = Interpreter::builder()
+        .tree_walker(true)
+        .build(bfc_ir::parse(&program).unwrap());
+    let (_walked, output) = std::thread::Builder::new()
+        .stack_size(64 * 1024)
+        .spawn(move || {
+            let output = walked.run_outcome(vec![]).into_result();
+            (walked, output)
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+
+    assert_eq!(output.unwrap(), vec![0]);
+}
+
+/// Parses `source` through the C API, panicking if it fails
+#[cfg(feature = "capi")]
+fn capi_program(source: &str) -> *mut crate::capi::BfiProgram {
+    use crate::capi::{bfi_parse, BfiStatus};
+
+    let mut program = std::ptr::null_mut();
+    let status = unsafe { bfi_parse(source.as_ptr(), source.len(), 0, &mut program) };
+    assert_eq!(status, BfiStatus::Ok);
+    program
+}
+
+#[cfg(feature = "capi")]
+fn capi_last_error() -> Option<String> {
+    let err = crate::capi::bfi_last_error();
+    (!err.is_null()).then(|| {
+        unsafe { std::ffi::CStr::from_ptr(err) }
+            .to_string_lossy()
+            .into_owned()
+    })
+}
+
+#[cfg(feature = "capi")]
+#[test]
+fn capi_run() {
+    use crate::capi::{bfi_free_output, bfi_program_free, bfi_run, BfiStatus};
+
+    let program = capi_program(",.+.");
+    let (mut output, mut len) = (std::ptr::null_mut(), 0);
+    let status = unsafe { bfi_run(program, b"a".as_ptr(), 1, &mut output, &mut len) };
+    assert_eq!(status, BfiStatus::Ok);
+    assert_eq!(unsafe { std::slice::from_raw_parts(output, len) }, b"ab");
+    unsafe { bfi_free_output(output, len) };
+
+    // No input can be a null pointer
+    let status = unsafe { bfi_run(program, std::ptr::null(), 0, &mut output, &mut len) };
+    assert_eq!(status, BfiStatus::RunTimeError);
+    assert_eq!(len, 0);
+    unsafe { bfi_free_output(output, len) };
+
+    let status = unsafe { bfi_run(program, std::ptr::null(), 1, &mut output, &mut len) };
+    assert_eq!(status, BfiStatus::InvalidArgument);
+    assert_eq!(capi_last_error().unwrap(), "null input");
+    unsafe { bfi_program_free(program) };
+
+    let program = capi_program("+.");
+    let status = unsafe { bfi_run(program, std::ptr::null(), 0, &mut output, &mut len) };
+    assert_eq!(status, BfiStatus::Ok);
+    assert_eq!(unsafe { std::slice::from_raw_parts(output, len) }, [1]);
+    unsafe { bfi_free_output(output, len) };
+    unsafe { bfi_program_free(program) };
+}
+
+#[cfg(feature = "capi")]
+#[test]
+fn capi_output_on_error() {
+    use crate::capi::{bfi_free_output, bfi_program_free, bfi_run, BfiStatus};
+
+    // The output written before the error is the caller's to free
+    let program = capi_program("+.+.<");
+    let (mut output, mut len) = (std::ptr::null_mut(), 0);
+    let status = unsafe { bfi_run(program, std::ptr::null(), 0, &mut output, &mut len) };
+    assert_eq!(status, BfiStatus::RunTimeError);
+    assert_eq!(unsafe { std::slice::from_raw_parts(output, len) }, [1, 2]);
+    assert!(capi_last_error().unwrap().contains("left"));
+    unsafe {
+        bfi_free_output(output, len);
+        bfi_program_free(program);
+    }
+}
+
+#[cfg(feature = "capi")]
+#[test]
+fn capi_last_error_lifetime() {
+    use crate::capi::{bfi_last_error, bfi_parse, bfi_program_free, bfi_run, BfiStatus};
+
+    let mut program = std::ptr::null_mut();
+    let status = unsafe { bfi_parse(b"[".as_ptr(), 1, 0, &mut program) };
+    assert_eq!(status, BfiStatus::ParseError);
+    assert!(program.is_null());
+    let err = bfi_last_error();
+    let message = capi_last_error().unwrap();
+
+    // Calls that succeed leave the last error alone
+    let program = capi_program("+.");
+    let (mut output, mut len) = (std::ptr::null_mut(), 0);
+    let status = unsafe { bfi_run(program, std::ptr::null(), 0, &mut output, &mut len) };
+    assert_eq!(status, BfiStatus::Ok);
+    assert_eq!(bfi_last_error(), err);
+    assert_eq!(
+        unsafe { std::ffi::CStr::from_ptr(err) }.to_string_lossy(),
+        message
+    );
+    unsafe { crate::capi::bfi_free_output(output, len) };
+
+    // Until the next failure replaces it
+    let status = unsafe { bfi_parse(std::ptr::null(), 0, 0, &mut std::ptr::null_mut()) };
+    assert_eq!(status, BfiStatus::InvalidArgument);
+    assert_eq!(capi_last_error().unwrap(), "null pointer");
+    let status = unsafe { bfi_parse([0xff].as_ptr(), 1, 0, &mut std::ptr::null_mut()) };
+    assert_eq!(status, BfiStatus::InvalidArgument);
+    assert!(capi_last_error().unwrap().contains("utf-8"));
+    unsafe { bfi_program_free(program) };
+
+    // Errors are per thread
+    assert!(std::thread::spawn(|| bfi_last_error().is_null())
+        .join()
+        .unwrap());
+}
+
+#[cfg(feature = "capi")]
+#[test]
+fn capi_callbacks() {
+    use crate::capi::{bfi_program_free, bfi_run_with_callbacks, BfiStatus};
+    use std::ffi::{c_int, c_void};
+
+    struct Buffers {
+        input: Vec<u8>,
+        output: Vec<u8>,
+    }
+    extern "C" fn read(user_data: *mut c_void) -> c_int {
+        let buffers = unsafe { &mut *(user_data as *mut Buffers) };
+        if buffers.input.is_empty() {
+            -1
+        } else {
+            buffers.input.remove(0) as c_int
+        }
+    }
+    extern "C" fn write(user_data: *mut c_void, byte: u8) {
+        let buffers = unsafe { &mut *(user_data as *mut Buffers) };
+        buffers.output.push(byte);
+    }
+
+    let program = capi_program(",[.,]");
+    let mut buffers = Buffers {
+        input: b"hi".to_vec(),
+        output: Vec::new(),
+    };
+    let user_data = &mut buffers as *mut Buffers as *mut c_void;
+    let status = unsafe { bfi_run_with_callbacks(program, Some(read), Some(write), user_data) };
+    // Reading past the end of the input fails like any other run
+    assert_eq!(status, BfiStatus::RunTimeError);
+    assert_eq!(buffers.output, b"hi");
+
+    let status = unsafe { bfi_run_with_callbacks(program, None, Some(write), user_data) };
+    assert_eq!(status, BfiStatus::InvalidArgument);
+    unsafe { bfi_program_free(program) };
+}
+
+#[test]
+fn program_reuse() {
+    let program = Program::compile(",.\n,.").unwrap();
+
+    assert_eq!(program.run(*b"ab", NonZeroU64::new(1000)).unwrap(), b"ab");
+    assert_eq!(program.run(*b"cd", NonZeroU64::new(1000)).unwrap(), b"cd");
+    match program.tests(
+        vec![b"xy".to_vec(), b"yz".to_vec()].into_iter(),
+        vec![b"xy".to_vec(), b"zz".to_vec()].into_iter(),
+        NonZeroU64::new(1000),
+    ) {
+        TestResults::Results(results) => {
+            assert!(matches!(results[0].outcome, TestOutcome::Ok));
+            assert!(matches!(
+                results[1].outcome,
+                TestOutcome::UnexpectedOutput { .. }
+            ));
+        }
+        _ => panic!("expected results"),
+    }
+
+    // The source map points back into the original text
+    let interpreter = Interpreter::from_program(&program, NonZeroU64::new(1000));
+    assert_eq!(interpreter.bytecode(), program.bytecode());
+    let last = program.bytecode().ops().len() - 1;
+    assert_eq!(program.location(last).unwrap().line, 2);
+
+    assert!(Program::compile("[").is_err());
+}
+
+#[cfg(feature = "macros")]
+#[test]
+fn bf_macro() {
+    let program = crate::bf!("++[->+++<]>.");
+
+    assert_eq!(program, Program::compile("++[->+++<]>.").unwrap());
+    assert_eq!(program.run(vec![], None).unwrap(), vec![6]);
+}
+
+#[test]
+fn bytecode_jump_targets() {
+    let instructions = bfc_ir::parse("+[-]").unwrap();
+    let bytecode = Bytecode::compile(&instructions);
+
+    assert_eq!(
+        bytecode.ops(),
+        &[
+            Op::Add {
+                amount: Wrapping(1),
+                offset: 0
+            },
+            Op::JumpIfZero { target: 4 },
+            Op::Add {
+                amount: Wrapping(u32::MAX),
+                offset: 0
+            },
+            Op::JumpIfNonZero { target: 2 },
+        ]
+    );
+}
+
+bf_test! {
+    bangbang => "sample_programs/bangbang.bf",
+    bottles => "sample_programs/bottles.bf",
+    hello_world => "sample_programs/hello_world.bf",
+    hello_world_ook => "sample_programs/hello_world.ook",
+    mandelbrot => "sample_programs/mandelbrot.bf",
+    multiply => "sample_programs/multiply.bf",
 }
@@ -0,0 +1,12 @@
+use tracing::Span;
+
+/// Enters `span` without holding on to a guard, so a loop's span can stay open across calls to
+/// `Machine::step` while the machine remains `Send`
+pub(crate) fn enter(span: &Span) {
+    span.with_subscriber(|(id, dispatch)| dispatch.enter(id));
+}
+
+/// Exits a span opened with [`enter`]
+pub(crate) fn exit(span: &Span) {
+    span.with_subscriber(|(id, dispatch)| dispatch.exit(id));
+}
@@ -0,0 +1,245 @@
+//! Runs programs by walking `bfc_ir`'s tree directly, how bfi ran everything before programs were
+//! lowered to [`Bytecode`](crate::Bytecode). It's much slower but has none of the bytecode's
+//! moving parts, which makes it something to check the bytecode interpreter against, see
+//! [`InterpreterBuilder::tree_walker`](crate::InterpreterBuilder::tree_walker).
+
+use alloc::{vec, vec::Vec};
+use core::num::{NonZeroU64, Wrapping};
+
+use bfc_ir::{AstNode, Position};
+
+use crate::{
+    interpreter::{
+        BoundsCheck, CellWidth, Config, EofBehavior, ErrorContext, IterationCost, NegativeCells,
+        OverflowBehavior, RunTimeError,
+    },
+    machine::Io,
+};
+
+/// A program kept as the tree it was parsed into
+#[derive(Debug)]
+pub(crate) struct TreeWalker {
+    instructions: Vec<AstNode>,
+    tape_size: usize,
+    initial_memory: Vec<u8>,
+    eof_behavior: EofBehavior,
+    max_iterations: Option<NonZeroU64>,
+}
+
+impl TreeWalker {
+    /// Whether walking the tree can honour every setting in `config`
+    pub(crate) fn supports(config: &Config) -> bool {
+        config.cell_width == CellWidth::U8
+            && config.overflow == OverflowBehavior::Wrap
+            && config.max_output.is_none()
+            && config.iteration_cost == IterationCost::Instruction
+            && config.fuel.is_none()
+            && config.max_tape_size.is_none()
+            && !config.circular_tape
+            && config.negative_cells == NegativeCells::Error
+            && config.bounds_check == BoundsCheck::Strict
+    }
+
+    pub(crate) fn new(instructions: Vec<AstNode>, config: &Config) -> Self {
+        TreeWalker {
+            instructions,
+            tape_size: config.tape_size.max(config.initial_memory.len()),
+            initial_memory: config.initial_memory.clone(),
+            eof_behavior: config.eof_behavior,
+            max_iterations: config.max_iterations,
+        }
+    }
+
+    /// Runs the program to completion. Iterations are counted the way the bytecode interpreter
+    /// counts them for the unoptimized program, a loop costs one to enter and one for each trip
+    /// back to its start.
+    pub(crate) fn execute(&self, io: &mut dyn Io) -> (Result<(), RunTimeError>, ErrorContext) {
+        let mut tape = vec![Wrapping(0u8); self.tape_size];
+        for (cell, byte) in tape.iter_mut().zip(&self.initial_memory) {
+            *cell = Wrapping(*byte);
+        }
+
+        let mut walk = Walk {
+            tape,
+            pointer: 0,
+            iterations: 0,
+            position: None,
+            max_iterations: self.max_iterations,
+            eof_behavior: self.eof_behavior,
+            io,
+        };
+        let result = walk.run(&self.instructions);
+        (result, walk.context())
+    }
+}
+
+/// A loop body part way through, `position` is the loop's or `None` for the whole program
+struct Frame<'a> {
+    body: &'a [AstNode],
+    next: usize,
+    position: Option<Position>,
+}
+
+/// The state of one run
+struct Walk<'a> {
+    tape: Vec<Wrapping<u8>>,
+    pointer: isize,
+    iterations: u64,
+    /// Where the instruction being run came from, for errors
+    position: Option<Position>,
+    max_iterations: Option<NonZeroU64>,
+    eof_behavior: EofBehavior,
+    io: &'a mut dyn Io,
+}
+
+impl Walk<'_> {
+    /// Walks `instructions` with an explicit stack of loop bodies, so deeply nested programs
+    /// can't overflow the native stack
+    fn run(&mut self, instructions: &[AstNode]) -> Result<(), RunTimeError> {
+        let mut stack = vec![Frame {
+            body: instructions,
+            next: 0,
+            position: None,
+        }];
+
+        while let Some(frame) = stack.last_mut() {
+            let Some(instruction) = frame.body.get(frame.next) else {
+                // The end of a loop body goes back to its start while the cell is non-zero
+                let Some(position) = frame.position else {
+                    break;
+                };
+                self.tick(Some(position))?;
+                if self.current()? != Wrapping(0) {
+                    frame.next = 0;
+                } else {
+                    stack.pop();
+                }
+                continue;
+            };
+            frame.next += 1;
+
+            match instruction {
+                AstNode::Loop { body, position } => {
+                    self.tick(*position)?;
+                    if self.current()? != Wrapping(0) {
+                        stack.push(Frame {
+                            body,
+                            next: 0,
+                            position: *position,
+                        });
+                    }
+                }
+                instruction => self.execute(instruction)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs a single instruction that isn't a loop
+    fn execute(&mut self, instruction: &AstNode) -> Result<(), RunTimeError> {
+        match instruction {
+            AstNode::Increment {
+                amount,
+                offset,
+                position,
+            } => {
+                self.tick(*position)?;
+                let index = self.index(*offset)?;
+                self.tape[index] += Wrapping(amount.0 as u8);
+            }
+            AstNode::PointerIncrement { amount, position } => {
+                self.tick(*position)?;
+                self.pointer += amount;
+                self.index(0)?;
+            }
+            AstNode::Read { position } => {
+                self.tick(*position)?;
+                let index = self.index(0)?;
+                match self.io.read()? {
+                    Some(input) => self.tape[index] = Wrapping(input),
+                    None => match self.eof_behavior {
+                        EofBehavior::Zero => self.tape[index] = Wrapping(0),
+                        EofBehavior::MinusOne => self.tape[index] = Wrapping(u8::MAX),
+                        EofBehavior::Unchanged => {}
+                        EofBehavior::Error => {
+                            return Err(RunTimeError::InputExhausted(self.context()))
+                        }
+                    },
+                }
+            }
+            AstNode::Write { position } => {
+                self.tick(*position)?;
+                let output = self.current()?;
+                self.io.write(output.0)?;
+            }
+            AstNode::Set {
+                amount,
+                offset,
+                position,
+            } => {
+                self.tick(*position)?;
+                let index = self.index(*offset)?;
+                self.tape[index] = Wrapping(amount.0 as u8);
+            }
+            AstNode::MultiplyMove { changes, position } => {
+                self.tick(*position)?;
+                let current = self.current()?;
+                if current != Wrapping(0) {
+                    // Visit the cells in the same order as the bytecode so errors match
+                    let mut changes: Vec<_> = changes.iter().collect();
+                    changes.sort_by_key(|(offset, _)| **offset);
+
+                    for (offset, factor) in changes {
+                        let index = self.index(*offset)?;
+                        self.tape[index] += current * Wrapping(factor.0 as u8);
+                    }
+                    let index = self.index(0)?;
+                    self.tape[index] = Wrapping(0);
+                }
+            }
+            AstNode::Loop { .. } => unreachable!("loops are walked by Walk::run"),
+        }
+
+        Ok(())
+    }
+
+    /// Counts an instruction from `position` towards the iteration limit
+    fn tick(&mut self, position: Option<Position>) -> Result<(), RunTimeError> {
+        self.position = position;
+        self.iterations += 1;
+        match self.max_iterations {
+            Some(max_iterations) if self.iterations > max_iterations.get() => {
+                Err(RunTimeError::MaxIterationsExceeded(self.context()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn current(&mut self) -> Result<Wrapping<u8>, RunTimeError> {
+        let index = self.index(0)?;
+        Ok(self.tape[index])
+    }
+
+    /// Resolves `pointer + offset` into an index into the tape
+    fn index(&self, offset: isize) -> Result<usize, RunTimeError> {
+        let index = self
+            .pointer
+            .checked_add(offset)
+            .ok_or_else(|| RunTimeError::OutOfBoundsRight(self.context()))?;
+
+        match usize::try_from(index) {
+            Ok(index) if index < self.tape.len() => Ok(index),
+            Ok(_) => Err(RunTimeError::OutOfBoundsRight(self.context())),
+            Err(_) => Err(RunTimeError::OutOfBoundsLeft(self.context())),
+        }
+    }
+
+    fn context(&self) -> ErrorContext {
+        ErrorContext {
+            position: self.position,
+            pointer: self.pointer,
+            iterations: self.iterations,
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::{fmt, num::NonZeroU64};
+
+use bfc_ir::ParseError;
+
+use crate::{compile, Bytecode, CellWidth, Interpreter, RunTimeError, RunTimeErrorKind};
+
+/// The result of one run, the output along with the error that stopped it if any
+pub type RunResult = Result<Vec<u8>, (Vec<u8>, RunTimeError)>;
+
+/// An optimized and unoptimized run of the same program that disagreed
+#[derive(Debug)]
+pub struct Divergence {
+    pub optimized: RunResult,
+    pub unoptimized: RunResult,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  optimized: {}", Summary(&self.optimized))?;
+        write!(f, "unoptimized: {}", Summary(&self.unoptimized))
+    }
+}
+
+struct Summary<'a>(&'a RunResult);
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Ok(output) => write!(f, "halted after {} bytes of output", output.len()),
+            Err((output, err)) => write!(f, "{} after {} bytes of output", err, output.len()),
+        }
+    }
+}
+
+/// Runs `program` once with every optimization and once with none, returning how they differed
+/// if they produced different output or stopped for different reasons.
+///
+/// Optimized code takes fewer iterations, so when either run hits `max_iterations` the runs
+/// only have to agree on the output they both got to.
+pub fn verify(
+    program: &str,
+    input: &[u8],
+    max_iterations: Option<NonZeroU64>,
+) -> Result<Option<Divergence>, ParseError> {
+    let optimized = compile(program, max_iterations, CellWidth::U8)?;
+    let unoptimized =
+        Interpreter::from_bytecode(Bytecode::compile(&bfc_ir::parse(program)?), max_iterations);
+
+    let optimized = optimized.run_outcome(input.iter().copied()).into_result();
+    let unoptimized = unoptimized.run_outcome(input.iter().copied()).into_result();
+
+    if agree(&optimized, &unoptimized) {
+        Ok(None)
+    } else {
+        Ok(Some(Divergence {
+            optimized,
+            unoptimized,
+        }))
+    }
+}
+
+fn agree(optimized: &RunResult, unoptimized: &RunResult) -> bool {
+    let limited = Some(RunTimeErrorKind::MaxIterationsExceeded);
+    if kind(optimized) == limited || kind(unoptimized) == limited {
+        let (optimized, unoptimized) = (output(optimized), output(unoptimized));
+        return optimized.starts_with(unoptimized) || unoptimized.starts_with(optimized);
+    }
+
+    kind(optimized) == kind(unoptimized) && output(optimized) == output(unoptimized)
+}
+
+fn output(result: &RunResult) -> &[u8] {
+    match result {
+        Ok(output) | Err((output, _)) => output,
+    }
+}
+
+fn kind(result: &RunResult) -> Option<RunTimeErrorKind> {
+    result.as_ref().err().map(|(_, err)| err.kind())
+}
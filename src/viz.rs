@@ -0,0 +1,201 @@
+//! GraphViz graphs of a program's loops
+//!
+//! Straight runs of instructions become boxes labelled with the source they came from, loops
+//! become diamonds with their bodies drawn in a dashed cluster, and multiply-moves and scans get
+//! nodes of their own since that's where the optimizer did its work.
+//!
+//! ```ignore
+//! let dot = bfi::to_dot(&bytecode, &program, Some(&profile));
+//! std::fs::write("graph.dot", dot)?;
+//! ```
+
+use std::{fmt::Write, ops::Range};
+
+use crate::{profile::excerpt, Bytecode, Location, Op, Profile};
+
+/// Draws the control flow of `bytecode` in GraphViz's DOT language. `program` is the source the
+/// bytecode was compiled from, with a `profile` of a run every node is labelled with how often
+/// it ran.
+pub fn to_dot(bytecode: &Bytecode, program: &str, profile: Option<&Profile>) -> String {
+    let mut graph = Graph {
+        bytecode,
+        program,
+        profile,
+        nodes: String::new(),
+        edges: String::new(),
+        count: 0,
+        pending: vec![("start".to_string(), None)],
+        depth: 1,
+    };
+    graph.block(0..bytecode.len());
+    graph.end();
+
+    let mut dot = String::new();
+    writeln!(dot, "digraph program {{").unwrap();
+    writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+    writeln!(dot, "    start [shape=circle, label=\"start\"];").unwrap();
+    dot.push_str(&graph.nodes);
+    dot.push_str(&graph.edges);
+    writeln!(dot, "}}").unwrap();
+    dot
+}
+
+struct Graph<'a> {
+    bytecode: &'a Bytecode,
+    program: &'a str,
+    profile: Option<&'a Profile>,
+    nodes: String,
+    /// Edges are written after every node, an edge inside a cluster would pull the nodes it
+    /// names into that cluster
+    edges: String,
+    count: usize,
+    /// Nodes waiting for an edge to whatever comes next, with the edge's label
+    pending: Vec<(String, Option<&'static str>)>,
+    /// Indentation of the cluster being drawn
+    depth: usize,
+}
+
+impl Graph<'_> {
+    fn block(&mut self, range: Range<usize>) {
+        let bytecode = self.bytecode;
+        let ops = bytecode.ops();
+        let mut run = None;
+
+        let mut pc = range.start;
+        while pc < range.end {
+            match &ops[pc] {
+                Op::JumpIfZero { target } => {
+                    self.run(run.take(), pc);
+                    self.loop_(pc, target - 1);
+                    pc = *target;
+                    continue;
+                }
+                Op::MultiplyMove { changes } => {
+                    self.run(run.take(), pc);
+                    let changes: Vec<String> = changes
+                        .iter()
+                        .map(|(offset, factor)| format!("{:+} x{}", offset, factor.0 as i32))
+                        .collect();
+                    let label = format!("multiply-move\n{}", changes.join(", "));
+                    self.counted_node(label, "hexagon", pc);
+                }
+                Op::Scan { stride } => {
+                    self.run(run.take(), pc);
+                    self.counted_node(format!("scan {:+}", stride), "hexagon", pc);
+                }
+                _ => {
+                    run.get_or_insert(pc);
+                }
+            }
+            pc += 1;
+        }
+        self.run(run, range.end);
+    }
+
+    /// Draws the straight line instructions from `start` up to `end`
+    fn run(&mut self, start: Option<usize>, end: usize) {
+        let start = match start {
+            Some(start) => start,
+            None => return,
+        };
+
+        let positions: Vec<_> = (start..end)
+            .filter_map(|pc| self.bytecode.position(pc))
+            .collect();
+        let label = match (positions.first(), positions.last()) {
+            (Some(first), Some(last)) => {
+                let mut span = *first;
+                span.end = last.end;
+                excerpt(self.program, span)
+            }
+            _ => format!("{} instructions", end - start),
+        };
+        self.counted_node(label, "box", start);
+    }
+
+    fn loop_(&mut self, open: usize, close: usize) {
+        let mut label = match self.bytecode.position(open) {
+            Some(position) => {
+                let location = Location::new(self.program, position.start);
+                format!("loop at {}:{}", location.line, location.column)
+            }
+            None => "loop".to_string(),
+        };
+        if let Some(profile) = self.profile {
+            let (entries, iterations) = profile
+                .loops()
+                .find(|profile| profile.pc == open)
+                .map_or((0, 0), |profile| (profile.entries, profile.iterations));
+            write!(label, "\n{} entries, {} iterations", entries, iterations).unwrap();
+        }
+        let head = self.node(label, "diamond");
+
+        let indent = "    ".repeat(self.depth);
+        writeln!(self.nodes, "{}subgraph cluster_{} {{", indent, head).unwrap();
+        writeln!(self.nodes, "{}    style=dashed;", indent).unwrap();
+        self.depth += 1;
+        self.pending = vec![(head.clone(), Some("non-zero"))];
+        self.block(open + 1..close);
+        self.depth -= 1;
+        writeln!(self.nodes, "{}}}", indent).unwrap();
+
+        // Back to the check at the top
+        for (from, label) in std::mem::take(&mut self.pending) {
+            self.edge(&from, &head, label);
+        }
+        self.pending = vec![(head, Some("zero"))];
+    }
+
+    /// Adds a node for the instruction at `pc`, noting how often it ran if there's a profile
+    fn counted_node(&mut self, mut label: String, shape: &str, pc: usize) {
+        if let Some(profile) = self.profile {
+            write!(label, "\nran {} times", profile.count(pc)).unwrap();
+        }
+        self.node(label, shape);
+    }
+
+    /// Adds a node that whatever came before leads to, returning its name
+    fn node(&mut self, label: String, shape: &str) -> String {
+        self.count += 1;
+        let name = format!("n{}", self.count);
+
+        writeln!(
+            self.nodes,
+            "{}{} [shape={}, label=\"{}\"];",
+            "    ".repeat(self.depth),
+            name,
+            shape,
+            escape(&label)
+        )
+        .unwrap();
+
+        for (from, label) in std::mem::take(&mut self.pending) {
+            self.edge(&from, &name, label);
+        }
+        self.pending = vec![(name.clone(), None)];
+        name
+    }
+
+    fn end(&mut self) {
+        writeln!(self.nodes, "    end [shape=circle, label=\"end\"];").unwrap();
+        for (from, label) in std::mem::take(&mut self.pending) {
+            self.edge(&from, "end", label);
+        }
+    }
+
+    fn edge(&mut self, from: &str, to: &str, label: Option<&str>) {
+        match label {
+            Some(label) => writeln!(self.edges, "    {} -> {} [label=\"{}\"];", from, to, label),
+            None => writeln!(self.edges, "    {} -> {};", from, to),
+        }
+        .unwrap();
+    }
+}
+
+/// Quotes a label for DOT, newlines become line breaks
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
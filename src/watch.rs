@@ -0,0 +1,299 @@
+//! `bfi watch`, a live view of the tape while a program runs
+
+use std::{
+    io::{self, Stdout},
+    time::{Duration, Instant},
+};
+
+use bfi::{Event, Location, Machine};
+use crossterm::{
+    event::{self, Event as TerminalEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Paragraph, Wrap},
+    Frame, Terminal,
+};
+
+/// Slowest and fastest speeds in instructions per second
+const MIN_SPEED: u32 = 1;
+const MAX_SPEED: u32 = 1_000_000;
+/// The screen is redrawn at most this often
+const FRAME_RATE: u32 = 30;
+/// Width of one cell in the tape view
+const CELL_WIDTH: usize = 5;
+
+const HELP: &str = "space pause/resume  s step  + faster  - slower  q quit";
+
+/// Runs `machine` until it halts and the user quits. `program` is the source it was compiled
+/// from, unoptimized so each step is one command. Starts at `speed` instructions per second.
+pub fn watch(machine: Machine, program: &str, speed: u32) -> io::Result<()> {
+    enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+
+    let mut app = App {
+        machine,
+        program,
+        speed: speed.clamp(MIN_SPEED, MAX_SPEED),
+        running: true,
+        output: Vec::new(),
+        status: "running".to_string(),
+        waiting: false,
+    };
+    let result = app.run(&mut terminal);
+
+    // Always give the terminal back, even if drawing failed
+    disable_raw_mode()?;
+    execute!(io::stdout(), LeaveAlternateScreen)?;
+    result
+}
+
+struct App<'a> {
+    machine: Machine,
+    program: &'a str,
+    speed: u32,
+    running: bool,
+    output: Vec<u8>,
+    /// Why the machine stopped, or that it's running
+    status: String,
+    /// The program is blocked on a read, keys are sent to it as input
+    waiting: bool,
+}
+
+impl App<'_> {
+    fn run(&mut self, terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> io::Result<()> {
+        let mut last_step = Instant::now();
+
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            // Slow speeds take a step per frame, fast ones batch up the steps between frames
+            let interval = Duration::from_secs(1) / self.speed.min(FRAME_RATE);
+            let timeout = interval.saturating_sub(last_step.elapsed());
+            if event::poll(timeout)? {
+                if let TerminalEvent::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press && !self.key(key) {
+                        return Ok(());
+                    }
+                }
+            }
+
+            if self.running && last_step.elapsed() >= interval {
+                last_step = Instant::now();
+                self.advance((self.speed / FRAME_RATE).max(1));
+            }
+        }
+    }
+
+    /// Handles a key press, returning `false` to quit
+    fn key(&mut self, key: KeyEvent) -> bool {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Esc => return false,
+            KeyCode::Char('c') if control => return false,
+            // While the program is reading, typing is input rather than commands
+            KeyCode::Char('d') if control && self.waiting => {
+                self.machine.close_input();
+                self.resume();
+            }
+            KeyCode::Char(c) if self.waiting => {
+                let mut buffer = [0; 4];
+                self.machine.push_input(c.encode_utf8(&mut buffer).bytes());
+                self.resume();
+            }
+            KeyCode::Enter if self.waiting => {
+                self.machine.push_input([b'\n']);
+                self.resume();
+            }
+            KeyCode::Char('q') => return false,
+            KeyCode::Char(' ') if !self.machine.is_halted() => {
+                self.running = !self.running;
+                self.status = if self.running { "running" } else { "paused" }.to_string();
+            }
+            KeyCode::Char('s') => {
+                self.running = false;
+                self.status = "paused".to_string();
+                self.advance(1);
+            }
+            KeyCode::Char('+') => self.speed = (self.speed * 2).min(MAX_SPEED),
+            KeyCode::Char('-') => self.speed = (self.speed / 2).max(MIN_SPEED),
+            _ => {}
+        }
+        true
+    }
+
+    fn resume(&mut self) {
+        self.waiting = false;
+        self.running = true;
+        self.status = "running".to_string();
+    }
+
+    /// Runs up to `n` instructions, stopping if the program halts or needs input
+    fn advance(&mut self, n: u32) {
+        for _ in 0..n {
+            match self.machine.step() {
+                Event::Executed | Event::Breakpoint(_) | Event::Watchpoint { .. } => {}
+                Event::Output(output) => self.output.push(output),
+                Event::NeedsInput => {
+                    self.running = false;
+                    self.waiting = true;
+                    self.status =
+                        "waiting for input, type to send it or ctrl-d to close".to_string();
+                    return;
+                }
+                Event::Halted => {
+                    self.running = false;
+                    self.status = "halted".to_string();
+                    return;
+                }
+                Event::FuelExhausted => {
+                    self.running = false;
+                    self.status = "out of fuel".to_string();
+                    return;
+                }
+                Event::Error(err) => {
+                    self.running = false;
+                    self.status = format!("runtime error: {}", err);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let areas = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(1),
+                Constraint::Min(3),
+                Constraint::Length(4),
+                Constraint::Length(6),
+                Constraint::Length(1),
+            ])
+            .split(frame.size());
+
+        frame.render_widget(Paragraph::new(self.summary()), areas[0]);
+        self.draw_program(frame, areas[1]);
+        self.draw_tape(frame, areas[2]);
+        self.draw_io(frame, areas[3]);
+        frame.render_widget(Paragraph::new(HELP), areas[4]);
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "pc {}, pointer {}, {} iterations, {} instructions/s, {}",
+            self.machine.pc(),
+            self.machine.pointer(),
+            self.machine.iterations(),
+            self.speed,
+            self.status
+        )
+    }
+
+    /// The source around the next instruction, which is highlighted
+    fn draw_program(&self, frame: &mut Frame, area: Rect) {
+        let position = self.machine.bytecode().position(self.machine.pc());
+        let current = position.map(|position| Location::new(self.program, position.start));
+
+        let lines: Vec<Line> = self
+            .program
+            .lines()
+            .enumerate()
+            .map(|(index, line)| match (position, current) {
+                (Some(position), Some(location)) if location.line == index + 1 => {
+                    // Split the line around the instruction, by characters
+                    let start = location.column - 1;
+                    let len = self
+                        .program
+                        .get(position.start..=position.end)
+                        .map_or(1, |instruction| instruction.chars().count());
+                    let before: String = line.chars().take(start).collect();
+                    let instruction: String = line.chars().skip(start).take(len).collect();
+                    let after: String = line.chars().skip(start + len).collect();
+                    Line::from(vec![
+                        Span::raw(before),
+                        Span::styled(
+                            instruction,
+                            Style::default().add_modifier(Modifier::REVERSED),
+                        ),
+                        Span::raw(after),
+                    ])
+                }
+                _ => Line::raw(line),
+            })
+            .collect();
+
+        // Keep the current line in the middle of the view
+        let height = area.height.saturating_sub(2) as usize;
+        let line = current.map_or(0, |location| location.line - 1);
+        let scroll = line.saturating_sub(height / 2) as u16;
+
+        let program = Paragraph::new(lines)
+            .block(Block::default().borders(Borders::ALL).title("program"))
+            .scroll((scroll, 0));
+        frame.render_widget(program, area);
+    }
+
+    /// The cells around the pointer with their indices, the current cell highlighted
+    fn draw_tape(&self, frame: &mut Frame, area: Rect) {
+        let tape = self.machine.tape();
+        let pointer = self.machine.pointer();
+        let count = (area.width.saturating_sub(2) as usize / CELL_WIDTH).max(1);
+        let start = (pointer - count as isize / 2).max(0) as usize;
+        let end = (start + count).min(tape.len());
+
+        let mut indices = Vec::new();
+        let mut values = Vec::new();
+        for index in start..end {
+            let style = if index as isize == pointer {
+                Style::default().add_modifier(Modifier::REVERSED)
+            } else {
+                Style::default()
+            };
+            indices.push(Span::raw(format!("{:>width$}", index, width = CELL_WIDTH)));
+            values.push(Span::styled(
+                format!("{:>width$}", tape[index].0, width = CELL_WIDTH),
+                style,
+            ));
+        }
+
+        let tape = Paragraph::new(vec![Line::from(indices), Line::from(values)])
+            .block(Block::default().borders(Borders::ALL).title("tape"));
+        frame.render_widget(tape, area);
+    }
+
+    /// What the program has written and the input it hasn't read yet
+    fn draw_io(&self, frame: &mut Frame, area: Rect) {
+        let halves = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(70), Constraint::Percentage(30)])
+            .split(area);
+
+        // Only the end of the output fits
+        let output = String::from_utf8_lossy(&self.output);
+        let height = area.height.saturating_sub(2) as usize;
+        let lines: Vec<&str> = output.lines().collect();
+        let visible = lines[lines.len().saturating_sub(height)..].join("\n");
+        let output = Paragraph::new(visible)
+            .block(Block::default().borders(Borders::ALL).title("output"))
+            .wrap(Wrap { trim: false });
+        frame.render_widget(output, halves[0]);
+
+        let pending: String = self
+            .machine
+            .pending_input()
+            .flat_map(std::ascii::escape_default)
+            .map(char::from)
+            .collect();
+        let input = Paragraph::new(pending)
+            .block(Block::default().borders(Borders::ALL).title("input"))
+            .wrap(Wrap { trim: false });
+        frame.render_widget(input, halves[1]);
+    }
+}